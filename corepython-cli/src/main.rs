@@ -1,4 +1,4 @@
-use corepython::python_to_wasm;
+use corepython::{python_to_wasm_with_options, CompileOptions};
 
 fn main() {
     let matches = clap::App::new("corepython compiler")
@@ -23,6 +23,21 @@ fn main() {
                 .takes_value(true)
                 .help("output file name"),
         )
+        .arg(
+            clap::Arg::with_name("no-bounds-checks")
+                .long("no-bounds-checks")
+                .help("Do not check list and tuple indices (unsafe)"),
+        )
+        .arg(
+            clap::Arg::with_name("no-wasm-exceptions")
+                .long("no-wasm-exceptions")
+                .help("Raise exceptions without the wasm exception handling proposal"),
+        )
+        .arg(
+            clap::Arg::with_name("no-asserts")
+                .long("no-asserts")
+                .help("Leave out assert statements"),
+        )
         .get_matches();
 
     let log_level = match matches.occurrences_of("v") {
@@ -39,6 +54,12 @@ fn main() {
         .map(std::path::Path::new)
         .unwrap_or_else(|| default_output.as_path());
 
+    let options = CompileOptions {
+        bounds_checks: !matches.is_present("no-bounds-checks"),
+        wasm_exceptions: !matches.is_present("no-wasm-exceptions"),
+        asserts: !matches.is_present("no-asserts"),
+    };
+
     simple_logger::init_with_level(log_level).unwrap();
 
     log::info!("Reading {}", filename.to_string_lossy());
@@ -50,7 +71,7 @@ fn main() {
     );
     let mut file = std::fs::File::create(output_filename).unwrap();
 
-    if let Err(err) = python_to_wasm(&source, &mut file) {
+    if let Err(err) = python_to_wasm_with_options(&source, &mut file, &options) {
         let prefix = match err.location {
             Some(location) => format!(
                 "{}:{}",
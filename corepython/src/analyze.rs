@@ -1,18 +1,33 @@
 //! Check a parsed AST for symbols and types.
 
-use std::collections::HashMap;
+use std::cell::RefCell;
+use std::collections::{HashMap, HashSet};
 use std::rc::Rc;
 
-use super::parser::{ast, Location};
-use super::CompilationError;
+use super::parser::{ast, parse_python, Location};
+use super::{CompilationError, CompileOptions};
+
+/// Builtin classes, such as the exceptions, defined in Python. Their methods
+/// are compiled into every module, but they are not exported.
+const PRELUDE: &str = include_str!("prelude.py");
+
+/// The base class of all exceptions.
+pub const EXCEPTION: &str = "Exception";
+
+/// The scopes of the builtins and of the module, which all functions see.
+const MODULE_SCOPES: usize = 2;
+
+/// Names which the module exports besides the functions, so they cannot
+/// be used for module level functions and globals.
+const RESERVED_NAMES: &[&str] = &["memory", "exception"];
 
 /// Analyze the given AST and construct a type checked and
 /// symbolically resolved program from it.
 ///
 /// Analyze also checks for some simple optimizations,
 /// such as ord('A') --> 65
-pub fn analyze(prog: ast::Program) -> Result<Program, CompilationError> {
-    let a = Analyzer::new();
+pub fn analyze(prog: ast::Program, options: &CompileOptions) -> Result<Program, CompilationError> {
+    let a = Analyzer::new(options);
     a.analyze_program(&prog)
 }
 
@@ -27,6 +42,11 @@ pub enum Symbol {
         local: Rc<Local>,
         index: usize,
     },
+    /// A variable at module level.
+    Global {
+        global: Rc<Global>,
+        index: usize,
+    },
     Function {
         function: Rc<Function>,
         index: usize,
@@ -36,24 +56,72 @@ pub enum Symbol {
         import: Rc<Import>,
     },
     Builtin(Builtin),
-    // TODO:
-    // Type {
-    //     typ: Type,
-    // },
+    /// A name for a type, such as a class.
+    Type {
+        typ: Type,
+    },
+    /// A name for a value known at compile time, as in X: Final = 3
+    Constant {
+        value: Expression,
+    },
+    /// A variable which is used by a nested function, so it is stored in
+    /// the environment of its function instead of in a local.
+    Cell {
+        cell: Rc<Cell>,
+        /// The local or parameter with an environment, which leads to the
+        /// environment with the cell by following this many parent links.
+        environment: Rc<Symbol>,
+        depth: usize,
+    },
+    /// A function with type parameters, which is analyzed for each
+    /// combination of types it is called with.
+    GenericFunction(Rc<GenericFunction>),
+    /// A type variable of generic functions, as in T = TypeVar('T')
+    TypeVariable,
     // Unresolved,
 }
 
 #[derive(Debug)]
+pub struct GenericFunction {
+    name: String,
+    type_parameters: Vec<String>,
+    function_def: ast::FunctionDef,
+}
+
+#[derive(Debug, PartialEq)]
 pub enum Builtin {
     Ord,
     Len,
+    Range,
+    Enumerate,
+    Set,
+    Super,
+    IsInstance,
+    Print,
+    Str,
+    Repr,
+    /// The class decorator from the dataclasses module.
+    Dataclass,
+    /// The base class of enumerations, from the enum module.
+    Enum,
+    /// The annotation for constants, from the typing module.
+    Final,
+    /// The annotation for values which can be None, from the typing module.
+    Optional,
+    /// The annotation for values of one of several types, from the typing module.
+    Union,
+    /// The annotation for functions, from the typing module.
+    Callable,
+    /// Makes type variables for generic functions, from the typing module.
+    TypeVar,
 }
 
 impl Symbol {
-    fn get_type(&self) -> &Type {
+    pub fn get_type(&self) -> &Type {
         match self {
             Symbol::Parameter { parameter, .. } => &parameter.typ,
             Symbol::Local { local, .. } => &local.typ,
+            Symbol::Global { global, .. } => &global.typ,
             Symbol::Builtin(..) => {
                 unimplemented!("TODO!");
             }
@@ -64,6 +132,14 @@ impl Symbol {
             Symbol::ExternFunction { .. } => {
                 unimplemented!();
             }
+            Symbol::Type { .. } => {
+                unimplemented!();
+            }
+            Symbol::Constant { value } => value.get_type(),
+            Symbol::Cell { cell, .. } => &cell.typ,
+            Symbol::GenericFunction(..) | Symbol::TypeVariable => {
+                unimplemented!();
+            }
         }
     }
 }
@@ -72,6 +148,17 @@ impl Symbol {
 pub struct Parameter {
     pub name: String,
     pub typ: Type,
+    /// The constant value when the argument is left out.
+    pub default: Option<Expression>,
+    /// Takes the remaining positional arguments, as a list.
+    pub variadic: bool,
+}
+
+/// An argument passed by name, as in f(3, scale=2.0)
+struct Keyword {
+    location: Location,
+    name: String,
+    value: Expression,
 }
 
 #[derive(Debug)]
@@ -80,9 +167,29 @@ pub struct Local {
     pub typ: Type,
 }
 
+/// A slot in an environment, which holds a variable of a function for its nested functions.
+#[derive(Debug)]
+pub struct Cell {
+    pub typ: Type,
+    pub slot: usize,
+}
+
+#[derive(Debug)]
+pub struct Global {
+    pub typ: Type,
+    /// The value of a global which is never changed, as in WIDTH = 80
+    pub value: Option<Expression>,
+}
+
 pub struct Program {
     pub functions: Vec<Rc<Function>>,
     pub imports: Vec<Rc<Import>>,
+    pub classes: Vec<Rc<Class>>,
+    pub globals: Vec<Rc<Global>>,
+    /// The function with the module level statements, run when the module is instantiated.
+    pub start: Option<usize>,
+    /// The first functions are the methods of the prelude classes, which are only compiled when used.
+    pub prelude_functions: usize,
 }
 
 #[derive(Debug)]
@@ -100,6 +207,65 @@ pub struct Function {
     pub locals: Vec<Rc<Local>>,
     pub body: Suite,
     pub return_type: Option<Type>,
+    /// A nested function, which is called through a closure. Its first
+    /// parameter is the environment of the function defining it.
+    pub closure: bool,
+    /// Holds the variables which nested functions use, if there are any.
+    pub environment: Option<Environment>,
+}
+
+/// The memory with the variables of a function which are used by its nested
+/// functions. It starts with a link to the environment of the enclosing function.
+#[derive(Debug)]
+pub struct Environment {
+    pub local: Rc<Symbol>,
+    /// The number of cells.
+    pub size: usize,
+}
+
+/// A class, its objects are structs with the fields in order.
+///
+/// The fields and methods of the base class come first, so that a
+/// subclass object can be used as base class object.
+#[derive(Debug)]
+pub struct Class {
+    pub name: String,
+    pub base: Option<String>,
+    pub fields: Vec<Field>,
+    /// Methods in vtable order.
+    pub methods: Vec<Method>,
+    /// Fields matched by positional patterns, which are those of a dataclass.
+    pub match_args: Vec<String>,
+}
+
+impl Class {
+    /// Find the index of a field by name.
+    pub fn get_field(&self, name: &str) -> Option<usize> {
+        self.fields.iter().position(|f| f.name == name)
+    }
+
+    /// Find the vtable slot of a method by name.
+    pub fn get_method(&self, name: &str) -> Option<usize> {
+        self.methods.iter().position(|m| m.name == name)
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct Field {
+    pub name: String,
+    pub typ: Type,
+}
+
+#[derive(Clone, Debug)]
+pub struct Method {
+    pub name: String,
+    pub function: Rc<Symbol>,
+}
+
+/// An enumeration, its members are folded into constants.
+struct Enumeration {
+    name: String,
+    members: Vec<(String, i32)>,
 }
 
 type Suite = Vec<Statement>;
@@ -107,7 +273,7 @@ type Suite = Vec<Statement>;
 #[derive(Debug)]
 pub enum Statement {
     Assignment {
-        target: Rc<Symbol>,
+        target: Target,
         value: Expression,
     },
     Expression(Expression),
@@ -123,13 +289,165 @@ pub enum Statement {
     For {
         loop_var: Rc<Symbol>,
         iter_var: Rc<Symbol>,
-        target: Rc<Symbol>,
+        /// Receives the loop counter when iterating over enumerate(iter)
+        index_target: Option<Target>,
+        target: Target,
         iter: Expression,
         suite: Suite,
     },
+    /// Loop over range(start, stop, step), without creating a list.
+    ForRange {
+        counter: Rc<Symbol>,
+        target: Rc<Symbol>,
+        range: Range,
+        suite: Suite,
+    },
+    /// Loop over the entries of a dictionary or set, in insertion order.
+    ForDict {
+        index_var: Rc<Symbol>,
+        dict_var: Rc<Symbol>,
+        view: DictView,
+        target: Target,
+        dict: Expression,
+        suite: Suite,
+    },
+    /// Remove a key from a dictionary, as in del d[key]
+    DeleteItem {
+        base: Expression,
+        key: Expression,
+    },
     Return {
         value: Expression,
+        /// The finally suites of the try statements which the return leaves.
+        finally_suite: Suite,
+    },
+    /// Throw an exception object, which is handled by the nearest handler for its class.
+    Raise {
+        exception: Expression,
+        /// The line of the raise, which is stored in the exception. This is
+        /// None when the exception being handled is raised again.
+        line: Option<usize>,
+    },
+    /// Call the host with the message and the line when the condition is false, and trap.
+    Assert {
+        condition: Expression,
+        /// A string, which is only evaluated when the condition is false.
+        message: Expression,
+        line: usize,
+        handler: Rc<Symbol>,
+    },
+    /// Run the suite, and the first handler for the class of an exception raised by it.
+    Try {
+        /// Holds the exception while the handlers are checked.
+        exception_var: Rc<Symbol>,
+        suite: Suite,
+        handlers: Vec<ExceptHandler>,
+        /// Runs when the suite raises no exception, its exceptions are not handled.
+        else_suite: Suite,
+        /// Runs in all cases, also when an exception is raised or a return leaves the suite.
+        finally_suite: Suite,
+    },
+    /// Run the suite of the first case which matches, as in match command:
+    Match {
+        /// Holds the subject while the cases are checked.
+        subject_var: Rc<Symbol>,
+        subject: Expression,
+        cases: Vec<MatchCase>,
+    },
+}
+
+/// A case of a match statement.
+#[derive(Debug)]
+pub struct MatchCase {
+    /// Checks the pattern, there is none when the pattern matches anything.
+    pub condition: Option<Expression>,
+    /// The numbers of a case which only compares the subject with numbers,
+    /// as in case 1 | 2: so that a jump table can select the case.
+    pub values: Option<Vec<i32>>,
+    /// Assignments of the values captured by the pattern, before the guard.
+    pub bindings: Suite,
+    pub guard: Option<Expression>,
+    pub suite: Suite,
+}
+
+/// An except clause of a try statement.
+#[derive(Debug)]
+pub struct ExceptHandler {
+    /// The classes of the exceptions to handle, all exceptions when empty.
+    pub classes: Vec<String>,
+    /// Starts with the assignment of the exception, as in except ValueError as e:
+    pub suite: Suite,
+}
+
+/// A resolved assignment target.
+#[derive(Debug)]
+pub enum Target {
+    Symbol(Rc<Symbol>),
+    Indexed {
+        base: Expression,
+        index: Expression,
+        typ: Type,
+    },
+    Slice {
+        base: Expression,
+        slice: Box<Slice>,
     },
+    /// Unpack a tuple into multiple targets.
+    Tuple {
+        targets: Vec<Target>,
+        typ: Type,
+    },
+    DictItem {
+        base: Expression,
+        key: Expression,
+        typ: Type,
+    },
+    /// Field of an object.
+    Attribute {
+        base: Expression,
+        field: usize,
+        typ: Type,
+    },
+}
+
+impl Target {
+    /// The type of the values stored in the target.
+    pub fn get_type(&self) -> &Type {
+        match self {
+            Target::Symbol(symbol) => symbol.get_type(),
+            Target::Slice { base, .. } => base.get_type(),
+            Target::Indexed { typ, .. }
+            | Target::Tuple { typ, .. }
+            | Target::DictItem { typ, .. }
+            | Target::Attribute { typ, .. } => typ,
+        }
+    }
+}
+
+/// What to iterate over when looping over a dictionary.
+#[derive(Debug)]
+pub enum DictView {
+    Keys,
+    Values,
+    Items,
+}
+
+/// Arguments of range(), stored in helper locals before the loop starts.
+#[derive(Debug)]
+pub struct Range {
+    pub start: Expression,
+    pub stop: Expression,
+    pub stop_var: Rc<Symbol>,
+    pub step: Expression,
+    pub step_var: Rc<Symbol>,
+}
+
+/// Optional bounds and step of a slice.
+#[derive(Debug)]
+pub struct Slice {
+    pub lower: Option<Expression>,
+    pub upper: Option<Expression>,
+    pub step: Option<Expression>,
 }
 
 #[derive(Debug)]
@@ -142,6 +460,10 @@ pub enum Expression {
         typ: Type,
         helper_local: Rc<Symbol>,
     },
+    Tuple {
+        elements: Vec<Expression>,
+        typ: Type,
+    },
     Identifier(Rc<Symbol>),
     BinaryOperation {
         a: Box<Expression>,
@@ -149,6 +471,17 @@ pub enum Expression {
         b: Box<Expression>,
         typ: Type,
     },
+    /// Division of integers, which raises ZeroDivisionError for a divisor of zero.
+    IntDivision {
+        a: Box<Expression>,
+        b: Box<Expression>,
+        /// The line of the division, which is stored in the exception.
+        line: usize,
+    },
+    UnaryOperation {
+        op: ast::UnaryOperation,
+        a: Box<Expression>,
+    },
     Call {
         callee: Rc<Symbol>,
         arguments: Vec<Expression>,
@@ -159,6 +492,149 @@ pub enum Expression {
         index: Box<Expression>,
         typ: Type,
     },
+    /// A copy of a part of a sequence, this has the type of the sequence.
+    Slice {
+        base: Box<Expression>,
+        slice: Box<Slice>,
+    },
+    /// Element of a tuple, at an index known at compile time.
+    TupleElement {
+        base: Box<Expression>,
+        index: usize,
+        typ: Type,
+    },
+    /// New tuple with selected elements of a tuple.
+    TupleSlice {
+        base: Box<Expression>,
+        indices: Vec<usize>,
+        typ: Type,
+    },
+    Dict {
+        entries: Vec<(Expression, Expression)>,
+        typ: Type,
+    },
+    Set {
+        elements: Vec<Expression>,
+        typ: Type,
+    },
+    /// Value of a key in a dictionary, the key must be present.
+    DictItem {
+        base: Box<Expression>,
+        key: Box<Expression>,
+        typ: Type,
+    },
+    /// Value of a key in a dictionary, or a default value, as in d.get(key, default)
+    DictGet {
+        base: Box<Expression>,
+        key: Box<Expression>,
+        default: Box<Expression>,
+    },
+    /// New set with the elements of a list.
+    ListToSet {
+        list: Box<Expression>,
+        typ: Type,
+    },
+    /// New set with the elements of two sets, as in a | b
+    SetOperation {
+        a: Box<Expression>,
+        op: ast::BinaryOperation,
+        b: Box<Expression>,
+    },
+    /// Add an element to a set, as in s.add(item)
+    SetAdd {
+        base: Box<Expression>,
+        item: Box<Expression>,
+    },
+    /// Remove an element from a set if it is present, as in s.discard(item)
+    SetDiscard {
+        base: Box<Expression>,
+        item: Box<Expression>,
+    },
+    /// Membership test, as in key in d
+    Contains {
+        item: Box<Expression>,
+        container: Box<Expression>,
+        negate: bool,
+    },
+    /// Field of an object, as in p.x
+    Attribute {
+        base: Box<Expression>,
+        field: usize,
+        typ: Type,
+    },
+    /// Call of a method, which can be overridden in a subclass.
+    ///
+    /// The object is the first argument, the method is in this slot of
+    /// the vtable of the class of the object.
+    MethodCall {
+        slot: usize,
+        arguments: Vec<Expression>,
+        typ: Type,
+    },
+    /// Check the class of an object, as in isinstance(x, A)
+    IsInstance {
+        object: Box<Expression>,
+        class: String,
+    },
+    /// Create an object, and call __init__ on it when the class has one.
+    NewObject {
+        init: Option<Rc<Symbol>>,
+        arguments: Vec<Expression>,
+        typ: Type,
+    },
+    /// Convert a value to a string, as in str(x) or repr(x)
+    ToStr {
+        value: Box<Expression>,
+        repr: bool,
+    },
+    /// Join strings, as in a + b
+    Concat {
+        parts: Vec<Expression>,
+    },
+    /// Write the values as a line of text, using the imported output function.
+    Print {
+        arguments: Vec<Expression>,
+        output: Rc<Symbol>,
+    },
+    /// A member of an enumeration, as in Color.RED
+    EnumMember {
+        typ: Type,
+        value: i32,
+    },
+    /// The integer value of an enum member, as in color.value
+    EnumValue {
+        member: Box<Expression>,
+    },
+    /// The None value, which is a null pointer.
+    None,
+    /// Store a value with the tag of its type, as in x: int | str = 1
+    ToUnion {
+        value: Box<Expression>,
+        typ: Type,
+    },
+    /// The value in a union, when its type is known, as in isinstance(x, int)
+    UnionValue {
+        union: Box<Expression>,
+        typ: Type,
+    },
+    /// Check the type of the value in a union, as in isinstance(x, (int, A))
+    UnionIsInstance {
+        union: Box<Expression>,
+        tags: Vec<TypeTag>,
+        classes: Vec<String>,
+    },
+    /// A nested function, with the environment of the function defining it.
+    Closure {
+        function: Rc<Symbol>,
+        environment: Option<Rc<Symbol>>,
+        typ: Type,
+    },
+    /// Call of a function value, which passes the environment of the closure first.
+    CallClosure {
+        closure: Box<Expression>,
+        arguments: Vec<Expression>,
+        typ: Type,
+    },
 }
 
 impl Expression {
@@ -168,10 +644,38 @@ impl Expression {
             Expression::Float(_) => &Type::Float,
             Expression::String(_) => &Type::Str,
             Expression::List { typ, .. } => typ,
+            Expression::Tuple { typ, .. } => typ,
             Expression::Identifier(symbol) => symbol.get_type(),
             Expression::BinaryOperation { typ, .. } => typ,
+            Expression::IntDivision { .. } => &Type::Integer,
+            Expression::UnaryOperation { a, .. } => a.get_type(),
             Expression::Call { typ, .. } => typ,
             Expression::Indexed { typ, .. } => typ,
+            Expression::Slice { base, .. } => base.get_type(),
+            Expression::TupleElement { typ, .. } => typ,
+            Expression::TupleSlice { typ, .. } => typ,
+            Expression::Dict { typ, .. } => typ,
+            Expression::Set { typ, .. } => typ,
+            Expression::DictItem { typ, .. } => typ,
+            Expression::DictGet { default, .. } => default.get_type(),
+            Expression::ListToSet { typ, .. } => typ,
+            Expression::SetOperation { a, .. } => a.get_type(),
+            Expression::SetAdd { .. } | Expression::SetDiscard { .. } => &Type::None,
+            Expression::Contains { .. } => &Type::Bool,
+            Expression::Attribute { typ, .. } => typ,
+            Expression::MethodCall { typ, .. } => typ,
+            Expression::IsInstance { .. } => &Type::Bool,
+            Expression::NewObject { typ, .. } => typ,
+            Expression::ToStr { .. } | Expression::Concat { .. } => &Type::Str,
+            Expression::Print { .. } => &Type::None,
+            Expression::EnumMember { typ, .. } => typ,
+            Expression::EnumValue { .. } => &Type::Integer,
+            Expression::None => &Type::NoneValue,
+            Expression::ToUnion { typ, .. } => typ,
+            Expression::UnionValue { typ, .. } => typ,
+            Expression::UnionIsInstance { .. } => &Type::Bool,
+            Expression::Closure { typ, .. } => typ,
+            Expression::CallClosure { typ, .. } => typ,
         }
     }
 }
@@ -190,6 +694,8 @@ pub enum Type {
     Bool,
     Str,
     Bytes,
+    /// Result of a function without return type.
+    None,
     // TODO: user type / function type
 
     // We do not know the type yet.
@@ -197,7 +703,67 @@ pub enum Type {
     /// A list of certain types
     List(Box<Type>),
 
-    Tuple(Box<Type>),
+    /// A tuple with a fixed number of elements of possibly different types.
+    Tuple(Vec<Type>),
+
+    /// A dictionary with key and value type.
+    Dict(Box<Type>, Box<Type>),
+
+    /// A set with elements of a certain type.
+    Set(Box<Type>),
+
+    /// An object of the class with this name.
+    Class(String),
+
+    /// A member of the enumeration with this name, represented by its value.
+    Enum(String),
+
+    /// A reference which can be None, represented by a null pointer.
+    Optional(Box<Type>),
+
+    /// The type of the None value, which can be stored in any Optional type.
+    NoneValue,
+
+    /// A value of one of these types, stored in memory with a tag of its type.
+    Union(Vec<Type>),
+
+    /// A function with these parameter types and result, which is a closure.
+    Callable(Vec<Type>, Box<Type>),
+}
+
+impl Type {
+    /// The tag which tells this type apart from the others in a union.
+    pub fn get_tag(&self) -> Option<TypeTag> {
+        match self {
+            Type::Integer => Some(TypeTag::Int),
+            Type::Float => Some(TypeTag::Float),
+            Type::Bool => Some(TypeTag::Bool),
+            Type::Str => Some(TypeTag::Str),
+            Type::Bytes => Some(TypeTag::Bytes),
+            Type::List(_) => Some(TypeTag::List),
+            Type::Tuple(_) => Some(TypeTag::Tuple),
+            Type::Dict(..) => Some(TypeTag::Dict),
+            Type::Set(_) => Some(TypeTag::Set),
+            Type::Class(_) => Some(TypeTag::Object),
+            _ => None,
+        }
+    }
+}
+
+/// The kind of value in a union, stored in front of the value.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum TypeTag {
+    Int,
+    Float,
+    Bool,
+    Str,
+    Bytes,
+    List,
+    Tuple,
+    Dict,
+    Set,
+    /// Objects of all classes share a tag, their class id tells them apart.
+    Object,
 }
 
 impl std::fmt::Display for Type {
@@ -208,8 +774,24 @@ impl std::fmt::Display for Type {
             Type::Bool => write!(f, "bool"),
             Type::Str => write!(f, "str"),
             Type::Bytes => write!(f, "bytes"),
+            Type::None | Type::NoneValue => write!(f, "None"),
             Type::List(element) => write!(f, "list[{}]", element),
-            Type::Tuple(element) => write!(f, "tuple[{}]", element),
+            Type::Tuple(elements) => {
+                let elements: Vec<String> = elements.iter().map(|e| e.to_string()).collect();
+                write!(f, "tuple[{}]", elements.join(", "))
+            }
+            Type::Dict(key, value) => write!(f, "dict[{}, {}]", key, value),
+            Type::Set(element) => write!(f, "set[{}]", element),
+            Type::Class(name) | Type::Enum(name) => write!(f, "{}", name),
+            Type::Optional(typ) => write!(f, "Optional[{}]", typ),
+            Type::Union(types) => {
+                let types: Vec<String> = types.iter().map(|t| t.to_string()).collect();
+                write!(f, "{}", types.join(" | "))
+            }
+            Type::Callable(parameters, result) => {
+                let parameters: Vec<String> = parameters.iter().map(|t| t.to_string()).collect();
+                write!(f, "Callable[[{}], {}]", parameters.join(", "), result)
+            }
         }
     }
 }
@@ -217,6 +799,10 @@ impl std::fmt::Display for Type {
 enum TypeConstructor {
     List,
     Tuple,
+    Dict,
+    Set,
+    Optional,
+    Union,
 }
 
 pub struct Scope {
@@ -250,32 +836,273 @@ impl Scope {
 struct Analyzer {
     scopes: Vec<Scope>,
     local_counter: usize,
+    return_type: Option<Type>,
+    classes: Vec<Class>,
+    enums: Vec<Enumeration>,
+    method: Option<MethodContext>,
+    imports: Vec<Rc<Import>>,
+    /// The analyzed functions, a function's index is its position.
+    functions: Vec<Rc<Function>>,
+    /// The wrappers of the functions which are used as values, by function name.
+    function_wrappers: HashMap<String, Rc<Symbol>>,
+    /// The functions made from generic functions, by name, such as first[int].
+    /// The function is None while it is being analyzed.
+    generic_instances: HashMap<String, Option<Rc<Symbol>>>,
+    /// The imported function which writes text, once print is used.
+    output: Option<Rc<Symbol>>,
+    /// The imported function which reports a failed assert, once an assert is compiled.
+    assertion_handler: Option<Rc<Symbol>>,
+    /// Assert statements are compiled, instead of only checked.
+    asserts: bool,
+    globals: Vec<Rc<Global>>,
+    /// Module level names which are assigned more than once, or in a function.
+    reassigned_globals: HashSet<String>,
+    /// Statements are at module level, instead of in a function.
+    module_level: bool,
+    /// Variables which are known to have a narrower type, such as not None.
+    narrowed: Vec<Narrowing>,
+    /// The exception being handled, which raise without an exception raises again.
+    exception: Option<Rc<Symbol>>,
+    /// The finally suites of the try statements around the statement being analyzed.
+    finally_suites: Vec<Vec<ast::Statement>>,
+    /// The functions being analyzed, nested functions come after the functions defining them.
+    frames: Vec<Frame>,
+}
+
+/// A function being analyzed, with the variables which its nested functions use.
+struct Frame {
+    name: String,
+    /// The index of the scope with the variables of the function.
+    scope: usize,
+    /// Variables which nested functions use, these are cells in the environment.
+    captured: HashSet<String>,
+    /// Variables which turned out to be used by nested functions, after they
+    /// were analyzed as locals. The function is analyzed again with these captured.
+    missed: RefCell<HashSet<String>>,
+    /// Variables of enclosing functions, as in nonlocal count
+    nonlocals: HashSet<String>,
+    /// The local with the environment, when variables are captured.
+    environment: Option<Rc<Symbol>>,
+    cells: usize,
+    /// The parameter with the environment of the enclosing function, for a nested function.
+    outer_environment: Option<Rc<Symbol>>,
+}
+
+/// A variable which is known to have a narrower type than declared,
+/// as in if x is not None: or if isinstance(x, int):
+#[derive(Clone)]
+struct Narrowing {
+    name: String,
+    /// The variable with its declared type.
+    symbol: Rc<Symbol>,
+    typ: Type,
+}
+
+impl Narrowing {
+    /// Read the variable, with its narrowed type.
+    fn get_value(&self) -> Expression {
+        let variable = Expression::Identifier(self.symbol.clone());
+        if matches!(self.symbol.get_type(), Type::Union(_)) && !matches!(self.typ, Type::Union(_)) {
+            return Expression::UnionValue {
+                union: Box::new(variable),
+                typ: self.typ.clone(),
+            };
+        }
+        Expression::Identifier(retype_variable(&self.symbol, self.typ.clone()))
+    }
+}
+
+/// A symbol which refers to the same variable, with a narrower type.
+fn retype_variable(symbol: &Symbol, typ: Type) -> Rc<Symbol> {
+    let symbol = match symbol {
+        Symbol::Local { index, .. } => Symbol::Local {
+            local: Rc::new(Local { typ }),
+            index: *index,
+        },
+        Symbol::Parameter { parameter, index } => Symbol::Parameter {
+            parameter: Rc::new(Parameter {
+                name: parameter.name.clone(),
+                typ,
+                default: None,
+                variadic: false,
+            }),
+            index: *index,
+        },
+        Symbol::Global { index, .. } => Symbol::Global {
+            global: Rc::new(Global { typ, value: None }),
+            index: *index,
+        },
+        Symbol::Cell {
+            cell,
+            environment,
+            depth,
+        } => Symbol::Cell {
+            cell: Rc::new(Cell {
+                typ,
+                slot: cell.slot,
+            }),
+            environment: environment.clone(),
+            depth: *depth,
+        },
+        _ => panic!("Only variables can be narrowed"),
+    };
+    Rc::new(symbol)
+}
+
+/// The value checked by a pattern, which is the subject of a match statement,
+/// or a part of it for a nested pattern.
+#[derive(Clone)]
+struct PatternSubject {
+    symbol: Rc<Symbol>,
+    /// The type of the subject, which patterns can narrow.
+    typ: Type,
+    /// How to get the part from the subject, with the type of each part.
+    steps: Vec<(SubjectStep, Type)>,
+}
+
+#[derive(Clone)]
+enum SubjectStep {
+    TupleElement(usize),
+    Field(usize),
+    UnionValue,
+}
+
+impl PatternSubject {
+    fn get_type(&self) -> &Type {
+        self.steps.last().map_or(&self.typ, |(_, typ)| typ)
+    }
+
+    /// Continue with a part of the value, as in case (x, y):
+    fn step(&self, step: SubjectStep, typ: Type) -> Self {
+        let mut subject = self.clone();
+        subject.steps.push((step, typ));
+        subject
+    }
+
+    /// Continue with the value, which is known to have a narrower type.
+    fn narrow(&mut self, typ: Type) {
+        if matches!(self.get_type(), Type::Union(_)) && !matches!(typ, Type::Union(_)) {
+            self.steps.push((SubjectStep::UnionValue, typ));
+        } else if let Some((_, last)) = self.steps.last_mut() {
+            *last = typ;
+        } else {
+            self.typ = typ;
+        }
+    }
+
+    fn get_value(&self) -> Expression {
+        let mut value = Expression::Identifier(retype_variable(&self.symbol, self.typ.clone()));
+        for (step, typ) in &self.steps {
+            let base = Box::new(value);
+            let typ = typ.clone();
+            value = match step {
+                SubjectStep::TupleElement(index) => Expression::TupleElement {
+                    base,
+                    index: *index,
+                    typ,
+                },
+                SubjectStep::Field(field) => Expression::Attribute {
+                    base,
+                    field: *field,
+                    typ,
+                },
+                SubjectStep::UnionValue => Expression::UnionValue { union: base, typ },
+            };
+        }
+        value
+    }
+}
+
+/// The method being analyzed.
+struct MethodContext {
+    class: String,
+    /// Name of the first parameter, which receives the object.
+    self_name: String,
+    /// Assigning to attributes of self in __init__ can add fields.
+    is_init: bool,
 }
 
 impl Analyzer {
-    fn new() -> Self {
+    fn new(options: &CompileOptions) -> Self {
         Analyzer {
             scopes: vec![],
             local_counter: 0,
+            return_type: None,
+            classes: vec![],
+            enums: vec![],
+            method: None,
+            imports: vec![],
+            functions: vec![],
+            function_wrappers: HashMap::new(),
+            generic_instances: HashMap::new(),
+            output: None,
+            assertion_handler: None,
+            asserts: options.asserts,
+            globals: vec![],
+            reassigned_globals: HashSet::new(),
+            module_level: false,
+            narrowed: vec![],
+            exception: None,
+            finally_suites: vec![],
+            frames: vec![],
         }
     }
 
     fn analyze_program(mut self, prog: &ast::Program) -> Result<Program, CompilationError> {
         self.define_intrinsics();
+        self.analyze_prelude();
+        let prelude_functions = self.functions.len();
+        self.reassigned_globals = find_reassigned_globals(prog);
         self.enter_scope();
-        let mut imports = vec![];
-        let mut functions = vec![];
+        let mut module_statements = vec![];
         for top_level in &prog.top_levels {
             match top_level {
                 ast::TopLevel::FunctionDef(function_def) => {
-                    let function = self.analyze_function_def(function_def)?;
+                    check_export_name(&function_def.location, &function_def.name)?;
+                    let type_parameters = self.get_type_parameters(function_def)?;
+                    if !type_parameters.is_empty() {
+                        // A generic function is analyzed when it is called:
+                        let generic = GenericFunction {
+                            name: function_def.name.clone(),
+                            type_parameters,
+                            function_def: function_def.clone(),
+                        };
+                        let symbol = Symbol::GenericFunction(Rc::new(generic));
+                        self.define(&function_def.name, Rc::new(symbol));
+                        continue;
+                    }
+                    let name = function_def.name.clone();
+                    let function = self.analyze_function_def(name, function_def, None, None)?;
                     let function = Rc::new(function);
-                    let index = functions.len();
-                    functions.push(function.clone());
+                    let index = self.functions.len();
+                    self.functions.push(function.clone());
                     let symbol = Symbol::Function { function, index };
                     self.define(&function_def.name, Rc::new(symbol));
                 }
-                ast::TopLevel::Import { module, name } => {
+                ast::TopLevel::Import {
+                    location,
+                    module,
+                    name,
+                } if matches!(module.as_str(), "dataclasses" | "enum" | "typing") => {
+                    // These are handled by the compiler, not imported from the host:
+                    let builtin = match (module.as_str(), name.as_str()) {
+                        ("dataclasses", "dataclass") => Builtin::Dataclass,
+                        ("enum", "Enum") => Builtin::Enum,
+                        ("typing", "Final") => Builtin::Final,
+                        ("typing", "Optional") => Builtin::Optional,
+                        ("typing", "Union") => Builtin::Union,
+                        ("typing", "Callable") => Builtin::Callable,
+                        ("typing", "TypeVar") => Builtin::TypeVar,
+                        _ => {
+                            return Err(CompilationError::new(
+                                location,
+                                format!("{}.{} is not supported", module, name),
+                            ));
+                        }
+                    };
+                    self.define(name, Rc::new(Symbol::Builtin(builtin)));
+                }
+                ast::TopLevel::Import { module, name, .. } => {
                     info!("Importing {}.{}", module, name);
 
                     // TODO: how to determine parameter types?
@@ -292,441 +1119,4144 @@ impl Analyzer {
                         module, name, parameter_types, return_type
                     );
 
-                    let index = imports.len();
-                    let import = Rc::new(Import {
-                        modname: module.clone(),
-                        name: name.clone(),
-                        parameter_types,
-                        return_type,
-                    });
-                    imports.push(import.clone());
-                    let symbol = Symbol::ExternFunction { index, import };
-                    self.define(name, Rc::new(symbol));
+                    let symbol = self.add_import(module, name, parameter_types, return_type);
+                    self.define(name, symbol);
+                }
+                ast::TopLevel::ClassDef(class_def) => {
+                    self.analyze_class_def(class_def)?;
                 }
-                ast::TopLevel::ClassDef { .. } => {
-                    unimplemented!("TODO");
+                ast::TopLevel::Statement(statement) => {
+                    if let Some(statement) = self.analyze_module_statement(statement)? {
+                        module_statements.push(statement);
+                    }
                 }
             }
         }
-        self.leave_scope();
-        Ok(Program { imports, functions })
-    }
+        let locals = self.leave_scope().locals;
 
-    /// Define internal functions such as len and ord.
-    fn define_intrinsics(&mut self) {
-        self.enter_scope();
-        let symbol = Symbol::Builtin(Builtin::Ord);
-        self.define("ord", Rc::new(symbol));
+        // Module level statements are run by the start function:
+        let start = if module_statements.is_empty() {
+            None
+        } else {
+            self.functions.push(Rc::new(Function {
+                name: "<module>".to_owned(),
+                parameters: vec![],
+                locals,
+                body: module_statements,
+                return_type: None,
+                closure: false,
+                environment: None,
+            }));
+            Some(self.functions.len() - 1)
+        };
 
-        let symbol = Symbol::Builtin(Builtin::Len);
-        self.define("len", Rc::new(symbol));
+        let classes = std::mem::take(&mut self.classes)
+            .into_iter()
+            .map(Rc::new)
+            .collect();
+        Ok(Program {
+            imports: self.imports,
+            functions: self.functions,
+            classes,
+            globals: self.globals,
+            start,
+            prelude_functions,
+        })
     }
 
-    /// Determine the type given a parsed expression
-    fn get_type(&self, typ: &ast::Expression) -> Result<Type, CompilationError> {
-        match &typ.kind {
-            ast::ExpressionType::Identifier(name) => match name.as_str() {
-                "float" => Ok(Type::Float),
-                "int" => Ok(Type::Integer),
-                "bool" => Ok(Type::Bool),
-                "str" => Ok(Type::Str),
-                "bytes" => Ok(Type::Bytes),
-                name => Err(new_error(
-                    typ,
-                    &format!("Invalid type identifier: {}", name),
-                )),
-            },
-            ast::ExpressionType::Indexed { base, index } => {
-                let base = self.get_type_constructor(base)?;
-                let index = self.get_type(index)?;
-                let typ = self.apply(base, index);
-                Ok(typ)
+    /// Define the builtin classes of the prelude, their methods are the first functions.
+    fn analyze_prelude(&mut self) {
+        let prelude = parse_python(PRELUDE).expect("The prelude parses");
+        for top_level in &prelude.top_levels {
+            match top_level {
+                ast::TopLevel::ClassDef(class_def) => self
+                    .analyze_class_def(class_def)
+                    .expect("The prelude is valid"),
+                _ => panic!("The prelude only defines classes"),
             }
-            _ => Err(new_error(typ, "Invalid type expression")),
         }
     }
 
-    fn get_type_constructor(
-        &self,
-        con: &ast::Expression,
-    ) -> Result<TypeConstructor, CompilationError> {
-        match &con.kind {
-            ast::ExpressionType::Identifier(name) => match name.as_str() {
-                "list" => Ok(TypeConstructor::List),
-                "tuple" => Ok(TypeConstructor::Tuple),
-                name => Err(new_error(
-                    con,
-                    &format!("No such type constructor {}", name),
-                )),
-            },
-            _ => Err(new_error(con, "Invalid type constructor")),
+    /// Analyze a statement at module level.
+    ///
+    /// A constant which is assigned once becomes an immutable global, which
+    /// needs no code. Other statements are run by the start function.
+    fn analyze_module_statement(
+        &mut self,
+        statement: &ast::Statement,
+    ) -> Result<Option<Statement>, CompilationError> {
+        if let ast::Statement::Assignment { target, value } = statement {
+            if let Some(arguments) = self.get_builtin_arguments(value, Builtin::TypeVar) {
+                self.define_type_variable(target, value, arguments)?;
+                return Ok(None);
+            }
+        }
+        if let ast::Statement::AnnotatedAssignment {
+            target,
+            annotation,
+            value,
+        } = statement
+        {
+            if self.is_final(annotation) {
+                self.analyze_final(target, annotation, value)?;
+                return Ok(None);
+            }
+        }
+
+        let definition = match statement {
+            ast::Statement::Assignment {
+                target: ast::AssignmentTarget::Identifier(name),
+                value,
+            } => Some((name, None, value)),
+            ast::Statement::AnnotatedAssignment {
+                target: ast::AssignmentTarget::Identifier(name),
+                annotation,
+                value,
+            } => Some((name, Some(annotation), value)),
+            _ => None,
+        };
+        if let Some((name, annotation, value)) = definition {
+            if !self.is_defined(name) && !self.reassigned_globals.contains(name) {
+                let location = &value.location;
+                check_export_name(location, name)?;
+                let (value, typ) = match annotation {
+                    Some(annotation) => {
+                        let typ = self.get_type(annotation)?;
+                        (self.analyze_typed_expression(value, &typ)?, typ)
+                    }
+                    None => {
+                        let value = self.analyze_expression(value)?;
+                        self.check_is_value(&value, location)?;
+                        let typ = value.get_type().clone();
+                        (value, typ)
+                    }
+                };
+                check_variable_type(location, &typ)?;
+                if copy_constant(&value).is_some() {
+                    self.new_global(name, typ, Some(value));
+                    return Ok(None);
+                }
+                let symbol = self.new_global(name, typ, None);
+                let target = Target::Symbol(symbol);
+                return Ok(Some(Statement::Assignment { target, value }));
+            }
         }
+
+        self.module_level = true;
+        let statement = self.analyze_statement(statement);
+        self.module_level = false;
+        // Module level statements are not analyzed as a suite:
+        self.narrowed.clear();
+        statement.map(Some)
     }
 
-    fn apply(&self, con: TypeConstructor, arg: Type) -> Type {
-        match con {
-            TypeConstructor::List => Type::List(Box::new(arg)),
-            TypeConstructor::Tuple => Type::Tuple(Box::new(arg)),
+    /// Define a type variable for generic functions, as in T = TypeVar('T')
+    fn define_type_variable(
+        &mut self,
+        target: &ast::AssignmentTarget,
+        value: &ast::Expression,
+        arguments: &[ast::Expression],
+    ) -> Result<(), CompilationError> {
+        let name = match (target, arguments) {
+            (
+                ast::AssignmentTarget::Identifier(name),
+                [ast::Expression {
+                    kind: ast::ExpressionType::Str(label),
+                    ..
+                }],
+            ) if name == label => name,
+            _ => {
+                return Err(new_error(
+                    value,
+                    "TypeVar takes the name of the variable, as in T = TypeVar('T')",
+                ));
+            }
+        };
+        if self.is_defined(name) {
+            return Err(new_error(value, &format!("{} is already defined", name)));
         }
+        self.define(name, Rc::new(Symbol::TypeVariable));
+        Ok(())
     }
 
-    fn analyze_function_def(
+    /// Define a constant, as in X: Final = 3, which is replaced by its
+    /// value where it is used.
+    fn analyze_final(
         &mut self,
-        function_def: &ast::FunctionDef,
-    ) -> Result<Function, CompilationError> {
-        debug!("Analyzing function {}", function_def.name);
-        self.enter_scope();
-        self.local_counter = 0;
-        let mut parameters = vec![];
-        for parameter in &function_def.parameters {
-            let index = self.local_counter;
-            self.local_counter += 1;
-            let param_type = self.get_type(&parameter.typ)?;
-            let param = Rc::new(Parameter {
-                name: parameter.name.clone(),
-                typ: param_type,
-            });
-            let symbol = Rc::new(Symbol::Parameter {
-                parameter: param.clone(),
-                index,
-            });
-            self.define(&parameter.name, symbol.clone());
-            parameters.push(param);
+        target: &ast::AssignmentTarget,
+        annotation: &ast::Expression,
+        value: &ast::Expression,
+    ) -> Result<(), CompilationError> {
+        let location = &value.location;
+        let name = match target {
+            ast::AssignmentTarget::Identifier(name) => name,
+            _ => {
+                return Err(CompilationError::new(
+                    location,
+                    "Final can only be used for a name, as in X: Final = 3",
+                ));
+            }
+        };
+        if self.is_defined(name) {
+            return Err(CompilationError::new(
+                location,
+                format!("{} is already defined", name),
+            ));
         }
 
-        let return_type = match &function_def.result {
-            Some(e) => Some(self.get_type(e)?),
-            None => None,
+        // The type is optional, as in Final[int]:
+        let value = match &annotation.kind {
+            ast::ExpressionType::Indexed { index, .. } => {
+                let typ = self.get_type(index)?;
+                self.analyze_typed_expression(value, &typ)?
+            }
+            _ => self.analyze_expression(value)?,
         };
-        let body = self.analyze_suite(&function_def.body)?;
+        if copy_constant(&value).is_none() {
+            return Err(CompilationError::new(
+                location,
+                format!("Value of {} must be known at compile time", name),
+            ));
+        }
+        self.define(name, Rc::new(Symbol::Constant { value }));
+        Ok(())
+    }
 
-        let locals = self.leave_scope().locals;
-        Ok(Function {
-            name: function_def.name.clone(),
-            parameters,
-            locals,
-            body,
-            return_type,
-        })
+    /// Check for a Final or Final[T] annotation.
+    fn is_final(&self, annotation: &ast::Expression) -> bool {
+        let name = match &annotation.kind {
+            ast::ExpressionType::Identifier(name) => name,
+            ast::ExpressionType::Indexed { base, .. } => match &base.kind {
+                ast::ExpressionType::Identifier(name) => name,
+                _ => return false,
+            },
+            _ => return false,
+        };
+        matches!(
+            self.lookup(name).as_deref(),
+            Some(Symbol::Builtin(Builtin::Final))
+        )
     }
 
-    fn analyze_suite(&mut self, suite: &[ast::Statement]) -> Result<Suite, CompilationError> {
-        let mut statements: Suite = vec![];
-        for statement in suite {
-            let statement = self.analyze_statement(statement)?;
-            statements.push(statement);
+    /// Analyze a class, its methods are added to the functions.
+    fn analyze_class_def(&mut self, class_def: &ast::ClassDef) -> Result<(), CompilationError> {
+        debug!("Analyzing class {}", class_def.name);
+        // Builtin classes cannot be replaced, since classes are known by name:
+        if self.is_defined(&class_def.name) || self.classes.iter().any(|c| c.name == class_def.name)
+        {
+            return Err(CompilationError::new(
+                &class_def.location,
+                format!("{} is already defined", class_def.name),
+            ));
         }
-        Ok(statements)
-    }
 
-    fn analyze_statement(
-        &mut self,
-        statement: &ast::Statement,
-    ) -> Result<Statement, CompilationError> {
-        match statement {
-            ast::Statement::Return(e) => {
-                let value = self.analyze_expression(e)?;
-                // TODO: Type check type with return type of function
-                Ok(Statement::Return { value })
+        if let Some(base) = &class_def.base {
+            if let Some(Symbol::Builtin(Builtin::Enum)) = self.lookup(base).as_deref() {
+                return self.analyze_enum_def(class_def);
             }
-            ast::Statement::If {
-                condition,
-                suite,
-                else_suite,
-            } => {
-                let condition = self.analyze_expression(condition)?;
-                let body = self.analyze_suite(suite)?;
-                let else_suite = self.analyze_suite(else_suite)?;
+        }
+        if let Some(attribute) = class_def.attributes.first() {
+            return Err(CompilationError::new(
+                &attribute.location,
+                format!(
+                    "Class attribute {} is not supported, only enums have them",
+                    attribute.name
+                ),
+            ));
+        }
 
-                Ok(Statement::If {
-                    condition,
-                    suite: body,
-                    else_suite,
-                })
-            }
-            ast::Statement::While { condition, suite } => {
-                let condition = self.analyze_expression(condition)?;
-                let suite = self.analyze_suite(suite)?;
+        let is_dataclass = self.is_dataclass(class_def)?;
 
-                Ok(Statement::While { condition, suite })
-            }
-            ast::Statement::For {
-                target,
-                iter,
-                suite,
-            } => {
-                let loop_var = self.new_local(None, Type::Integer);
-                let iter_var = self.new_local(None, Type::Integer);
-                let location = &iter.location;
-                let iter = self.analyze_expression(iter)?;
-                let typ = iter.get_type();
-                let element_typ = match typ {
-                    Type::List(element_typ) => element_typ,
-                    other => {
-                        return Err(CompilationError::new(
-                            location,
-                            format!("Cannot iterate over this type: {}", other),
-                        ));
-                    }
-                };
-                let target = self.store_value(target, element_typ);
-                let suite = self.analyze_suite(suite)?;
-                Ok(Statement::For {
-                    loop_var,
-                    iter_var,
-                    target,
-                    iter,
-                    suite,
-                })
+        // A subclass starts with the fields and methods of its base class:
+        let (mut fields, mut methods, mut match_args) = match &class_def.base {
+            Some(base) => match self.lookup(base).as_deref() {
+                Some(Symbol::Type {
+                    typ: Type::Class(base),
+                }) => {
+                    let base = self.get_class(base);
+                    (
+                        base.fields.clone(),
+                        base.methods.clone(),
+                        base.match_args.clone(),
+                    )
+                }
+                _ => {
+                    return Err(CompilationError::new(
+                        &class_def.location,
+                        format!("Base class {} is not a class", base),
+                    ));
+                }
+            },
+            None => (vec![], vec![], vec![]),
+        };
+
+        let typ = Type::Class(class_def.name.clone());
+        let symbol = Symbol::Type { typ: typ.clone() };
+        self.define(&class_def.name, Rc::new(symbol));
+
+        for field in &class_def.fields {
+            if fields.iter().any(|f| f.name == field.name) {
+                return Err(CompilationError::new(
+                    &field.location,
+                    format!("Field {} is declared twice", field.name),
+                ));
             }
-            ast::Statement::Pass => {
-                unimplemented!();
+            let typ = self.get_type(&field.typ)?;
+            fields.push(Field {
+                name: field.name.clone(),
+                typ,
+            });
+        }
+
+        // Methods are checked against the methods they override:
+        for method in &class_def.methods {
+            if !method.type_parameters.is_empty() {
+                return Err(CompilationError::new(
+                    &method.location,
+                    "Only module level functions can have type parameters",
+                ));
             }
-            ast::Statement::Break => {
-                unimplemented!();
+            if method.parameters.is_empty() {
+                return Err(CompilationError::new(
+                    &method.location,
+                    format!("Method {} must take self as first parameter", method.name),
+                ));
             }
-            ast::Statement::Continue => {
-                unimplemented!();
+            if class_def
+                .methods
+                .iter()
+                .filter(|m| m.name == method.name)
+                .count()
+                > 1
+            {
+                return Err(CompilationError::new(
+                    &method.location,
+                    format!("Method {} is defined twice", method.name),
+                ));
             }
-            ast::Statement::Expression(expr) => {
-                let expr = self.analyze_expression(expr)?;
-                Ok(Statement::Expression(expr))
+            if method.name == "__init__" && method.result.is_some() {
+                return Err(CompilationError::new(
+                    &method.location,
+                    "__init__ cannot return a value",
+                ));
             }
-            ast::Statement::Assignment { target, value } => {
-                let value = self.analyze_expression(value)?;
-                let typ = value.get_type();
-                // TODO: derive type!
-                let target = self.store_value(target, typ);
-                Ok(Statement::Assignment { target, value })
-            } // ast::Statement::AugmentAssignment { .. } => {
-              // self.get_local(target);
-              // self.compile_expression(value);
-              // // TODO: coerce!
-              // self.emit_operator(op);
+        }
+
+        if is_dataclass {
+            match_args = fields.iter().map(|f| f.name.clone()).collect();
+        }
+        self.classes.push(Class {
+            name: class_def.name.clone(),
+            base: class_def.base.clone(),
+            fields,
+            methods: vec![],
+            match_args,
+        });
+
+        if is_dataclass {
+            self.get_class_mut(&class_def.name).methods = methods.clone();
+            self.analyze_dataclass(class_def, &mut methods)?;
+        }
 
-              // let typ = wasm::Type::I32; // TODO!
-              // self.store_value(target, typ);
-              // unimplemented!();
-              // }
+        // __init__ goes first, since it can add fields, and other methods can create objects:
+        let (init, new_methods): (Vec<&ast::FunctionDef>, Vec<&ast::FunctionDef>) =
+            class_def.methods.iter().partition(|m| m.name == "__init__");
+        for method in init.into_iter().chain(new_methods) {
+            self.method = Some(MethodContext {
+                class: class_def.name.clone(),
+                self_name: method.parameters[0].name.clone(),
+                is_init: method.name == "__init__",
+            });
+            // Methods of the base class are available in the new methods:
+            self.get_class_mut(&class_def.name).methods = methods.clone();
+            let name = format!("{}.{}", class_def.name, method.name);
+            let function = self.analyze_function_def(name, method, Some(&typ), None)?;
+            self.method = None;
+
+            add_method(
+                &method.location,
+                &method.name,
+                function,
+                &mut methods,
+                &mut self.functions,
+            )?;
         }
+        self.get_class_mut(&class_def.name).methods = methods;
+        Ok(())
     }
 
-    // fn compile_condition(&self, condition: &ast::Expression) {
-    //     self.compile_expression
-    //     unimplemented!();
-    // }
+    /// Analyze an enumeration, as in class Color(Enum):
+    ///
+    /// The members are constants, so no code or storage is needed for them.
+    fn analyze_enum_def(&mut self, class_def: &ast::ClassDef) -> Result<(), CompilationError> {
+        if let Some(decorator) = class_def.decorators.first() {
+            return Err(CompilationError::new(
+                &decorator.location,
+                format!("Unknown decorator {} for enum", decorator.name),
+            ));
+        }
+        if let Some(field) = class_def.fields.first() {
+            return Err(CompilationError::new(
+                &field.location,
+                format!(
+                    "Enum member {} needs a value, as in {} = 1",
+                    field.name, field.name
+                ),
+            ));
+        }
+        if let Some(method) = class_def.methods.first() {
+            return Err(CompilationError::new(
+                &method.location,
+                "Methods are not supported in enums",
+            ));
+        }
 
-    fn analyze_expressions(
-        &mut self,
-        expressions: &[ast::Expression],
-    ) -> Result<Vec<Expression>, CompilationError> {
-        let mut new_expressions = vec![];
-        for expression in expressions {
-            let new_expr = self.analyze_expression(expression)?;
-            new_expressions.push(new_expr);
+        let mut members: Vec<(String, i32)> = vec![];
+        for attribute in &class_def.attributes {
+            if members.iter().any(|(name, _)| name == &attribute.name) {
+                return Err(CompilationError::new(
+                    &attribute.location,
+                    format!("Enum member {} is defined twice", attribute.name),
+                ));
+            }
+            match self.analyze_expression(&attribute.value)? {
+                Expression::Number(value) => members.push((attribute.name.clone(), value)),
+                _ => {
+                    return Err(CompilationError::new(
+                        &attribute.location,
+                        format!(
+                            "Enum member {} must have a constant int value",
+                            attribute.name
+                        ),
+                    ));
+                }
+            }
         }
-        Ok(new_expressions)
+
+        self.enums.push(Enumeration {
+            name: class_def.name.clone(),
+            members,
+        });
+        let typ = Type::Enum(class_def.name.clone());
+        self.define(&class_def.name, Rc::new(Symbol::Type { typ }));
+        Ok(())
     }
 
-    fn analyze_expression(
-        &mut self,
-        expression: &ast::Expression,
+    /// Get a member of an enumeration, as in Color.RED
+    fn analyze_enum_member(
+        &self,
+        location: &Location,
+        enum_name: &str,
+        name: &str,
     ) -> Result<Expression, CompilationError> {
-        match &expression.kind {
-            ast::ExpressionType::Number(value) => Ok(Expression::Number(*value)),
-            ast::ExpressionType::Float(value) => Ok(Expression::Float(*value)),
-            // ast::Expression::Bool(_) => {
-            //     unimplemented!();
-            // }
-            ast::ExpressionType::Str(value) => {
-                // unimplemented!("STR: {}", value);
+        let enumeration = self.get_enum(enum_name);
+        match enumeration
+            .members
+            .iter()
+            .find(|(member, _)| member == name)
+        {
+            Some((_, value)) => Ok(Expression::EnumMember {
+                typ: Type::Enum(enum_name.to_owned()),
+                value: *value,
+            }),
+            None => Err(CompilationError::new(
+                location,
+                format!("{} has no member {}", enum_name, name),
+            )),
+        }
+    }
 
-                // println!("Str: {} {}", value, value.len());
-                // TODO: how to represent strings?
-                Ok(Expression::String(value.clone()))
+    /// Get the value or name of an enum member, as in color.value
+    fn analyze_enum_attribute(
+        &self,
+        location: &Location,
+        enum_name: &str,
+        member: Expression,
+        name: &str,
+    ) -> Result<Expression, CompilationError> {
+        match (name, member) {
+            ("value", Expression::EnumMember { value, .. }) => Ok(Expression::Number(value)),
+            ("value", member) => Ok(Expression::EnumValue {
+                member: Box::new(member),
+            }),
+            // Members with the same value are aliases for the first one:
+            ("name", Expression::EnumMember { value, .. }) => {
+                let enumeration = self.get_enum(enum_name);
+                let (name, _) = enumeration
+                    .members
+                    .iter()
+                    .find(|(_, v)| *v == value)
+                    .expect("Member with value");
+                Ok(Expression::String(name.clone()))
             }
-            ast::ExpressionType::List { elements } => {
-                let elements = self.analyze_expressions(elements)?;
+            ("name", _) => Err(CompilationError::new(
+                location,
+                "The name of an enum member is only known for constants, as in Color.RED.name",
+            )),
+            (name, _) => Err(CompilationError::new(
+                location,
+                format!("{} has no attribute {}", enum_name, name),
+            )),
+        }
+    }
 
-                // Well. A list.
-                // Constraints:
-                // - At least one element
-                // - All elements have equal type
-                if elements.is_empty() {
-                    return Err(new_error(expression, "List must contain at least 1 value"));
+    /// Check the decorators of a class, only @dataclass is supported.
+    fn is_dataclass(&self, class_def: &ast::ClassDef) -> Result<bool, CompilationError> {
+        let mut is_dataclass = false;
+        for decorator in &class_def.decorators {
+            match self.lookup(&decorator.name).as_deref() {
+                Some(Symbol::Builtin(Builtin::Dataclass)) => is_dataclass = true,
+                _ => {
+                    return Err(CompilationError::new(
+                        &decorator.location,
+                        format!("Unknown decorator {}", decorator.name),
+                    ));
                 }
+            }
+        }
+        Ok(is_dataclass)
+    }
 
-                let (first, rest) = elements.split_first().expect("At least 1 element");
+    /// Generate __init__, __eq__ and __repr__ for a dataclass, from the
+    /// fields in the class body. Methods defined in the class are kept.
+    fn analyze_dataclass(
+        &mut self,
+        class_def: &ast::ClassDef,
+        methods: &mut Vec<Method>,
+    ) -> Result<(), CompilationError> {
+        let location = &class_def.location;
+        let typ = Type::Class(class_def.name.clone());
+        let fields = self.get_class(&class_def.name).fields.clone();
+        let is_defined = |name: &str| class_def.methods.iter().any(|m| m.name == name);
 
-                let element_typ = first.get_type();
-                for item in rest {
-                    if element_typ != item.get_type() {
-                        return Err(new_error(
-                            expression,
-                            "All elements must be of the same type",
-                        ));
-                    }
+        if !is_defined("__init__") {
+            let (self_parameter, self_symbol) = new_parameter("self", &typ, 0);
+            let mut parameters = vec![self_parameter];
+            let mut body = vec![];
+            for (field, Field { name, typ }) in fields.iter().enumerate() {
+                let (parameter, symbol) = new_parameter(name, typ, parameters.len());
+                parameters.push(parameter);
+                body.push(Statement::Assignment {
+                    target: Target::Attribute {
+                        base: Expression::Identifier(self_symbol.clone()),
+                        field,
+                        typ: typ.clone(),
+                    },
+                    value: Expression::Identifier(symbol),
+                });
+            }
+            let function = Function {
+                name: format!("{}.__init__", class_def.name),
+                parameters,
+                locals: vec![],
+                body,
+                return_type: None,
+                closure: false,
+                environment: None,
+            };
+            add_method(location, "__init__", function, methods, &mut self.functions)?;
+        }
+
+        if !is_defined("__eq__") {
+            // When overriding, other has the type of the base class:
+            let other_typ = match methods.iter().find(|m| m.name == "__eq__") {
+                Some(method) => match method.function.as_ref() {
+                    Symbol::Function { function, .. } => function.parameters[1].typ.clone(),
+                    _ => panic!("Not a function"),
+                },
+                None => typ.clone(),
+            };
+            if !self.is_assignable(&typ, &other_typ) {
+                return Err(CompilationError::new(
+                    location,
+                    format!("Cannot compare {} with {}", typ, other_typ),
+                ));
+            }
+            let (self_parameter, self_symbol) = new_parameter("self", &typ, 0);
+            let (other_parameter, other_symbol) = new_parameter("other", &other_typ, 1);
+            let local = Rc::new(Local { typ: typ.clone() });
+            let local_symbol = Rc::new(Symbol::Local {
+                local: local.clone(),
+                index: 2,
+            });
+
+            // Compare field by field, a class without fields is equal to itself:
+            let mut equal = None;
+            for (field, Field { typ, .. }) in fields.iter().enumerate() {
+                let get_field = |symbol: &Rc<Symbol>| Expression::Attribute {
+                    base: Box::new(Expression::Identifier(symbol.clone())),
+                    field,
+                    typ: typ.clone(),
+                };
+                let field_equal = self.analyze_equality(
+                    location,
+                    get_field(&self_symbol),
+                    get_field(&local_symbol),
+                    false,
+                )?;
+                equal = Some(match equal {
+                    Some(equal) => Expression::BinaryOperation {
+                        a: Box::new(equal),
+                        op: BinaryOperation::Boolean(ast::BooleanOperator::And),
+                        b: Box::new(field_equal),
+                        typ: Type::Bool,
+                    },
+                    None => field_equal,
+                });
+            }
+            let equal = equal.unwrap_or(Expression::Number(1));
+            let body = vec![Statement::If {
+                condition: Expression::IsInstance {
+                    object: Box::new(Expression::Identifier(other_symbol.clone())),
+                    class: class_def.name.clone(),
+                },
+                suite: vec![
+                    Statement::Assignment {
+                        target: Target::Symbol(local_symbol),
+                        value: Expression::Identifier(other_symbol),
+                    },
+                    Statement::Return {
+                        value: equal,
+                        finally_suite: vec![],
+                    },
+                ],
+                else_suite: vec![Statement::Return {
+                    value: Expression::Number(0),
+                    finally_suite: vec![],
+                }],
+            }];
+            let function = Function {
+                name: format!("{}.__eq__", class_def.name),
+                parameters: vec![self_parameter, other_parameter],
+                locals: vec![local],
+                body,
+                return_type: Some(Type::Bool),
+                closure: false,
+                environment: None,
+            };
+            add_method(location, "__eq__", function, methods, &mut self.functions)?;
+        }
+
+        if !is_defined("__repr__") {
+            // Point(x=1, y=2)
+            let (self_parameter, self_symbol) = new_parameter("self", &typ, 0);
+            let mut parts = vec![];
+            let mut text = format!("{}(", class_def.name);
+            for (field, Field { name, typ }) in fields.iter().enumerate() {
+                if field > 0 {
+                    text.push_str(", ");
                 }
+                text.push_str(name);
+                text.push('=');
+                parts.push(Expression::String(std::mem::take(&mut text)));
+                let value = Expression::Attribute {
+                    base: Box::new(Expression::Identifier(self_symbol.clone())),
+                    field,
+                    typ: typ.clone(),
+                };
+                parts.push(Expression::ToStr {
+                    value: Box::new(value),
+                    repr: true,
+                });
+            }
+            text.push(')');
+            parts.push(Expression::String(text));
+            let function = Function {
+                name: format!("{}.__repr__", class_def.name),
+                parameters: vec![self_parameter],
+                locals: vec![],
+                body: vec![Statement::Return {
+                    value: Expression::Concat { parts },
+                    finally_suite: vec![],
+                }],
+                return_type: Some(Type::Str),
+                closure: false,
+                environment: None,
+            };
+            add_method(location, "__repr__", function, methods, &mut self.functions)?;
+        }
+        Ok(())
+    }
 
-                let typ = Type::List(Box::new(element_typ.clone()));
+    /// Define internal functions such as len and ord.
+    fn define_intrinsics(&mut self) {
+        self.enter_scope();
+        let symbol = Symbol::Builtin(Builtin::Ord);
+        self.define("ord", Rc::new(symbol));
 
-                let helper_local = self.new_local(None, Type::Integer);
+        let symbol = Symbol::Builtin(Builtin::Len);
+        self.define("len", Rc::new(symbol));
 
-                Ok(Expression::List {
-                    elements,
-                    typ,
-                    helper_local,
-                })
+        let symbol = Symbol::Builtin(Builtin::Range);
+        self.define("range", Rc::new(symbol));
+
+        let symbol = Symbol::Builtin(Builtin::Enumerate);
+        self.define("enumerate", Rc::new(symbol));
+
+        let symbol = Symbol::Builtin(Builtin::Set);
+        self.define("set", Rc::new(symbol));
+
+        let symbol = Symbol::Builtin(Builtin::Super);
+        self.define("super", Rc::new(symbol));
+
+        let symbol = Symbol::Builtin(Builtin::IsInstance);
+        self.define("isinstance", Rc::new(symbol));
+
+        let symbol = Symbol::Builtin(Builtin::Print);
+        self.define("print", Rc::new(symbol));
+
+        let symbol = Symbol::Builtin(Builtin::Str);
+        self.define("str", Rc::new(symbol));
+
+        let symbol = Symbol::Builtin(Builtin::Repr);
+        self.define("repr", Rc::new(symbol));
+    }
+
+    /// Determine the type given a parsed expression
+    fn get_type(&self, typ: &ast::Expression) -> Result<Type, CompilationError> {
+        if self.is_final(typ) {
+            return Err(new_error(
+                typ,
+                "Final can only be used for module level constants, as in X: Final = 3",
+            ));
+        }
+        match &typ.kind {
+            ast::ExpressionType::Identifier(name) => match name.as_str() {
+                "float" => Ok(Type::Float),
+                "int" => Ok(Type::Integer),
+                "bool" => Ok(Type::Bool),
+                "str" => Ok(Type::Str),
+                "bytes" => Ok(Type::Bytes),
+                name => match self.lookup(name).as_deref() {
+                    Some(Symbol::Type { typ }) => Ok(typ.clone()),
+                    Some(Symbol::TypeVariable) => Err(new_error(
+                        typ,
+                        &format!(
+                            "Type variable {} can only be used by generic functions",
+                            name
+                        ),
+                    )),
+                    _ => Err(new_error(
+                        typ,
+                        &format!("Invalid type identifier: {}", name),
+                    )),
+                },
+            },
+            ast::ExpressionType::BinaryOperation {
+                a,
+                op: ast::BinaryOperation::BitOr,
+                b,
+            } => match (&a.kind, &b.kind) {
+                (_, ast::ExpressionType::None) => make_optional(&typ.location, self.get_type(a)?),
+                (ast::ExpressionType::None, _) => make_optional(&typ.location, self.get_type(b)?),
+                _ => make_union(&typ.location, vec![self.get_type(a)?, self.get_type(b)?]),
+            },
+            ast::ExpressionType::None => Err(new_error(
+                typ,
+                "None is only a type in Optional[T] or T | None, or as function result",
+            )),
+            ast::ExpressionType::Indexed { base, index } if self.is_callable(base) => {
+                self.get_callable_type(typ, index)
             }
-            ast::ExpressionType::Identifier(value) => {
-                let symbol = self.get_local(value);
-                Ok(Expression::Identifier(symbol))
+            ast::ExpressionType::Indexed { base, index } => {
+                let base = self.get_type_constructor(base)?;
+                let arguments = match &index.kind {
+                    ast::ExpressionType::Tuple { elements } => elements
+                        .iter()
+                        .map(|e| self.get_type(e))
+                        .collect::<Result<Vec<Type>, CompilationError>>()?,
+                    _ => vec![self.get_type(index)?],
+                };
+                self.apply(typ, base, arguments)
             }
-            ast::ExpressionType::Comparison { a, op, b } => {
-                let a = self.analyze_expression(a)?;
-                let b = self.analyze_expression(b)?;
-                self.equal_types(a.get_type(), b.get_type(), &expression.location)?;
+            _ => Err(new_error(typ, "Invalid type expression")),
+        }
+    }
 
-                let typ = Type::Bool;
-                Ok(Expression::BinaryOperation {
-                    a: Box::new(a),
-                    op: BinaryOperation::Comparison(op.clone()),
-                    b: Box::new(b),
+    fn is_callable(&self, annotation: &ast::Expression) -> bool {
+        matches!(
+            &annotation.kind,
+            ast::ExpressionType::Identifier(name)
+                if matches!(self.lookup(name).as_deref(), Some(Symbol::Builtin(Builtin::Callable)))
+        )
+    }
+
+    /// Determine a function type, as in Callable[[int, int], bool]
+    fn get_callable_type(
+        &self,
+        typ: &ast::Expression,
+        arguments: &ast::Expression,
+    ) -> Result<Type, CompilationError> {
+        let (parameters, result) = match &arguments.kind {
+            ast::ExpressionType::Tuple { elements } if elements.len() == 2 => {
+                match &elements[0].kind {
+                    ast::ExpressionType::List {
+                        elements: parameters,
+                    } => (parameters, &elements[1]),
+                    _ => return Err(new_error(typ, "Callable takes a list of parameter types")),
+                }
+            }
+            _ => {
+                return Err(new_error(
                     typ,
-                })
+                    "Callable takes parameter types and a result type, as in Callable[[int], int]",
+                ));
             }
-            ast::ExpressionType::BinaryOperation { a, op, b } => {
-                let a = self.analyze_expression(a)?;
-                let b = self.analyze_expression(b)?;
-                self.equal_types(a.get_type(), b.get_type(), &expression.location)?;
+        };
+        let parameters = parameters
+            .iter()
+            .map(|p| self.get_type(p))
+            .collect::<Result<Vec<Type>, CompilationError>>()?;
+        let result = match &result.kind {
+            ast::ExpressionType::None => Type::None,
+            _ => self.get_type(result)?,
+        };
+        Ok(Type::Callable(parameters, Box::new(result)))
+    }
 
-                let typ = a.get_type().clone();
-                // TODO: type checking!
-                Ok(Expression::BinaryOperation {
-                    a: Box::new(a),
-                    op: BinaryOperation::ArithmaticOperation(op.clone()),
-                    b: Box::new(b),
-                    typ,
-                })
+    fn get_type_constructor(
+        &self,
+        con: &ast::Expression,
+    ) -> Result<TypeConstructor, CompilationError> {
+        match &con.kind {
+            ast::ExpressionType::Identifier(name) => match name.as_str() {
+                "list" => Ok(TypeConstructor::List),
+                "tuple" => Ok(TypeConstructor::Tuple),
+                "dict" => Ok(TypeConstructor::Dict),
+                "set" => Ok(TypeConstructor::Set),
+                name => match self.lookup(name).as_deref() {
+                    Some(Symbol::Builtin(Builtin::Optional)) => Ok(TypeConstructor::Optional),
+                    Some(Symbol::Builtin(Builtin::Union)) => Ok(TypeConstructor::Union),
+                    _ => Err(new_error(
+                        con,
+                        &format!("No such type constructor {}", name),
+                    )),
+                },
+            },
+            _ => Err(new_error(con, "Invalid type constructor")),
+        }
+    }
+
+    fn apply(
+        &self,
+        typ: &ast::Expression,
+        con: TypeConstructor,
+        mut args: Vec<Type>,
+    ) -> Result<Type, CompilationError> {
+        match con {
+            TypeConstructor::List => {
+                if args.len() != 1 {
+                    return Err(new_error(typ, "list takes a single type argument"));
+                }
+                Ok(Type::List(Box::new(args.pop().unwrap())))
             }
-            ast::ExpressionType::BoolOp { a, op, b } => {
-                let a = self.analyze_expression(a)?;
-                let b = self.analyze_expression(b)?;
+            TypeConstructor::Tuple => Ok(Type::Tuple(args)),
+            TypeConstructor::Dict => {
+                if args.len() != 2 {
+                    return Err(new_error(typ, "dict takes a key and a value type argument"));
+                }
+                let value = args.pop().unwrap();
+                let key = args.pop().unwrap();
+                check_key_type(&typ.location, &key)?;
+                Ok(Type::Dict(Box::new(key), Box::new(value)))
+            }
+            TypeConstructor::Set => {
+                if args.len() != 1 {
+                    return Err(new_error(typ, "set takes a single type argument"));
+                }
+                let element = args.pop().unwrap();
+                check_key_type(&typ.location, &element)?;
+                Ok(Type::Set(Box::new(element)))
+            }
+            TypeConstructor::Optional => {
+                if args.len() != 1 {
+                    return Err(new_error(typ, "Optional takes a single type argument"));
+                }
+                make_optional(&typ.location, args.pop().unwrap())
+            }
+            TypeConstructor::Union => make_union(&typ.location, args),
+        }
+    }
 
-                Ok(Expression::BinaryOperation {
-                    a: Box::new(a),
-                    op: BinaryOperation::Boolean(op.clone()),
-                    b: Box::new(b),
-                    typ: Type::Bool,
-                })
+    /// Analyze a function, or a method when the type of self is given.
+    ///
+    /// When a nested function turns out to use variables of the function,
+    /// the function is analyzed again, with these variables in its environment.
+    ///
+    /// A lambda has no annotations, its types are given by a Callable signature.
+    fn analyze_function_def(
+        &mut self,
+        name: String,
+        function_def: &ast::FunctionDef,
+        self_type: Option<&Type>,
+        signature: Option<&Type>,
+    ) -> Result<Function, CompilationError> {
+        debug!("Analyzing function {}", name);
+        let functions = self.functions.len();
+        let mut captured = HashSet::new();
+        loop {
+            // The nested functions of an earlier attempt are replaced:
+            self.functions.truncate(functions);
+            self.function_wrappers
+                .retain(|_, wrapper| match wrapper.as_ref() {
+                    Symbol::Function { index, .. } => *index < functions,
+                    _ => panic!("Not a function"),
+                });
+            self.generic_instances
+                .retain(|_, instance| match instance.as_deref() {
+                    Some(Symbol::Function { index, .. }) => *index < functions,
+                    _ => true,
+                });
+            let (function, missed) =
+                self.analyze_function(name.clone(), function_def, self_type, signature, &captured)?;
+            if missed.is_empty() {
+                return Ok(function);
+            }
+            captured.extend(missed);
+        }
+    }
+
+    /// Analyze a function once, and return the variables which nested
+    /// functions use, but which are not captured yet.
+    fn analyze_function(
+        &mut self,
+        name: String,
+        function_def: &ast::FunctionDef,
+        self_type: Option<&Type>,
+        signature: Option<&Type>,
+        captured: &HashSet<String>,
+    ) -> Result<(Function, HashSet<String>), CompilationError> {
+        let (signature_parameters, signature_result) = match signature {
+            Some(Type::Callable(parameters, result)) => (Some(parameters), Some(result.as_ref())),
+            _ => (None, None),
+        };
+        self.enter_scope();
+        // Module level statements have their own locals:
+        let module_local_counter = self.local_counter;
+        self.local_counter = 0;
+        let mut parameters = vec![];
+        // A nested function or lambda receives the environment of the function defining it first:
+        let outer_environment = if self.frames.is_empty() && signature.is_none() {
+            None
+        } else {
+            let (parameter, symbol) = new_parameter("<environment>", &Type::Integer, 0);
+            parameters.push(parameter);
+            self.local_counter = 1;
+            Some(symbol)
+        };
+        for (position, parameter) in function_def.parameters.iter().enumerate() {
+            let index = self.local_counter;
+            self.local_counter += 1;
+            let param_type = match (&parameter.typ, self_type) {
+                (None, Some(self_type)) if index == 0 => self_type.clone(),
+                (Some(typ), Some(self_type)) if index == 0 => {
+                    let typ = self.get_type(typ)?;
+                    self.equal_types(&typ, self_type, &parameter.location)?;
+                    typ
+                }
+                (Some(typ), _) => self.get_type(typ)?,
+                (None, _) if signature_parameters.is_some() => {
+                    signature_parameters.unwrap()[position].clone()
+                }
+                (None, _) => {
+                    return Err(CompilationError::new(
+                        &parameter.location,
+                        format!("Parameter {} needs a type annotation", parameter.name),
+                    ));
+                }
+            };
+            // The arguments for *values: int are passed as list[int]:
+            let param_type = if parameter.variadic {
+                if parameters.iter().any(|p| p.variadic) {
+                    return Err(CompilationError::new(
+                        &parameter.location,
+                        "A function can only have one * parameter",
+                    ));
+                }
+                Type::List(Box::new(param_type))
+            } else {
+                param_type
+            };
+            // Parameters after *values can only be passed by keyword:
+            let keyword_only = parameter.variadic || parameters.iter().any(|p| p.variadic);
+            let default = match &parameter.default {
+                Some(default) => Some(self.analyze_default(default, &param_type)?),
+                None if !keyword_only && parameters.iter().any(|p| p.default.is_some()) => {
+                    return Err(CompilationError::new(
+                        &parameter.location,
+                        format!(
+                            "Parameter {} follows a parameter with a default value, so it needs one too",
+                            parameter.name
+                        ),
+                    ));
+                }
+                None => None,
+            };
+            let param = Rc::new(Parameter {
+                name: parameter.name.clone(),
+                typ: param_type,
+                default,
+                variadic: parameter.variadic,
+            });
+            let symbol = Rc::new(Symbol::Parameter {
+                parameter: param.clone(),
+                index,
+            });
+            self.define(&parameter.name, symbol.clone());
+            parameters.push(param);
+        }
+
+        self.frames.push(Frame {
+            name: name.clone(),
+            scope: self.scopes.len() - 1,
+            captured: captured.clone(),
+            missed: RefCell::new(HashSet::new()),
+            nonlocals: HashSet::new(),
+            environment: None,
+            cells: 0,
+            outer_environment,
+        });
+        // Captured parameters are copied to their cells:
+        let mut body = vec![];
+        if !captured.is_empty() {
+            let environment = self.new_local(None, Type::Integer);
+            self.frames.last_mut().unwrap().environment = Some(environment);
+            for parameter in &function_def.parameters {
+                if captured.contains(&parameter.name) {
+                    let value = Expression::Identifier(self.get_local(&parameter.name));
+                    let cell = self.new_cell(&parameter.name, value.get_type().clone());
+                    body.push(Statement::Assignment {
+                        target: Target::Symbol(cell),
+                        value,
+                    });
+                }
+            }
+        }
+
+        let return_type = match &function_def.result {
+            Some(ast::Expression {
+                kind: ast::ExpressionType::None,
+                ..
+            }) => None,
+            Some(e) => Some(self.get_type(e)?),
+            None => match signature_result {
+                Some(Type::None) | None => None,
+                Some(typ) => Some(typ.clone()),
+            },
+        };
+        self.return_type = return_type.clone();
+        body.extend(self.analyze_suite(&function_def.body)?);
+
+        let frame = self.frames.pop().unwrap();
+        let locals = self.leave_scope().locals;
+        self.local_counter = module_local_counter;
+        self.return_type = None;
+        let closure = frame.outer_environment.is_some();
+        let size = frame.cells;
+        let environment = frame.environment.map(|local| Environment { local, size });
+        let function = Function {
+            name,
+            parameters,
+            locals,
+            body,
+            return_type,
+            closure,
+            environment,
+        };
+        Ok((function, frame.missed.into_inner()))
+    }
+
+    /// Analyze the default value of a parameter, which must be a constant.
+    fn analyze_default(
+        &mut self,
+        value: &ast::Expression,
+        typ: &Type,
+    ) -> Result<Expression, CompilationError> {
+        let location = &value.location;
+        let value = self.analyze_expression(value)?;
+        if copy_constant(&value).is_none() && !matches!(value, Expression::None) {
+            return Err(CompilationError::new(
+                location,
+                "Default values must be known at compile time, as in scale: float = 1.0",
+            ));
+        }
+        self.check_type(value.get_type(), typ, location)?;
+        Ok(value)
+    }
+
+    /// Analyze a function in a function, which becomes a closure assigned to its name.
+    fn analyze_nested_function(
+        &mut self,
+        function_def: &ast::FunctionDef,
+    ) -> Result<Statement, CompilationError> {
+        let location = &function_def.location;
+        let outer_name = match self.frames.last() {
+            Some(frame) => frame.name.clone(),
+            None => {
+                return Err(CompilationError::new(
+                    location,
+                    "Functions can only be defined at module level or in functions",
+                ));
+            }
+        };
+        if !function_def.type_parameters.is_empty() {
+            return Err(CompilationError::new(
+                location,
+                "Only module level functions can have type parameters",
+            ));
+        }
+        let mut parameter_types = vec![];
+        for parameter in &function_def.parameters {
+            // A nested function is called through its Callable type, which has no defaults:
+            if let Some(default) = &parameter.default {
+                return Err(new_error(
+                    default,
+                    "Default values are only supported for module level functions and methods",
+                ));
+            }
+            if parameter.variadic {
+                return Err(CompilationError::new(
+                    &parameter.location,
+                    "* parameters are only supported for module level functions and methods",
+                ));
+            }
+            match &parameter.typ {
+                Some(typ) => parameter_types.push(self.get_type(typ)?),
+                None => {
+                    return Err(CompilationError::new(
+                        &parameter.location,
+                        format!("Parameter {} needs a type annotation", parameter.name),
+                    ));
+                }
+            }
+        }
+        let return_type = match &function_def.result {
+            Some(ast::Expression {
+                kind: ast::ExpressionType::None,
+                ..
+            })
+            | None => Type::None,
+            Some(e) => self.get_type(e)?,
+        };
+        let typ = Type::Callable(parameter_types, Box::new(return_type));
+
+        // The variable exists before the body is analyzed, so the function can call itself:
+        let name = ast::AssignmentTarget::Identifier(function_def.name.clone());
+        let target = self.analyze_target(&name, &typ, location)?;
+
+        let name = format!("{}.{}", outer_name, function_def.name);
+        let function = self.analyze_closure_function(name, function_def, None)?;
+        Ok(Statement::Assignment {
+            target,
+            value: Expression::Closure {
+                function,
+                environment: self.get_closure_environment(),
+                typ,
+            },
+        })
+    }
+
+    /// Analyze the function of a closure, and add it to the functions of the module.
+    fn analyze_closure_function(
+        &mut self,
+        name: String,
+        function_def: &ast::FunctionDef,
+        signature: Option<&Type>,
+    ) -> Result<Rc<Symbol>, CompilationError> {
+        let function = self.analyze_separate_function(name, function_def, signature)?;
+        let function = Rc::new(function);
+        let index = self.functions.len();
+        self.functions.push(function.clone());
+        Ok(Rc::new(Symbol::Function { function, index }))
+    }
+
+    /// Analyze a function while analyzing another function. It has its own
+    /// return type, handlers and narrowings.
+    fn analyze_separate_function(
+        &mut self,
+        name: String,
+        function_def: &ast::FunctionDef,
+        signature: Option<&Type>,
+    ) -> Result<Function, CompilationError> {
+        let return_type = self.return_type.take();
+        let method = self.method.take();
+        let exception = self.exception.take();
+        let finally_suites = std::mem::take(&mut self.finally_suites);
+        let narrowed = std::mem::take(&mut self.narrowed);
+        let module_level = std::mem::replace(&mut self.module_level, false);
+        let function = self.analyze_function_def(name, function_def, None, signature);
+        self.return_type = return_type;
+        self.method = method;
+        self.exception = exception;
+        self.finally_suites = finally_suites;
+        self.narrowed = narrowed;
+        self.module_level = module_level;
+        function
+    }
+
+    /// Find the type parameters of a function, which are declared as in
+    /// def first[T](xs: list[T]) -> T, or are type variables made with TypeVar.
+    ///
+    /// Their types are inferred from the arguments, so each must be used by a parameter.
+    fn get_type_parameters(
+        &self,
+        function_def: &ast::FunctionDef,
+    ) -> Result<Vec<String>, CompilationError> {
+        let mut names = vec![];
+        for parameter in &function_def.parameters {
+            if let Some(annotation) = &parameter.typ {
+                collect_type_names(annotation, &mut names);
+            }
+        }
+        let is_type_variable =
+            |name: &String| matches!(self.lookup(name).as_deref(), Some(Symbol::TypeVariable));
+        let mut type_parameters = function_def.type_parameters.clone();
+        if type_parameters.is_empty() {
+            for name in names.iter().filter(|name| is_type_variable(name)) {
+                if !type_parameters.contains(name) {
+                    type_parameters.push(name.clone());
+                }
+            }
+        }
+        let mut result_names = vec![];
+        if let Some(result) = &function_def.result {
+            collect_type_names(result, &mut result_names);
+        }
+        let type_variables = result_names.iter().filter(|name| is_type_variable(name));
+        for name in function_def.type_parameters.iter().chain(type_variables) {
+            if !names.contains(name) {
+                return Err(CompilationError::new(
+                    &function_def.location,
+                    format!(
+                        "Type parameter {} must be used by a parameter, so its type can be inferred",
+                        name
+                    ),
+                ));
+            }
+        }
+        if !type_parameters.is_empty() {
+            if let Some(parameter) = function_def.parameters.iter().find(|p| p.variadic) {
+                return Err(CompilationError::new(
+                    &parameter.location,
+                    "Generic functions cannot have a * parameter",
+                ));
+            }
+        }
+        Ok(type_parameters)
+    }
+
+    /// Analyze a generic function for the types of the arguments of a call.
+    ///
+    /// The types of the type parameters are inferred from the arguments, and
+    /// each combination of types gives one function, such as first[int].
+    fn instantiate_generic_function(
+        &mut self,
+        location: &Location,
+        generic: &GenericFunction,
+        arguments: &[Expression],
+        keywords: &[Keyword],
+    ) -> Result<Rc<Symbol>, CompilationError> {
+        let parameters = &generic.function_def.parameters;
+        let keyword_arguments = keywords.iter().filter_map(|keyword| {
+            let parameter = parameters.iter().find(|p| p.name == keyword.name)?;
+            Some((parameter, &keyword.value))
+        });
+        let mut types = HashMap::new();
+        for (parameter, argument) in parameters.iter().zip(arguments).chain(keyword_arguments) {
+            if let Some(annotation) = &parameter.typ {
+                self.infer_type_arguments(
+                    location,
+                    annotation,
+                    argument.get_type(),
+                    &generic.type_parameters,
+                    &mut types,
+                )?;
+            }
+        }
+        let mut type_arguments = vec![];
+        for name in &generic.type_parameters {
+            match types.remove(name) {
+                Some(typ) => type_arguments.push(typ),
+                None => {
+                    return Err(CompilationError::new(
+                        location,
+                        format!(
+                            "Cannot infer type {} of {} from the arguments",
+                            name, generic.name
+                        ),
+                    ));
+                }
+            }
+        }
+
+        let names: Vec<String> = type_arguments.iter().map(|t| t.to_string()).collect();
+        let name = format!("{}[{}]", generic.name, names.join(", "));
+        match self.generic_instances.get(&name) {
+            Some(Some(instance)) => return Ok(instance.clone()),
+            Some(None) => {
+                return Err(CompilationError::new(
+                    location,
+                    format!("Generic function {} cannot call itself", generic.name),
+                ));
+            }
+            None => {}
+        }
+        self.generic_instances.insert(name.clone(), None);
+
+        // The function only sees the module level names, and its type parameters:
+        let scopes = self.scopes.split_off(MODULE_SCOPES);
+        let frames = std::mem::take(&mut self.frames);
+        self.enter_scope();
+        for (parameter, typ) in generic.type_parameters.iter().zip(type_arguments) {
+            self.define(parameter, Rc::new(Symbol::Type { typ }));
+        }
+        let function = self.analyze_separate_function(name.clone(), &generic.function_def, None);
+        self.leave_scope();
+        self.scopes.extend(scopes);
+        self.frames = frames;
+
+        let function = Rc::new(function?);
+        let index = self.functions.len();
+        self.functions.push(function.clone());
+        let instance = Rc::new(Symbol::Function { function, index });
+        self.generic_instances.insert(name, Some(instance.clone()));
+        Ok(instance)
+    }
+
+    /// Find the types of type parameters by matching a parameter annotation,
+    /// such as list[T], with the type of the argument, such as list[int].
+    fn infer_type_arguments(
+        &self,
+        location: &Location,
+        annotation: &ast::Expression,
+        typ: &Type,
+        type_parameters: &[String],
+        types: &mut HashMap<String, Type>,
+    ) -> Result<(), CompilationError> {
+        let mut infer = |annotation, typ| {
+            self.infer_type_arguments(location, annotation, typ, type_parameters, types)
+        };
+        match &annotation.kind {
+            ast::ExpressionType::Identifier(name) if type_parameters.contains(name) => {
+                match types.get(name) {
+                    Some(other) if other != typ => Err(CompilationError::new(
+                        location,
+                        format!("Type {} cannot be both {} and {}", name, other, typ),
+                    )),
+                    Some(_) => Ok(()),
+                    None => {
+                        types.insert(name.clone(), typ.clone());
+                        Ok(())
+                    }
+                }
+            }
+            // An optional type, as in T | None
+            ast::ExpressionType::BinaryOperation {
+                a,
+                op: ast::BinaryOperation::BitOr,
+                b,
+            } => match (&a.kind, &b.kind, typ) {
+                (_, _, Type::NoneValue) => Ok(()),
+                (_, ast::ExpressionType::None, Type::Optional(typ)) => infer(a, typ),
+                (_, ast::ExpressionType::None, typ) => infer(a, typ),
+                (ast::ExpressionType::None, _, Type::Optional(typ)) => infer(b, typ),
+                (ast::ExpressionType::None, _, typ) => infer(b, typ),
+                _ => Ok(()),
+            },
+            ast::ExpressionType::Indexed { base, index } if self.is_callable(base) => {
+                match (&index.kind, typ) {
+                    (ast::ExpressionType::Tuple { elements }, Type::Callable(types, result))
+                        if elements.len() == 2 =>
+                    {
+                        if let ast::ExpressionType::List { elements } = &elements[0].kind {
+                            for (annotation, typ) in elements.iter().zip(types) {
+                                infer(annotation, typ)?;
+                            }
+                        }
+                        infer(&elements[1], result)
+                    }
+                    _ => Ok(()),
+                }
+            }
+            ast::ExpressionType::Indexed { base, index } => {
+                let annotations: Vec<&ast::Expression> = match &index.kind {
+                    ast::ExpressionType::Tuple { elements } => elements.iter().collect(),
+                    _ => vec![index],
+                };
+                let types: Vec<&Type> = match (self.get_type_constructor(base), typ) {
+                    (Ok(TypeConstructor::List), Type::List(element))
+                    | (Ok(TypeConstructor::Set), Type::Set(element)) => vec![element],
+                    (Ok(TypeConstructor::Dict), Type::Dict(key, value)) => vec![key, value],
+                    (Ok(TypeConstructor::Tuple), Type::Tuple(elements)) => {
+                        elements.iter().collect()
+                    }
+                    (Ok(TypeConstructor::Optional), Type::NoneValue) => vec![],
+                    (Ok(TypeConstructor::Optional), Type::Optional(typ)) => vec![typ],
+                    (Ok(TypeConstructor::Optional), typ) => vec![typ],
+                    _ => vec![],
+                };
+                if annotations.len() == types.len() {
+                    for (annotation, typ) in annotations.into_iter().zip(types) {
+                        infer(annotation, typ)?;
+                    }
+                }
+                Ok(())
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// The environment which a closure created here receives, if any.
+    fn get_closure_environment(&self) -> Option<Rc<Symbol>> {
+        self.frames.last().and_then(|frame| {
+            frame
+                .environment
+                .clone()
+                .or_else(|| frame.outer_environment.clone())
+        })
+    }
+
+    /// Analyze a lambda, as in lambda x: x * 2, which becomes a nested function
+    /// without a name. Its types are given by the Callable type where it is used.
+    fn analyze_lambda(
+        &mut self,
+        location: &Location,
+        parameters: &[ast::Parameter],
+        body: &ast::Expression,
+        typ: &Type,
+    ) -> Result<Expression, CompilationError> {
+        let (parameter_types, result) = match typ {
+            Type::Callable(parameter_types, result) => (parameter_types, result),
+            _ => panic!("A lambda must have a Callable type"),
+        };
+        if parameters.len() != parameter_types.len() {
+            return Err(CompilationError::new(
+                location,
+                format!(
+                    "Expected a lambda with {} parameters, but got {}",
+                    parameter_types.len(),
+                    parameters.len()
+                ),
+            ));
+        }
+        let body = match result.as_ref() {
+            Type::None => ast::Statement::Expression(body.clone()),
+            _ => ast::Statement::Return(body.clone()),
+        };
+        let function_def = ast::FunctionDef {
+            location: location.clone(),
+            name: "<lambda>".to_owned(),
+            type_parameters: vec![],
+            parameters: parameters.to_vec(),
+            result: None,
+            body: vec![body],
+        };
+        let name = match self.frames.last() {
+            Some(frame) => format!("{}.<lambda>", frame.name),
+            None => "<lambda>".to_owned(),
+        };
+        let function = self.analyze_closure_function(name, &function_def, Some(typ))?;
+        Ok(Expression::Closure {
+            function,
+            environment: self.get_closure_environment(),
+            typ: typ.clone(),
+        })
+    }
+
+    fn analyze_suite(&mut self, suite: &[ast::Statement]) -> Result<Suite, CompilationError> {
+        let narrowed = self.narrowed.clone();
+        let mut statements: Suite = vec![];
+        for statement in suite {
+            if let ast::Statement::Global { location, names } = statement {
+                self.declare_globals(location, names)?;
+                continue;
+            }
+            if let ast::Statement::Nonlocal { location, names } = statement {
+                self.declare_nonlocals(location, names)?;
+                continue;
+            }
+            if let ast::Statement::Assert {
+                location,
+                condition,
+                message,
+            } = statement
+            {
+                let (when_true, _) = self.get_narrowings(condition);
+                if let Some(statement) = self.analyze_assert(location, condition, message)? {
+                    statements.push(statement);
+                }
+                // The statements after an assert rely on its condition, as in assert x is not None
+                self.narrowed.extend(when_true);
+                continue;
+            }
+            // A variable which is assigned is no longer known not to be None,
+            // but the value of the assignment can still use it:
+            let assigned = assigned_names(statement);
+            let target = match statement {
+                ast::Statement::Assignment {
+                    target: ast::AssignmentTarget::Identifier(name),
+                    ..
+                }
+                | ast::Statement::AnnotatedAssignment {
+                    target: ast::AssignmentTarget::Identifier(name),
+                    ..
+                } => Some(name),
+                _ => None,
+            };
+            self.narrowed
+                .retain(|n| !assigned.contains(&n.name) || Some(&n.name) == target);
+            let statement = self.analyze_statement(statement)?;
+            self.narrowed.retain(|n| !assigned.contains(&n.name));
+            statements.push(statement);
+        }
+        self.narrowed = narrowed;
+        Ok(statements)
+    }
+
+    /// Analyze an assert statement, which is left out when asserts are disabled.
+    fn analyze_assert(
+        &mut self,
+        location: &Location,
+        condition: &ast::Expression,
+        message: &Option<ast::Expression>,
+    ) -> Result<Option<Statement>, CompilationError> {
+        let condition = self.analyze_condition(condition)?;
+        let message = match message {
+            Some(message) => {
+                let location = &message.location;
+                let message = self.analyze_expression(message)?;
+                check_printable(location, message.get_type())?;
+                match message.get_type() {
+                    Type::Str => message,
+                    _ => Expression::ToStr {
+                        value: Box::new(message),
+                        repr: false,
+                    },
+                }
+            }
+            None => Expression::String(String::new()),
+        };
+        if !self.asserts {
+            return Ok(None);
+        }
+        Ok(Some(Statement::Assert {
+            condition,
+            message,
+            line: location.row,
+            handler: self.get_assertion_handler(),
+        }))
+    }
+
+    /// Let a function assign to module level variables, as in global counter
+    fn declare_globals(
+        &mut self,
+        location: &Location,
+        names: &[String],
+    ) -> Result<(), CompilationError> {
+        if self.module_level {
+            // Variables at module level are global already.
+            return Ok(());
+        }
+        for name in names {
+            if self.is_defined(name) {
+                return Err(CompilationError::new(
+                    location,
+                    format!("{} is used before its global declaration", name),
+                ));
+            }
+            match self.lookup(name) {
+                Some(symbol) if matches!(symbol.as_ref(), Symbol::Global { .. }) => {
+                    self.define(name, symbol);
+                }
+                _ => {
+                    return Err(CompilationError::new(
+                        location,
+                        format!("{} is not a global variable", name),
+                    ));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Let a nested function assign to variables of enclosing functions, as in nonlocal count
+    fn declare_nonlocals(
+        &mut self,
+        location: &Location,
+        names: &[String],
+    ) -> Result<(), CompilationError> {
+        for name in names {
+            if self.is_defined(name) {
+                return Err(CompilationError::new(
+                    location,
+                    format!("{} is used before its nonlocal declaration", name),
+                ));
+            }
+            let owner = match self.find_enclosing_frame(name) {
+                Some(owner) => owner,
+                None => {
+                    return Err(CompilationError::new(
+                        location,
+                        format!("No binding for nonlocal {} found", name),
+                    ));
+                }
+            };
+            let symbol = self.capture_variable(owner, name);
+            self.frames
+                .last_mut()
+                .unwrap()
+                .nonlocals
+                .insert(name.clone());
+            self.define(name, symbol);
+        }
+        Ok(())
+    }
+
+    fn analyze_statement(
+        &mut self,
+        statement: &ast::Statement,
+    ) -> Result<Statement, CompilationError> {
+        match statement {
+            ast::Statement::Return(e) => {
+                if self.module_level {
+                    return Err(new_error(e, "return outside function"));
+                }
+                // A returned lambda has the return type of the function:
+                let value = match (&e.kind, self.return_type.clone()) {
+                    (ast::ExpressionType::Lambda { .. }, Some(typ)) => {
+                        self.analyze_typed_expression(e, &typ)?
+                    }
+                    _ => self.analyze_expression(e)?,
+                };
+                let value = match &self.return_type {
+                    Some(typ) => {
+                        self.check_type(value.get_type(), typ, &e.location)?;
+                        coerce(value, typ)
+                    }
+                    None => {
+                        return Err(new_error(e, "Function has no return type"));
+                    }
+                };
+                let finally_suite = self.analyze_finally_suites()?;
+                Ok(Statement::Return {
+                    value,
+                    finally_suite,
+                })
+            }
+            ast::Statement::Raise {
+                location,
+                exception,
+            } => {
+                let (exception, line) = match (exception, &self.exception) {
+                    (Some(exception), _) => {
+                        (self.analyze_exception(exception)?, Some(location.row))
+                    }
+                    (None, Some(symbol)) => (Expression::Identifier(symbol.clone()), None),
+                    (None, None) => {
+                        return Err(CompilationError::new(
+                            location,
+                            "raise without an exception can only be used in an except clause",
+                        ));
+                    }
+                };
+                Ok(Statement::Raise { exception, line })
+            }
+            ast::Statement::Try {
+                suite,
+                handlers,
+                else_suite,
+                finally_suite,
+            } => self.analyze_try(suite, handlers, else_suite, finally_suite),
+            ast::Statement::If {
+                condition,
+                suite,
+                else_suite,
+            } => {
+                let (when_true, when_false) = self.get_narrowings(condition);
+                let condition = self.analyze_condition(condition)?;
+                let narrowed = self.narrowed.clone();
+                self.narrowed.extend(when_true.iter().cloned());
+                let body = self.analyze_suite(suite)?;
+                self.narrowed = narrowed.clone();
+                self.narrowed.extend(when_false.iter().cloned());
+                let else_body = self.analyze_suite(else_suite)?;
+                self.narrowed = narrowed;
+
+                // When a branch returns, the statements after the if follow the other branch:
+                if ends_with_return(suite) {
+                    self.narrowed.extend(when_false);
+                } else if ends_with_return(else_suite) {
+                    self.narrowed.extend(when_true);
+                }
+
+                Ok(Statement::If {
+                    condition,
+                    suite: body,
+                    else_suite: else_body,
+                })
+            }
+            ast::Statement::While { condition, suite } => {
+                let (when_true, _) = self.get_narrowings(condition);
+                let condition = self.analyze_condition(condition)?;
+                let narrowed = self.narrowed.clone();
+                self.narrowed.extend(when_true);
+                let suite = self.analyze_suite(suite)?;
+                self.narrowed = narrowed;
+
+                Ok(Statement::While { condition, suite })
+            }
+            ast::Statement::For {
+                target,
+                iter,
+                suite,
+            } => {
+                if let Some(arguments) = self.get_builtin_arguments(iter, Builtin::Range) {
+                    let range = self.analyze_range(&iter.location, arguments)?;
+                    let counter = self.new_local(None, Type::Integer);
+                    let target = match target {
+                        ast::AssignmentTarget::Identifier(name) => {
+                            if !self.is_defined(name) && self.module_level {
+                                check_export_name(&iter.location, name)?;
+                            }
+                            self.store_value(name, &Type::Integer)
+                        }
+                        _ => {
+                            return Err(new_error(
+                                iter,
+                                "range can only be assigned to a single variable",
+                            ));
+                        }
+                    };
+                    let suite = self.analyze_suite(suite)?;
+                    return Ok(Statement::ForRange {
+                        counter,
+                        target,
+                        range,
+                        suite,
+                    });
+                }
+
+                let location = &iter.location;
+
+                // Iterating over d.keys(), d.values() or d.items():
+                if let ast::ExpressionType::Call {
+                    callee,
+                    arguments,
+                    keywords,
+                } = &iter.kind
+                {
+                    if let ast::ExpressionType::Attribute { base, name } = &callee.kind {
+                        let view = match name.as_str() {
+                            "keys" => Some(DictView::Keys),
+                            "values" => Some(DictView::Values),
+                            "items" => Some(DictView::Items),
+                            _ => None,
+                        };
+                        if let (Some(view), true) =
+                            (view, arguments.is_empty() && keywords.is_empty())
+                        {
+                            let dict = self.analyze_expression(base)?;
+                            return self.analyze_for_dict(location, target, dict, view, suite);
+                        }
+                    }
+                }
+
+                let loop_var = self.new_local(None, Type::Integer);
+                let iter_var = self.new_local(None, Type::Integer);
+
+                // Iterating over enumerate(x) is iterating over x, with an extra target:
+                let (index_target, target, iter) =
+                    match self.get_builtin_arguments(iter, Builtin::Enumerate) {
+                        Some([iter]) => match target {
+                            ast::AssignmentTarget::Tuple(targets) if targets.len() == 2 => {
+                                let index_target = self.analyze_unpacked_target(
+                                    &targets[0],
+                                    &Type::Integer,
+                                    location,
+                                )?;
+                                (Some(index_target), &targets[1], iter)
+                            }
+                            _ => {
+                                return Err(CompilationError::new(
+                                    location,
+                                    "enumerate must be unpacked into two targets",
+                                ));
+                            }
+                        },
+                        Some(_) => {
+                            return Err(CompilationError::new(
+                                location,
+                                "enumerate takes a single argument",
+                            ));
+                        }
+                        None => (None, target, iter.as_ref()),
+                    };
+
+                let iter = self.analyze_expression(iter)?;
+                let typ = iter.get_type();
+                let element_typ = match typ {
+                    Type::List(element_typ) => element_typ,
+                    Type::Dict(..) | Type::Set(_) if index_target.is_none() => {
+                        return self.analyze_for_dict(
+                            location,
+                            target,
+                            iter,
+                            DictView::Keys,
+                            suite,
+                        );
+                    }
+                    other => {
+                        return Err(CompilationError::new(
+                            location,
+                            format!("Cannot iterate over this type: {}", other),
+                        ));
+                    }
+                };
+                let target = self.analyze_unpacked_target(target, element_typ, location)?;
+                let suite = self.analyze_suite(suite)?;
+                Ok(Statement::For {
+                    loop_var,
+                    iter_var,
+                    index_target,
+                    target,
+                    iter,
+                    suite,
+                })
+            }
+            ast::Statement::Match {
+                location,
+                subject,
+                cases,
+            } => self.analyze_match(location, subject, cases),
+            ast::Statement::Pass => {
+                unimplemented!();
+            }
+            ast::Statement::Break => {
+                unimplemented!();
+            }
+            ast::Statement::Continue => {
+                unimplemented!();
+            }
+            ast::Statement::Expression(expr) => {
+                let expr = self.analyze_expression(expr)?;
+                Ok(Statement::Expression(expr))
+            }
+            ast::Statement::Global { .. } => {
+                panic!("Global declarations are handled by analyze_suite");
+            }
+            ast::Statement::Nonlocal { .. } => {
+                panic!("Nonlocal declarations are handled by analyze_suite");
+            }
+            ast::Statement::Assert { .. } => {
+                panic!("Assert statements are handled by analyze_suite");
+            }
+            ast::Statement::FunctionDef(function_def) => self.analyze_nested_function(function_def),
+            ast::Statement::Assignment { target, value } => {
+                let location = &value.location;
+                // A declared field gives the type of empty containers, as in self.d = {}
+                let value = match self.get_declared_field_type(target)? {
+                    Some(typ) => self.analyze_typed_expression(value, &typ)?,
+                    None => self.analyze_expression(value)?,
+                };
+                self.check_is_value(&value, location)?;
+                let target = self.analyze_target(target, value.get_type(), location)?;
+                let value = coerce(value, target.get_type());
+                Ok(Statement::Assignment { target, value })
+            }
+            ast::Statement::AnnotatedAssignment {
+                target,
+                annotation,
+                value,
+            } => {
+                let location = &value.location;
+                let typ = self.get_type(annotation)?;
+                let value = self.analyze_typed_expression(value, &typ)?;
+                let target = self.analyze_target(target, &typ, location)?;
+                Ok(Statement::Assignment { target, value })
+            }
+            ast::Statement::Delete { location, target } => match target {
+                ast::AssignmentTarget::Indexed { base, index } => {
+                    let location = &base.location;
+                    let base = self.analyze_expression(base)?;
+                    match base.get_type() {
+                        Type::Dict(key_typ, _) => {
+                            let key_typ = *key_typ.clone();
+                            let key = self.analyze_typed_expression(index, &key_typ)?;
+                            Ok(Statement::DeleteItem { base, key })
+                        }
+                        other => Err(CompilationError::new(
+                            location,
+                            format!("Cannot delete elements of type: {}", other),
+                        )),
+                    }
+                }
+                _ => Err(CompilationError::new(
+                    location,
+                    "Only items of a dict can be deleted",
+                )),
+            }, // ast::Statement::AugmentAssignment { .. } => {
+               // self.get_local(target);
+               // self.compile_expression(value);
+               // // TODO: coerce!
+               // self.emit_operator(op);
+
+               // let typ = wasm::Type::I32; // TODO!
+               // self.store_value(target, typ);
+               // unimplemented!();
+               // }
+        }
+    }
+
+    // fn compile_condition(&self, condition: &ast::Expression) {
+    //     self.compile_expression
+    //     unimplemented!();
+    // }
+
+    /// Analyze the arguments of a call, where a lambda gets the type of its parameter.
+    fn analyze_arguments(
+        &mut self,
+        arguments: &[ast::Expression],
+        keywords: &[(String, ast::Expression)],
+        parameters: &[Rc<Parameter>],
+    ) -> Result<(Vec<Expression>, Vec<Keyword>), CompilationError> {
+        let variadic = parameters.iter().position(|p| p.variadic);
+        let (arguments, extra_arguments) = match variadic {
+            Some(index) if index < arguments.len() => arguments.split_at(index),
+            _ => (arguments, &[][..]),
+        };
+        let mut new_arguments = vec![];
+        for (index, argument) in arguments.iter().enumerate() {
+            let parameter = parameters.get(index);
+            new_arguments.push(self.analyze_argument(argument, parameter)?);
+        }
+        let mut new_keywords = vec![];
+        for (name, value) in keywords {
+            let parameter = parameters.iter().find(|p| &p.name == name && !p.variadic);
+            if parameter.is_none() && parameters.iter().any(|p| &p.name == name) {
+                return Err(new_error(
+                    value,
+                    &format!("Unexpected keyword argument {}", name),
+                ));
+            }
+            new_keywords.push(Keyword {
+                location: value.location.clone(),
+                name: name.clone(),
+                value: self.analyze_argument(value, parameter)?,
+            });
+        }
+
+        // The remaining positional arguments are packed into a list:
+        if let Some(index) = variadic {
+            let parameter = &parameters[index];
+            let value = self.analyze_variadic_arguments(extra_arguments, parameter)?;
+            if new_arguments.len() == index {
+                new_arguments.push(value);
+            } else {
+                // Left out arguments before it get their default value:
+                new_keywords.push(Keyword {
+                    location: Location::default(),
+                    name: parameter.name.clone(),
+                    value,
+                });
+            }
+        }
+        Ok((new_arguments, new_keywords))
+    }
+
+    /// Pack the arguments for a * parameter into a new list.
+    fn analyze_variadic_arguments(
+        &mut self,
+        arguments: &[ast::Expression],
+        parameter: &Parameter,
+    ) -> Result<Expression, CompilationError> {
+        // A single unpacked list is copied, as in total(*xs)
+        if let [ast::Expression {
+            kind: ast::ExpressionType::Starred(value),
+            ..
+        }] = arguments
+        {
+            let location = &value.location;
+            let value = self.analyze_expression(value)?;
+            self.check_type(value.get_type(), &parameter.typ, location)?;
+            let slice = Slice {
+                lower: None,
+                upper: None,
+                step: None,
+            };
+            return Ok(Expression::Slice {
+                base: Box::new(value),
+                slice: Box::new(slice),
+            });
+        }
+
+        let element_typ = match &parameter.typ {
+            Type::List(element_typ) => *element_typ.clone(),
+            other => panic!("A * parameter has type {}", other),
+        };
+        let mut elements = vec![];
+        for argument in arguments {
+            if let ast::ExpressionType::Starred(_) = &argument.kind {
+                return Err(new_error(
+                    argument,
+                    "Only a single list can be unpacked into a * parameter, as in total(*xs)",
+                ));
+            }
+            elements.push(self.analyze_typed_expression(argument, &element_typ)?);
+        }
+        let helper_local = self.new_local(None, Type::Integer);
+        Ok(Expression::List {
+            elements,
+            typ: parameter.typ.clone(),
+            helper_local,
+        })
+    }
+
+    fn analyze_argument(
+        &mut self,
+        argument: &ast::Expression,
+        parameter: Option<&Rc<Parameter>>,
+    ) -> Result<Expression, CompilationError> {
+        let value = match (&argument.kind, parameter) {
+            (ast::ExpressionType::Lambda { .. }, Some(parameter)) => {
+                self.analyze_typed_expression(argument, &parameter.typ)?
+            }
+            _ => self.analyze_expression(argument)?,
+        };
+        self.check_is_value(&value, &argument.location)?;
+        Ok(value)
+    }
+
+    /// The parameters of the function which is called, if they are known
+    /// before the arguments are analyzed.
+    fn get_parameters(&self, callee: &ast::Expression) -> Vec<Rc<Parameter>> {
+        let symbol = match &callee.kind {
+            ast::ExpressionType::Identifier(name) => self.lookup(name),
+            _ => None,
+        };
+        let symbol = match symbol {
+            Some(symbol) => symbol,
+            None => return vec![],
+        };
+        match symbol.as_ref() {
+            Symbol::Function { function, .. } => function.parameters.clone(),
+            Symbol::ExternFunction { import, .. } => unnamed_parameters(&import.parameter_types),
+            // The new object is passed as self:
+            Symbol::Type {
+                typ: Type::Class(class_name),
+            } => {
+                let class = self.get_class(class_name);
+                match class.get_method("__init__") {
+                    Some(slot) => match class.methods[slot].function.as_ref() {
+                        Symbol::Function { function, .. } => function.parameters[1..].to_vec(),
+                        _ => vec![],
+                    },
+                    None => vec![],
+                }
+            }
+            Symbol::Local { .. }
+            | Symbol::Parameter { .. }
+            | Symbol::Global { .. }
+            | Symbol::Cell { .. } => match symbol.get_type() {
+                Type::Callable(parameter_types, _) => unnamed_parameters(parameter_types),
+                _ => vec![],
+            },
+            _ => vec![],
+        }
+    }
+
+    fn analyze_expressions(
+        &mut self,
+        expressions: &[ast::Expression],
+    ) -> Result<Vec<Expression>, CompilationError> {
+        let mut new_expressions = vec![];
+        for expression in expressions {
+            let new_expr = self.analyze_expression(expression)?;
+            self.check_is_value(&new_expr, &expression.location)?;
+            new_expressions.push(new_expr);
+        }
+        Ok(new_expressions)
+    }
+
+    /// Analyze the condition of an if or while statement.
+    fn analyze_condition(
+        &mut self,
+        condition: &ast::Expression,
+    ) -> Result<Expression, CompilationError> {
+        let location = &condition.location;
+        let condition = self.analyze_expression(condition)?;
+        match condition.get_type() {
+            Type::Bool | Type::Integer => Ok(condition),
+            other => Err(CompilationError::new(
+                location,
+                format!("Cannot use {} as condition", other),
+            )),
+        }
+    }
+
+    /// Check that an expression produces a value, which is not the
+    /// case for calls to functions without a return type.
+    fn check_is_value(
+        &self,
+        expression: &Expression,
+        location: &Location,
+    ) -> Result<(), CompilationError> {
+        if let Type::None = expression.get_type() {
+            return Err(CompilationError::new(
+                location,
+                "Function does not return a value".to_owned(),
+            ));
+        }
+        Ok(())
+    }
+
+    fn analyze_expression(
+        &mut self,
+        expression: &ast::Expression,
+    ) -> Result<Expression, CompilationError> {
+        match &expression.kind {
+            ast::ExpressionType::Number(value) => Ok(Expression::Number(*value)),
+            ast::ExpressionType::Float(value) => Ok(Expression::Float(*value)),
+            // ast::Expression::Bool(_) => {
+            //     unimplemented!();
+            // }
+            ast::ExpressionType::Str(value) => {
+                // unimplemented!("STR: {}", value);
+
+                // println!("Str: {} {}", value, value.len());
+                // TODO: how to represent strings?
+                Ok(Expression::String(value.clone()))
+            }
+            ast::ExpressionType::None => Ok(Expression::None),
+            ast::ExpressionType::List { elements } => {
+                let elements = self.analyze_expressions(elements)?;
+
+                // Well. A list.
+                // Constraints:
+                // - At least one element
+                // - All elements have equal type
+                if elements.is_empty() {
+                    return Err(new_error(expression, "List must contain at least 1 value"));
+                }
+
+                let (first, rest) = elements.split_first().expect("At least 1 element");
+
+                let element_typ = first.get_type();
+                for item in rest {
+                    if element_typ != item.get_type() {
+                        return Err(new_error(
+                            expression,
+                            "All elements must be of the same type",
+                        ));
+                    }
+                }
+
+                let typ = Type::List(Box::new(element_typ.clone()));
+
+                let helper_local = self.new_local(None, Type::Integer);
+
+                Ok(Expression::List {
+                    elements,
+                    typ,
+                    helper_local,
+                })
+            }
+            ast::ExpressionType::Identifier(value) => {
+                // Constants are replaced by their value:
+                if let Some(Symbol::Constant { value }) = self.lookup(value).as_deref() {
+                    return Ok(copy_constant(value).expect("Constant value"));
+                }
+                if let Some(narrowing) = self.narrowed.iter().rev().find(|n| &n.name == value) {
+                    return Ok(narrowing.get_value());
+                }
+                if let Some(symbol) = self.lookup(value) {
+                    if let Symbol::Function { .. } | Symbol::ExternFunction { .. } = symbol.as_ref()
+                    {
+                        return Ok(self.analyze_function_value(value, symbol));
+                    }
+                }
+                let symbol = self.lookup_variable(&expression.location, value)?;
+                Ok(Expression::Identifier(symbol))
+            }
+            ast::ExpressionType::Comparison {
+                a,
+                op: op @ ast::Comparison::In,
+                b,
+            }
+            | ast::ExpressionType::Comparison {
+                a,
+                op: op @ ast::Comparison::NotIn,
+                b,
+            } => {
+                let item = self.analyze_expression(a)?;
+                let container = self.analyze_expression(b)?;
+                match container.get_type() {
+                    Type::Dict(key_typ, _) | Type::Set(key_typ) => {
+                        self.equal_types(item.get_type(), key_typ, &expression.location)?;
+                    }
+                    other => {
+                        return Err(CompilationError::new(
+                            &expression.location,
+                            format!("Cannot test membership of type: {}", other),
+                        ));
+                    }
+                }
+                Ok(Expression::Contains {
+                    item: Box::new(item),
+                    container: Box::new(container),
+                    negate: matches!(op, ast::Comparison::NotIn),
+                })
+            }
+            ast::ExpressionType::Comparison {
+                a,
+                op: op @ ast::Comparison::Is,
+                b,
+            }
+            | ast::ExpressionType::Comparison {
+                a,
+                op: op @ ast::Comparison::IsNot,
+                b,
+            } => {
+                let a = self.analyze_expression(a)?;
+                let b = self.analyze_expression(b)?;
+                self.analyze_identity(&expression.location, a, op, b)
+            }
+            ast::ExpressionType::Comparison { a, op, b } => {
+                let a = self.analyze_expression(a)?;
+                let b = self.analyze_expression(b)?;
+                match op {
+                    ast::Comparison::Equal => {
+                        return self.analyze_equality(&expression.location, a, b, false);
+                    }
+                    ast::Comparison::NotEqual => {
+                        return self.analyze_equality(&expression.location, a, b, true);
+                    }
+                    _ => {}
+                }
+                self.equal_types(a.get_type(), b.get_type(), &expression.location)?;
+                match a.get_type() {
+                    Type::Enum(name) => {
+                        return Err(CompilationError::new(
+                            &expression.location,
+                            format!("Members of {} can only be compared with == and !=", name),
+                        ));
+                    }
+                    Type::Union(_) => {
+                        return Err(CompilationError::new(
+                            &expression.location,
+                            format!(
+                                "Cannot compare {}, check the type with isinstance first",
+                                a.get_type()
+                            ),
+                        ));
+                    }
+                    _ => {}
+                }
+
+                let typ = Type::Bool;
+                Ok(Expression::BinaryOperation {
+                    a: Box::new(a),
+                    op: BinaryOperation::Comparison(op.clone()),
+                    b: Box::new(b),
+                    typ,
+                })
+            }
+            ast::ExpressionType::BinaryOperation { a, op, b } => {
+                let a = self.analyze_expression(a)?;
+                let b = self.analyze_expression(b)?;
+                self.equal_types(a.get_type(), b.get_type(), &expression.location)?;
+                if let Some(value) = fold_binary_operation(&a, op, &b) {
+                    return Ok(value);
+                }
+
+                match (a.get_type(), op) {
+                    (Type::Str, ast::BinaryOperation::Add) => {
+                        // Join a + b + c at once:
+                        let mut parts = match a {
+                            Expression::Concat { parts } => parts,
+                            a => vec![a],
+                        };
+                        parts.push(b);
+                        return Ok(Expression::Concat { parts });
+                    }
+                    (Type::Set(_), ast::BinaryOperation::BitOr)
+                    | (Type::Set(_), ast::BinaryOperation::BitAnd)
+                    | (Type::Set(_), ast::BinaryOperation::Sub) => {
+                        return Ok(Expression::SetOperation {
+                            a: Box::new(a),
+                            op: op.clone(),
+                            b: Box::new(b),
+                        });
+                    }
+                    // A constant divisor other than 0 and -1 needs no checks:
+                    (Type::Integer, ast::BinaryOperation::Div) if !matches!(b, Expression::Number(value) if value != 0 && value != -1) =>
+                    {
+                        return Ok(Expression::IntDivision {
+                            a: Box::new(a),
+                            b: Box::new(b),
+                            line: expression.location.row,
+                        });
+                    }
+                    (Type::Integer, _)
+                    | (Type::Float, ast::BinaryOperation::Add)
+                    | (Type::Float, ast::BinaryOperation::Sub)
+                    | (Type::Float, ast::BinaryOperation::Mul)
+                    | (Type::Float, ast::BinaryOperation::Div) => {}
+                    _ => {
+                        return Err(CompilationError::new(
+                            &expression.location,
+                            format!("Unsupported operation {:?} for type {}", op, a.get_type()),
+                        ));
+                    }
+                }
+
+                let typ = a.get_type().clone();
+                // TODO: type checking!
+                Ok(Expression::BinaryOperation {
+                    a: Box::new(a),
+                    op: BinaryOperation::ArithmaticOperation(op.clone()),
+                    b: Box::new(b),
+                    typ,
+                })
+            }
+            ast::ExpressionType::BoolOp { a, op, b } => {
+                // b is only evaluated when a does not decide the result, as in
+                // x is not None and len(x) > 0
+                let (when_true, when_false) = self.get_narrowings(a);
+                let a = self.analyze_expression(a)?;
+                let narrowed = self.narrowed.clone();
+                match op {
+                    ast::BooleanOperator::And => self.narrowed.extend(when_true),
+                    ast::BooleanOperator::Or => self.narrowed.extend(when_false),
+                }
+                let b = self.analyze_expression(b);
+                self.narrowed = narrowed;
+                let b = b?;
+
+                Ok(Expression::BinaryOperation {
+                    a: Box::new(a),
+                    op: BinaryOperation::Boolean(op.clone()),
+                    b: Box::new(b),
+                    typ: Type::Bool,
+                })
+            }
+            ast::ExpressionType::Slice { .. } => {
+                Err(new_error(expression, "Slices can only be used as index"))
+            }
+            ast::ExpressionType::Starred(_) => Err(new_error(
+                expression,
+                "A list can only be unpacked into a * parameter, as in total(*xs)",
+            )),
+            ast::ExpressionType::Lambda { .. } => Err(new_error(
+                expression,
+                "The type of a lambda must be known, as in an argument of type Callable[[int], int]",
+            )),
+            ast::ExpressionType::UnaryOperation { op, a } => {
+                let a = self.analyze_expression(a)?;
+                match (op, a) {
+                    // Fold negative constants:
+                    (ast::UnaryOperation::Minus, Expression::Number(value)) => {
+                        Ok(Expression::Number(value.wrapping_neg()))
+                    }
+                    (ast::UnaryOperation::Minus, Expression::Float(value)) => {
+                        Ok(Expression::Float(-value))
+                    }
+                    (op, a) => match a.get_type() {
+                        Type::Integer | Type::Float => Ok(Expression::UnaryOperation {
+                            op: op.clone(),
+                            a: Box::new(a),
+                        }),
+                        other => Err(CompilationError::new(
+                            &expression.location,
+                            format!("Cannot negate type: {}", other),
+                        )),
+                    },
+                }
+            }
+            ast::ExpressionType::Call {
+                callee,
+                arguments,
+                keywords,
+            } => {
+                if let ast::ExpressionType::Attribute { base, name } = &callee.kind {
+                    return self.analyze_method_call(
+                        &expression.location,
+                        base,
+                        name,
+                        arguments,
+                        keywords,
+                    );
+                }
+                // The class argument is a type, not a value:
+                if let Some(arguments) = self.get_builtin_arguments(expression, Builtin::IsInstance)
+                {
+                    return self.analyze_isinstance(&expression.location, arguments);
+                }
+
+                let parameters = self.get_parameters(callee);
+                let (mut args, keywords) =
+                    self.analyze_arguments(arguments, keywords, &parameters)?;
+
+                match &callee.kind {
+                    ast::ExpressionType::Identifier(name) => {
+                        // arg
+                        // return Err(Self::new_error(callee, "TODO".to_owned()));
+                        if let Some(callee) = self.lookup(name) {
+                            // callee
+
+                            match callee.as_ref() {
+                                Symbol::Function { .. } => self.analyze_function_call(
+                                    &expression.location,
+                                    callee,
+                                    args,
+                                    keywords,
+                                ),
+                                Symbol::ExternFunction { index: _, import } => {
+                                    // Check argument types:
+                                    self.check_arguments(
+                                        &expression.location,
+                                        &mut args,
+                                        keywords,
+                                        &unnamed_parameters(&import.parameter_types),
+                                    )?;
+
+                                    // determine return type!
+                                    let typ = import.return_type.as_ref().unwrap().clone();
+
+                                    Ok(Expression::Call {
+                                        callee,
+                                        arguments: args,
+                                        typ,
+                                    })
+                                }
+                                Symbol::GenericFunction(generic) => {
+                                    let callee = self.instantiate_generic_function(
+                                        &expression.location,
+                                        generic,
+                                        &args,
+                                        &keywords,
+                                    )?;
+                                    self.analyze_function_call(
+                                        &expression.location,
+                                        callee,
+                                        args,
+                                        keywords,
+                                    )
+                                }
+                                Symbol::Builtin(_) if !keywords.is_empty() => Err(new_error(
+                                    expression,
+                                    &format!("{} takes no keyword arguments", name),
+                                )),
+                                Symbol::Builtin(builtin) => self.analyze_builtin_call(
+                                    &callee,
+                                    &expression.location,
+                                    builtin,
+                                    args,
+                                ),
+                                Symbol::Local { .. }
+                                | Symbol::Parameter { .. }
+                                | Symbol::Global { .. }
+                                | Symbol::Cell { .. }
+                                    if matches!(callee.get_type(), Type::Callable(..)) =>
+                                {
+                                    let closure =
+                                        self.lookup_variable(&expression.location, name)?;
+                                    let closure = Expression::Identifier(closure);
+                                    self.analyze_closure_call(
+                                        &expression.location,
+                                        closure,
+                                        args,
+                                        keywords,
+                                    )
+                                }
+                                Symbol::Local { .. } | Symbol::Cell { .. } => {
+                                    Err(new_error(expression, "Cannot call local variable"))
+                                }
+                                Symbol::Parameter { .. } => {
+                                    Err(new_error(expression, "Cannot call parameter"))
+                                }
+                                Symbol::Global { .. } => {
+                                    Err(new_error(expression, "Cannot call global variable"))
+                                }
+                                Symbol::Constant { .. } => {
+                                    Err(new_error(expression, "Cannot call constant"))
+                                }
+                                Symbol::TypeVariable => {
+                                    Err(new_error(expression, "Cannot call type variable"))
+                                }
+                                Symbol::Type { typ } => self.analyze_new_object(
+                                    &expression.location,
+                                    typ,
+                                    args,
+                                    keywords,
+                                ),
+                            }
+                        } else {
+                            Err(new_error(callee, &format!("Undefined: {}", name)))
+                        }
+                    }
+                    _ => {
+                        // A function value, as in handlers[0](x)
+                        let closure = self.analyze_expression(callee)?;
+                        match closure.get_type() {
+                            Type::Callable(..) => self.analyze_closure_call(
+                                &expression.location,
+                                closure,
+                                args,
+                                keywords,
+                            ),
+                            _ => Err(new_error(callee, "Cannot call")),
+                        }
+                    }
+                }
+            }
+            ast::ExpressionType::Indexed { base, index } => {
+                let base = self.analyze_expression(base)?;
+                self.check_not_none(&expression.location, &base)?;
+                if let ast::ExpressionType::Slice { lower, upper, step } = &index.kind {
+                    match base.get_type() {
+                        Type::Tuple(element_types) => {
+                            let element_types = element_types.clone();
+                            return self.analyze_tuple_slice(
+                                &expression.location,
+                                base,
+                                element_types,
+                                [lower, upper, step],
+                            );
+                        }
+                        Type::List(_) | Type::Str => {}
+                        other => {
+                            return Err(CompilationError::new(
+                                &expression.location,
+                                format!("Cannot slice type: {}", other),
+                            ));
+                        }
+                    }
+                    let slice = self.analyze_slice(lower, upper, step)?;
+                    return Ok(Expression::Slice {
+                        base: Box::new(base),
+                        slice: Box::new(slice),
+                    });
+                }
+
+                let typ: Type = match &base.get_type() {
+                    Type::List(element_typ) => *element_typ.clone(),
+                    Type::Dict(key_typ, value_typ) => {
+                        let typ = *value_typ.clone();
+                        let key = self.analyze_typed_expression(index, key_typ)?;
+                        return Ok(Expression::DictItem {
+                            base: Box::new(base),
+                            key: Box::new(key),
+                            typ,
+                        });
+                    }
+                    Type::Tuple(element_types) => {
+                        let index = self.analyze_index(index)?;
+                        let index = get_tuple_index(&expression.location, &index, element_types)?;
+                        let typ = element_types[index].clone();
+                        return Ok(Expression::TupleElement {
+                            base: Box::new(base),
+                            index,
+                            typ,
+                        });
+                    }
+                    other => {
+                        return Err(CompilationError::new(
+                            &expression.location,
+                            format!("Cannot index type: {}", other),
+                        ));
+                    }
+                };
+
+                let index = self.analyze_index(index)?;
+
+                Ok(Expression::Indexed {
+                    base: Box::new(base),
+                    index: Box::new(index),
+                    typ,
+                })
+            }
+            ast::ExpressionType::Tuple { elements } => {
+                let elements = self.analyze_expressions(elements)?;
+                let typ = Type::Tuple(elements.iter().map(|e| e.get_type().clone()).collect());
+                Ok(Expression::Tuple { elements, typ })
+            }
+            ast::ExpressionType::Dict { entries } => {
+                if entries.is_empty() {
+                    return Err(new_error(
+                        expression,
+                        "Empty dict needs a type annotation, as in d: dict[str, int] = {}",
+                    ));
+                }
+
+                let mut new_entries = vec![];
+                for (key, value) in entries {
+                    let key = self.analyze_expression(key)?;
+                    let value = self.analyze_expression(value)?;
+                    new_entries.push((key, value));
+                }
+
+                // The first entry determines the type:
+                let key_typ = new_entries[0].0.get_type().clone();
+                let value_typ = new_entries[0].1.get_type().clone();
+                check_key_type(&expression.location, &key_typ)?;
+                self.check_is_value(&new_entries[0].1, &expression.location)?;
+                let typ = Type::Dict(Box::new(key_typ), Box::new(value_typ));
+                self.check_dict_entries(&new_entries, &typ, &expression.location)?;
+
+                Ok(Expression::Dict {
+                    entries: new_entries,
+                    typ,
+                })
+            }
+            ast::ExpressionType::Set { elements } => {
+                let elements = self.analyze_expressions(elements)?;
+                let element_typ = elements[0].get_type().clone();
+                check_key_type(&expression.location, &element_typ)?;
+                for element in &elements {
+                    self.equal_types(element.get_type(), &element_typ, &expression.location)?;
+                }
+                Ok(Expression::Set {
+                    elements,
+                    typ: Type::Set(Box::new(element_typ)),
+                })
+            }
+            ast::ExpressionType::Attribute { base, name } => {
+                if let ast::ExpressionType::Identifier(enum_name) = &base.kind {
+                    if let Some(Symbol::Type {
+                        typ: Type::Enum(enum_name),
+                    }) = self.lookup(enum_name).as_deref()
+                    {
+                        return self.analyze_enum_member(&expression.location, enum_name, name);
+                    }
+                }
+                let base = self.analyze_expression(base)?;
+                self.check_not_none(&expression.location, &base)?;
+                if let Type::Enum(enum_name) = base.get_type().clone() {
+                    return self.analyze_enum_attribute(
+                        &expression.location,
+                        &enum_name,
+                        base,
+                        name,
+                    );
+                }
+                if let Type::Class(class_name) = base.get_type() {
+                    let class = self.get_class(class_name);
+                    if let Some(field) = class.get_field(name) {
+                        let typ = class.fields[field].typ.clone();
+                        return Ok(Expression::Attribute {
+                            base: Box::new(base),
+                            field,
+                            typ,
+                        });
+                    }
+                    if class.get_method(name).is_some() {
+                        return Err(CompilationError::new(
+                            &expression.location,
+                            format!("Method {} can only be called", name),
+                        ));
+                    }
+                }
+                Err(CompilationError::new(
+                    &expression.location,
+                    format!("{} has no attribute {}", base.get_type(), name),
+                ))
+            }
+        }
+    }
+
+    /// Compare two values with ==, or with != when negated.
+    ///
+    /// Objects are compared with __eq__ when their class has it,
+    /// otherwise objects are only equal to themselves.
+    fn analyze_equality(
+        &self,
+        location: &Location,
+        a: Expression,
+        b: Expression,
+        negate: bool,
+    ) -> Result<Expression, CompilationError> {
+        if let Type::Class(class_name) = a.get_type().clone() {
+            let class = self.get_class(&class_name);
+            if let Some(slot) = class.get_method("__eq__") {
+                let callee = class.methods[slot].function.clone();
+                let mut arguments = vec![a, b];
+                let typ =
+                    self.check_function_arguments(location, &callee, &mut arguments, vec![])?;
+                if typ != Type::Bool {
+                    return Err(CompilationError::new(
+                        location,
+                        format!("__eq__ of {} must return bool", class_name),
+                    ));
+                }
+                let equal = Expression::MethodCall {
+                    slot,
+                    arguments,
+                    typ,
+                };
+                if !negate {
+                    return Ok(equal);
+                }
+                // a != b is not a.__eq__(b)
+                return Ok(Expression::BinaryOperation {
+                    a: Box::new(equal),
+                    op: BinaryOperation::Comparison(ast::Comparison::Equal),
+                    b: Box::new(Expression::Number(0)),
+                    typ: Type::Bool,
+                });
+            }
+        }
+
+        // None is only equal to itself, like objects without __eq__:
+        if is_optional(a.get_type()) || is_optional(b.get_type()) {
+            if !self.compares_by_identity(a.get_type()) || !self.compares_by_identity(b.get_type())
+            {
+                let typ = if is_optional(a.get_type()) { &a } else { &b }.get_type();
+                return Err(CompilationError::new(
+                    location,
+                    format!(
+                        "Equality of {} is not supported, check for None with is None first",
+                        typ
+                    ),
+                ));
+            }
+            let op = if negate {
+                ast::Comparison::IsNot
+            } else {
+                ast::Comparison::Is
+            };
+            return self.analyze_identity(location, a, &op, b);
+        }
+
+        self.equal_types(a.get_type(), b.get_type(), location)?;
+        match a.get_type() {
+            Type::Bytes
+            | Type::List(_)
+            | Type::Tuple(_)
+            | Type::Dict(_, _)
+            | Type::Set(_)
+            | Type::Union(_) => {
+                return Err(CompilationError::new(
+                    location,
+                    format!("Equality of {} is not supported", a.get_type()),
+                ));
+            }
+            _ => {}
+        }
+        let op = if negate {
+            ast::Comparison::NotEqual
+        } else {
+            ast::Comparison::Equal
+        };
+        Ok(Expression::BinaryOperation {
+            a: Box::new(a),
+            op: BinaryOperation::Comparison(op),
+            b: Box::new(b),
+            typ: Type::Bool,
+        })
+    }
+
+    /// Check if two values are the same object, as in x is None
+    fn analyze_identity(
+        &self,
+        location: &Location,
+        a: Expression,
+        op: &ast::Comparison,
+        b: Expression,
+    ) -> Result<Expression, CompilationError> {
+        for typ in [a.get_type(), b.get_type()] {
+            if !is_reference(typ) {
+                return Err(CompilationError::new(
+                    location,
+                    format!("Cannot use is with {}, use == instead", typ),
+                ));
+            }
+        }
+        let (a_typ, b_typ) = (a.get_type(), b.get_type());
+        if !(*a_typ == Type::NoneValue
+            || *b_typ == Type::NoneValue
+            || self.is_assignable(a_typ, b_typ)
+            || self.is_assignable(b_typ, a_typ))
+        {
+            return Err(CompilationError::new(
+                location,
+                format!("Type mismatch: '{}' is not '{}'", a_typ, b_typ),
+            ));
+        }
+        Ok(Expression::BinaryOperation {
+            a: Box::new(a),
+            op: BinaryOperation::Comparison(op.clone()),
+            b: Box::new(b),
+            typ: Type::Bool,
+        })
+    }
+
+    /// Objects without __eq__ are only equal to themselves, like None.
+    fn compares_by_identity(&self, typ: &Type) -> bool {
+        match typ {
+            Type::NoneValue => true,
+            Type::Optional(typ) => self.compares_by_identity(typ),
+            Type::Class(name) => self.get_class(name).get_method("__eq__").is_none(),
+            _ => false,
+        }
+    }
+
+    /// Find the variables which have a narrower type when a condition is true,
+    /// and those which have a narrower type when it is false.
+    fn get_narrowings(&self, condition: &ast::Expression) -> (Vec<Narrowing>, Vec<Narrowing>) {
+        match &condition.kind {
+            ast::ExpressionType::Comparison { a, op, b } => {
+                let (name, typ) = match (&a.kind, &b.kind) {
+                    (ast::ExpressionType::Identifier(name), ast::ExpressionType::None) => {
+                        match self.get_narrowed_type(&a.location, name) {
+                            Some(Type::Optional(typ)) => (name, *typ),
+                            _ => return (vec![], vec![]),
+                        }
+                    }
+                    _ => return (vec![], vec![]),
+                };
+                let narrowing = self.narrow(&a.location, name, typ);
+                match op {
+                    ast::Comparison::IsNot => (narrowing.into_iter().collect(), vec![]),
+                    ast::Comparison::Is => (vec![], narrowing.into_iter().collect()),
+                    _ => (vec![], vec![]),
+                }
+            }
+            ast::ExpressionType::Call { .. } => {
+                match self.get_builtin_arguments(condition, Builtin::IsInstance) {
+                    Some([object, classes]) => self.get_isinstance_narrowings(object, classes),
+                    _ => (vec![], vec![]),
+                }
+            }
+            ast::ExpressionType::BoolOp { a, op, b } => {
+                let (a_true, a_false) = self.get_narrowings(a);
+                let (b_true, b_false) = self.get_narrowings(b);
+                match op {
+                    ast::BooleanOperator::And => ([a_true, b_true].concat(), vec![]),
+                    ast::BooleanOperator::Or => (vec![], [a_false, b_false].concat()),
+                }
+            }
+            _ => (vec![], vec![]),
+        }
+    }
+
+    /// Narrow a union or an object to the types which isinstance checks for,
+    /// and a union to its other types when the check fails.
+    fn get_isinstance_narrowings(
+        &self,
+        object: &ast::Expression,
+        classes: &ast::Expression,
+    ) -> (Vec<Narrowing>, Vec<Narrowing>) {
+        let name = match &object.kind {
+            ast::ExpressionType::Identifier(name) => name,
+            _ => return (vec![], vec![]),
+        };
+        let (typ, (tags, classes)) = match (
+            self.get_narrowed_type(&object.location, name),
+            self.get_isinstance_classes(classes),
+        ) {
+            (Some(typ), Ok(classes)) => (typ, classes),
+            _ => return (vec![], vec![]),
+        };
+        let (matching, others) = match self.split_instance_types(&typ, &tags, &classes) {
+            Some(split) => split,
+            None => return (vec![], vec![]),
+        };
+        let narrow = |types: Vec<Type>| {
+            if types.is_empty() {
+                return None;
+            }
+            let typ = make_union(&object.location, types).ok()?;
+            self.narrow(&object.location, name, typ)
+        };
+        (
+            narrow(matching).into_iter().collect(),
+            narrow(others).into_iter().collect(),
+        )
+    }
+
+    /// Split the types of a union or an object into those which can be instances
+    /// of the given types and classes, and those which can be something else.
+    fn split_instance_types(
+        &self,
+        typ: &Type,
+        tags: &[TypeTag],
+        classes: &[String],
+    ) -> Option<(Vec<Type>, Vec<Type>)> {
+        let types = match typ {
+            Type::Union(types) => types.clone(),
+            Type::Class(_) => vec![typ.clone()],
+            _ => return None,
+        };
+        let (mut matching, mut others) = (vec![], vec![]);
+        for typ in types {
+            if let Type::Class(_) = typ {
+                // An object of a base class can be an instance of a subclass:
+                let classes: Vec<Type> = classes.iter().cloned().map(Type::Class).collect();
+                if classes.iter().any(|class| self.is_assignable(&typ, class)) {
+                    matching.push(typ);
+                    continue;
+                }
+                matching.extend(
+                    classes
+                        .into_iter()
+                        .filter(|class| self.is_assignable(class, &typ)),
+                );
+                others.push(typ);
+            } else if tags.contains(&typ.get_tag().unwrap()) {
+                matching.push(typ);
+            } else {
+                others.push(typ);
+            }
+        }
+        Some((matching, others))
+    }
+
+    /// The type of a variable, narrowed by the checks done so far.
+    fn get_narrowed_type(&self, location: &Location, name: &str) -> Option<Type> {
+        if let Some(narrowing) = self.narrowed.iter().rev().find(|n| n.name == name) {
+            return Some(narrowing.typ.clone());
+        }
+        let symbol = self.lookup_variable(location, name).ok()?;
+        Some(symbol.get_type().clone())
+    }
+
+    /// Let a variable have a narrower type, as in the branches of an if.
+    ///
+    /// Globals and cells are not narrowed, since any call can assign them.
+    fn narrow(&self, location: &Location, name: &str, typ: Type) -> Option<Narrowing> {
+        let symbol = self.lookup_variable(location, name).ok()?;
+        match symbol.as_ref() {
+            Symbol::Local { .. } | Symbol::Parameter { .. } => {}
+            _ => return None,
+        }
+        Some(Narrowing {
+            name: name.to_owned(),
+            symbol,
+            typ,
+        })
+    }
+
+    /// Values which may be None must be checked first, as in if x is not None:
+    fn check_not_none(
+        &self,
+        location: &Location,
+        value: &Expression,
+    ) -> Result<(), CompilationError> {
+        if is_optional(value.get_type()) {
+            return Err(CompilationError::new(
+                location,
+                format!(
+                    "Value of type {} may be None, check it with is not None first",
+                    value.get_type()
+                ),
+            ));
+        }
+        Ok(())
+    }
+
+    /// Analyze an expression which must have the given type.
+    ///
+    /// The type is used for expressions which do not have a type by themselves,
+    /// such as an empty dict.
+    fn analyze_typed_expression(
+        &mut self,
+        expression: &ast::Expression,
+        typ: &Type,
+    ) -> Result<Expression, CompilationError> {
+        let value = match (&expression.kind, typ) {
+            (ast::ExpressionType::Dict { entries }, Type::Dict(..)) if entries.is_empty() => {
+                Expression::Dict {
+                    entries: vec![],
+                    typ: typ.clone(),
+                }
+            }
+            (_, Type::Set(_))
+                if matches!(
+                    self.get_builtin_arguments(expression, Builtin::Set),
+                    Some([])
+                ) =>
+            {
+                Expression::Set {
+                    elements: vec![],
+                    typ: typ.clone(),
+                }
+            }
+            // Elements can be objects of subclasses of the element type:
+            (ast::ExpressionType::List { elements }, Type::List(element_typ))
+                if !elements.is_empty() =>
+            {
+                let elements = elements
+                    .iter()
+                    .map(|e| self.analyze_typed_expression(e, element_typ))
+                    .collect::<Result<Vec<Expression>, CompilationError>>()?;
+                let helper_local = self.new_local(None, Type::Integer);
+                Expression::List {
+                    elements,
+                    typ: typ.clone(),
+                    helper_local,
+                }
+            }
+            (ast::ExpressionType::Lambda { parameters, body }, Type::Callable(..)) => {
+                self.analyze_lambda(&expression.location, parameters, body, typ)?
+            }
+            _ => self.analyze_expression(expression)?,
+        };
+        self.check_type(value.get_type(), typ, &expression.location)?;
+        Ok(coerce(value, typ))
+    }
+
+    /// Check that all keys and values of a dict literal have the types of the dict.
+    fn check_dict_entries(
+        &self,
+        entries: &[(Expression, Expression)],
+        typ: &Type,
+        location: &Location,
+    ) -> Result<(), CompilationError> {
+        if let Type::Dict(key_typ, value_typ) = typ {
+            for (key, value) in entries {
+                self.equal_types(key.get_type(), key_typ, location)?;
+                self.equal_types(value.get_type(), value_typ, location)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Analyze a call of a method, such as d.get(key, default)
+    fn analyze_method_call(
+        &mut self,
+        location: &Location,
+        base: &ast::Expression,
+        name: &str,
+        arguments: &[ast::Expression],
+        keywords: &[(String, ast::Expression)],
+    ) -> Result<Expression, CompilationError> {
+        if let Some(super_arguments) = self.get_builtin_arguments(base, Builtin::Super) {
+            if !super_arguments.is_empty() {
+                return Err(new_error(base, "super takes no arguments"));
+            }
+            return self.analyze_super_call(location, name, arguments, keywords);
+        }
+
+        let base = self.analyze_expression(base)?;
+        self.check_not_none(location, &base)?;
+        let method = match base.get_type() {
+            Type::Class(class_name) => {
+                let class = self.get_class(class_name);
+                class
+                    .get_method(name)
+                    .map(|slot| (slot, class.methods[slot].function.clone()))
+            }
+            _ => None,
+        };
+        if let Some((slot, callee)) = method {
+            // The object is passed as self:
+            let mut args = vec![base];
+            let parameters = match callee.as_ref() {
+                Symbol::Function { function, .. } => function.parameters[1..].to_vec(),
+                _ => vec![],
+            };
+            let (arguments, keywords) = self.analyze_arguments(arguments, keywords, &parameters)?;
+            args.extend(arguments);
+            if name == "__init__" {
+                return self.analyze_function_call(location, callee, args, keywords);
+            }
+            let typ = self.check_function_arguments(location, &callee, &mut args, keywords)?;
+            return Ok(Expression::MethodCall {
+                slot,
+                arguments: args,
+                typ,
+            });
+        }
+
+        // A field can hold a function, as in self.callback(x)
+        if let Type::Class(class_name) = base.get_type() {
+            let class = self.get_class(class_name);
+            if let Some(field) = class.get_field(name) {
+                if let typ @ Type::Callable(parameter_types, _) = &class.fields[field].typ {
+                    let parameters = unnamed_parameters(parameter_types);
+                    let closure = Expression::Attribute {
+                        base: Box::new(base),
+                        field,
+                        typ: typ.clone(),
+                    };
+                    let (args, keywords) =
+                        self.analyze_arguments(arguments, keywords, &parameters)?;
+                    return self.analyze_closure_call(location, closure, args, keywords);
+                }
+            }
+        }
+
+        if !keywords.is_empty() {
+            return Err(CompilationError::new(
+                location,
+                format!("{} takes no keyword arguments", name),
+            ));
+        }
+        match (base.get_type(), name) {
+            (Type::Dict(key_typ, value_typ), "get") => {
+                if arguments.len() != 2 {
+                    return Err(CompilationError::new(
+                        location,
+                        "get takes a key and a default value",
+                    ));
+                }
+                let (key_typ, value_typ) = (*key_typ.clone(), *value_typ.clone());
+                let key = self.analyze_typed_expression(&arguments[0], &key_typ)?;
+                let default = self.analyze_typed_expression(&arguments[1], &value_typ)?;
+                Ok(Expression::DictGet {
+                    base: Box::new(base),
+                    key: Box::new(key),
+                    default: Box::new(default),
+                })
+            }
+            (Type::Set(element_typ), "add") | (Type::Set(element_typ), "discard") => {
+                if arguments.len() != 1 {
+                    return Err(CompilationError::new(
+                        location,
+                        format!("{} takes a single argument", name),
+                    ));
+                }
+                let element_typ = *element_typ.clone();
+                let item = Box::new(self.analyze_typed_expression(&arguments[0], &element_typ)?);
+                let base = Box::new(base);
+                if name == "add" {
+                    Ok(Expression::SetAdd { base, item })
+                } else {
+                    Ok(Expression::SetDiscard { base, item })
+                }
+            }
+            (Type::Dict(..), "keys") | (Type::Dict(..), "values") | (Type::Dict(..), "items") => {
+                Err(CompilationError::new(
+                    location,
+                    format!("{}() can only be used as iterable in a for loop", name),
+                ))
+            }
+            (typ, name) => Err(CompilationError::new(
+                location,
+                format!("{} has no method {}", typ, name),
+            )),
+        }
+    }
+
+    /// Analyze super().name(arguments), which calls the method of the base class.
+    fn analyze_super_call(
+        &mut self,
+        location: &Location,
+        name: &str,
+        arguments: &[ast::Expression],
+        keywords: &[(String, ast::Expression)],
+    ) -> Result<Expression, CompilationError> {
+        let (class, self_name) = match &self.method {
+            Some(method) => (method.class.clone(), method.self_name.clone()),
+            None => {
+                return Err(CompilationError::new(
+                    location,
+                    "super() can only be used in methods",
+                ));
+            }
+        };
+        let base = match &self.get_class(&class).base {
+            Some(base) => self.get_class(base),
+            None => {
+                return Err(CompilationError::new(
+                    location,
+                    format!("{} has no base class", class),
+                ));
+            }
+        };
+        let callee = match base.get_method(name) {
+            Some(slot) => base.methods[slot].function.clone(),
+            None => {
+                return Err(CompilationError::new(
+                    location,
+                    format!("{} has no method {}", base.name, name),
+                ));
+            }
+        };
+
+        // Not looked up in the vtable, since that would find the method of the subclass:
+        let mut args = vec![Expression::Identifier(self.get_local(&self_name))];
+        let parameters = match callee.as_ref() {
+            Symbol::Function { function, .. } => function.parameters[1..].to_vec(),
+            _ => vec![],
+        };
+        let (arguments, keywords) = self.analyze_arguments(arguments, keywords, &parameters)?;
+        args.extend(arguments);
+        self.analyze_function_call(location, callee, args, keywords)
+    }
+
+    /// Analyze isinstance(object, class)
+    /// Analyze a try statement, its handlers are checked one by one, like the cases of a match.
+    fn analyze_try(
+        &mut self,
+        suite: &[ast::Statement],
+        handlers: &[ast::ExceptHandler],
+        else_suite: &[ast::Statement],
+        finally_suite: &[ast::Statement],
+    ) -> Result<Statement, CompilationError> {
+        let exception_var = self.new_local(None, Type::Class(EXCEPTION.to_owned()));
+        // A return in the other suites runs the finally suite first:
+        if !finally_suite.is_empty() {
+            self.finally_suites.push(finally_suite.to_vec());
+        }
+        let body = self.analyze_suite(suite)?;
+
+        let mut new_handlers = vec![];
+        for (index, handler) in handlers.iter().enumerate() {
+            let (classes, typ) = match &handler.typ {
+                Some(typ) => self.get_exception_classes(typ)?,
+                None if index + 1 < handlers.len() => {
+                    return Err(CompilationError::new(
+                        &handler.location,
+                        "except without a class must be the last except clause",
+                    ));
+                }
+                None => (vec![], Type::Class(EXCEPTION.to_owned())),
+            };
+            let mut handler_suite = vec![];
+            // Like in Python, the name after as is only bound in the handler:
+            let mut outer_symbol = None;
+            if let Some(name) = &handler.name {
+                outer_symbol = self.get_scope_mut().variables.remove(name);
+                let symbol = self.new_local(Some(name), typ.clone());
+                let value = Expression::Identifier(retype_variable(&exception_var, typ));
+                handler_suite.push(Statement::Assignment {
+                    target: Target::Symbol(symbol),
+                    value,
+                });
+            }
+            let outer_exception = self.exception.replace(exception_var.clone());
+            handler_suite.extend(self.analyze_suite(&handler.suite)?);
+            self.exception = outer_exception;
+            if let Some(name) = &handler.name {
+                let variables = &mut self.get_scope_mut().variables;
+                match outer_symbol {
+                    Some(symbol) => variables.insert(name.clone(), symbol),
+                    None => variables.remove(name),
+                };
+            }
+            new_handlers.push(ExceptHandler {
+                classes,
+                suite: handler_suite,
+            });
+        }
+
+        let else_suite = self.analyze_suite(else_suite)?;
+        if !finally_suite.is_empty() {
+            self.finally_suites.pop();
+        }
+        let finally_suite = self.analyze_suite(finally_suite)?;
+        Ok(Statement::Try {
+            exception_var,
+            suite: body,
+            handlers: new_handlers,
+            else_suite,
+            finally_suite,
+        })
+    }
+
+    /// Analyze the finally suites which a return leaves, the innermost first.
+    fn analyze_finally_suites(&mut self) -> Result<Suite, CompilationError> {
+        let finally_suites = std::mem::take(&mut self.finally_suites);
+        let mut statements = vec![];
+        for (depth, suite) in finally_suites.iter().enumerate().rev() {
+            // A return in a finally suite only leaves the try statements around it:
+            self.finally_suites = finally_suites[..depth].to_vec();
+            statements.extend(self.analyze_suite(suite)?);
+        }
+        self.finally_suites = finally_suites;
+        Ok(statements)
+    }
+
+    /// Determine the classes handled by an except clause, as in except (KeyError, IndexError):
+    /// and the type of the exception, which is their nearest common base class.
+    fn get_exception_classes(
+        &self,
+        typ: &ast::Expression,
+    ) -> Result<(Vec<String>, Type), CompilationError> {
+        let elements = match &typ.kind {
+            ast::ExpressionType::Tuple { elements } => elements.iter().collect(),
+            _ => vec![typ],
+        };
+        let mut classes = vec![];
+        for element in elements {
+            match self.get_type(element)? {
+                Type::Class(class) if self.is_exception(&class) => classes.push(class),
+                other => {
+                    return Err(new_error(
+                        element,
+                        &format!(
+                            "Cannot catch {}, exceptions must derive from Exception",
+                            other
+                        ),
+                    ));
+                }
+            }
+        }
+        let mut common = classes[0].clone();
+        for class in &classes[1..] {
+            let class = Type::Class(class.clone());
+            while !self.is_assignable(&class, &Type::Class(common.clone())) {
+                common = self
+                    .get_class(&common)
+                    .base
+                    .clone()
+                    .expect("Exception is the base");
+            }
+        }
+        // Every exception is an Exception, so no check is needed then:
+        if classes.iter().any(|c| c == EXCEPTION) {
+            classes.clear();
+        }
+        Ok((classes, Type::Class(common)))
+    }
+
+    /// Analyze the exception of a raise statement, a class is
+    /// called without a message, as in raise ValueError
+    fn analyze_exception(
+        &mut self,
+        exception: &ast::Expression,
+    ) -> Result<Expression, CompilationError> {
+        let location = &exception.location;
+        let exception = match &exception.kind {
+            ast::ExpressionType::Identifier(name) => match self.lookup(name).as_deref() {
+                Some(Symbol::Type { typ }) => {
+                    self.analyze_new_object(location, typ, vec![], vec![])?
+                }
+                _ => self.analyze_expression(exception)?,
+            },
+            _ => self.analyze_expression(exception)?,
+        };
+        match exception.get_type() {
+            Type::Class(class) if self.is_exception(class) => Ok(exception),
+            other => Err(CompilationError::new(
+                location,
+                format!(
+                    "Cannot raise {}, exceptions must derive from Exception",
+                    other
+                ),
+            )),
+        }
+    }
+
+    fn is_exception(&self, class: &str) -> bool {
+        self.is_assignable(
+            &Type::Class(class.to_owned()),
+            &Type::Class(EXCEPTION.to_owned()),
+        )
+    }
+
+    /// Analyze a match statement, which is checked like a chain of if statements.
+    fn analyze_match(
+        &mut self,
+        location: &Location,
+        subject: &ast::Expression,
+        cases: &[ast::MatchCase],
+    ) -> Result<Statement, CompilationError> {
+        let subject_name = match &subject.kind {
+            ast::ExpressionType::Identifier(name) => Some(name),
+            _ => None,
+        };
+        let subject = self.analyze_expression(subject)?;
+        self.check_is_value(&subject, location)?;
+        let typ = subject.get_type().clone();
+        let subject_var = self.new_local(None, typ.clone());
+
+        let mut match_cases = vec![];
+        for (index, case) in cases.iter().enumerate() {
+            let mut pattern_subject = PatternSubject {
+                symbol: subject_var.clone(),
+                typ: typ.clone(),
+                steps: vec![],
+            };
+            let (mut conditions, mut bindings) = (vec![], vec![]);
+            let values = self.analyze_pattern(
+                &case.pattern,
+                &mut pattern_subject,
+                &mut conditions,
+                &mut bindings,
+            )?;
+
+            let narrowed = self.narrowed.clone();
+            // A check of the type of the subject also holds in the case:
+            if let Some(name) = subject_name {
+                if *pattern_subject.get_type() != typ
+                    && !captured_names(&case.pattern).contains(name)
+                {
+                    let location = &case.pattern.location;
+                    let typ = pattern_subject.get_type().clone();
+                    self.narrowed.extend(self.narrow(location, name, typ));
+                }
+            }
+            let guard = match &case.guard {
+                Some(guard) => {
+                    let (when_true, _) = self.get_narrowings(guard);
+                    let guard = self.analyze_condition(guard)?;
+                    self.narrowed.extend(when_true);
+                    Some(guard)
+                }
+                None => None,
+            };
+            let suite = self.analyze_suite(&case.suite);
+            self.narrowed = narrowed;
+            let suite = suite?;
+
+            let condition = combine_conditions(conditions, ast::BooleanOperator::And);
+            if condition.is_none() && guard.is_none() && index + 1 < cases.len() {
+                return Err(CompilationError::new(
+                    &case.location,
+                    "This case matches everything, so the cases after it are never used",
+                ));
+            }
+            let values = values.filter(|_| bindings.is_empty() && guard.is_none());
+            match_cases.push(MatchCase {
+                condition,
+                values,
+                bindings,
+                guard,
+                suite,
+            });
+        }
+
+        Ok(Statement::Match {
+            subject_var,
+            subject,
+            cases: match_cases,
+        })
+    }
+
+    /// Add the checks and captures of a pattern, and return the numbers the
+    /// subject is compared with, when that is all the pattern does.
+    fn analyze_pattern(
+        &mut self,
+        pattern: &ast::Pattern,
+        subject: &mut PatternSubject,
+        conditions: &mut Vec<Expression>,
+        bindings: &mut Suite,
+    ) -> Result<Option<Vec<i32>>, CompilationError> {
+        let location = &pattern.location;
+        match &pattern.kind {
+            ast::PatternType::Wildcard => Ok(None),
+            ast::PatternType::Capture(name) => {
+                let target = ast::AssignmentTarget::Identifier(name.clone());
+                let target = self.analyze_target(&target, subject.get_type(), location)?;
+                let value = coerce(subject.get_value(), target.get_type());
+                bindings.push(Statement::Assignment { target, value });
+                Ok(None)
+            }
+            ast::PatternType::Value(value) => {
+                let value = self.analyze_expression(value)?;
+                if copy_constant(&value).is_none() && !matches!(value, Expression::None) {
+                    return Err(CompilationError::new(
+                        location,
+                        "Patterns can only compare with literals and constants, as in case Color.RED:",
+                    ));
+                }
+                if let (Type::Union(_), Some(tag)) =
+                    (subject.get_type(), value.get_type().get_tag())
+                {
+                    self.narrow_subject(location, subject, &[tag], &[], conditions)?;
+                }
+                let number = match value {
+                    Expression::Number(number) | Expression::EnumMember { value: number, .. }
+                        if subject.steps.is_empty() && conditions.is_empty() =>
+                    {
+                        Some(vec![number])
+                    }
+                    _ => None,
+                };
+                // Like Python, None is compared by identity:
+                let condition = match value {
+                    Expression::None => self.analyze_identity(
+                        location,
+                        subject.get_value(),
+                        &ast::Comparison::Is,
+                        value,
+                    )?,
+                    value => self.analyze_equality(location, subject.get_value(), value, false)?,
+                };
+                conditions.push(condition);
+                Ok(number)
+            }
+            ast::PatternType::Or(alternatives) => {
+                let mut options = vec![];
+                let mut values = Some(vec![]);
+                let mut matches_anything = false;
+                for alternative in alternatives {
+                    let mut subject = subject.clone();
+                    let (mut alternative_conditions, mut alternative_bindings) = (vec![], vec![]);
+                    let alternative_values = self.analyze_pattern(
+                        alternative,
+                        &mut subject,
+                        &mut alternative_conditions,
+                        &mut alternative_bindings,
+                    )?;
+                    if !alternative_bindings.is_empty() {
+                        return Err(CompilationError::new(
+                            &alternative.location,
+                            "Alternatives in a pattern cannot capture names",
+                        ));
+                    }
+                    values = match (values, alternative_values) {
+                        (Some(mut values), Some(more)) => {
+                            values.extend(more);
+                            Some(values)
+                        }
+                        _ => None,
+                    };
+                    match combine_conditions(alternative_conditions, ast::BooleanOperator::And) {
+                        Some(condition) => options.push(condition),
+                        None => matches_anything = true,
+                    }
+                }
+                if matches_anything {
+                    return Ok(None);
+                }
+                let values = values.filter(|_| subject.steps.is_empty() && conditions.is_empty());
+                conditions.extend(combine_conditions(options, ast::BooleanOperator::Or));
+                Ok(values)
+            }
+            ast::PatternType::Sequence(patterns) => {
+                if let Type::Union(types) = subject.get_type() {
+                    if types
+                        .iter()
+                        .any(|t| matches!(t, Type::Tuple(e) if e.len() == patterns.len()))
+                    {
+                        self.narrow_subject(location, subject, &[TypeTag::Tuple], &[], conditions)?;
+                    }
+                }
+                let element_types = match subject.get_type() {
+                    Type::Tuple(element_types) if element_types.len() == patterns.len() => {
+                        element_types.clone()
+                    }
+                    other => {
+                        return Err(CompilationError::new(
+                            location,
+                            format!(
+                                "Cannot match {} with a tuple of {} elements",
+                                other,
+                                patterns.len()
+                            ),
+                        ));
+                    }
+                };
+                for (index, (pattern, typ)) in patterns.iter().zip(element_types).enumerate() {
+                    let mut element = subject.step(SubjectStep::TupleElement(index), typ);
+                    self.analyze_pattern(pattern, &mut element, conditions, bindings)?;
+                }
+                Ok(None)
+            }
+            ast::PatternType::Class {
+                class,
+                arguments,
+                keywords,
+            } => {
+                let (tags, classes) = self.get_isinstance_classes(class)?;
+                self.narrow_subject(location, subject, &tags, &classes, conditions)?;
+                let class_name = match subject.get_type() {
+                    Type::Class(class_name) => class_name.clone(),
+                    // A builtin type matches the whole value, as in case int(x):
+                    _ => {
+                        if !keywords.is_empty() || arguments.len() > 1 {
+                            return Err(CompilationError::new(
+                                location,
+                                "Patterns of builtin types take a single pattern, as in case int(x):",
+                            ));
+                        }
+                        if let Some(argument) = arguments.first() {
+                            self.analyze_pattern(argument, subject, conditions, bindings)?;
+                        }
+                        return Ok(None);
+                    }
+                };
+                let class = self.get_class(&class_name);
+                if arguments.len() > class.match_args.len() {
+                    return Err(CompilationError::new(
+                        location,
+                        format!(
+                            "{} takes {} positional patterns, but got {}",
+                            class_name,
+                            class.match_args.len(),
+                            arguments.len()
+                        ),
+                    ));
+                }
+                let named = class.match_args.iter().zip(arguments);
+                let named = named.chain(keywords.iter().map(|(name, pattern)| (name, pattern)));
+                let mut fields = vec![];
+                for (name, pattern) in named {
+                    let field = class.get_field(name).ok_or_else(|| {
+                        CompilationError::new(
+                            &pattern.location,
+                            format!("{} has no attribute {}", class_name, name),
+                        )
+                    })?;
+                    fields.push((field, class.fields[field].typ.clone(), pattern));
+                }
+                for (field, typ, pattern) in fields {
+                    let mut value = subject.step(SubjectStep::Field(field), typ);
+                    self.analyze_pattern(pattern, &mut value, conditions, bindings)?;
+                }
+                Ok(None)
+            }
+        }
+    }
+
+    /// Check that the subject of a pattern is an instance of one of the types
+    /// or classes, and continue with its narrower type.
+    fn narrow_subject(
+        &self,
+        location: &Location,
+        subject: &mut PatternSubject,
+        tags: &[TypeTag],
+        classes: &[String],
+        conditions: &mut Vec<Expression>,
+    ) -> Result<(), CompilationError> {
+        if let Type::Optional(typ) = subject.get_type().clone() {
+            let value = subject.get_value();
+            conditions.push(self.analyze_identity(
+                location,
+                value,
+                &ast::Comparison::IsNot,
+                Expression::None,
+            )?);
+            subject.narrow(*typ);
+        }
+        let typ = subject.get_type().clone();
+        let never = CompilationError::new(
+            location,
+            format!("This pattern never matches a value of type {}", typ),
+        );
+        let (matching, others) = match self.split_instance_types(&typ, tags, classes) {
+            Some(split) => split,
+            // Other values always have their own type:
+            None => {
+                return match typ.get_tag() {
+                    Some(tag) if tags.contains(&tag) => Ok(()),
+                    _ => Err(never),
+                };
+            }
+        };
+        if matching.is_empty() {
+            return Err(never);
+        }
+        if !others.is_empty() {
+            let value = Box::new(subject.get_value());
+            conditions.push(match &typ {
+                Type::Union(_) => Expression::UnionIsInstance {
+                    union: value,
+                    tags: tags.to_vec(),
+                    classes: classes.to_vec(),
+                },
+                _ => Expression::IsInstance {
+                    object: value,
+                    class: classes[0].clone(),
+                },
+            });
+        }
+        subject.narrow(make_union(location, matching)?);
+        Ok(())
+    }
+
+    fn analyze_isinstance(
+        &mut self,
+        location: &Location,
+        arguments: &[ast::Expression],
+    ) -> Result<Expression, CompilationError> {
+        if arguments.len() != 2 {
+            return Err(CompilationError::new(
+                location,
+                "isinstance takes an object and a class",
+            ));
+        }
+        let object = self.analyze_expression(&arguments[0])?;
+        let (tags, classes) = self.get_isinstance_classes(&arguments[1])?;
+        match object.get_type() {
+            Type::Union(_) => Ok(Expression::UnionIsInstance {
+                union: Box::new(object),
+                tags,
+                classes,
+            }),
+            Type::Class(_) if tags.is_empty() && classes.len() == 1 => Ok(Expression::IsInstance {
+                object: Box::new(object),
+                class: classes[0].clone(),
+            }),
+            Type::Class(_) => Err(new_error(
+                &arguments[1],
+                "isinstance of an object takes a single class",
+            )),
+            other => Err(CompilationError::new(
+                location,
+                format!("Cannot use isinstance on type {}", other),
+            )),
+        }
+    }
+
+    /// Determine the builtin types and classes checked by isinstance,
+    /// as in isinstance(x, (int, A))
+    fn get_isinstance_classes(
+        &self,
+        classes: &ast::Expression,
+    ) -> Result<(Vec<TypeTag>, Vec<String>), CompilationError> {
+        let elements = match &classes.kind {
+            ast::ExpressionType::Tuple { elements } => elements.iter().collect(),
+            _ => vec![classes],
+        };
+        let (mut tags, mut names) = (vec![], vec![]);
+        for element in elements {
+            let tag = match &element.kind {
+                ast::ExpressionType::Identifier(name) => match name.as_str() {
+                    "int" => Some(TypeTag::Int),
+                    "float" => Some(TypeTag::Float),
+                    "bool" => Some(TypeTag::Bool),
+                    "str" => Some(TypeTag::Str),
+                    "bytes" => Some(TypeTag::Bytes),
+                    "list" => Some(TypeTag::List),
+                    "tuple" => Some(TypeTag::Tuple),
+                    "dict" => Some(TypeTag::Dict),
+                    "set" => Some(TypeTag::Set),
+                    _ => None,
+                },
+                _ => None,
+            };
+            match (tag, self.get_type(element)) {
+                // bool is a subclass of int:
+                (Some(TypeTag::Int), _) => tags.extend([TypeTag::Int, TypeTag::Bool]),
+                (Some(tag), _) => tags.push(tag),
+                (None, Ok(Type::Class(class))) => names.push(class),
+                (None, Ok(other)) => {
+                    return Err(new_error(
+                        element,
+                        &format!(
+                            "isinstance only supports classes and builtin types, not {}",
+                            other
+                        ),
+                    ));
+                }
+                (None, Err(error)) => return Err(error),
+            }
+        }
+        Ok((tags, names))
+    }
+
+    /// Analyze a loop over the keys, values or items of a dict.
+    fn analyze_for_dict(
+        &mut self,
+        location: &Location,
+        target: &ast::AssignmentTarget,
+        dict: Expression,
+        view: DictView,
+        suite: &[ast::Statement],
+    ) -> Result<Statement, CompilationError> {
+        let (key_typ, value_typ) = match dict.get_type() {
+            Type::Dict(key_typ, value_typ) => (*key_typ.clone(), *value_typ.clone()),
+            Type::Set(element_typ) => (*element_typ.clone(), Type::None),
+            other => {
+                return Err(CompilationError::new(
+                    location,
+                    format!("Cannot iterate over this type: {}", other),
+                ));
             }
-            ast::ExpressionType::Call { callee, arguments } => {
-                let args = self.analyze_expressions(arguments)?;
+        };
+        let typ = match view {
+            DictView::Keys => key_typ,
+            DictView::Values => value_typ,
+            DictView::Items => Type::Tuple(vec![key_typ, value_typ]),
+        };
 
-                match &callee.kind {
-                    ast::ExpressionType::Identifier(name) => {
-                        // arg
-                        // return Err(Self::new_error(callee, "TODO".to_owned()));
-                        if let Some(callee) = self.lookup(name) {
-                            // callee
+        let index_var = self.new_local(None, Type::Integer);
+        let dict_var = self.new_local(None, dict.get_type().clone());
+        let target = self.analyze_unpacked_target(target, &typ, location)?;
+        let suite = self.analyze_suite(suite)?;
+        Ok(Statement::ForDict {
+            index_var,
+            dict_var,
+            view,
+            target,
+            dict,
+            suite,
+        })
+    }
 
-                            match callee.as_ref() {
-                                Symbol::Function { function, index: _ } => {
-                                    // Check args now!!!
-                                    let expected_types: Vec<Type> =
-                                        function.parameters.iter().map(|p| p.typ.clone()).collect();
-                                    self.check_arguments(
-                                        &expression.location,
-                                        &args,
-                                        &expected_types,
-                                    )?;
+    /// Slice a tuple, the slice must be known at compile time to determine the type.
+    fn analyze_tuple_slice(
+        &mut self,
+        location: &Location,
+        base: Expression,
+        element_types: Vec<Type>,
+        bounds: [&Option<Box<ast::Expression>>; 3],
+    ) -> Result<Expression, CompilationError> {
+        let [lower, upper, step] = bounds;
+        let slice = self.analyze_slice(lower, upper, step)?;
+        let get_bound = |bound: &Option<Expression>| match bound {
+            None => Ok(None),
+            Some(Expression::Number(value)) => Ok(Some(*value as isize)),
+            Some(_) => Err(CompilationError::new(
+                location,
+                "Tuple slice must be constant",
+            )),
+        };
+        let lower = get_bound(&slice.lower)?;
+        let upper = get_bound(&slice.upper)?;
+        let step = get_bound(&slice.step)?.unwrap_or(1);
+        if step == 0 {
+            return Err(CompilationError::new(location, "Slice step cannot be zero"));
+        }
 
-                                    // Arg: TODO: determine type!
-                                    let typ = Type::Integer;
+        // Same rules as for lists at runtime:
+        let length = element_types.len() as isize;
+        let adjust = |bound: Option<isize>, default: isize| match bound {
+            None => default,
+            Some(index) if index < 0 => {
+                let index = index + length;
+                if index < 0 {
+                    if step < 0 {
+                        -1
+                    } else {
+                        0
+                    }
+                } else {
+                    index
+                }
+            }
+            Some(index) if index >= length => {
+                if step < 0 {
+                    length - 1
+                } else {
+                    length
+                }
+            }
+            Some(index) => index,
+        };
+        let (start, stop) = if step < 0 {
+            (adjust(lower, length - 1), adjust(upper, -1))
+        } else {
+            (adjust(lower, 0), adjust(upper, length))
+        };
 
-                                    Ok(Expression::Call {
-                                        callee,
-                                        arguments: args,
-                                        typ,
-                                    })
-                                }
-                                Symbol::ExternFunction { index: _, import } => {
-                                    // Check argument types:
-                                    self.check_arguments(
-                                        &expression.location,
-                                        &args,
-                                        &import.parameter_types,
-                                    )?;
+        let mut indices = vec![];
+        let mut index = start;
+        while (step > 0 && index < stop) || (step < 0 && index > stop) {
+            indices.push(index as usize);
+            index += step;
+        }
 
-                                    // determine return type!
-                                    let typ = import.return_type.as_ref().unwrap().clone();
+        let typ = Type::Tuple(indices.iter().map(|i| element_types[*i].clone()).collect());
+        Ok(Expression::TupleSlice {
+            base: Box::new(base),
+            indices,
+            typ,
+        })
+    }
 
-                                    Ok(Expression::Call {
-                                        callee,
-                                        arguments: args,
-                                        typ,
-                                    })
-                                }
-                                Symbol::Builtin(builtin) => self.analyze_builtin_call(
-                                    &callee,
-                                    &expression.location,
-                                    builtin,
-                                    args,
-                                ),
-                                Symbol::Local { .. } => {
-                                    Err(new_error(expression, "Cannot call local variable"))
-                                }
-                                Symbol::Parameter { .. } => {
-                                    Err(new_error(expression, "Cannot call parameter"))
-                                }
-                            }
-                        } else {
-                            Err(new_error(callee, &format!("Undefined: {}", name)))
+    /// Check if an expression is a call to the given builtin, and return the arguments.
+    fn get_builtin_arguments<'e>(
+        &self,
+        expression: &'e ast::Expression,
+        builtin: Builtin,
+    ) -> Option<&'e [ast::Expression]> {
+        if let ast::ExpressionType::Call {
+            callee,
+            arguments,
+            keywords,
+        } = &expression.kind
+        {
+            if !keywords.is_empty() {
+                return None;
+            }
+            if let ast::ExpressionType::Identifier(name) = &callee.kind {
+                if let Some(symbol) = self.lookup(name) {
+                    if let Symbol::Builtin(b) = symbol.as_ref() {
+                        if *b == builtin {
+                            return Some(arguments);
                         }
                     }
-                    _ => Err(new_error(callee, "Cannot call")),
                 }
             }
-            ast::ExpressionType::Indexed { base, index } => {
+        }
+        None
+    }
+
+    /// Resolve the target of an assignment of a value with the given type.
+    fn analyze_target(
+        &mut self,
+        target: &ast::AssignmentTarget,
+        typ: &Type,
+        location: &Location,
+    ) -> Result<Target, CompilationError> {
+        match target {
+            ast::AssignmentTarget::Identifier(name) => {
+                if !self.is_defined(name) {
+                    check_variable_type(location, typ)?;
+                    if self.module_level {
+                        check_export_name(location, name)?;
+                    }
+                }
+                if self.is_defined(name) {
+                    let symbol = self.get_local(name);
+                    match symbol.as_ref() {
+                        Symbol::Parameter { .. }
+                        | Symbol::Local { .. }
+                        | Symbol::Global { .. }
+                        | Symbol::Cell { .. } => {}
+                        _ => {
+                            return Err(CompilationError::new(
+                                location,
+                                format!("Cannot assign to {}", name),
+                            ));
+                        }
+                    }
+                    self.check_type(typ, symbol.get_type(), location)?;
+                }
+                Ok(Target::Symbol(self.store_value(name, typ)))
+            }
+            ast::AssignmentTarget::Indexed { base, index } => {
+                let location = &base.location;
+                if let ast::ExpressionType::Slice { lower, upper, step } = &index.kind {
+                    let base = self.analyze_expression(base)?;
+                    match base.get_type() {
+                        Type::List(_) => {}
+                        other => {
+                            return Err(CompilationError::new(
+                                location,
+                                format!("Cannot assign to slice of type: {}", other),
+                            ));
+                        }
+                    }
+                    self.equal_types(base.get_type(), typ, location)?;
+                    let slice = Box::new(self.analyze_slice(lower, upper, step)?);
+                    return Ok(Target::Slice { base, slice });
+                }
+
                 let base = self.analyze_expression(base)?;
-                let typ: Type = match &base.get_type() {
-                    Type::List(element_typ) | Type::Tuple(element_typ) => *element_typ.clone(),
+                self.check_not_none(location, &base)?;
+                let element_typ: Type = match base.get_type() {
+                    Type::List(element_typ) => *element_typ.clone(),
+                    Type::Dict(key_typ, value_typ) => {
+                        let value_typ = *value_typ.clone();
+                        let key = self.analyze_typed_expression(index, key_typ)?;
+                        self.check_type(typ, &value_typ, location)?;
+                        return Ok(Target::DictItem {
+                            base,
+                            key,
+                            typ: value_typ,
+                        });
+                    }
                     other => {
                         return Err(CompilationError::new(
-                            &expression.location,
-                            format!("Cannot index type: {}", other),
+                            location,
+                            format!("Cannot assign to elements of type: {}", other),
                         ));
                     }
                 };
+                let index = self.analyze_index(index)?;
 
-                let index = self.analyze_expression(index)?;
-                match index.get_type() {
-                    Type::Integer => {
-                        // Ok
-                    }
+                if !self.is_assignable(typ, &element_typ) {
+                    return Err(CompilationError::new(
+                        location,
+                        format!("Cannot assign {} to element of type {}", typ, element_typ),
+                    ));
+                }
+
+                Ok(Target::Indexed {
+                    base,
+                    index,
+                    typ: element_typ,
+                })
+            }
+            ast::AssignmentTarget::Attribute { base, name } => {
+                let location = &base.location;
+                let base = self.analyze_expression(base)?;
+                self.check_not_none(location, &base)?;
+                let class_name = match base.get_type() {
+                    Type::Class(class_name) => class_name.clone(),
                     other => {
                         return Err(CompilationError::new(
-                            &expression.location,
-                            format!("Cannot use {} as index", other),
+                            location,
+                            format!("{} has no attribute {}", other, name),
+                        ));
+                    }
+                };
+                let field = match self.get_class(&class_name).get_field(name) {
+                    Some(field) => field,
+                    None if self.is_defining_fields(&base, &class_name) => {
+                        let class = self.get_class_mut(&class_name);
+                        class.fields.push(Field {
+                            name: name.clone(),
+                            typ: typ.clone(),
+                        });
+                        class.fields.len() - 1
+                    }
+                    None => {
+                        return Err(CompilationError::new(
+                            location,
+                            format!("{} has no attribute {}", class_name, name),
                         ));
                     }
+                };
+                let field_typ = self.get_class(&class_name).fields[field].typ.clone();
+                self.check_type(typ, &field_typ, location)?;
+                Ok(Target::Attribute {
+                    base,
+                    field,
+                    typ: field_typ,
+                })
+            }
+            ast::AssignmentTarget::Tuple(targets) => match typ {
+                Type::Tuple(element_types) if element_types.len() == targets.len() => {
+                    let targets = targets
+                        .iter()
+                        .zip(element_types.iter())
+                        .map(|(target, typ)| self.analyze_unpacked_target(target, typ, location))
+                        .collect::<Result<Vec<Target>, CompilationError>>()?;
+                    Ok(Target::Tuple {
+                        targets,
+                        typ: typ.clone(),
+                    })
                 }
+                other => Err(CompilationError::new(
+                    location,
+                    format!("Cannot unpack {} into {} targets", other, targets.len()),
+                )),
+            },
+        }
+    }
 
-                Ok(Expression::Indexed {
-                    base: Box::new(base),
-                    index: Box::new(index),
-                    typ,
-                })
+    /// Analyze a target which receives a value without an assignment, as in a for loop.
+    ///
+    /// Such values are not stored in a union, so the target cannot be one.
+    fn analyze_unpacked_target(
+        &mut self,
+        target: &ast::AssignmentTarget,
+        typ: &Type,
+        location: &Location,
+    ) -> Result<Target, CompilationError> {
+        let target = self.analyze_target(target, typ, location)?;
+        let target_typ = target.get_type();
+        if matches!(target_typ, Type::Union(_)) && !matches!(typ, Type::Union(_)) {
+            return Err(CompilationError::new(
+                location,
+                format!(
+                    "Cannot unpack {} into a variable of type {}",
+                    typ, target_typ
+                ),
+            ));
+        }
+        Ok(target)
+    }
+
+    /// Check if fields can be added by assigning to an attribute of this object,
+    /// which is the case for self in __init__.
+    fn is_defining_fields(&self, base: &Expression, class_name: &str) -> bool {
+        match &self.method {
+            Some(method) if method.is_init && method.class == class_name => {}
+            _ => return false,
+        }
+        match base {
+            Expression::Identifier(symbol) => {
+                matches!(symbol.as_ref(), Symbol::Parameter { index: 0, .. })
+            }
+            _ => false,
+        }
+    }
+
+    /// Determine the type of a field which is assigned to, if it is already known.
+    fn get_declared_field_type(
+        &mut self,
+        target: &ast::AssignmentTarget,
+    ) -> Result<Option<Type>, CompilationError> {
+        if let ast::AssignmentTarget::Attribute { base, name } = target {
+            let base = self.analyze_expression(base)?;
+            if let Type::Class(class_name) = base.get_type() {
+                let class = self.get_class(class_name);
+                if let Some(field) = class.get_field(name) {
+                    return Ok(Some(class.fields[field].typ.clone()));
+                }
             }
         }
+        Ok(None)
+    }
+
+    /// Analyze range(stop) or range(start, stop[, step])
+    fn analyze_range(
+        &mut self,
+        location: &Location,
+        arguments: &[ast::Expression],
+    ) -> Result<Range, CompilationError> {
+        let mut arguments = arguments
+            .iter()
+            .map(|a| self.analyze_index(a))
+            .collect::<Result<Vec<Expression>, CompilationError>>()?;
+
+        if arguments.is_empty() || arguments.len() > 3 {
+            return Err(CompilationError::new(
+                location,
+                format!("range expects 1 to 3 arguments, got {}", arguments.len()),
+            ));
+        }
+
+        let step = if arguments.len() == 3 {
+            arguments.pop().unwrap()
+        } else {
+            Expression::Number(1)
+        };
+        if let Expression::Number(0) = step {
+            return Err(CompilationError::new(
+                location,
+                "range step must not be zero",
+            ));
+        }
+        let stop = arguments.pop().unwrap();
+        let start = arguments.pop().unwrap_or(Expression::Number(0));
+
+        let stop_var = self.new_local(None, Type::Integer);
+        let step_var = self.new_local(None, Type::Integer);
+        Ok(Range {
+            start,
+            stop,
+            stop_var,
+            step,
+            step_var,
+        })
+    }
+
+    /// Analyze optional slice bounds, which must all be integers.
+    fn analyze_slice(
+        &mut self,
+        lower: &Option<Box<ast::Expression>>,
+        upper: &Option<Box<ast::Expression>>,
+        step: &Option<Box<ast::Expression>>,
+    ) -> Result<Slice, CompilationError> {
+        let mut analyze_bound = |bound: &Option<Box<ast::Expression>>| match bound {
+            Some(bound) => self.analyze_index(bound).map(Some),
+            None => Ok(None),
+        };
+        Ok(Slice {
+            lower: analyze_bound(lower)?,
+            upper: analyze_bound(upper)?,
+            step: analyze_bound(step)?,
+        })
+    }
+
+    /// Analyze an expression used as index into a list or tuple.
+    fn analyze_index(&mut self, index: &ast::Expression) -> Result<Expression, CompilationError> {
+        let location = &index.location;
+        let index = self.analyze_expression(index)?;
+        match index.get_type() {
+            Type::Integer => Ok(index),
+            other => Err(CompilationError::new(
+                location,
+                format!("Cannot use {} as index", other),
+            )),
+        }
     }
 
     fn analyze_builtin_call(
-        &self,
+        &mut self,
         callee: &Rc<Symbol>,
         location: &Location,
         builtin: &Builtin,
-        args: Vec<Expression>,
+        mut args: Vec<Expression>,
     ) -> Result<Expression, CompilationError> {
         match builtin {
             Builtin::Len => {
@@ -740,7 +5270,7 @@ impl Analyzer {
 
                 let arg = &args[0];
                 match arg.get_type() {
-                    Type::List(_) | Type::Tuple(_) => {
+                    Type::List(_) | Type::Tuple(_) | Type::Str | Type::Dict(..) | Type::Set(_) => {
                         // Ok
                     }
                     other => {
@@ -751,6 +5281,11 @@ impl Analyzer {
                     }
                 }
 
+                // The length of a constant string is known:
+                if let Expression::String(value) = arg {
+                    return Ok(Expression::Number(value.len() as i32));
+                }
+
                 let typ = Type::Integer;
 
                 Ok(Expression::Call {
@@ -759,63 +5294,421 @@ impl Analyzer {
                     typ,
                 })
             }
-            Builtin::Ord => {
-                self.check_arguments(location, &args, &[Type::Str])?;
-                let arg = &args[0];
+            Builtin::Ord => {
+                self.check_arguments(
+                    location,
+                    &mut args,
+                    vec![],
+                    &unnamed_parameters(&[Type::Str]),
+                )?;
+                let arg = &args[0];
+
+                match arg {
+                    Expression::String(value) => {
+                        // For now treat chars as strings of len = 1
+                        if value.len() == 1 {
+                            let value: i32 = value.chars().next().unwrap() as i32;
+
+                            Ok(Expression::Number(value))
+                        } else {
+                            Err(CompilationError::new(
+                                location,
+                                "String passed to ord must be a single character",
+                            ))
+                        }
+                    }
+                    _ => {
+                        unimplemented!();
+                    }
+                }
+            }
+            Builtin::Range => Err(CompilationError::new(
+                location,
+                "range can only be used as iterable in a for loop",
+            )),
+            Builtin::Enumerate => Err(CompilationError::new(
+                location,
+                "enumerate can only be used as iterable in a for loop",
+            )),
+            Builtin::Super => Err(CompilationError::new(
+                location,
+                "super() can only be used to call a method, as in super().__init__()",
+            )),
+            Builtin::IsInstance => panic!("isinstance is analyzed before its arguments"),
+            Builtin::Print => {
+                for arg in &args {
+                    check_printable(location, arg.get_type())?;
+                }
+                Ok(Expression::Print {
+                    arguments: args,
+                    output: self.get_output(),
+                })
+            }
+            Builtin::Str | Builtin::Repr => {
+                let name = if *builtin == Builtin::Str {
+                    "str"
+                } else {
+                    "repr"
+                };
+                let value = match args.pop() {
+                    Some(value) if args.is_empty() => value,
+                    _ => {
+                        return Err(CompilationError::new(
+                            location,
+                            format!("{} takes a single argument", name),
+                        ));
+                    }
+                };
+                check_printable(location, value.get_type())?;
+                match (builtin, value.get_type()) {
+                    (Builtin::Str, Type::Str) => Ok(value),
+                    _ => Ok(Expression::ToStr {
+                        value: Box::new(value),
+                        repr: *builtin == Builtin::Repr,
+                    }),
+                }
+            }
+            Builtin::Dataclass => Err(CompilationError::new(
+                location,
+                "dataclass can only be used as class decorator, as in @dataclass",
+            )),
+            Builtin::Enum => Err(CompilationError::new(
+                location,
+                "Enum can only be used as base class, as in class Color(Enum):",
+            )),
+            Builtin::Final => Err(CompilationError::new(
+                location,
+                "Final can only be used as annotation, as in X: Final = 3",
+            )),
+            Builtin::Optional => Err(CompilationError::new(
+                location,
+                "Optional can only be used as annotation, as in x: Optional[str]",
+            )),
+            Builtin::Union => Err(CompilationError::new(
+                location,
+                "Union can only be used as annotation, as in x: Union[int, str]",
+            )),
+            Builtin::Callable => Err(CompilationError::new(
+                location,
+                "Callable can only be used as annotation, as in f: Callable[[int], int]",
+            )),
+            Builtin::TypeVar => Err(CompilationError::new(
+                location,
+                "TypeVar can only be used at module level, as in T = TypeVar('T')",
+            )),
+            Builtin::Set => match args.pop() {
+                Some(list) if args.is_empty() => match list.get_type() {
+                    Type::List(element_typ) => {
+                        check_key_type(location, element_typ)?;
+                        let typ = Type::Set(element_typ.clone());
+                        Ok(Expression::ListToSet {
+                            list: Box::new(list),
+                            typ,
+                        })
+                    }
+                    other => Err(CompilationError::new(
+                        location,
+                        format!("Cannot create a set from {}", other),
+                    )),
+                },
+                Some(_) => Err(CompilationError::new(
+                    location,
+                    "set takes at most one argument",
+                )),
+                None => Err(CompilationError::new(
+                    location,
+                    "Empty set needs a type annotation, as in s: set[int] = set()",
+                )),
+            },
+        }
+    }
+
+    /// Analyze a direct call of a function or method.
+    fn analyze_function_call(
+        &self,
+        location: &Location,
+        callee: Rc<Symbol>,
+        mut args: Vec<Expression>,
+        keywords: Vec<Keyword>,
+    ) -> Result<Expression, CompilationError> {
+        let typ = self.check_function_arguments(location, &callee, &mut args, keywords)?;
+        Ok(Expression::Call {
+            callee,
+            arguments: args,
+            typ,
+        })
+    }
+
+    /// Use a function as a value, which is a closure without environment.
+    ///
+    /// The closure calls a wrapper, which takes an environment like nested
+    /// functions do, so that all function values are called alike.
+    fn analyze_function_value(&mut self, name: &str, callee: Rc<Symbol>) -> Expression {
+        let (parameter_types, return_type) = match callee.as_ref() {
+            Symbol::Function { function, .. } => (
+                function.parameters.iter().map(|p| p.typ.clone()).collect(),
+                function.return_type.clone(),
+            ),
+            Symbol::ExternFunction { import, .. } => {
+                (import.parameter_types.clone(), import.return_type.clone())
+            }
+            _ => panic!("Not a function"),
+        };
+        let result = return_type.clone().unwrap_or(Type::None);
+        let typ = Type::Callable(parameter_types.clone(), Box::new(result.clone()));
+        if let Some(wrapper) = self.function_wrappers.get(name) {
+            return Expression::Closure {
+                function: wrapper.clone(),
+                environment: None,
+                typ,
+            };
+        }
+
+        let (environment, _) = new_parameter("<environment>", &Type::Integer, 0);
+        let mut parameters = vec![environment];
+        let mut arguments = vec![];
+        for typ in &parameter_types {
+            let index = parameters.len();
+            let (parameter, symbol) = new_parameter(&format!("arg{}", index), typ, index);
+            parameters.push(parameter);
+            arguments.push(Expression::Identifier(symbol));
+        }
+        let call = Expression::Call {
+            callee,
+            arguments,
+            typ: result,
+        };
+        let body = match &return_type {
+            Some(_) => Statement::Return {
+                value: call,
+                finally_suite: vec![],
+            },
+            None => Statement::Expression(call),
+        };
+        let function = Rc::new(Function {
+            name: format!("{}.<wrapper>", name),
+            parameters,
+            locals: vec![],
+            body: vec![body],
+            return_type,
+            closure: true,
+            environment: None,
+        });
+        let index = self.functions.len();
+        self.functions.push(function.clone());
+        let wrapper = Rc::new(Symbol::Function { function, index });
+        self.function_wrappers
+            .insert(name.to_owned(), wrapper.clone());
+        Expression::Closure {
+            function: wrapper,
+            environment: None,
+            typ,
+        }
+    }
+
+    /// Analyze a call of a function value, which is a closure.
+    fn analyze_closure_call(
+        &self,
+        location: &Location,
+        closure: Expression,
+        mut args: Vec<Expression>,
+        keywords: Vec<Keyword>,
+    ) -> Result<Expression, CompilationError> {
+        let (parameters, typ) = match closure.get_type() {
+            Type::Callable(parameter_types, result) => {
+                (unnamed_parameters(parameter_types), *result.clone())
+            }
+            other => panic!("Not callable: {}", other),
+        };
+        self.check_arguments(location, &mut args, keywords, &parameters)?;
+        Ok(Expression::CallClosure {
+            closure: Box::new(closure),
+            arguments: args,
+            typ,
+        })
+    }
 
-                match arg {
-                    Expression::String(value) => {
-                        // For now treat chars as strings of len = 1
-                        if value.len() == 1 {
-                            let value: i32 = value.chars().next().unwrap() as i32;
+    /// Check the arguments of a call to a function or method, and return the result type.
+    fn check_function_arguments(
+        &self,
+        location: &Location,
+        callee: &Symbol,
+        args: &mut Vec<Expression>,
+        keywords: Vec<Keyword>,
+    ) -> Result<Type, CompilationError> {
+        match callee {
+            Symbol::Function { function, .. } => {
+                self.check_arguments(location, args, keywords, &function.parameters)?;
+                Ok(function.return_type.clone().unwrap_or(Type::None))
+            }
+            _ => panic!("Not a function"),
+        }
+    }
 
-                            Ok(Expression::Number(value))
-                        } else {
-                            Err(CompilationError::new(
-                                location,
-                                "String passed to ord must be a single character",
-                            ))
-                        }
-                    }
-                    _ => {
-                        unimplemented!();
-                    }
+    /// Analyze a call to a class, which creates an object.
+    fn analyze_new_object(
+        &self,
+        location: &Location,
+        typ: &Type,
+        mut args: Vec<Expression>,
+        keywords: Vec<Keyword>,
+    ) -> Result<Expression, CompilationError> {
+        let class_name = match typ {
+            Type::Class(class_name) => class_name,
+            other => {
+                return Err(CompilationError::new(
+                    location,
+                    format!("Cannot call type {}", other),
+                ));
+            }
+        };
+        let class = self.get_class(class_name);
+        let init = class
+            .get_method("__init__")
+            .map(|slot| class.methods[slot].function.clone());
+        let parameters = match init.as_deref() {
+            // The new object is passed as self:
+            Some(Symbol::Function { function, .. }) => {
+                // The message of a builtin exception is optional, as in raise ValueError()
+                if function.name == "Exception.__init__" && args.is_empty() && keywords.is_empty() {
+                    args.push(Expression::String(String::new()));
                 }
+                function.parameters[1..].to_vec()
             }
-        }
+            _ => vec![],
+        };
+        self.check_arguments(location, &mut args, keywords, &parameters)?;
+        Ok(Expression::NewObject {
+            init,
+            arguments: args,
+            typ: typ.clone(),
+        })
     }
 
+    /// Check the types of arguments, and store values in a union where one is expected.
+    /// Check the arguments of a call against the parameters of the callee.
+    ///
+    /// Keyword arguments are put in the place of their parameter, and
+    /// parameters which are left out get their default value.
     fn check_arguments(
         &self,
         location: &Location,
-        actual_args: &[Expression],
-        expected_types: &[Type],
+        actual_args: &mut Vec<Expression>,
+        keywords: Vec<Keyword>,
+        parameters: &[Rc<Parameter>],
     ) -> Result<(), CompilationError> {
-        if actual_args.len() != expected_types.len() {
+        if actual_args.len() > parameters.len() {
             return Err(CompilationError::new(
                 location,
                 format!(
                     "Expected {} arguments, but got {}",
-                    expected_types.len(),
+                    parameters.len(),
                     actual_args.len()
                 ),
             ));
         }
 
-        for (arg, typ) in actual_args.iter().zip(expected_types.iter()) {
+        let positional = actual_args.len();
+        let mut values: Vec<Option<Expression>> = actual_args.drain(..).map(Some).collect();
+        values.resize_with(parameters.len(), || None);
+        for keyword in keywords {
+            let index = match parameters.iter().position(|p| p.name == keyword.name) {
+                Some(index) => index,
+                None => {
+                    return Err(CompilationError::new(
+                        &keyword.location,
+                        format!("Unexpected keyword argument {}", keyword.name),
+                    ));
+                }
+            };
+            if values[index].is_some() {
+                let message = if index < positional {
+                    format!("Argument {} is given by position and keyword", keyword.name)
+                } else {
+                    format!("Keyword argument {} is repeated", keyword.name)
+                };
+                return Err(CompilationError::new(&keyword.location, message));
+            }
+            values[index] = Some(keyword.value);
+        }
+
+        for (value, parameter) in values.into_iter().zip(parameters.iter()) {
+            let arg = match (value, &parameter.default) {
+                (Some(value), _) => value,
+                // Defaults are constants, so they are copied to each call:
+                (None, Some(default)) => copy_constant(default).unwrap_or(Expression::None),
+                (None, None) if parameter.name.is_empty() => {
+                    return Err(CompilationError::new(
+                        location,
+                        format!(
+                            "Expected {} arguments, but got {}",
+                            parameters.len(),
+                            positional
+                        ),
+                    ));
+                }
+                (None, None) => {
+                    return Err(CompilationError::new(
+                        location,
+                        format!("Missing argument {}", parameter.name),
+                    ));
+                }
+            };
             let arg_typ = arg.get_type();
-            if arg_typ != typ {
+            if !self.is_assignable(arg_typ, &parameter.typ) {
                 return Err(CompilationError::new(
                     location,
-                    format!("Expected {} but got {}", typ, arg_typ),
+                    format!("Expected {} but got {}", parameter.typ, arg_typ),
                 ));
             }
+            actual_args.push(coerce(arg, &parameter.typ));
         }
+        Ok(())
+    }
 
-        // return Err(new_error(act, message: String))
+    /// Check that a value of a type can be used where the other type is expected.
+    fn check_type(
+        &self,
+        value_typ: &Type,
+        typ: &Type,
+        location: &Location,
+    ) -> Result<(), CompilationError> {
+        if !self.is_assignable(value_typ, typ) {
+            return Err(CompilationError::new(
+                location,
+                format!("Type mismatch: '{}' is not '{}'", value_typ, typ),
+            ));
+        }
         Ok(())
     }
 
+    /// A subclass object can be used where an object of the base class is expected.
+    fn is_assignable(&self, value_typ: &Type, typ: &Type) -> bool {
+        match (value_typ, typ) {
+            (Type::Class(class), Type::Class(expected)) => {
+                let mut class = Some(class);
+                while let Some(name) = class {
+                    if name == expected {
+                        return true;
+                    }
+                    class = self.get_class(name).base.as_ref();
+                }
+                false
+            }
+            (Type::NoneValue, Type::Optional(_)) => true,
+            (Type::Optional(value_typ), Type::Optional(typ)) => self.is_assignable(value_typ, typ),
+            (value_typ, Type::Optional(typ)) => self.is_assignable(value_typ, typ),
+            // A union can hold a value of any of its types:
+            (Type::Union(value_types), Type::Union(types)) => value_types
+                .iter()
+                .all(|value_typ| types.iter().any(|typ| self.is_assignable(value_typ, typ))),
+            (value_typ, Type::Union(types)) => {
+                types.iter().any(|typ| self.is_assignable(value_typ, typ))
+            }
+            (value_typ, typ) => value_typ == typ,
+        }
+    }
+
     fn equal_types(
         &self,
         a_typ: &Type,
@@ -832,6 +5725,11 @@ impl Analyzer {
     }
 
     fn new_local(&mut self, name: Option<&str>, typ: Type) -> Rc<Symbol> {
+        if let (Some(name), Some(frame)) = (name, self.frames.last()) {
+            if frame.captured.contains(name) {
+                return self.new_cell(name, typ);
+            }
+        }
         let index = self.local_counter;
         self.local_counter += 1;
         let local = Rc::new(Local {
@@ -849,6 +5747,169 @@ impl Analyzer {
         symbol
     }
 
+    /// Add a variable in the environment of the function, for its nested functions.
+    fn new_cell(&mut self, name: &str, typ: Type) -> Rc<Symbol> {
+        let frame = self.frames.last_mut().unwrap();
+        let cell = Rc::new(Cell {
+            typ,
+            slot: frame.cells,
+        });
+        frame.cells += 1;
+        let symbol = Rc::new(Symbol::Cell {
+            cell,
+            environment: frame.environment.clone().expect("Captured variables"),
+            depth: 0,
+        });
+        self.define(name, symbol.clone());
+        symbol
+    }
+
+    /// Add a variable at module level, with a value when it is a constant.
+    fn new_global(&mut self, name: &str, typ: Type, value: Option<Expression>) -> Rc<Symbol> {
+        let index = self.globals.len();
+        let global = Rc::new(Global { typ, value });
+        self.globals.push(global.clone());
+        let symbol = Rc::new(Symbol::Global { global, index });
+        self.define(name, symbol.clone());
+        symbol
+    }
+
+    /// Find a variable, which is local to the function, of an enclosing
+    /// function, or global to the module.
+    fn lookup_variable(
+        &self,
+        location: &Location,
+        name: &str,
+    ) -> Result<Rc<Symbol>, CompilationError> {
+        match self.lookup(name) {
+            Some(symbol) => match symbol.as_ref() {
+                Symbol::Global { .. } => Ok(symbol),
+                Symbol::Parameter { .. } | Symbol::Local { .. } | Symbol::Cell { .. }
+                    if self.is_defined(name) =>
+                {
+                    Ok(symbol)
+                }
+                Symbol::Parameter { .. } | Symbol::Local { .. } | Symbol::Cell { .. } => {
+                    match self.find_enclosing_frame(name) {
+                        Some(owner) => Ok(self.capture_variable(owner, name)),
+                        None => Err(CompilationError::new(
+                            location,
+                            format!("{} is not a variable", name),
+                        )),
+                    }
+                }
+                _ => Err(CompilationError::new(
+                    location,
+                    format!("{} is not a variable", name),
+                )),
+            },
+            None => Err(CompilationError::new(
+                location,
+                format!("Undefined: {}", name),
+            )),
+        }
+    }
+
+    /// Find the enclosing function with a variable, for a nested function.
+    fn find_enclosing_frame(&self, name: &str) -> Option<usize> {
+        let current = self.frames.len().checked_sub(1)?;
+        (0..current).rev().find(|&index| {
+            let frame = &self.frames[index];
+            self.scopes[frame.scope].contains(name) && !frame.nonlocals.contains(name)
+        })
+    }
+
+    /// Use a variable of an enclosing function, which is a cell in its environment.
+    ///
+    /// A variable which is not captured yet is used as is, since the enclosing
+    /// function is analyzed again with it captured.
+    fn capture_variable(&self, owner: usize, name: &str) -> Rc<Symbol> {
+        let frame = &self.frames[owner];
+        let symbol = self.scopes[frame.scope].lookup(name).clone();
+        let cell = match symbol.as_ref() {
+            Symbol::Cell { cell, .. } if frame.captured.contains(name) => cell.clone(),
+            _ => {
+                frame.missed.borrow_mut().insert(name.to_owned());
+                return symbol;
+            }
+        };
+        // Each enclosing function with an environment links to the next one:
+        let current = self.frames.len() - 1;
+        let depth = self.frames[owner + 1..current]
+            .iter()
+            .filter(|f| f.environment.is_some())
+            .count();
+        let environment = self.frames[current].outer_environment.clone().unwrap();
+        Rc::new(Symbol::Cell {
+            cell,
+            environment,
+            depth,
+        })
+    }
+
+    /// Add a function which is imported from the host.
+    fn add_import(
+        &mut self,
+        modname: &str,
+        name: &str,
+        parameter_types: Vec<Type>,
+        return_type: Option<Type>,
+    ) -> Rc<Symbol> {
+        let index = self.imports.len();
+        let import = Rc::new(Import {
+            modname: modname.to_owned(),
+            name: name.to_owned(),
+            parameter_types,
+            return_type,
+        });
+        self.imports.push(import.clone());
+        Rc::new(Symbol::ExternFunction { index, import })
+    }
+
+    /// Get the host function env.print, which receives the address and length of a line.
+    fn get_output(&mut self) -> Rc<Symbol> {
+        if let Some(output) = &self.output {
+            return output.clone();
+        }
+        let parameter_types = vec![Type::Integer, Type::Integer];
+        let output = self.add_import("env", "print", parameter_types, None);
+        self.output = Some(output.clone());
+        output
+    }
+
+    /// Get the host function env.assertion_failed, which receives the address and
+    /// length of the message, and the line of the assert.
+    fn get_assertion_handler(&mut self) -> Rc<Symbol> {
+        if let Some(handler) = &self.assertion_handler {
+            return handler.clone();
+        }
+        let parameter_types = vec![Type::Integer, Type::Integer, Type::Integer];
+        let handler = self.add_import("env", "assertion_failed", parameter_types, None);
+        self.assertion_handler = Some(handler.clone());
+        handler
+    }
+
+    fn get_class(&self, name: &str) -> &Class {
+        self.classes
+            .iter()
+            .find(|c| c.name == name)
+            .expect("Class is defined")
+    }
+
+    fn get_enum(&self, name: &str) -> &Enumeration {
+        self.enums
+            .iter()
+            .find(|e| e.name == name)
+            .expect("Enum is defined")
+    }
+
+    fn get_class_mut(&mut self, name: &str) -> &mut Class {
+        self.classes
+            .iter_mut()
+            .find(|c| c.name == name)
+            .expect("Class is defined")
+    }
+
     fn define(&mut self, name: &str, symbol: Rc<Symbol>) {
         self.get_scope_mut().register(name, symbol);
     }
@@ -873,7 +5934,9 @@ impl Analyzer {
     }
 
     fn store_value(&mut self, name: &str, typ: &Type) -> Rc<Symbol> {
-        if !self.is_defined(name) {
+        if !self.is_defined(name) && self.module_level {
+            self.new_global(name, typ.clone(), None);
+        } else if !self.is_defined(name) {
             self.new_local(Some(name), typ.clone());
             // TODO: type deduction?
             // self.locals.push(typ.clone());
@@ -899,6 +5962,541 @@ impl Analyzer {
     }
 }
 
+/// Check that a module level name does not clash with an export of the module.
+fn check_export_name(location: &Location, name: &str) -> Result<(), CompilationError> {
+    if RESERVED_NAMES.contains(&name) {
+        return Err(CompilationError::new(
+            location,
+            format!("{} is reserved for an export of the module", name),
+        ));
+    }
+    Ok(())
+}
+
+/// Find the module level names which are assigned more than once, or
+/// from within a function. These need a mutable global.
+fn find_reassigned_globals(prog: &ast::Program) -> HashSet<String> {
+    let mut counts = HashMap::new();
+    let mut reassigned = HashSet::new();
+    for top_level in &prog.top_levels {
+        match top_level {
+            ast::TopLevel::Statement(statement) => count_assignments(statement, 1, &mut counts),
+            ast::TopLevel::FunctionDef(function_def) => {
+                collect_global_declarations(&function_def.body, &mut reassigned)
+            }
+            ast::TopLevel::ClassDef(class_def) => {
+                for method in &class_def.methods {
+                    collect_global_declarations(&method.body, &mut reassigned);
+                }
+            }
+            ast::TopLevel::Import { .. } => {}
+        }
+    }
+    reassigned.extend(
+        counts
+            .into_iter()
+            .filter(|(_, count)| *count > 1)
+            .map(|(name, _)| name),
+    );
+    reassigned
+}
+
+/// Count the assignments to names, those in loops and branches count as many.
+fn count_assignments(
+    statement: &ast::Statement,
+    weight: usize,
+    counts: &mut HashMap<String, usize>,
+) {
+    fn count_target(
+        target: &ast::AssignmentTarget,
+        weight: usize,
+        counts: &mut HashMap<String, usize>,
+    ) {
+        match target {
+            ast::AssignmentTarget::Identifier(name) => {
+                *counts.entry(name.clone()).or_insert(0) += weight;
+            }
+            ast::AssignmentTarget::Tuple(targets) => {
+                for target in targets {
+                    count_target(target, weight, counts);
+                }
+            }
+            ast::AssignmentTarget::Indexed { .. } | ast::AssignmentTarget::Attribute { .. } => {}
+        }
+    }
+
+    let nested = 2;
+    match statement {
+        ast::Statement::Assignment { target, .. }
+        | ast::Statement::AnnotatedAssignment { target, .. } => {
+            count_target(target, weight, counts)
+        }
+        ast::Statement::FunctionDef(function_def) => {
+            *counts.entry(function_def.name.clone()).or_insert(0) += weight;
+        }
+        ast::Statement::If {
+            suite, else_suite, ..
+        } => {
+            for statement in suite.iter().chain(else_suite.iter()) {
+                count_assignments(statement, nested, counts);
+            }
+        }
+        ast::Statement::While { suite, .. } => {
+            for statement in suite.iter() {
+                count_assignments(statement, nested, counts);
+            }
+        }
+        ast::Statement::For { target, suite, .. } => {
+            count_target(target, nested, counts);
+            for statement in suite.iter() {
+                count_assignments(statement, nested, counts);
+            }
+        }
+        ast::Statement::Match { cases, .. } => {
+            for case in cases {
+                for name in captured_names(&case.pattern) {
+                    *counts.entry(name).or_insert(0) += nested;
+                }
+                for statement in case.suite.iter() {
+                    count_assignments(statement, nested, counts);
+                }
+            }
+        }
+        ast::Statement::Try {
+            suite,
+            handlers,
+            else_suite,
+            finally_suite,
+        } => {
+            let handler_suites = handlers.iter().flat_map(|h| h.suite.iter());
+            for statement in suite
+                .iter()
+                .chain(handler_suites)
+                .chain(else_suite.iter())
+                .chain(finally_suite.iter())
+            {
+                count_assignments(statement, nested, counts);
+            }
+        }
+        _ => {}
+    }
+}
+
+/// Collect the names in global declarations of a function body.
+fn collect_global_declarations(suite: &[ast::Statement], names: &mut HashSet<String>) {
+    for statement in suite {
+        match statement {
+            ast::Statement::Global {
+                names: declared, ..
+            } => {
+                names.extend(declared.iter().cloned());
+            }
+            ast::Statement::If {
+                suite, else_suite, ..
+            } => {
+                collect_global_declarations(suite, names);
+                collect_global_declarations(else_suite, names);
+            }
+            ast::Statement::While { suite, .. } | ast::Statement::For { suite, .. } => {
+                collect_global_declarations(suite, names);
+            }
+            ast::Statement::FunctionDef(function_def) => {
+                collect_global_declarations(&function_def.body, names);
+            }
+            ast::Statement::Match { cases, .. } => {
+                for case in cases {
+                    collect_global_declarations(&case.suite, names);
+                }
+            }
+            ast::Statement::Try {
+                suite,
+                handlers,
+                else_suite,
+                finally_suite,
+            } => {
+                collect_global_declarations(suite, names);
+                for handler in handlers {
+                    collect_global_declarations(&handler.suite, names);
+                }
+                collect_global_declarations(else_suite, names);
+                collect_global_declarations(finally_suite, names);
+            }
+            _ => {}
+        }
+    }
+}
+
+/// Determine the element of a tuple, which must be known at compile time.
+fn get_tuple_index(
+    location: &Location,
+    index: &Expression,
+    element_types: &[Type],
+) -> Result<usize, CompilationError> {
+    match index {
+        Expression::Number(value) => {
+            let length = element_types.len() as i32;
+            let value = if *value < 0 { *value + length } else { *value };
+            if value < 0 || value >= length {
+                Err(CompilationError::new(location, "Tuple index out of range"))
+            } else {
+                Ok(value as usize)
+            }
+        }
+        _ => Err(CompilationError::new(
+            location,
+            "Tuple index must be a constant",
+        )),
+    }
+}
+
+/// Add a method to the methods of a class, replacing the method it overrides.
+fn add_method(
+    location: &Location,
+    name: &str,
+    function: Function,
+    methods: &mut Vec<Method>,
+    functions: &mut Vec<Rc<Function>>,
+) -> Result<(), CompilationError> {
+    let function = Rc::new(function);
+    let index = functions.len();
+    functions.push(function.clone());
+    let symbol = Rc::new(Symbol::Function { function, index });
+    let new_method = Method {
+        name: name.to_owned(),
+        function: symbol,
+    };
+    match methods.iter().position(|m| m.name == name) {
+        Some(slot) => {
+            // __init__ is never looked up in the vtable, so it can differ:
+            if name != "__init__" {
+                check_override(location, &methods[slot], &new_method)?;
+            }
+            methods[slot] = new_method;
+        }
+        None => methods.push(new_method),
+    }
+    Ok(())
+}
+
+/// Collect the names used in a type annotation, as in dict[str, T]
+fn collect_type_names(annotation: &ast::Expression, names: &mut Vec<String>) {
+    match &annotation.kind {
+        ast::ExpressionType::Identifier(name) => names.push(name.clone()),
+        ast::ExpressionType::Indexed { base, index } => {
+            collect_type_names(base, names);
+            collect_type_names(index, names);
+        }
+        ast::ExpressionType::Tuple { elements } | ast::ExpressionType::List { elements } => {
+            for element in elements {
+                collect_type_names(element, names);
+            }
+        }
+        ast::ExpressionType::BinaryOperation { a, b, .. } => {
+            collect_type_names(a, names);
+            collect_type_names(b, names);
+        }
+        _ => {}
+    }
+}
+
+/// Create the parameters of a function which is only known by its type,
+/// so its arguments can only be passed by position.
+fn unnamed_parameters(types: &[Type]) -> Vec<Rc<Parameter>> {
+    types
+        .iter()
+        .map(|typ| {
+            Rc::new(Parameter {
+                name: String::new(),
+                typ: typ.clone(),
+                default: None,
+                variadic: false,
+            })
+        })
+        .collect()
+}
+
+/// Create a parameter of a generated function.
+fn new_parameter(name: &str, typ: &Type, index: usize) -> (Rc<Parameter>, Rc<Symbol>) {
+    let parameter = Rc::new(Parameter {
+        name: name.to_owned(),
+        typ: typ.clone(),
+        default: None,
+        variadic: false,
+    });
+    let symbol = Rc::new(Symbol::Parameter {
+        parameter: parameter.clone(),
+        index,
+    });
+    (parameter, symbol)
+}
+
+/// Check that a method has the same signature as the method it overrides.
+fn check_override(
+    location: &Location,
+    method: &Method,
+    new_method: &Method,
+) -> Result<(), CompilationError> {
+    let get_signature = |method: &Method| match method.function.as_ref() {
+        Symbol::Function { function, .. } => {
+            let parameter_types: Vec<Type> = function.parameters[1..]
+                .iter()
+                .map(|p| p.typ.clone())
+                .collect();
+            (parameter_types, function.return_type.clone())
+        }
+        _ => panic!("Not a function"),
+    };
+    if get_signature(method) != get_signature(new_method) {
+        let name = match method.function.as_ref() {
+            Symbol::Function { function, .. } => &function.name,
+            _ => panic!("Not a function"),
+        };
+        return Err(CompilationError::new(
+            location,
+            format!(
+                "Method {} must have the same signature as {}",
+                new_method.name, name
+            ),
+        ));
+    }
+    Ok(())
+}
+
+/// Only some types can be used as dict keys, since they must be hashed.
+fn check_key_type(location: &Location, typ: &Type) -> Result<(), CompilationError> {
+    match typ {
+        Type::Integer | Type::Str | Type::Bool | Type::Enum(_) => Ok(()),
+        other => Err(CompilationError::new(
+            location,
+            format!("Cannot use {} as dict key", other),
+        )),
+    }
+}
+
+/// Check that values of a type can be converted to str.
+fn check_printable(location: &Location, typ: &Type) -> Result<(), CompilationError> {
+    match typ {
+        Type::Bytes | Type::None | Type::Enum(_) => Err(CompilationError::new(
+            location,
+            format!("Cannot convert {} to str", typ),
+        )),
+        Type::List(element) | Type::Set(element) => check_printable(location, element),
+        Type::Dict(key, value) => {
+            check_printable(location, key)?;
+            check_printable(location, value)
+        }
+        Type::Tuple(elements) => elements
+            .iter()
+            .try_for_each(|element| check_printable(location, element)),
+        Type::Optional(typ) => check_printable(location, typ),
+        Type::Union(types) => types
+            .iter()
+            .try_for_each(|typ| check_printable(location, typ)),
+        Type::Integer
+        | Type::Float
+        | Type::Bool
+        | Type::Str
+        | Type::Class(_)
+        | Type::NoneValue
+        | Type::Callable(..) => Ok(()),
+    }
+}
+
+/// Check that the type of a new variable is known, None needs an annotation.
+fn check_variable_type(location: &Location, typ: &Type) -> Result<(), CompilationError> {
+    if *typ == Type::NoneValue {
+        return Err(CompilationError::new(
+            location,
+            "None needs a type annotation, as in x: Optional[str] = None",
+        ));
+    }
+    Ok(())
+}
+
+/// Only references can be None, since None is a null pointer.
+fn make_optional(location: &Location, typ: Type) -> Result<Type, CompilationError> {
+    match typ {
+        Type::Str
+        | Type::Bytes
+        | Type::List(_)
+        | Type::Dict(..)
+        | Type::Set(_)
+        | Type::Class(_) => Ok(Type::Optional(Box::new(typ))),
+        Type::Optional(_) => Ok(typ),
+        other => Err(CompilationError::new(
+            location,
+            format!(
+                "{} cannot be None, only str, bytes, list, dict, set and objects can",
+                other
+            ),
+        )),
+    }
+}
+
+/// Combine types into a union, in which each type has its own tag.
+fn make_union(location: &Location, types: Vec<Type>) -> Result<Type, CompilationError> {
+    let mut members: Vec<Type> = vec![];
+    for typ in types {
+        let typs = match typ {
+            Type::Union(typs) => typs,
+            typ => vec![typ],
+        };
+        for typ in typs {
+            let tag = typ.get_tag().ok_or_else(|| {
+                CompilationError::new(location, format!("{} cannot be part of a union", typ))
+            })?;
+            if members.contains(&typ) {
+                continue;
+            }
+            if let Some(other) = members.iter().find(|m| m.get_tag() == Some(tag)) {
+                return Err(CompilationError::new(
+                    location,
+                    format!("{} and {} cannot be told apart in a union", other, typ),
+                ));
+            }
+            members.push(typ);
+        }
+    }
+    if members.len() == 1 {
+        Ok(members.pop().unwrap())
+    } else {
+        Ok(Type::Union(members))
+    }
+}
+
+/// Combine conditions with and or or, there is none when the list is empty.
+fn combine_conditions(conditions: Vec<Expression>, op: ast::BooleanOperator) -> Option<Expression> {
+    conditions
+        .into_iter()
+        .reduce(|a, b| Expression::BinaryOperation {
+            a: Box::new(a),
+            op: BinaryOperation::Boolean(op.clone()),
+            b: Box::new(b),
+            typ: Type::Bool,
+        })
+}
+
+/// Find the names which are assigned by a pattern, as in case Point(x=x):
+fn captured_names(pattern: &ast::Pattern) -> Vec<String> {
+    match &pattern.kind {
+        ast::PatternType::Capture(name) => vec![name.clone()],
+        ast::PatternType::Value(_) | ast::PatternType::Wildcard => vec![],
+        ast::PatternType::Or(patterns) | ast::PatternType::Sequence(patterns) => {
+            patterns.iter().flat_map(captured_names).collect()
+        }
+        ast::PatternType::Class {
+            arguments,
+            keywords,
+            ..
+        } => arguments
+            .iter()
+            .chain(keywords.iter().map(|(_, pattern)| pattern))
+            .flat_map(captured_names)
+            .collect(),
+    }
+}
+
+/// Store a value in a union, when a union is expected.
+fn coerce(value: Expression, typ: &Type) -> Expression {
+    match typ {
+        Type::Union(_) if !matches!(value.get_type(), Type::Union(_)) => Expression::ToUnion {
+            value: Box::new(value),
+            typ: typ.clone(),
+        },
+        _ => value,
+    }
+}
+
+fn is_optional(typ: &Type) -> bool {
+    matches!(typ, Type::Optional(_) | Type::NoneValue)
+}
+
+/// Values of these types are pointers to memory.
+fn is_reference(typ: &Type) -> bool {
+    matches!(
+        typ,
+        Type::Str
+            | Type::Bytes
+            | Type::List(_)
+            | Type::Tuple(_)
+            | Type::Dict(..)
+            | Type::Set(_)
+            | Type::Class(_)
+            | Type::Optional(_)
+            | Type::NoneValue
+            | Type::Callable(..)
+    )
+}
+
+/// Check if a suite always returns or raises at its end.
+fn ends_with_return(suite: &[ast::Statement]) -> bool {
+    match suite.last() {
+        Some(ast::Statement::Return(_)) | Some(ast::Statement::Raise { .. }) => true,
+        Some(ast::Statement::If {
+            suite, else_suite, ..
+        }) => ends_with_return(suite) && ends_with_return(else_suite),
+        _ => false,
+    }
+}
+
+/// The names which are assigned by a statement.
+fn assigned_names(statement: &ast::Statement) -> HashSet<String> {
+    let mut counts = HashMap::new();
+    count_assignments(statement, 1, &mut counts);
+    counts.into_keys().collect()
+}
+
+/// Copy a value which is known at compile time, so it can replace a constant.
+fn copy_constant(value: &Expression) -> Option<Expression> {
+    match value {
+        Expression::Number(value) => Some(Expression::Number(*value)),
+        Expression::Float(value) => Some(Expression::Float(*value)),
+        Expression::String(value) => Some(Expression::String(value.clone())),
+        Expression::EnumMember { typ, value } => Some(Expression::EnumMember {
+            typ: typ.clone(),
+            value: *value,
+        }),
+        _ => None,
+    }
+}
+
+/// Calculate an operation on constants, as in 60 * 60 --> 3600
+fn fold_binary_operation(
+    a: &Expression,
+    op: &ast::BinaryOperation,
+    b: &Expression,
+) -> Option<Expression> {
+    use ast::BinaryOperation::*;
+    match (a, b) {
+        (Expression::Number(a), Expression::Number(b)) => {
+            let value = match op {
+                Add => a.wrapping_add(*b),
+                Sub => a.wrapping_sub(*b),
+                Mul => a.wrapping_mul(*b),
+                BitOr => a | b,
+                BitAnd => a & b,
+                _ => return None,
+            };
+            Some(Expression::Number(value))
+        }
+        (Expression::Float(a), Expression::Float(b)) => {
+            let value = match op {
+                Add => a + b,
+                Sub => a - b,
+                Mul => a * b,
+                Div => a / b,
+                _ => return None,
+            };
+            Some(Expression::Float(value))
+        }
+        (Expression::String(a), Expression::String(b)) => match op {
+            Add => Some(Expression::String(format!("{}{}", a, b))),
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
 fn new_error(expression: &ast::Expression, message: &str) -> CompilationError {
     CompilationError::new(&expression.location, message)
 }
@@ -0,0 +1,938 @@
+//! Conversion of values to strings, as used by str(), repr() and print().
+//!
+//! Numbers are converted by runtime functions. Containers and objects are
+//! converted inline, by converting their elements one by one and joining
+//! the parts.
+//!
+//! Floats are written like Python does, with the shortest number of digits
+//! which reads back as the same value. This uses float arithmetic instead
+//! of big integers, so the last digit can differ from Python for values
+//! with a large exponent.
+
+use super::hashtable::{ENTRY_KEY, ENTRY_VALUE};
+use super::runtime::RuntimeFunction;
+use super::{analyze, wasm, Compiler, UNION_VALUE};
+
+/// Floats have at most 17 significant digits.
+const MAX_DIGITS: i32 = 17;
+
+/// Python switches to scientific notation outside of 1e-4 to 1e16.
+const MIN_FIXED_EXPONENT: i32 = -4;
+const MAX_FIXED_EXPONENT: i32 = 16;
+
+/// Floats below this value are scaled by a power of ten first, so that
+/// the powers of ten needed to get their digits stay finite.
+const TINY: f64 = 1e-280;
+const TINY_BIAS: i32 = 200;
+
+/// The largest power of ten which is a finite float.
+const MAX_POWER: i32 = 308;
+
+impl Compiler {
+    /// Convert the value on top of the stack to a string.
+    ///
+    /// With repr, strings are quoted, like elements of containers are.
+    pub(super) fn emit_to_str(&mut self, typ: &analyze::Type, repr: bool) {
+        match typ {
+            analyze::Type::Str => {
+                if repr {
+                    self.call_runtime(RuntimeFunction::StrRepr);
+                }
+            }
+            analyze::Type::Integer => self.call_runtime(RuntimeFunction::IntToStr),
+            analyze::Type::Float => self.call_runtime(RuntimeFunction::FloatToStr),
+            analyze::Type::Bool => {
+                let value = self.new_temporary(wasm::Type::I32);
+                self.emit(wasm::Instruction::LocalSet(value));
+                self.emit_text("True");
+                self.emit_text("False");
+                self.emit(wasm::Instruction::LocalGet(value));
+                self.emit(wasm::Instruction::Select);
+            }
+            analyze::Type::List(element_type) => self.emit_list_to_str(element_type),
+            analyze::Type::Tuple(element_types) => self.emit_tuple_to_str(typ, element_types),
+            analyze::Type::Dict(key_type, value_type) => {
+                self.emit_table_to_str(key_type, Some(value_type))
+            }
+            analyze::Type::Set(element_type) => self.emit_table_to_str(element_type, None),
+            analyze::Type::Class(name) => self.emit_object_to_str(name, repr),
+            analyze::Type::NoneValue => {
+                self.emit(wasm::Instruction::Drp);
+                self.emit_text("None");
+            }
+            analyze::Type::Optional(typ) => {
+                // None is a null pointer:
+                let (value, result) = (
+                    self.new_temporary(wasm::Type::I32),
+                    self.new_temporary(wasm::Type::I32),
+                );
+                self.emit(wasm::Instruction::LocalTee(value));
+                self.emit(wasm::Instruction::I32Eqz);
+                self.emit(wasm::Instruction::If);
+                self.emit_text("None");
+                self.emit(wasm::Instruction::LocalSet(result));
+                self.emit(wasm::Instruction::Else);
+                self.emit(wasm::Instruction::LocalGet(value));
+                self.emit_to_str(typ, repr);
+                self.emit(wasm::Instruction::LocalSet(result));
+                self.emit(wasm::Instruction::End);
+                self.emit(wasm::Instruction::LocalGet(result));
+            }
+            analyze::Type::Union(types) => {
+                // Convert the value with the type given by its tag:
+                let (union, result) = (
+                    self.new_temporary(wasm::Type::I32),
+                    self.new_temporary(wasm::Type::I32),
+                );
+                self.emit(wasm::Instruction::LocalSet(union));
+                for typ in types {
+                    let tag = typ.get_tag().expect("Union member");
+                    self.emit_tag_check(union, tag);
+                    self.emit(wasm::Instruction::If);
+                    self.emit(wasm::Instruction::LocalGet(union));
+                    self.read_mem(UNION_VALUE, &self.get_type(typ));
+                    self.emit_to_str(typ, repr);
+                    self.emit(wasm::Instruction::LocalSet(result));
+                    self.emit(wasm::Instruction::End);
+                }
+                self.emit(wasm::Instruction::LocalGet(result));
+            }
+            analyze::Type::Callable(..) => {
+                // Functions have no name at runtime:
+                self.emit(wasm::Instruction::Drp);
+                self.emit_text("<function>");
+            }
+            analyze::Type::Bytes | analyze::Type::None | analyze::Type::Enum(_) => {
+                panic!("Cannot convert {} to str", typ);
+            }
+        }
+    }
+
+    /// Emit the address of a string in static data.
+    fn emit_text(&mut self, text: &str) {
+        let address = self.add_string(text);
+        self.emit(wasm::Instruction::I32Const(address as i32));
+    }
+
+    /// Append the string produced by the given function to the string in a local.
+    fn emit_append<F>(&mut self, result: usize, part: F)
+    where
+        F: FnOnce(&mut Self),
+    {
+        self.emit(wasm::Instruction::LocalGet(result));
+        part(self);
+        self.call_runtime(RuntimeFunction::StrConcat);
+        self.emit(wasm::Instruction::LocalSet(result));
+    }
+
+    fn emit_append_text(&mut self, result: usize, text: &str) {
+        self.emit_append(result, |c| c.emit_text(text));
+    }
+
+    /// Append ", " unless only the opening bracket is in the string.
+    fn emit_append_separator(&mut self, result: usize) {
+        self.emit(wasm::Instruction::LocalGet(result));
+        self.builtin_list_len();
+        self.emit(wasm::Instruction::I32Const(1));
+        self.emit(wasm::Instruction::I32GtS);
+        self.emit(wasm::Instruction::If);
+        self.emit_append_text(result, ", ");
+        self.emit(wasm::Instruction::End);
+    }
+
+    /// [1, 2, 3]
+    fn emit_list_to_str(&mut self, element_type: &analyze::Type) {
+        let (list, result, i, count) = (
+            self.new_temporary(wasm::Type::I32),
+            self.new_temporary(wasm::Type::I32),
+            self.new_temporary(wasm::Type::I32),
+            self.new_temporary(wasm::Type::I32),
+        );
+        self.emit(wasm::Instruction::LocalSet(list));
+        self.emit_text("[");
+        self.emit(wasm::Instruction::LocalSet(result));
+        self.emit(wasm::Instruction::LocalGet(list));
+        self.builtin_list_len();
+        self.emit(wasm::Instruction::LocalSet(count));
+        self.emit_counted_loop(i, count, |c| {
+            c.emit_append_separator(result);
+            c.emit_append(result, |c| {
+                c.emit(wasm::Instruction::LocalGet(list));
+                c.emit(wasm::Instruction::LocalGet(i));
+                c.builtin_list_index(element_type, false);
+                c.emit_to_str(element_type, true);
+            });
+        });
+        self.emit_append_text(result, "]");
+        self.emit(wasm::Instruction::LocalGet(result));
+    }
+
+    /// (1, 'a'), a tuple with one element has a trailing comma.
+    fn emit_tuple_to_str(&mut self, typ: &analyze::Type, element_types: &[analyze::Type]) {
+        let result = self.new_temporary(wasm::Type::I32);
+        self.unbox_tuple(typ);
+        let temporaries = self.spill_tuple(typ);
+        self.emit_text("(");
+        self.emit(wasm::Instruction::LocalSet(result));
+        for (index, (element_type, temporary)) in element_types.iter().zip(temporaries).enumerate()
+        {
+            if index > 0 {
+                self.emit_append_text(result, ", ");
+            }
+            self.emit_append(result, |c| {
+                c.emit(wasm::Instruction::LocalGet(temporary));
+                c.emit_to_str(element_type, true);
+            });
+        }
+        if element_types.len() == 1 {
+            self.emit_append_text(result, ",");
+        }
+        self.emit_append_text(result, ")");
+        self.emit(wasm::Instruction::LocalGet(result));
+    }
+
+    /// {'a': 1} for dicts, {1, 2} for sets, and set() for an empty set.
+    fn emit_table_to_str(&mut self, key_type: &analyze::Type, value_type: Option<&analyze::Type>) {
+        let (table, result, i, used, entry) = (
+            self.new_temporary(wasm::Type::I32),
+            self.new_temporary(wasm::Type::I32),
+            self.new_temporary(wasm::Type::I32),
+            self.new_temporary(wasm::Type::I32),
+            self.new_temporary(wasm::Type::I32),
+        );
+        self.emit(wasm::Instruction::LocalSet(table));
+        self.emit_text("{");
+        self.emit(wasm::Instruction::LocalSet(result));
+        self.emit_for_each_entry(table, i, used, entry, |c| {
+            c.emit_append_separator(result);
+            c.emit_append(result, |c| {
+                c.emit(wasm::Instruction::LocalGet(entry));
+                let wasm_typ = c.get_type(key_type);
+                c.read_mem(ENTRY_KEY, &wasm_typ);
+                c.emit_to_str(key_type, true);
+            });
+            if let Some(value_type) = value_type {
+                c.emit_append_text(result, ": ");
+                c.emit_append(result, |c| {
+                    c.emit(wasm::Instruction::LocalGet(entry));
+                    let wasm_typ = c.get_type(value_type);
+                    c.read_mem(ENTRY_VALUE, &wasm_typ);
+                    c.emit_to_str(value_type, true);
+                });
+            }
+        });
+        self.emit_append_text(result, "}");
+
+        if value_type.is_none() {
+            self.emit(wasm::Instruction::LocalGet(table));
+            self.builtin_list_len();
+            self.emit(wasm::Instruction::I32Eqz);
+            self.emit(wasm::Instruction::If);
+            self.emit_text("set()");
+            self.emit(wasm::Instruction::LocalSet(result));
+            self.emit(wasm::Instruction::End);
+        }
+        self.emit(wasm::Instruction::LocalGet(result));
+    }
+
+    /// Call __str__ or __repr__ of an object, as in Python str uses
+    /// __repr__ when there is no __str__.
+    fn emit_object_to_str(&mut self, name: &str, repr: bool) {
+        let class = self.classes[name].clone();
+        let slot = if repr {
+            None
+        } else {
+            class.get_method("__str__")
+        };
+        match slot.or_else(|| class.get_method("__repr__")) {
+            Some(slot) => {
+                let object = self.new_temporary(wasm::Type::I32);
+                self.emit(wasm::Instruction::LocalSet(object));
+                self.emit(wasm::Instruction::LocalGet(object));
+                match self.get_static_method(name, slot) {
+                    Some(method) => {
+                        let func = self.get_function_index(&method);
+                        self.emit(wasm::Instruction::Call(func));
+                        self.emit_exception_check();
+                    }
+                    None => self.emit_vtable_call(name, slot, object),
+                }
+            }
+            None => {
+                self.emit(wasm::Instruction::Drp);
+                self.emit_text(&format!("<{} object>", name));
+            }
+        }
+    }
+
+    /// Append a byte to the string at result, and advance pos.
+    fn emit_put_byte<F>(&mut self, result: usize, pos: usize, byte: F)
+    where
+        F: FnOnce(&mut Self),
+    {
+        self.emit(wasm::Instruction::LocalGet(result));
+        self.emit(wasm::Instruction::LocalGet(pos));
+        self.emit(wasm::Instruction::I32Add);
+        byte(self);
+        self.emit(wasm::Instruction::I32Store8(0, 4));
+        self.emit_increment(pos, 1);
+    }
+
+    fn emit_put_char(&mut self, result: usize, pos: usize, c: char) {
+        self.emit_put_byte(result, pos, |this| {
+            this.emit(wasm::Instruction::I32Const(c as i32))
+        });
+    }
+
+    fn emit_increment(&mut self, local: usize, amount: i32) {
+        self.emit(wasm::Instruction::LocalGet(local));
+        self.emit(wasm::Instruction::I32Const(amount));
+        self.emit(wasm::Instruction::I32Add);
+        self.emit(wasm::Instruction::LocalSet(local));
+    }
+
+    /// Emit a loop which runs while the given condition holds.
+    fn emit_while<C, B>(&mut self, condition: C, body: B)
+    where
+        C: Fn(&mut Self),
+        B: Fn(&mut Self),
+    {
+        self.emit(wasm::Instruction::Block);
+        self.emit(wasm::Instruction::Loop);
+        condition(self);
+        self.emit(wasm::Instruction::I32Eqz);
+        self.emit(wasm::Instruction::BrIf(1));
+        body(self);
+        self.emit(wasm::Instruction::Br(0));
+        self.emit(wasm::Instruction::End);
+        self.emit(wasm::Instruction::End);
+    }
+
+    pub(super) fn compile_str_concat(&mut self) {
+        // Parameters: a, b
+        let (a, b) = (0, 1);
+        let (length_a, length_b, result) = (2, 3, 4);
+
+        self.emit(wasm::Instruction::LocalGet(a));
+        self.builtin_list_len();
+        self.emit(wasm::Instruction::LocalSet(length_a));
+        self.emit(wasm::Instruction::LocalGet(b));
+        self.builtin_list_len();
+        self.emit(wasm::Instruction::LocalSet(length_b));
+
+        self.emit(wasm::Instruction::LocalGet(length_a));
+        self.emit(wasm::Instruction::LocalGet(length_b));
+        self.emit(wasm::Instruction::I32Add);
+        self.emit(wasm::Instruction::I32Const(4));
+        self.emit(wasm::Instruction::I32Add);
+        self.call_runtime(RuntimeFunction::Allocate);
+        self.emit(wasm::Instruction::LocalSet(result));
+        self.emit(wasm::Instruction::LocalGet(result));
+        self.emit(wasm::Instruction::LocalGet(length_a));
+        self.emit(wasm::Instruction::LocalGet(length_b));
+        self.emit(wasm::Instruction::I32Add);
+        self.emit(wasm::Instruction::I32Store(2, 0));
+
+        for (source, offset) in &[(a, None), (b, Some(length_a))] {
+            self.emit(wasm::Instruction::LocalGet(result));
+            self.emit(wasm::Instruction::I32Const(4));
+            self.emit(wasm::Instruction::I32Add);
+            if let Some(offset) = offset {
+                self.emit(wasm::Instruction::LocalGet(*offset));
+                self.emit(wasm::Instruction::I32Add);
+            }
+            self.emit(wasm::Instruction::LocalGet(*source));
+            self.emit(wasm::Instruction::I32Const(4));
+            self.emit(wasm::Instruction::I32Add);
+            self.emit(wasm::Instruction::LocalGet(*source));
+            self.builtin_list_len();
+            self.call_runtime(RuntimeFunction::CopyMemory);
+        }
+
+        self.emit(wasm::Instruction::LocalGet(result));
+    }
+
+    /// Write a number of decimal digits of an unsigned value, padded with zeros.
+    pub(super) fn compile_write_digits(&mut self) {
+        // Parameters: address, value, count
+        let (address, value, count) = (0, 1, 2);
+
+        self.emit_while(
+            |c| c.emit(wasm::Instruction::LocalGet(count)),
+            |c| {
+                c.emit_increment(count, -1);
+                c.emit(wasm::Instruction::LocalGet(address));
+                c.emit(wasm::Instruction::LocalGet(count));
+                c.emit(wasm::Instruction::I32Add);
+                c.emit(wasm::Instruction::LocalGet(value));
+                c.emit(wasm::Instruction::I32Const(10));
+                c.emit(wasm::Instruction::I32RemU);
+                c.emit(wasm::Instruction::I32Const('0' as i32));
+                c.emit(wasm::Instruction::I32Add);
+                c.emit(wasm::Instruction::I32Store8(0, 0));
+                c.emit(wasm::Instruction::LocalGet(value));
+                c.emit(wasm::Instruction::I32Const(10));
+                c.emit(wasm::Instruction::I32DivU);
+                c.emit(wasm::Instruction::LocalSet(value));
+            },
+        );
+    }
+
+    pub(super) fn compile_int_to_str(&mut self) {
+        // Parameters: value
+        let value = 0;
+        let (magnitude, count, result, negative, rest) = (1, 2, 3, 4, 5);
+
+        self.emit(wasm::Instruction::LocalGet(value));
+        self.emit(wasm::Instruction::I32Const(0));
+        self.emit(wasm::Instruction::I32LtS);
+        self.emit(wasm::Instruction::LocalSet(negative));
+
+        // The magnitude is unsigned, which also works for the smallest integer:
+        self.emit(wasm::Instruction::I32Const(0));
+        self.emit(wasm::Instruction::LocalGet(value));
+        self.emit(wasm::Instruction::I32Sub);
+        self.emit(wasm::Instruction::LocalGet(value));
+        self.emit(wasm::Instruction::LocalGet(negative));
+        self.emit(wasm::Instruction::Select);
+        self.emit(wasm::Instruction::LocalSet(magnitude));
+
+        self.emit(wasm::Instruction::I32Const(1));
+        self.emit(wasm::Instruction::LocalSet(count));
+        self.emit(wasm::Instruction::LocalGet(magnitude));
+        self.emit(wasm::Instruction::LocalSet(rest));
+        self.emit_while(
+            |c| {
+                c.emit(wasm::Instruction::LocalGet(rest));
+                c.emit(wasm::Instruction::I32Const(10));
+                c.emit(wasm::Instruction::I32DivU);
+                c.emit(wasm::Instruction::LocalSet(rest));
+                c.emit(wasm::Instruction::LocalGet(rest));
+            },
+            |c| c.emit_increment(count, 1),
+        );
+
+        self.emit(wasm::Instruction::LocalGet(count));
+        self.emit(wasm::Instruction::LocalGet(negative));
+        self.emit(wasm::Instruction::I32Add);
+        self.emit(wasm::Instruction::LocalSet(count));
+        self.emit(wasm::Instruction::LocalGet(count));
+        self.emit(wasm::Instruction::I32Const(4));
+        self.emit(wasm::Instruction::I32Add);
+        self.call_runtime(RuntimeFunction::Allocate);
+        self.emit(wasm::Instruction::LocalSet(result));
+        self.emit(wasm::Instruction::LocalGet(result));
+        self.emit(wasm::Instruction::LocalGet(count));
+        self.emit(wasm::Instruction::I32Store(2, 0));
+
+        // The minus sign, which is overwritten by a digit for positive values:
+        self.emit(wasm::Instruction::LocalGet(result));
+        self.emit(wasm::Instruction::I32Const('-' as i32));
+        self.emit(wasm::Instruction::I32Store8(0, 4));
+
+        self.emit(wasm::Instruction::LocalGet(result));
+        self.emit(wasm::Instruction::I32Const(4));
+        self.emit(wasm::Instruction::I32Add);
+        self.emit(wasm::Instruction::LocalGet(negative));
+        self.emit(wasm::Instruction::I32Add);
+        self.emit(wasm::Instruction::LocalGet(magnitude));
+        self.emit(wasm::Instruction::LocalGet(count));
+        self.emit(wasm::Instruction::LocalGet(negative));
+        self.emit(wasm::Instruction::I32Sub);
+        self.call_runtime(RuntimeFunction::WriteDigits);
+
+        self.emit(wasm::Instruction::LocalGet(result));
+    }
+
+    /// Quote a string. Like Python, double quotes are used when the string
+    /// contains single quotes but no double quotes.
+    pub(super) fn compile_str_repr(&mut self) {
+        // Parameters: string
+        let string = 0;
+        let (length, quote, i, byte, result, pos, single, double) = (1, 2, 3, 4, 5, 6, 7, 8);
+
+        self.emit(wasm::Instruction::LocalGet(string));
+        self.builtin_list_len();
+        self.emit(wasm::Instruction::LocalSet(length));
+
+        self.emit_counted_loop(i, length, |c| {
+            for (local, quote) in &[(single, '\''), (double, '"')] {
+                c.emit_string_byte(string, i);
+                c.emit(wasm::Instruction::I32Const(*quote as i32));
+                c.emit(wasm::Instruction::I32Eq);
+                c.emit(wasm::Instruction::LocalGet(*local));
+                c.emit(wasm::Instruction::I32Or);
+                c.emit(wasm::Instruction::LocalSet(*local));
+            }
+        });
+        self.emit(wasm::Instruction::I32Const('"' as i32));
+        self.emit(wasm::Instruction::I32Const('\'' as i32));
+        self.emit(wasm::Instruction::LocalGet(single));
+        self.emit(wasm::Instruction::LocalGet(double));
+        self.emit(wasm::Instruction::I32Eqz);
+        self.emit(wasm::Instruction::I32And);
+        self.emit(wasm::Instruction::Select);
+        self.emit(wasm::Instruction::LocalSet(quote));
+
+        // Each byte takes at most two bytes when escaped, plus the quotes:
+        self.emit(wasm::Instruction::LocalGet(length));
+        self.emit(wasm::Instruction::I32Const(2));
+        self.emit(wasm::Instruction::I32Mul);
+        self.emit(wasm::Instruction::I32Const(6));
+        self.emit(wasm::Instruction::I32Add);
+        self.call_runtime(RuntimeFunction::Allocate);
+        self.emit(wasm::Instruction::LocalSet(result));
+
+        let put_quote = |c: &mut Self| {
+            c.emit_put_byte(result, pos, |c| c.emit(wasm::Instruction::LocalGet(quote)))
+        };
+        put_quote(self);
+        self.emit_counted_loop(i, length, |c| {
+            c.emit_string_byte(string, i);
+            c.emit(wasm::Instruction::LocalSet(byte));
+
+            // Backslash, the quote and some control characters are escaped:
+            c.emit(wasm::Instruction::LocalGet(byte));
+            c.emit(wasm::Instruction::LocalGet(quote));
+            c.emit(wasm::Instruction::I32Eq);
+            for escaped in &['\\', '\n', '\t', '\r'] {
+                c.emit(wasm::Instruction::LocalGet(byte));
+                c.emit(wasm::Instruction::I32Const(*escaped as i32));
+                c.emit(wasm::Instruction::I32Eq);
+                c.emit(wasm::Instruction::I32Or);
+            }
+            c.emit(wasm::Instruction::If);
+            c.emit_put_char(result, pos, '\\');
+            for (escaped, letter) in &[('\n', 'n'), ('\t', 't'), ('\r', 'r')] {
+                c.emit(wasm::Instruction::LocalGet(byte));
+                c.emit(wasm::Instruction::I32Const(*escaped as i32));
+                c.emit(wasm::Instruction::I32Eq);
+                c.emit(wasm::Instruction::If);
+                c.emit(wasm::Instruction::I32Const(*letter as i32));
+                c.emit(wasm::Instruction::LocalSet(byte));
+                c.emit(wasm::Instruction::End);
+            }
+            c.emit(wasm::Instruction::End);
+            c.emit_put_byte(result, pos, |c| c.emit(wasm::Instruction::LocalGet(byte)));
+        });
+        put_quote(self);
+
+        self.emit(wasm::Instruction::LocalGet(result));
+        self.emit(wasm::Instruction::LocalGet(pos));
+        self.emit(wasm::Instruction::I32Store(2, 0));
+        self.emit(wasm::Instruction::LocalGet(result));
+    }
+
+    /// Look up 10 to the power of an exponent from 0 up to 308.
+    ///
+    /// The powers are placed in static data, since computing them gives
+    /// rounding errors beyond 1e22.
+    pub(super) fn compile_pow10(&mut self) {
+        // Parameters: exponent
+        let exponent = 0;
+
+        let table: Vec<u8> = (0..=MAX_POWER)
+            .flat_map(|k| {
+                format!("1e{}", k)
+                    .parse::<f64>()
+                    .unwrap()
+                    .to_le_bytes()
+                    .to_vec()
+            })
+            .collect();
+        let address = self.add_static_data(&table, 8);
+        self.emit(wasm::Instruction::LocalGet(exponent));
+        self.emit(wasm::Instruction::I32Const(3));
+        self.emit(wasm::Instruction::I32Shl);
+        self.emit(wasm::Instruction::F64Load(3, address));
+    }
+
+    pub(super) fn compile_float_to_str(&mut self) {
+        // Parameters: value
+        let value = 0;
+        // Locals of type f64:
+        let (a, m, rest) = (1, 2, 3);
+        // Locals of type i32:
+        let (exponent, n, scale, result, pos, digits, i, high, ok, bias) =
+            (4, 5, 6, 7, 8, 9, 10, 11, 12, 13);
+
+        // Special values:
+        self.emit(wasm::Instruction::LocalGet(value));
+        self.emit(wasm::Instruction::LocalGet(value));
+        self.emit(wasm::Instruction::F64Ne);
+        self.emit(wasm::Instruction::If);
+        self.emit_text("nan");
+        self.emit(wasm::Instruction::Return);
+        self.emit(wasm::Instruction::End);
+
+        self.emit(wasm::Instruction::LocalGet(value));
+        self.emit(wasm::Instruction::F64Abs);
+        self.emit(wasm::Instruction::LocalSet(a));
+        for (special, negative, positive) in &[(f64::INFINITY, "-inf", "inf"), (0.0, "-0.0", "0.0")]
+        {
+            self.emit(wasm::Instruction::LocalGet(a));
+            self.emit(wasm::Instruction::F64Const(*special));
+            self.emit(wasm::Instruction::F64Eq);
+            self.emit(wasm::Instruction::If);
+            self.emit_text(negative);
+            self.emit_text(positive);
+            // This also detects -0.0, since 1 / -0.0 is -inf:
+            self.emit(wasm::Instruction::F64Const(1.0));
+            self.emit(wasm::Instruction::LocalGet(value));
+            self.emit(wasm::Instruction::F64Div);
+            self.emit(wasm::Instruction::F64Const(0.0));
+            self.emit(wasm::Instruction::F64Lt);
+            self.emit(wasm::Instruction::Select);
+            self.emit(wasm::Instruction::Return);
+            self.emit(wasm::Instruction::End);
+        }
+
+        // Sign, digits, dot or exponent, fits in 32 bytes:
+        self.emit(wasm::Instruction::I32Const(36));
+        self.call_runtime(RuntimeFunction::Allocate);
+        self.emit(wasm::Instruction::LocalSet(result));
+        self.emit(wasm::Instruction::LocalGet(value));
+        self.emit(wasm::Instruction::F64Const(0.0));
+        self.emit(wasm::Instruction::F64Lt);
+        self.emit(wasm::Instruction::If);
+        self.emit_put_char(result, pos, '-');
+        self.emit(wasm::Instruction::End);
+
+        // Scale tiny values, so that the powers of ten used below are finite:
+        self.emit(wasm::Instruction::LocalGet(a));
+        self.emit(wasm::Instruction::F64Const(TINY));
+        self.emit(wasm::Instruction::F64Lt);
+        self.emit(wasm::Instruction::If);
+        self.emit(wasm::Instruction::LocalGet(a));
+        self.emit(wasm::Instruction::I32Const(TINY_BIAS));
+        self.call_runtime(RuntimeFunction::Pow10);
+        self.emit(wasm::Instruction::F64Mul);
+        self.emit(wasm::Instruction::LocalSet(a));
+        self.emit(wasm::Instruction::I32Const(-TINY_BIAS));
+        self.emit(wasm::Instruction::LocalSet(bias));
+        self.emit(wasm::Instruction::End);
+
+        // Determine the exponent of the first digit:
+        self.emit(wasm::Instruction::LocalGet(a));
+        self.emit(wasm::Instruction::F64Const(1.0));
+        self.emit(wasm::Instruction::F64Ge);
+        self.emit(wasm::Instruction::If);
+        self.emit_while(
+            |c| {
+                c.emit(wasm::Instruction::LocalGet(exponent));
+                c.emit(wasm::Instruction::I32Const(MAX_POWER));
+                c.emit(wasm::Instruction::I32LtS);
+                c.emit(wasm::Instruction::LocalGet(exponent));
+                c.emit(wasm::Instruction::I32Const(1));
+                c.emit(wasm::Instruction::I32Add);
+                c.call_runtime(RuntimeFunction::Pow10);
+                c.emit(wasm::Instruction::LocalGet(a));
+                c.emit(wasm::Instruction::F64Le);
+                c.emit(wasm::Instruction::I32And);
+            },
+            |c| c.emit_increment(exponent, 1),
+        );
+        self.emit(wasm::Instruction::Else);
+        self.emit_while(
+            |c| {
+                c.emit(wasm::Instruction::LocalGet(a));
+                c.emit(wasm::Instruction::I32Const(0));
+                c.emit(wasm::Instruction::LocalGet(exponent));
+                c.emit(wasm::Instruction::I32Sub);
+                c.call_runtime(RuntimeFunction::Pow10);
+                c.emit(wasm::Instruction::F64Mul);
+                c.emit(wasm::Instruction::F64Const(1.0));
+                c.emit(wasm::Instruction::F64Lt);
+            },
+            |c| c.emit_increment(exponent, -1),
+        );
+        self.emit(wasm::Instruction::End);
+
+        // Find the fewest digits m, such that m * 10 ** (exponent - n + 1) reads back as a:
+        self.emit(wasm::Instruction::Block);
+        self.emit(wasm::Instruction::Loop);
+        self.emit_increment(n, 1);
+        self.emit(wasm::Instruction::LocalGet(n));
+        self.emit(wasm::Instruction::I32Const(1));
+        self.emit(wasm::Instruction::I32Sub);
+        self.emit(wasm::Instruction::LocalGet(exponent));
+        self.emit(wasm::Instruction::I32Sub);
+        self.emit(wasm::Instruction::LocalSet(scale));
+        self.emit(wasm::Instruction::LocalGet(scale));
+        self.emit(wasm::Instruction::I32Const(0));
+        self.emit(wasm::Instruction::I32GeS);
+        self.emit(wasm::Instruction::If);
+        self.emit_scaled_digits(a, m, scale, ok, true);
+        self.emit(wasm::Instruction::Else);
+        self.emit(wasm::Instruction::I32Const(0));
+        self.emit(wasm::Instruction::LocalGet(scale));
+        self.emit(wasm::Instruction::I32Sub);
+        self.emit(wasm::Instruction::LocalSet(scale));
+        self.emit_scaled_digits(a, m, scale, ok, false);
+        self.emit(wasm::Instruction::End);
+        self.emit(wasm::Instruction::LocalGet(ok));
+        self.emit(wasm::Instruction::BrIf(1));
+        self.emit(wasm::Instruction::LocalGet(n));
+        self.emit(wasm::Instruction::I32Const(MAX_DIGITS));
+        self.emit(wasm::Instruction::I32GeS);
+        self.emit(wasm::Instruction::BrIf(1));
+        self.emit(wasm::Instruction::Br(0));
+        self.emit(wasm::Instruction::End);
+        self.emit(wasm::Instruction::End);
+
+        // Rounding can give an extra digit, as in 9.99 rounded to 10:
+        self.emit(wasm::Instruction::LocalGet(m));
+        self.emit(wasm::Instruction::LocalGet(n));
+        self.call_runtime(RuntimeFunction::Pow10);
+        self.emit(wasm::Instruction::F64Ge);
+        self.emit(wasm::Instruction::If);
+        self.emit(wasm::Instruction::LocalGet(m));
+        self.emit(wasm::Instruction::F64Const(10.0));
+        self.emit(wasm::Instruction::F64Div);
+        self.emit(wasm::Instruction::LocalSet(m));
+        self.emit_increment(exponent, 1);
+        self.emit(wasm::Instruction::End);
+
+        // Write all 17 digits, as two parts which fit in 32 bits:
+        self.emit(wasm::Instruction::LocalGet(m));
+        self.emit(wasm::Instruction::F64Const(1e9));
+        self.emit(wasm::Instruction::F64Div);
+        self.emit(wasm::Instruction::F64Floor);
+        self.emit(wasm::Instruction::I32TruncF64U);
+        self.emit(wasm::Instruction::LocalSet(high));
+        self.emit(wasm::Instruction::LocalGet(m));
+        self.emit(wasm::Instruction::LocalGet(high));
+        self.emit(wasm::Instruction::F64ConvertI32U);
+        self.emit(wasm::Instruction::F64Const(1e9));
+        self.emit(wasm::Instruction::F64Mul);
+        self.emit(wasm::Instruction::F64Sub);
+        self.emit(wasm::Instruction::LocalSet(rest));
+        // The division can be rounded up:
+        self.emit(wasm::Instruction::LocalGet(rest));
+        self.emit(wasm::Instruction::F64Const(0.0));
+        self.emit(wasm::Instruction::F64Lt);
+        self.emit(wasm::Instruction::If);
+        self.emit_increment(high, -1);
+        self.emit(wasm::Instruction::LocalGet(rest));
+        self.emit(wasm::Instruction::F64Const(1e9));
+        self.emit(wasm::Instruction::F64Add);
+        self.emit(wasm::Instruction::LocalSet(rest));
+        self.emit(wasm::Instruction::End);
+
+        self.emit(wasm::Instruction::I32Const(MAX_DIGITS));
+        self.call_runtime(RuntimeFunction::Allocate);
+        self.emit(wasm::Instruction::LocalSet(digits));
+        self.emit(wasm::Instruction::LocalGet(digits));
+        self.emit(wasm::Instruction::LocalGet(high));
+        self.emit(wasm::Instruction::I32Const(8));
+        self.call_runtime(RuntimeFunction::WriteDigits);
+        self.emit(wasm::Instruction::LocalGet(digits));
+        self.emit(wasm::Instruction::I32Const(8));
+        self.emit(wasm::Instruction::I32Add);
+        self.emit(wasm::Instruction::LocalGet(rest));
+        self.emit(wasm::Instruction::I32TruncF64U);
+        self.emit(wasm::Instruction::I32Const(9));
+        self.call_runtime(RuntimeFunction::WriteDigits);
+
+        // Keep the last n digits, without leading and trailing zeros:
+        self.emit(wasm::Instruction::LocalGet(digits));
+        self.emit(wasm::Instruction::I32Const(MAX_DIGITS));
+        self.emit(wasm::Instruction::I32Add);
+        self.emit(wasm::Instruction::LocalGet(n));
+        self.emit(wasm::Instruction::I32Sub);
+        self.emit(wasm::Instruction::LocalSet(digits));
+        let is_zero_digit = |c: &mut Self, offset: i32| {
+            c.emit(wasm::Instruction::LocalGet(n));
+            c.emit(wasm::Instruction::I32Const(1));
+            c.emit(wasm::Instruction::I32GtS);
+            c.emit(wasm::Instruction::LocalGet(digits));
+            if offset < 0 {
+                c.emit(wasm::Instruction::LocalGet(n));
+                c.emit(wasm::Instruction::I32Add);
+                c.emit(wasm::Instruction::I32Const(offset));
+                c.emit(wasm::Instruction::I32Add);
+            }
+            c.emit(wasm::Instruction::I32Load8U(0, 0));
+            c.emit(wasm::Instruction::I32Const('0' as i32));
+            c.emit(wasm::Instruction::I32Eq);
+            c.emit(wasm::Instruction::I32And);
+        };
+        self.emit_while(
+            |c| is_zero_digit(c, 0),
+            |c| {
+                c.emit_increment(digits, 1);
+                c.emit_increment(n, -1);
+                c.emit_increment(exponent, -1);
+            },
+        );
+        self.emit_while(|c| is_zero_digit(c, -1), |c| c.emit_increment(n, -1));
+        self.emit(wasm::Instruction::LocalGet(exponent));
+        self.emit(wasm::Instruction::LocalGet(bias));
+        self.emit(wasm::Instruction::I32Add);
+        self.emit(wasm::Instruction::LocalSet(exponent));
+
+        let put_digit = |c: &mut Self| {
+            c.emit_put_byte(result, pos, |c| {
+                c.emit(wasm::Instruction::LocalGet(digits));
+                c.emit(wasm::Instruction::LocalGet(i));
+                c.emit(wasm::Instruction::I32Add);
+                c.emit(wasm::Instruction::I32Load8U(0, 0));
+            })
+        };
+        // Put digits from i up to the value of the given local:
+        let put_digits = |c: &mut Self, end: usize| {
+            c.emit_while(
+                |c| {
+                    c.emit(wasm::Instruction::LocalGet(i));
+                    c.emit(wasm::Instruction::LocalGet(end));
+                    c.emit(wasm::Instruction::I32LtS);
+                },
+                |c| {
+                    put_digit(c);
+                    c.emit_increment(i, 1);
+                },
+            );
+        };
+
+        self.emit(wasm::Instruction::LocalGet(exponent));
+        self.emit(wasm::Instruction::I32Const(MIN_FIXED_EXPONENT));
+        self.emit(wasm::Instruction::I32LtS);
+        self.emit(wasm::Instruction::LocalGet(exponent));
+        self.emit(wasm::Instruction::I32Const(MAX_FIXED_EXPONENT));
+        self.emit(wasm::Instruction::I32GeS);
+        self.emit(wasm::Instruction::I32Or);
+        self.emit(wasm::Instruction::If);
+        {
+            // 1.5e+16
+            put_digit(self);
+            self.emit(wasm::Instruction::I32Const(1));
+            self.emit(wasm::Instruction::LocalSet(i));
+            self.emit(wasm::Instruction::LocalGet(n));
+            self.emit(wasm::Instruction::I32Const(1));
+            self.emit(wasm::Instruction::I32GtS);
+            self.emit(wasm::Instruction::If);
+            self.emit_put_char(result, pos, '.');
+            put_digits(self, n);
+            self.emit(wasm::Instruction::End);
+            self.emit_put_char(result, pos, 'e');
+            self.emit_put_byte(result, pos, |c| {
+                c.emit(wasm::Instruction::I32Const('-' as i32));
+                c.emit(wasm::Instruction::I32Const('+' as i32));
+                c.emit(wasm::Instruction::LocalGet(exponent));
+                c.emit(wasm::Instruction::I32Const(0));
+                c.emit(wasm::Instruction::I32LtS);
+                c.emit(wasm::Instruction::Select);
+            });
+            // At least two digits, there are at most three:
+            self.emit(wasm::Instruction::I32Const(0));
+            self.emit(wasm::Instruction::LocalGet(exponent));
+            self.emit(wasm::Instruction::I32Sub);
+            self.emit(wasm::Instruction::LocalGet(exponent));
+            self.emit(wasm::Instruction::LocalGet(exponent));
+            self.emit(wasm::Instruction::I32Const(0));
+            self.emit(wasm::Instruction::I32LtS);
+            self.emit(wasm::Instruction::Select);
+            self.emit(wasm::Instruction::LocalSet(exponent));
+            self.emit(wasm::Instruction::I32Const(3));
+            self.emit(wasm::Instruction::I32Const(2));
+            self.emit(wasm::Instruction::LocalGet(exponent));
+            self.emit(wasm::Instruction::I32Const(100));
+            self.emit(wasm::Instruction::I32GeS);
+            self.emit(wasm::Instruction::Select);
+            self.emit(wasm::Instruction::LocalSet(i));
+            self.emit(wasm::Instruction::LocalGet(result));
+            self.emit(wasm::Instruction::I32Const(4));
+            self.emit(wasm::Instruction::I32Add);
+            self.emit(wasm::Instruction::LocalGet(pos));
+            self.emit(wasm::Instruction::I32Add);
+            self.emit(wasm::Instruction::LocalGet(exponent));
+            self.emit(wasm::Instruction::LocalGet(i));
+            self.call_runtime(RuntimeFunction::WriteDigits);
+            self.emit(wasm::Instruction::LocalGet(pos));
+            self.emit(wasm::Instruction::LocalGet(i));
+            self.emit(wasm::Instruction::I32Add);
+            self.emit(wasm::Instruction::LocalSet(pos));
+        }
+        self.emit(wasm::Instruction::Else);
+        self.emit(wasm::Instruction::LocalGet(exponent));
+        self.emit(wasm::Instruction::I32Const(0));
+        self.emit(wasm::Instruction::I32GeS);
+        self.emit(wasm::Instruction::If);
+        {
+            // 1500.0 or 1.25, the integer part can end with zeros:
+            self.emit_while(
+                |c| {
+                    c.emit(wasm::Instruction::LocalGet(i));
+                    c.emit(wasm::Instruction::LocalGet(exponent));
+                    c.emit(wasm::Instruction::I32LeS);
+                },
+                |c| {
+                    c.emit(wasm::Instruction::LocalGet(i));
+                    c.emit(wasm::Instruction::LocalGet(n));
+                    c.emit(wasm::Instruction::I32LtS);
+                    c.emit(wasm::Instruction::If);
+                    put_digit(c);
+                    c.emit(wasm::Instruction::Else);
+                    c.emit_put_char(result, pos, '0');
+                    c.emit(wasm::Instruction::End);
+                    c.emit_increment(i, 1);
+                },
+            );
+            self.emit_put_char(result, pos, '.');
+            self.emit(wasm::Instruction::LocalGet(i));
+            self.emit(wasm::Instruction::LocalGet(n));
+            self.emit(wasm::Instruction::I32GeS);
+            self.emit(wasm::Instruction::If);
+            self.emit_put_char(result, pos, '0');
+            self.emit(wasm::Instruction::Else);
+            put_digits(self, n);
+            self.emit(wasm::Instruction::End);
+        }
+        self.emit(wasm::Instruction::Else);
+        {
+            // 0.0015
+            self.emit_put_char(result, pos, '0');
+            self.emit_put_char(result, pos, '.');
+            self.emit_while(
+                |c| {
+                    c.emit_increment(exponent, 1);
+                    c.emit(wasm::Instruction::LocalGet(exponent));
+                    c.emit(wasm::Instruction::I32Const(0));
+                    c.emit(wasm::Instruction::I32LtS);
+                },
+                |c| c.emit_put_char(result, pos, '0'),
+            );
+            put_digits(self, n);
+        }
+        self.emit(wasm::Instruction::End);
+        self.emit(wasm::Instruction::End);
+
+        self.emit(wasm::Instruction::LocalGet(result));
+        self.emit(wasm::Instruction::LocalGet(pos));
+        self.emit(wasm::Instruction::I32Store(2, 0));
+        self.emit(wasm::Instruction::LocalGet(result));
+    }
+
+    /// Round a to an integer m after scaling by 10 ** scale, and check if
+    /// scaling m back gives a. The scale is negated when not multiplying.
+    fn emit_scaled_digits(&mut self, a: usize, m: usize, scale: usize, ok: usize, multiply: bool) {
+        let (forward, backward) = if multiply {
+            (wasm::Instruction::F64Mul, wasm::Instruction::F64Div)
+        } else {
+            (wasm::Instruction::F64Div, wasm::Instruction::F64Mul)
+        };
+        self.emit(wasm::Instruction::LocalGet(a));
+        self.emit(wasm::Instruction::LocalGet(scale));
+        self.call_runtime(RuntimeFunction::Pow10);
+        self.emit(forward);
+        self.emit(wasm::Instruction::F64Nearest);
+        self.emit(wasm::Instruction::LocalSet(m));
+        self.emit(wasm::Instruction::LocalGet(m));
+        self.emit(wasm::Instruction::LocalGet(scale));
+        self.call_runtime(RuntimeFunction::Pow10);
+        self.emit(backward);
+        self.emit(wasm::Instruction::LocalGet(a));
+        self.emit(wasm::Instruction::F64Eq);
+        self.emit(wasm::Instruction::LocalSet(ok));
+    }
+}
@@ -0,0 +1,652 @@
+//! Hash table runtime, used for dictionaries and sets.
+//!
+//! The table keeps insertion order, like the one of CPython. Entries are
+//! appended to an entry array. A separate index table, of which the size
+//! is a power of two, maps hashes to positions in the entry array.
+//!
+//! Layout of a dictionary in memory:
+//! - 0: number of keys, at the same place as the length of a list
+//! - 4: number of used entries, including deleted entries
+//! - 8: capacity of the entry array
+//! - 12: mask for the index table, which is its size minus one
+//! - 16: pointer to the index table
+//! - 20: pointer to the entry array
+//!
+//! An entry contains the hash, the key and the value. Deleted entries
+//! have a hash of -1, real hashes are never negative.
+//!
+//! Sets use the same layout, their entries have no value.
+
+use super::runtime::RuntimeFunction;
+use super::{analyze, wasm, Compiler};
+
+pub const DICT_USED: usize = 4;
+const DICT_CAPACITY: usize = 8;
+const DICT_MASK: usize = 12;
+const DICT_INDICES: usize = 16;
+pub const DICT_ENTRIES: usize = 20;
+const DICT_SIZE: usize = 24;
+
+pub const ENTRY_HASH: usize = 0;
+pub const ENTRY_KEY: usize = 4;
+/// Values are at most 8 bytes, and aligned to 8 bytes.
+pub const ENTRY_VALUE: usize = 8;
+pub const ENTRY_SIZE: usize = 16;
+
+/// Markers in the index table.
+const EMPTY: i32 = -1;
+const DELETED: i32 = -2;
+
+/// Smallest size of the index table.
+const MIN_SIZE: i32 = 8;
+
+/// Number of bits of the hash which are mixed in at each probe.
+const PERTURB_SHIFT: i32 = 5;
+
+/// How keys are hashed and compared.
+#[derive(Clone, Copy, PartialEq)]
+pub enum KeyKind {
+    /// Integers and booleans, compared by value.
+    Int,
+
+    /// Strings, compared by content.
+    Str,
+}
+
+/// Operators which create a new set from two sets.
+#[derive(Clone, Copy, PartialEq)]
+pub enum SetOperator {
+    Union,
+    Intersection,
+    Difference,
+}
+
+impl SetOperator {
+    pub fn name(self) -> &'static str {
+        match self {
+            SetOperator::Union => "union",
+            SetOperator::Intersection => "intersection",
+            SetOperator::Difference => "difference",
+        }
+    }
+}
+
+impl KeyKind {
+    pub fn from_type(typ: &analyze::Type) -> Self {
+        match typ {
+            analyze::Type::Integer | analyze::Type::Bool | analyze::Type::Enum(_) => KeyKind::Int,
+            analyze::Type::Str => KeyKind::Str,
+            other => panic!("Cannot hash type: {}", other),
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            KeyKind::Int => "int",
+            KeyKind::Str => "str",
+        }
+    }
+}
+
+impl Compiler {
+    pub(super) fn compile_hash(&mut self, kind: KeyKind) {
+        // Parameters: key
+        let key = 0;
+        let (hash, i, length) = (1, 2, 3);
+
+        match kind {
+            KeyKind::Int => {
+                self.emit(wasm::Instruction::LocalGet(key));
+            }
+            KeyKind::Str => {
+                // FNV-1a hash over the bytes of the string:
+                self.emit(wasm::Instruction::I32Const(0x811c_9dc5_u32 as i32));
+                self.emit(wasm::Instruction::LocalSet(hash));
+                self.emit(wasm::Instruction::LocalGet(key));
+                self.builtin_list_len();
+                self.emit(wasm::Instruction::LocalSet(length));
+                self.emit_counted_loop(i, length, |c| {
+                    c.emit(wasm::Instruction::LocalGet(hash));
+                    c.emit_string_byte(key, i);
+                    c.emit(wasm::Instruction::I32Xor);
+                    c.emit(wasm::Instruction::I32Const(0x0100_0193));
+                    c.emit(wasm::Instruction::I32Mul);
+                    c.emit(wasm::Instruction::LocalSet(hash));
+                });
+                self.emit(wasm::Instruction::LocalGet(hash));
+            }
+        }
+
+        // Negative values are used for deleted entries:
+        self.emit(wasm::Instruction::I32Const(i32::MAX));
+        self.emit(wasm::Instruction::I32And);
+    }
+
+    pub(super) fn compile_str_equal(&mut self) {
+        // Parameters: a, b
+        let (a, b) = (0, 1);
+        let (i, length) = (2, 3);
+
+        self.emit(wasm::Instruction::LocalGet(a));
+        self.emit(wasm::Instruction::LocalGet(b));
+        self.emit(wasm::Instruction::I32Eq);
+        self.emit(wasm::Instruction::If);
+        self.emit(wasm::Instruction::I32Const(1));
+        self.emit(wasm::Instruction::Return);
+        self.emit(wasm::Instruction::End);
+
+        self.emit(wasm::Instruction::LocalGet(a));
+        self.builtin_list_len();
+        self.emit(wasm::Instruction::LocalSet(length));
+        self.emit(wasm::Instruction::LocalGet(length));
+        self.emit(wasm::Instruction::LocalGet(b));
+        self.builtin_list_len();
+        self.emit(wasm::Instruction::I32Ne);
+        self.emit(wasm::Instruction::If);
+        self.emit(wasm::Instruction::I32Const(0));
+        self.emit(wasm::Instruction::Return);
+        self.emit(wasm::Instruction::End);
+
+        self.emit_counted_loop(i, length, |c| {
+            c.emit_string_byte(a, i);
+            c.emit_string_byte(b, i);
+            c.emit(wasm::Instruction::I32Ne);
+            c.emit(wasm::Instruction::If);
+            c.emit(wasm::Instruction::I32Const(0));
+            c.emit(wasm::Instruction::Return);
+            c.emit(wasm::Instruction::End);
+        });
+
+        self.emit(wasm::Instruction::I32Const(1));
+    }
+
+    pub(super) fn compile_dict_new(&mut self) {
+        let dict = 0;
+
+        self.allocate(DICT_SIZE);
+        self.emit(wasm::Instruction::LocalSet(dict));
+        for offset in &[0, DICT_USED] {
+            self.emit(wasm::Instruction::LocalGet(dict));
+            self.emit(wasm::Instruction::I32Const(0));
+            self.emit(wasm::Instruction::I32Store(2, *offset));
+        }
+
+        // Resizing an empty dictionary creates the tables:
+        self.emit(wasm::Instruction::LocalGet(dict));
+        self.call_runtime(RuntimeFunction::DictResize);
+        self.emit(wasm::Instruction::LocalGet(dict));
+    }
+
+    /// Create new tables, large enough for the current number of keys,
+    /// and move the entries which are not deleted.
+    pub(super) fn compile_dict_resize(&mut self) {
+        // Parameters: dict
+        let dict = 0;
+        let (size, capacity, indices, entries, i, j, used, entry, slot, perturb) =
+            (1, 2, 3, 4, 5, 6, 7, 8, 9, 10);
+
+        // Keep the index table at most 1/3 filled after resizing:
+        self.emit(wasm::Instruction::I32Const(MIN_SIZE));
+        self.emit(wasm::Instruction::LocalSet(size));
+        self.emit(wasm::Instruction::Block);
+        self.emit(wasm::Instruction::Loop);
+        self.emit(wasm::Instruction::LocalGet(size));
+        self.emit(wasm::Instruction::LocalGet(dict));
+        self.builtin_list_len();
+        self.emit(wasm::Instruction::I32Const(3));
+        self.emit(wasm::Instruction::I32Mul);
+        self.emit(wasm::Instruction::I32GtS);
+        self.emit(wasm::Instruction::BrIf(1));
+        self.emit(wasm::Instruction::LocalGet(size));
+        self.emit(wasm::Instruction::I32Const(1));
+        self.emit(wasm::Instruction::I32Shl);
+        self.emit(wasm::Instruction::LocalSet(size));
+        self.emit(wasm::Instruction::Br(0));
+        self.emit(wasm::Instruction::End);
+        self.emit(wasm::Instruction::End);
+
+        // Up to 2/3 of the index table can be used:
+        self.emit(wasm::Instruction::LocalGet(size));
+        self.emit(wasm::Instruction::I32Const(1));
+        self.emit(wasm::Instruction::I32Shl);
+        self.emit(wasm::Instruction::I32Const(3));
+        self.emit(wasm::Instruction::I32DivS);
+        self.emit(wasm::Instruction::LocalSet(capacity));
+
+        self.emit(wasm::Instruction::LocalGet(size));
+        self.emit(wasm::Instruction::I32Const(4));
+        self.emit(wasm::Instruction::I32Mul);
+        self.call_runtime(RuntimeFunction::Allocate);
+        self.emit(wasm::Instruction::LocalSet(indices));
+        self.emit_counted_loop(i, size, |c| {
+            c.emit_table_address(indices, i, 4);
+            c.emit(wasm::Instruction::I32Const(EMPTY));
+            c.emit(wasm::Instruction::I32Store(2, 0));
+        });
+
+        self.emit(wasm::Instruction::LocalGet(capacity));
+        self.emit(wasm::Instruction::I32Const(ENTRY_SIZE as i32));
+        self.emit(wasm::Instruction::I32Mul);
+        self.call_runtime(RuntimeFunction::Allocate);
+        self.emit(wasm::Instruction::LocalSet(entries));
+
+        // Move entries, and insert them in the new index table:
+        self.emit(wasm::Instruction::I32Const(0));
+        self.emit(wasm::Instruction::LocalSet(j));
+        self.emit_for_each_entry(dict, i, used, entry, |c| {
+            c.emit_table_address(entries, j, ENTRY_SIZE);
+            c.emit(wasm::Instruction::LocalGet(entry));
+            c.emit(wasm::Instruction::I32Const(ENTRY_SIZE as i32));
+            c.call_runtime(RuntimeFunction::CopyMemory);
+
+            // The key is not in the table yet, so take the first empty slot:
+            c.emit(wasm::Instruction::LocalGet(entry));
+            c.emit(wasm::Instruction::I32Load(2, ENTRY_HASH));
+            c.emit(wasm::Instruction::LocalSet(perturb));
+            c.emit(wasm::Instruction::LocalGet(perturb));
+            c.emit(wasm::Instruction::LocalGet(size));
+            c.emit(wasm::Instruction::I32Const(1));
+            c.emit(wasm::Instruction::I32Sub);
+            c.emit(wasm::Instruction::I32And);
+            c.emit(wasm::Instruction::LocalSet(slot));
+            c.emit(wasm::Instruction::Block);
+            c.emit(wasm::Instruction::Loop);
+            c.emit_table_address(indices, slot, 4);
+            c.emit(wasm::Instruction::I32Load(2, 0));
+            c.emit(wasm::Instruction::I32Const(EMPTY));
+            c.emit(wasm::Instruction::I32Eq);
+            c.emit(wasm::Instruction::BrIf(1));
+            c.emit_next_probe(slot, perturb, |c| {
+                c.emit(wasm::Instruction::LocalGet(size));
+                c.emit(wasm::Instruction::I32Const(1));
+                c.emit(wasm::Instruction::I32Sub);
+            });
+            c.emit(wasm::Instruction::Br(0));
+            c.emit(wasm::Instruction::End);
+            c.emit(wasm::Instruction::End);
+
+            c.emit_table_address(indices, slot, 4);
+            c.emit(wasm::Instruction::LocalGet(j));
+            c.emit(wasm::Instruction::I32Store(2, 0));
+
+            c.emit(wasm::Instruction::LocalGet(j));
+            c.emit(wasm::Instruction::I32Const(1));
+            c.emit(wasm::Instruction::I32Add);
+            c.emit(wasm::Instruction::LocalSet(j));
+        });
+
+        let fields = [
+            (DICT_USED, j),
+            (DICT_CAPACITY, capacity),
+            (DICT_INDICES, indices),
+            (DICT_ENTRIES, entries),
+        ];
+        for (offset, value) in &fields {
+            self.emit(wasm::Instruction::LocalGet(dict));
+            self.emit(wasm::Instruction::LocalGet(*value));
+            self.emit(wasm::Instruction::I32Store(2, *offset));
+        }
+        self.emit(wasm::Instruction::LocalGet(dict));
+        self.emit(wasm::Instruction::LocalGet(size));
+        self.emit(wasm::Instruction::I32Const(1));
+        self.emit(wasm::Instruction::I32Sub);
+        self.emit(wasm::Instruction::I32Store(2, DICT_MASK));
+    }
+
+    /// Find the slot in the index table for a key, which is either the slot
+    /// of the entry with this key, or an empty slot where the key can go.
+    pub(super) fn compile_dict_find(&mut self, kind: KeyKind) {
+        // Parameters: dict, key, hash
+        let (dict, key, hash) = (0, 1, 2);
+        let (indices, slot, perturb, index, entry) = (3, 4, 5, 6, 7);
+
+        self.emit(wasm::Instruction::LocalGet(dict));
+        self.emit(wasm::Instruction::I32Load(2, DICT_INDICES));
+        self.emit(wasm::Instruction::LocalSet(indices));
+        self.emit(wasm::Instruction::LocalGet(hash));
+        self.emit(wasm::Instruction::LocalSet(perturb));
+        self.emit(wasm::Instruction::LocalGet(hash));
+        self.emit(wasm::Instruction::LocalGet(dict));
+        self.emit(wasm::Instruction::I32Load(2, DICT_MASK));
+        self.emit(wasm::Instruction::I32And);
+        self.emit(wasm::Instruction::LocalSet(slot));
+
+        self.emit(wasm::Instruction::Loop);
+        self.emit_table_address(indices, slot, 4);
+        self.emit(wasm::Instruction::I32Load(2, 0));
+        self.emit(wasm::Instruction::LocalSet(index));
+
+        self.emit(wasm::Instruction::LocalGet(index));
+        self.emit(wasm::Instruction::I32Const(EMPTY));
+        self.emit(wasm::Instruction::I32Eq);
+        self.emit(wasm::Instruction::If);
+        self.emit_table_address(indices, slot, 4);
+        self.emit(wasm::Instruction::Return);
+        self.emit(wasm::Instruction::End);
+
+        // Skip deleted slots, and check the entry of other slots:
+        self.emit(wasm::Instruction::LocalGet(index));
+        self.emit(wasm::Instruction::I32Const(0));
+        self.emit(wasm::Instruction::I32GeS);
+        self.emit(wasm::Instruction::If);
+        self.emit(wasm::Instruction::LocalGet(dict));
+        self.emit(wasm::Instruction::I32Load(2, DICT_ENTRIES));
+        self.emit(wasm::Instruction::LocalSet(entry));
+        self.emit_table_address(entry, index, ENTRY_SIZE);
+        self.emit(wasm::Instruction::LocalSet(entry));
+        self.emit(wasm::Instruction::LocalGet(entry));
+        self.emit(wasm::Instruction::I32Load(2, ENTRY_HASH));
+        self.emit(wasm::Instruction::LocalGet(hash));
+        self.emit(wasm::Instruction::I32Eq);
+        self.emit(wasm::Instruction::If);
+        self.emit(wasm::Instruction::LocalGet(entry));
+        self.emit(wasm::Instruction::I32Load(2, ENTRY_KEY));
+        self.emit(wasm::Instruction::LocalGet(key));
+        match kind {
+            KeyKind::Int => self.emit(wasm::Instruction::I32Eq),
+            KeyKind::Str => self.call_runtime(RuntimeFunction::StrEqual),
+        }
+        self.emit(wasm::Instruction::If);
+        self.emit_table_address(indices, slot, 4);
+        self.emit(wasm::Instruction::Return);
+        self.emit(wasm::Instruction::End);
+        self.emit(wasm::Instruction::End);
+        self.emit(wasm::Instruction::End);
+
+        self.emit_next_probe(slot, perturb, |c| {
+            c.emit(wasm::Instruction::LocalGet(dict));
+            c.emit(wasm::Instruction::I32Load(2, DICT_MASK));
+        });
+        self.emit(wasm::Instruction::Br(0));
+        self.emit(wasm::Instruction::End);
+
+        // There is always an empty slot, so the loop above never ends:
+        self.emit(wasm::Instruction::Unreachable);
+    }
+
+    /// Return the entry for a key, or 0 if the key is not present.
+    pub(super) fn compile_dict_lookup(&mut self, kind: KeyKind) {
+        // Parameters: dict, key
+        let (dict, key) = (0, 1);
+        let (index, entry) = (2, 3);
+
+        self.emit_find_slot(kind, dict, key);
+        self.emit(wasm::Instruction::I32Load(2, 0));
+        self.emit(wasm::Instruction::LocalSet(index));
+
+        self.emit(wasm::Instruction::LocalGet(index));
+        self.emit(wasm::Instruction::I32Const(0));
+        self.emit(wasm::Instruction::I32LtS);
+        self.emit(wasm::Instruction::If);
+        self.emit(wasm::Instruction::I32Const(0));
+        self.emit(wasm::Instruction::Return);
+        self.emit(wasm::Instruction::End);
+
+        self.emit(wasm::Instruction::LocalGet(dict));
+        self.emit(wasm::Instruction::I32Load(2, DICT_ENTRIES));
+        self.emit(wasm::Instruction::LocalSet(entry));
+        self.emit_table_address(entry, index, ENTRY_SIZE);
+    }
+
+    /// Return the entry for a key, which must be present.
+    pub(super) fn compile_dict_item(&mut self, kind: KeyKind) {
+        // Parameters: dict, key
+        let (dict, key) = (0, 1);
+        let entry = 2;
+
+        self.emit(wasm::Instruction::LocalGet(dict));
+        self.emit(wasm::Instruction::LocalGet(key));
+        self.call_runtime(RuntimeFunction::DictLookup(kind));
+        self.emit(wasm::Instruction::LocalSet(entry));
+
+        self.emit(wasm::Instruction::LocalGet(entry));
+        self.emit(wasm::Instruction::I32Eqz);
+        self.emit(wasm::Instruction::If);
+        self.call_runtime(RuntimeFunction::KeyError);
+        self.emit(wasm::Instruction::End);
+
+        self.emit(wasm::Instruction::LocalGet(entry));
+    }
+
+    /// Return the entry for a key, a new entry is appended if the key is not present.
+    pub(super) fn compile_dict_insert(&mut self, kind: KeyKind) {
+        // Parameters: dict, key
+        let (dict, key) = (0, 1);
+        let (hash, slot, index, entry) = (2, 3, 4, 5);
+
+        self.emit(wasm::Instruction::LocalGet(key));
+        self.call_runtime(RuntimeFunction::Hash(kind));
+        self.emit(wasm::Instruction::LocalSet(hash));
+        self.emit_find_slot_with_hash(kind, dict, key, hash);
+        self.emit(wasm::Instruction::LocalSet(slot));
+        self.emit(wasm::Instruction::LocalGet(slot));
+        self.emit(wasm::Instruction::I32Load(2, 0));
+        self.emit(wasm::Instruction::LocalSet(index));
+
+        // Existing key:
+        self.emit(wasm::Instruction::LocalGet(index));
+        self.emit(wasm::Instruction::I32Const(0));
+        self.emit(wasm::Instruction::I32GeS);
+        self.emit(wasm::Instruction::If);
+        self.emit(wasm::Instruction::LocalGet(dict));
+        self.emit(wasm::Instruction::I32Load(2, DICT_ENTRIES));
+        self.emit(wasm::Instruction::LocalSet(entry));
+        self.emit_table_address(entry, index, ENTRY_SIZE);
+        self.emit(wasm::Instruction::Return);
+        self.emit(wasm::Instruction::End);
+
+        // Make room when the entry array is full:
+        self.emit(wasm::Instruction::LocalGet(dict));
+        self.emit(wasm::Instruction::I32Load(2, DICT_USED));
+        self.emit(wasm::Instruction::LocalGet(dict));
+        self.emit(wasm::Instruction::I32Load(2, DICT_CAPACITY));
+        self.emit(wasm::Instruction::I32Eq);
+        self.emit(wasm::Instruction::If);
+        self.emit(wasm::Instruction::LocalGet(dict));
+        self.call_runtime(RuntimeFunction::DictResize);
+        self.emit_find_slot_with_hash(kind, dict, key, hash);
+        self.emit(wasm::Instruction::LocalSet(slot));
+        self.emit(wasm::Instruction::End);
+
+        // Append a new entry:
+        self.emit(wasm::Instruction::LocalGet(dict));
+        self.emit(wasm::Instruction::I32Load(2, DICT_USED));
+        self.emit(wasm::Instruction::LocalSet(index));
+        self.emit(wasm::Instruction::LocalGet(slot));
+        self.emit(wasm::Instruction::LocalGet(index));
+        self.emit(wasm::Instruction::I32Store(2, 0));
+
+        self.emit(wasm::Instruction::LocalGet(dict));
+        self.emit(wasm::Instruction::I32Load(2, DICT_ENTRIES));
+        self.emit(wasm::Instruction::LocalSet(entry));
+        self.emit_table_address(entry, index, ENTRY_SIZE);
+        self.emit(wasm::Instruction::LocalSet(entry));
+        self.emit(wasm::Instruction::LocalGet(entry));
+        self.emit(wasm::Instruction::LocalGet(hash));
+        self.emit(wasm::Instruction::I32Store(2, ENTRY_HASH));
+        self.emit(wasm::Instruction::LocalGet(entry));
+        self.emit(wasm::Instruction::LocalGet(key));
+        self.emit(wasm::Instruction::I32Store(2, ENTRY_KEY));
+
+        self.emit(wasm::Instruction::LocalGet(dict));
+        self.emit(wasm::Instruction::LocalGet(index));
+        self.emit(wasm::Instruction::I32Const(1));
+        self.emit(wasm::Instruction::I32Add);
+        self.emit(wasm::Instruction::I32Store(2, DICT_USED));
+        self.emit_add_length(dict, 1);
+
+        self.emit(wasm::Instruction::LocalGet(entry));
+    }
+
+    /// Create a new set from two sets.
+    pub(super) fn compile_set_operation(&mut self, op: SetOperator, kind: KeyKind) {
+        // Parameters: a, b
+        let (a, b) = (0, 1);
+        let (result, i, used, entry) = (2, 3, 4, 5);
+
+        self.call_runtime(RuntimeFunction::DictNew);
+        self.emit(wasm::Instruction::LocalSet(result));
+
+        let add_key = |c: &mut Self| {
+            c.emit(wasm::Instruction::LocalGet(result));
+            c.emit(wasm::Instruction::LocalGet(entry));
+            c.emit(wasm::Instruction::I32Load(2, ENTRY_KEY));
+            c.call_runtime(RuntimeFunction::DictInsert(kind));
+            c.emit(wasm::Instruction::Drp);
+        };
+
+        match op {
+            SetOperator::Union => {
+                self.emit_for_each_entry(a, i, used, entry, add_key);
+                self.emit_for_each_entry(b, i, used, entry, add_key);
+            }
+            SetOperator::Intersection | SetOperator::Difference => {
+                self.emit_for_each_entry(a, i, used, entry, |c| {
+                    c.emit(wasm::Instruction::LocalGet(b));
+                    c.emit(wasm::Instruction::LocalGet(entry));
+                    c.emit(wasm::Instruction::I32Load(2, ENTRY_KEY));
+                    c.call_runtime(RuntimeFunction::DictLookup(kind));
+                    if op == SetOperator::Difference {
+                        c.emit(wasm::Instruction::I32Eqz);
+                    }
+                    c.emit(wasm::Instruction::If);
+                    add_key(c);
+                    c.emit(wasm::Instruction::End);
+                });
+            }
+        }
+
+        self.emit(wasm::Instruction::LocalGet(result));
+    }
+
+    /// Remove a key, which must be present unless missing keys are allowed.
+    pub(super) fn compile_dict_delete(&mut self, kind: KeyKind, missing_ok: bool) {
+        // Parameters: dict, key
+        let (dict, key) = (0, 1);
+        let (slot, index, entry) = (2, 3, 4);
+
+        self.emit_find_slot(kind, dict, key);
+        self.emit(wasm::Instruction::LocalSet(slot));
+        self.emit(wasm::Instruction::LocalGet(slot));
+        self.emit(wasm::Instruction::I32Load(2, 0));
+        self.emit(wasm::Instruction::LocalSet(index));
+
+        self.emit(wasm::Instruction::LocalGet(index));
+        self.emit(wasm::Instruction::I32Const(0));
+        self.emit(wasm::Instruction::I32LtS);
+        self.emit(wasm::Instruction::If);
+        if missing_ok {
+            self.emit(wasm::Instruction::Return);
+        } else {
+            self.call_runtime(RuntimeFunction::KeyError);
+        }
+        self.emit(wasm::Instruction::End);
+
+        // The entry stays in place, to keep the order of the other entries:
+        self.emit(wasm::Instruction::LocalGet(slot));
+        self.emit(wasm::Instruction::I32Const(DELETED));
+        self.emit(wasm::Instruction::I32Store(2, 0));
+        self.emit(wasm::Instruction::LocalGet(dict));
+        self.emit(wasm::Instruction::I32Load(2, DICT_ENTRIES));
+        self.emit(wasm::Instruction::LocalSet(entry));
+        self.emit_table_address(entry, index, ENTRY_SIZE);
+        self.emit(wasm::Instruction::I32Const(-1));
+        self.emit(wasm::Instruction::I32Store(2, ENTRY_HASH));
+        self.emit_add_length(dict, -1);
+    }
+
+    /// Emit a loop over the entries which are not deleted.
+    pub(super) fn emit_for_each_entry<F>(
+        &mut self,
+        table: usize,
+        i: usize,
+        used: usize,
+        entry: usize,
+        body: F,
+    ) where
+        F: Fn(&mut Self),
+    {
+        self.emit(wasm::Instruction::LocalGet(table));
+        self.emit(wasm::Instruction::I32Load(2, DICT_USED));
+        self.emit(wasm::Instruction::LocalSet(used));
+        self.emit_counted_loop(i, used, |c| {
+            c.emit(wasm::Instruction::LocalGet(table));
+            c.emit(wasm::Instruction::I32Load(2, DICT_ENTRIES));
+            c.emit(wasm::Instruction::LocalSet(entry));
+            c.emit_table_address(entry, i, ENTRY_SIZE);
+            c.emit(wasm::Instruction::LocalSet(entry));
+
+            c.emit(wasm::Instruction::LocalGet(entry));
+            c.emit(wasm::Instruction::I32Load(2, ENTRY_HASH));
+            c.emit(wasm::Instruction::I32Const(0));
+            c.emit(wasm::Instruction::I32GeS);
+            c.emit(wasm::Instruction::If);
+            body(c);
+            c.emit(wasm::Instruction::End);
+        });
+    }
+
+    /// Emit the address of the slot for a key.
+    fn emit_find_slot(&mut self, kind: KeyKind, dict: usize, key: usize) {
+        self.emit(wasm::Instruction::LocalGet(dict));
+        self.emit(wasm::Instruction::LocalGet(key));
+        self.emit(wasm::Instruction::LocalGet(key));
+        self.call_runtime(RuntimeFunction::Hash(kind));
+        self.call_runtime(RuntimeFunction::DictFind(kind));
+    }
+
+    fn emit_find_slot_with_hash(&mut self, kind: KeyKind, dict: usize, key: usize, hash: usize) {
+        self.emit(wasm::Instruction::LocalGet(dict));
+        self.emit(wasm::Instruction::LocalGet(key));
+        self.emit(wasm::Instruction::LocalGet(hash));
+        self.call_runtime(RuntimeFunction::DictFind(kind));
+    }
+
+    /// Emit the address of element index of a table.
+    fn emit_table_address(&mut self, table: usize, index: usize, element_size: usize) {
+        self.emit(wasm::Instruction::LocalGet(table));
+        self.emit(wasm::Instruction::LocalGet(index));
+        self.emit(wasm::Instruction::I32Const(element_size as i32));
+        self.emit(wasm::Instruction::I32Mul);
+        self.emit(wasm::Instruction::I32Add);
+    }
+
+    /// Move to the next slot, with the same probing as CPython.
+    fn emit_next_probe<F>(&mut self, slot: usize, perturb: usize, mask: F)
+    where
+        F: Fn(&mut Self),
+    {
+        self.emit(wasm::Instruction::LocalGet(perturb));
+        self.emit(wasm::Instruction::I32Const(PERTURB_SHIFT));
+        self.emit(wasm::Instruction::I32ShrU);
+        self.emit(wasm::Instruction::LocalSet(perturb));
+
+        self.emit(wasm::Instruction::LocalGet(slot));
+        self.emit(wasm::Instruction::I32Const(5));
+        self.emit(wasm::Instruction::I32Mul);
+        self.emit(wasm::Instruction::LocalGet(perturb));
+        self.emit(wasm::Instruction::I32Add);
+        self.emit(wasm::Instruction::I32Const(1));
+        self.emit(wasm::Instruction::I32Add);
+        mask(self);
+        self.emit(wasm::Instruction::I32And);
+        self.emit(wasm::Instruction::LocalSet(slot));
+    }
+
+    fn emit_add_length(&mut self, dict: usize, amount: i32) {
+        self.emit(wasm::Instruction::LocalGet(dict));
+        self.emit(wasm::Instruction::LocalGet(dict));
+        self.builtin_list_len();
+        self.emit(wasm::Instruction::I32Const(amount));
+        self.emit(wasm::Instruction::I32Add);
+        self.emit(wasm::Instruction::I32Store(2, 0));
+    }
+
+    /// Emit byte i of a string.
+    pub(super) fn emit_string_byte(&mut self, string: usize, i: usize) {
+        self.emit(wasm::Instruction::LocalGet(string));
+        self.emit(wasm::Instruction::LocalGet(i));
+        self.emit(wasm::Instruction::I32Add);
+        self.emit(wasm::Instruction::I32Load8U(0, 4));
+    }
+}
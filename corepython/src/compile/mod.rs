@@ -0,0 +1,2619 @@
+mod format;
+mod hashtable;
+mod runtime;
+
+use std::collections::HashMap;
+use std::rc::Rc;
+
+use super::analyze;
+use super::{parser::ast, wasm, CompilationError, CompileOptions};
+use hashtable::{
+    KeyKind, SetOperator, DICT_ENTRIES, DICT_USED, ENTRY_HASH, ENTRY_KEY, ENTRY_SIZE, ENTRY_VALUE,
+};
+use runtime::{RuntimeFunction, SLICE_NONE};
+
+/// Constant data, such as string literals, is placed in memory from here on.
+///
+/// The first bytes of memory contain the pointer to free memory.
+const STATIC_DATA_START: usize = 8;
+
+/// Objects start with the id of their class, which is used for
+/// isinstance and to find methods in the vtable.
+const OBJECT_CLASS_ID: usize = 0;
+
+/// A union is a pointer to the tag of the type of its value, followed by the value.
+const UNION_TAG: usize = 0;
+const UNION_VALUE: usize = 8;
+const UNION_SIZE: usize = 16;
+
+/// A closure is a pointer to the table slot of its function, followed by its environment.
+const CLOSURE_FUNCTION: usize = 0;
+const CLOSURE_ENVIRONMENT: usize = 4;
+const CLOSURE_SIZE: usize = 8;
+
+/// An environment starts with a pointer to the environment of the enclosing
+/// function, the cells follow with room for a float each.
+const ENVIRONMENT_PARENT: usize = 0;
+const ENVIRONMENT_CELLS: usize = 8;
+const CELL_SIZE: usize = 8;
+
+/// A match statement uses a jump table when it compares with at least this
+/// many numbers, and the table has no more than two entries per number.
+const MIN_JUMP_TABLE_VALUES: usize = 3;
+
+pub fn compile_ast(
+    prog: ast::Program,
+    options: &CompileOptions,
+) -> Result<wasm::WasmModule, CompilationError> {
+    info!("Compiling AST");
+
+    let prog = analyze::analyze(prog, options)?;
+
+    let c = Compiler::new(options.clone());
+    Ok(c.compile_prog(&prog))
+}
+
+/// Helper struct to compile a typed and resolved program to WebAssembly.
+struct Compiler {
+    options: CompileOptions,
+    code: Vec<wasm::Instruction>,
+    /// Locals of the function being compiled, including temporaries.
+    locals: Vec<wasm::Type>,
+    num_params: usize,
+    func_offset: usize,
+    /// The methods of the prelude classes, which are only added to the module when they are used.
+    prelude: Vec<Rc<analyze::Function>>,
+    runtime_offset: usize,
+    runtime_functions: Vec<RuntimeFunction>,
+    static_data: Vec<u8>,
+    strings: HashMap<String, usize>,
+    classes: HashMap<String, Rc<analyze::Class>>,
+    /// Id of each class, and the number of classes in its subtree.
+    class_ids: HashMap<String, (usize, usize)>,
+    /// Number of table entries per class, its vtable is at id * stride.
+    vtable_stride: usize,
+    uses_vtables: bool,
+    /// The tag of exceptions, once an exception can be raised.
+    exception_tag: Option<usize>,
+    /// The global which holds the raised exception, when exceptions
+    /// are lowered without the exception handling proposal.
+    exception_global: Option<usize>,
+    /// Number of blocks around the code being emitted.
+    depth: usize,
+    /// Depths of the blocks which are left when an exception is raised in them,
+    /// when exceptions are lowered without the exception handling proposal.
+    handler_depths: Vec<usize>,
+    /// Positions of the returns which pass an exception to the caller,
+    /// they get default results once the results of the function are known.
+    exception_returns: Vec<usize>,
+    /// Functions of closures, which are placed in the table after the vtables.
+    closure_functions: Vec<usize>,
+    module: wasm::WasmModule,
+}
+
+impl Compiler {
+    fn new(options: CompileOptions) -> Self {
+        Compiler {
+            options,
+            code: vec![],
+            locals: vec![],
+            num_params: 0,
+            func_offset: 0,
+            prelude: vec![],
+            runtime_offset: 0,
+            runtime_functions: vec![],
+            static_data: vec![],
+            strings: HashMap::new(),
+            classes: HashMap::new(),
+            class_ids: HashMap::new(),
+            vtable_stride: 0,
+            uses_vtables: false,
+            exception_tag: None,
+            exception_global: None,
+            depth: 0,
+            handler_depths: vec![],
+            exception_returns: vec![],
+            closure_functions: vec![],
+            module: wasm::WasmModule::new(),
+        }
+    }
+
+    fn compile_prog(mut self, prog: &analyze::Program) -> wasm::WasmModule {
+        for class in &prog.classes {
+            self.classes.insert(class.name.clone(), class.clone());
+        }
+        self.class_ids = number_classes(&prog.classes);
+        self.vtable_stride = prog
+            .classes
+            .iter()
+            .map(|c| c.methods.len())
+            .max()
+            .unwrap_or(0);
+
+        for import in &prog.imports {
+            let params: Vec<wasm::Type> = import
+                .parameter_types
+                .iter()
+                .map(|t| self.get_type(t))
+                .collect();
+            let results: Vec<wasm::Type> = if let Some(t) = &import.return_type {
+                vec![self.get_type(t)]
+            } else {
+                vec![]
+            };
+            self.module
+                .add_import(&import.modname, &import.name, params, results);
+        }
+        self.func_offset += prog.imports.len();
+
+        // Prelude methods are placed with the runtime functions, after the user functions:
+        self.prelude = prog.functions[..prog.prelude_functions].to_vec();
+        self.runtime_offset = self.func_offset + prog.functions.len() - prog.prelude_functions;
+
+        for global in &prog.globals {
+            self.compile_global(global);
+        }
+        if !self.options.wasm_exceptions {
+            let global =
+                self.module
+                    .add_global(wasm::Type::I32, true, wasm::Instruction::I32Const(0));
+            self.module.export_global("exception".to_owned(), global);
+            self.exception_global = Some(global);
+        }
+
+        let mut entry_points = vec![];
+        for (index, function) in prog.functions.iter().enumerate() {
+            if index < prog.prelude_functions {
+                continue;
+            }
+            let func = self.compile_function(function);
+            // The start function runs once, so it is not exported:
+            if prog.start == Some(index) {
+                entry_points.push((function, func, true));
+            } else if !function.closure {
+                entry_points.push((function, func, false));
+            }
+        }
+
+        if self.uses_vtables {
+            self.compile_vtables();
+        }
+        if !self.closure_functions.is_empty() {
+            let functions = std::mem::take(&mut self.closure_functions);
+            self.module
+                .add_elements(self.get_closure_table_offset(), functions);
+        }
+        self.compile_runtime_functions();
+
+        for (function, func, start) in entry_points {
+            let func = if self.options.wasm_exceptions {
+                func
+            } else {
+                self.compile_entry_point(function, func)
+            };
+            if start {
+                self.module.set_start(func);
+            } else {
+                self.module.add_export(function.name.clone(), func);
+            }
+        }
+        self.compile_static_data();
+        self.module.export_memory("memory".to_owned());
+        self.module
+    }
+
+    /// Place the methods of each class in the table, to be called with call_indirect.
+    fn compile_vtables(&mut self) {
+        // Exceptions only exist when something can raise, otherwise their methods are left out:
+        let (exception_id, count) = self.class_ids[analyze::EXCEPTION];
+        let raises = self.exception_tag.is_some()
+            || self
+                .runtime_functions
+                .iter()
+                .any(|f| f.can_raise() || matches!(f, RuntimeFunction::Method(_)));
+
+        let mut vtables = vec![];
+        for (name, (id, _)) in self.class_ids.clone() {
+            if !raises && id >= exception_id && id < exception_id + count {
+                continue;
+            }
+            let class = self.classes[&name].clone();
+            let functions = class
+                .methods
+                .iter()
+                .map(|m| self.get_function_index(&m.function))
+                .collect();
+            vtables.push((id * self.vtable_stride, functions));
+        }
+        vtables.sort();
+        for (offset, functions) in vtables {
+            self.module.add_elements(offset, functions);
+        }
+    }
+
+    /// The closure functions are in the table after the vtables of all classes.
+    fn get_closure_table_offset(&self) -> usize {
+        self.class_ids.len() * self.vtable_stride
+    }
+
+    /// Find the table slot of the function of a closure.
+    fn get_closure_slot(&mut self, function: &analyze::Symbol) -> usize {
+        let func = self.get_function_index(function);
+        let position = match self.closure_functions.iter().position(|f| *f == func) {
+            Some(position) => position,
+            None => {
+                self.closure_functions.push(func);
+                self.closure_functions.len() - 1
+            }
+        };
+        self.get_closure_table_offset() + position
+    }
+
+    fn get_function_index(&mut self, symbol: &analyze::Symbol) -> usize {
+        match symbol {
+            analyze::Symbol::Function { index, .. } if *index < self.prelude.len() => {
+                self.get_runtime_function_index(RuntimeFunction::Method(*index))
+            }
+            analyze::Symbol::Function { index, .. } => {
+                *index - self.prelude.len() + self.func_offset
+            }
+            _ => panic!("Not a function"),
+        }
+    }
+
+    /// Place string literal in static data, and return its address.
+    ///
+    /// A string has the same layout as a list: a length followed by the bytes.
+    fn add_string(&mut self, value: &str) -> usize {
+        if let Some(address) = self.strings.get(value) {
+            return *address;
+        }
+
+        let aligned = round_to_multiple_of(self.static_data.len(), 4);
+        self.static_data.resize(aligned, 0);
+        let address = STATIC_DATA_START + self.static_data.len();
+        self.static_data.extend(&(value.len() as u32).to_le_bytes());
+        self.static_data.extend(value.as_bytes());
+        self.strings.insert(value.to_owned(), address);
+        address
+    }
+
+    /// Place bytes in static data, and return their address.
+    fn add_static_data(&mut self, data: &[u8], alignment: usize) -> usize {
+        let aligned = round_to_multiple_of(STATIC_DATA_START + self.static_data.len(), alignment);
+        self.static_data.resize(aligned - STATIC_DATA_START, 0);
+        self.static_data.extend(data);
+        aligned
+    }
+
+    fn compile_static_data(&mut self) {
+        if self.static_data.is_empty() {
+            return;
+        }
+
+        // Let memory allocation start after the static data:
+        let data_end = round_to_multiple_of(STATIC_DATA_START + self.static_data.len(), 8);
+        let free_memory = (data_end - 8) as u32;
+        self.module.add_data(0, free_memory.to_le_bytes().to_vec());
+
+        let data = std::mem::take(&mut self.static_data);
+        self.module.add_data(STATIC_DATA_START, data);
+    }
+
+    fn get_type(&self, typ: &analyze::Type) -> wasm::Type {
+        match typ {
+            analyze::Type::Float => wasm::Type::F64,
+            analyze::Type::Integer => wasm::Type::I32,
+            analyze::Type::Str | analyze::Type::Bytes => {
+                // unimplemented!();
+                // Assume pointer to bytes or string object in memory
+                wasm::Type::I32
+            }
+            analyze::Type::Bool | analyze::Type::Enum(_) => {
+                wasm::Type::I32
+                // unimplemented!("Ugh, what now?")
+            }
+            analyze::Type::List(_)
+            | analyze::Type::Tuple(_)
+            | analyze::Type::Dict(..)
+            | analyze::Type::Set(_)
+            | analyze::Type::Class(_)
+            | analyze::Type::Optional(_)
+            | analyze::Type::NoneValue
+            | analyze::Type::Union(_)
+            | analyze::Type::Callable(..) => {
+                // Assume pointer to some data structure in wasm memory.
+                wasm::Type::I32
+                // unimplemented!("TODO: lists");
+            } // analyze::Type::Unknown => {
+            //     panic!("Cannot compile partially typed program");
+            //     // wasm::Type::I32
+            // }
+            analyze::Type::None => {
+                panic!("None is not a value");
+            }
+        }
+    }
+
+    /// Determine the WebAssembly values returned by a function.
+    ///
+    /// Tuples are returned as multiple values, which avoids
+    /// allocating memory for them.
+    fn get_result_types(&self, typ: &analyze::Type) -> Vec<wasm::Type> {
+        match typ {
+            analyze::Type::None => vec![],
+            analyze::Type::Tuple(element_types) => {
+                element_types.iter().map(|t| self.get_type(t)).collect()
+            }
+            typ => vec![self.get_type(typ)],
+        }
+    }
+
+    /// Determine the WebAssembly parameters and results of a function.
+    fn get_signature(&self, function: &analyze::Function) -> (Vec<wasm::Type>, Vec<wasm::Type>) {
+        let params = function
+            .parameters
+            .iter()
+            .map(|p| self.get_type(&p.typ))
+            .collect();
+        let results = match &function.return_type {
+            Some(t) => self.get_result_types(t),
+            None => vec![],
+        };
+        (params, results)
+    }
+
+    /// Compile a function, and return its function index.
+    fn compile_function(&mut self, function: &analyze::Function) -> usize {
+        debug!("Compiling function {}", function.name);
+
+        let (params, results) = self.get_signature(function);
+        self.num_params = params.len();
+
+        for local in &function.locals {
+            let local_type = self.get_type(&local.typ);
+            self.locals.push(local_type);
+        }
+
+        if let Some(environment) = &function.environment {
+            // A nested function links its environment to the one it received:
+            self.allocate(ENVIRONMENT_CELLS + environment.size * CELL_SIZE);
+            self.store_value(&environment.local, &analyze::Type::Integer);
+            self.get_local(&environment.local);
+            if function.closure {
+                self.emit(wasm::Instruction::LocalGet(0));
+            } else {
+                self.emit(wasm::Instruction::I32Const(0));
+            }
+            self.write_mem(ENVIRONMENT_PARENT, &wasm::Type::I32);
+        }
+        self.compile_suite(&function.body);
+
+        if let Some(t) = &function.return_type {
+            match t {
+                analyze::Type::Integer
+                | analyze::Type::Bool
+                | analyze::Type::Enum(_)
+                | analyze::Type::Optional(_)
+                | analyze::Type::NoneValue => {
+                    // Implicit return 0, which is None for optional values:
+                    self.emit(wasm::Instruction::I32Const(0));
+                }
+                analyze::Type::Float => {
+                    // Implicit return 0:
+                    self.emit(wasm::Instruction::F64Const(0.0));
+                }
+                analyze::Type::Str
+                | analyze::Type::Bytes
+                | analyze::Type::List(_)
+                | analyze::Type::Tuple(_)
+                | analyze::Type::Dict(..)
+                | analyze::Type::Set(_)
+                | analyze::Type::Class(_)
+                | analyze::Type::Union(_)
+                | analyze::Type::Callable(..) => {
+                    // There is no sensible default value:
+                    self.emit(wasm::Instruction::Unreachable);
+                }
+                analyze::Type::None => {}
+            }
+        }
+
+        let code = self.take_code(&results);
+        let locals = std::mem::take(&mut self.locals);
+
+        self.module
+            .add_function(function.name.clone(), params, results, locals, code)
+    }
+
+    /// Wrap an exported or start function, which traps when it raises an
+    /// exception, as a thrown exception would. The host finds the exception
+    /// in the exported global, until the next call clears it.
+    fn compile_entry_point(&mut self, function: &analyze::Function, func: usize) -> usize {
+        let (params, results) = self.get_signature(function);
+        let global = self.exception_global.unwrap();
+        self.emit(wasm::Instruction::I32Const(0));
+        self.emit(wasm::Instruction::GlobalSet(global));
+        for index in 0..params.len() {
+            self.emit(wasm::Instruction::LocalGet(index));
+        }
+        self.emit(wasm::Instruction::Call(func));
+        self.emit(wasm::Instruction::GlobalGet(global));
+        self.emit(wasm::Instruction::If);
+        self.emit(wasm::Instruction::Unreachable);
+        self.emit(wasm::Instruction::End);
+
+        let code = std::mem::take(&mut self.code);
+        self.module
+            .add_function(function.name.clone(), params, results, vec![], code)
+    }
+
+    /// Take the code of the function being compiled.
+    ///
+    /// The returns which pass an exception to the caller get the default
+    /// values of the results, which are not used by the caller.
+    fn take_code(&mut self, results: &[wasm::Type]) -> Vec<wasm::Instruction> {
+        let mut code = std::mem::take(&mut self.code);
+        for position in std::mem::take(&mut self.exception_returns)
+            .into_iter()
+            .rev()
+        {
+            let defaults = results.iter().map(|typ| match typ {
+                wasm::Type::I32 => wasm::Instruction::I32Const(0),
+                wasm::Type::F64 => wasm::Instruction::F64Const(0.0),
+            });
+            code.splice(position..position, defaults);
+        }
+        code
+    }
+
+    /// Add a global, a constant global gets its value right away.
+    fn compile_global(&mut self, global: &analyze::Global) {
+        let typ = self.get_type(&global.typ);
+        let init = match &global.value {
+            Some(analyze::Expression::Number(value))
+            | Some(analyze::Expression::EnumMember { value, .. }) => {
+                wasm::Instruction::I32Const(*value)
+            }
+            Some(analyze::Expression::Float(value)) => wasm::Instruction::F64Const(*value),
+            Some(analyze::Expression::String(value)) => {
+                let address = self.add_string(value);
+                wasm::Instruction::I32Const(address as i32)
+            }
+            Some(other) => panic!("Not a constant: {:?}", other),
+            None => match typ {
+                wasm::Type::I32 => wasm::Instruction::I32Const(0),
+                wasm::Type::F64 => wasm::Instruction::F64Const(0.0),
+            },
+        };
+        self.module.add_global(typ, global.value.is_none(), init);
+    }
+
+    fn compile_suite(&mut self, suite: &[analyze::Statement]) {
+        for statement in suite {
+            self.compile_statement(statement);
+        }
+    }
+
+    fn compile_statement(&mut self, statement: &analyze::Statement) {
+        match statement {
+            analyze::Statement::Return {
+                value,
+                finally_suite,
+            } => {
+                if let analyze::Type::Tuple(_) = value.get_type() {
+                    self.compile_unpacked(value);
+                } else {
+                    self.compile_expression(value);
+                }
+                if !finally_suite.is_empty() {
+                    // The value is determined before the finally suites run:
+                    let temporaries: Vec<usize> = self
+                        .get_result_types(value.get_type())
+                        .into_iter()
+                        .map(|typ| self.new_temporary(typ))
+                        .collect();
+                    for temporary in temporaries.iter().rev() {
+                        self.emit(wasm::Instruction::LocalSet(*temporary));
+                    }
+                    self.compile_suite(finally_suite);
+                    for temporary in temporaries {
+                        self.emit(wasm::Instruction::LocalGet(temporary));
+                    }
+                }
+                self.emit(wasm::Instruction::Return);
+            }
+            analyze::Statement::Raise { exception, line } => {
+                self.compile_expression(exception);
+                if let Some(line) = line {
+                    let object = self.new_temporary(wasm::Type::I32);
+                    self.emit(wasm::Instruction::LocalTee(object));
+                    self.emit(wasm::Instruction::I32Const(*line as i32));
+                    self.emit(wasm::Instruction::I32Store(2, self.get_lineno_offset()));
+                    self.emit(wasm::Instruction::LocalGet(object));
+                }
+                self.emit_raise();
+            }
+            analyze::Statement::Try {
+                exception_var,
+                suite,
+                handlers,
+                else_suite,
+                finally_suite,
+            } => {
+                if finally_suite.is_empty() {
+                    self.compile_try_except(exception_var, suite, handlers, else_suite);
+                } else if self.exception_global.is_some() {
+                    self.compile_try_finally(
+                        exception_var,
+                        suite,
+                        handlers,
+                        else_suite,
+                        finally_suite,
+                    );
+                } else {
+                    // The finally suite runs when an exception is raised, and after the other suites:
+                    self.emit(wasm::Instruction::Try);
+                    self.compile_try_except(exception_var, suite, handlers, else_suite);
+                    self.emit(wasm::Instruction::CatchAll);
+                    self.compile_suite(finally_suite);
+                    self.emit(wasm::Instruction::Rethrow(0));
+                    self.emit(wasm::Instruction::End);
+                    self.compile_suite(finally_suite);
+                }
+            }
+            analyze::Statement::Assert {
+                condition,
+                message,
+                line,
+                handler,
+            } => {
+                self.compile_expression(condition);
+                self.emit(wasm::Instruction::I32Eqz);
+                self.emit(wasm::Instruction::If);
+                self.compile_expression(message);
+                self.emit_text_for_host();
+                self.emit(wasm::Instruction::I32Const(*line as i32));
+                self.compile_call(handler, &[]);
+                self.emit(wasm::Instruction::Unreachable);
+                self.emit(wasm::Instruction::End);
+            }
+            analyze::Statement::Expression(expr) => {
+                self.compile_discarded(expr);
+            }
+            analyze::Statement::Match {
+                subject_var,
+                subject,
+                cases,
+            } => {
+                self.compile_expression(subject);
+                self.store_value(subject_var, subject.get_type());
+                match get_jump_table(cases) {
+                    Some((lowest, size)) => {
+                        self.compile_match_table(subject_var, cases, lowest, size)
+                    }
+                    None => self.compile_match_chain(cases),
+                }
+            }
+            analyze::Statement::If {
+                condition,
+                suite,
+                else_suite,
+            } => {
+                self.compile_expression(condition);
+                self.emit(wasm::Instruction::If);
+                self.compile_suite(suite);
+                self.emit(wasm::Instruction::Else);
+                self.compile_suite(else_suite);
+                self.emit(wasm::Instruction::End);
+            }
+            analyze::Statement::ForDict {
+                index_var,
+                dict_var,
+                view,
+                target,
+                dict,
+                suite,
+            } => {
+                let int_type = analyze::Type::Integer;
+                // Sets have keys only:
+                let item_type = analyze::Type::Tuple(match dict.get_type() {
+                    analyze::Type::Dict(key_type, value_type) => {
+                        vec![*key_type.clone(), *value_type.clone()]
+                    }
+                    analyze::Type::Set(element_type) => vec![*element_type.clone()],
+                    other => panic!("Not a dict or set: {}", other),
+                });
+                let item_types: Vec<wasm::Type> = self
+                    .get_tuple_types(&item_type)
+                    .iter()
+                    .map(|t| self.get_type(t))
+                    .collect();
+                let entry = self.new_temporary(wasm::Type::I32);
+
+                self.compile_expression(dict);
+                self.store_value(dict_var, dict.get_type());
+                self.emit(wasm::Instruction::I32Const(0));
+                self.store_value(index_var, &int_type);
+
+                self.emit(wasm::Instruction::Block);
+                self.emit(wasm::Instruction::Loop);
+
+                // The dict can change during the loop, so check the number of entries each time:
+                self.get_local(index_var);
+                self.get_local(dict_var);
+                self.emit(wasm::Instruction::I32Load(2, DICT_USED));
+                self.emit(wasm::Instruction::I32GeS);
+                self.emit(wasm::Instruction::BrIf(1));
+
+                self.get_local(dict_var);
+                self.emit(wasm::Instruction::I32Load(2, DICT_ENTRIES));
+                self.get_local(index_var);
+                self.emit(wasm::Instruction::I32Const(ENTRY_SIZE as i32));
+                self.emit(wasm::Instruction::I32Mul);
+                self.emit(wasm::Instruction::I32Add);
+                self.emit(wasm::Instruction::LocalSet(entry));
+
+                self.get_local(index_var);
+                self.emit(wasm::Instruction::I32Const(1));
+                self.emit(wasm::Instruction::I32Add);
+                self.store_value(index_var, &int_type);
+
+                // Skip deleted entries:
+                self.emit(wasm::Instruction::LocalGet(entry));
+                self.emit(wasm::Instruction::I32Load(2, ENTRY_HASH));
+                self.emit(wasm::Instruction::I32Const(0));
+                self.emit(wasm::Instruction::I32LtS);
+                self.emit(wasm::Instruction::BrIf(0));
+
+                let load_key = |c: &mut Self| {
+                    c.emit(wasm::Instruction::LocalGet(entry));
+                    c.read_mem(ENTRY_KEY, &item_types[0]);
+                };
+                let load_value = |c: &mut Self| {
+                    c.emit(wasm::Instruction::LocalGet(entry));
+                    c.read_mem(ENTRY_VALUE, &item_types[1]);
+                };
+                match (view, target) {
+                    (analyze::DictView::Keys, target) => self.store_target(target, load_key),
+                    (analyze::DictView::Values, target) => self.store_target(target, load_value),
+                    (analyze::DictView::Items, analyze::Target::Tuple { targets, typ }) => {
+                        load_key(self);
+                        load_value(self);
+                        self.store_unpacked(targets, typ);
+                    }
+                    (analyze::DictView::Items, target) => self.store_target(target, |c| {
+                        load_key(c);
+                        load_value(c);
+                        c.box_tuple(&item_type);
+                    }),
+                }
+
+                self.compile_suite(suite);
+                self.emit(wasm::Instruction::Br(0));
+
+                self.emit(wasm::Instruction::End);
+                self.emit(wasm::Instruction::End);
+            }
+            analyze::Statement::DeleteItem { base, key } => {
+                self.compile_expression(base);
+                self.compile_expression(key);
+                let kind = self.get_key_kind(base.get_type());
+                self.call_runtime(RuntimeFunction::DictDelete(kind));
+            }
+            analyze::Statement::While { condition, suite } => {
+                self.emit(wasm::Instruction::Block);
+                self.emit(wasm::Instruction::Loop);
+                self.compile_expression(condition);
+                self.emit(wasm::Instruction::I32Eqz); // Invert condition, and branch if not good.
+                self.emit(wasm::Instruction::BrIf(1));
+                self.compile_suite(suite);
+                self.emit(wasm::Instruction::Br(0));
+                self.emit(wasm::Instruction::End);
+                self.emit(wasm::Instruction::End);
+            }
+            analyze::Statement::For {
+                loop_var, // counts from 0 to len
+                iter_var, // points to iterable (list)
+                index_target,
+                target,
+                iter,
+                suite,
+            } => {
+                let int_type = analyze::Type::Integer;
+
+                info!("Loop var {:?}", loop_var);
+                info!("Iter var {:?}", iter_var);
+
+                // Evaluate iterator:
+                self.compile_expression(iter);
+
+                // Store iterator var:
+                self.store_value(iter_var, &int_type);
+
+                // Init loop var:
+                self.emit(wasm::Instruction::I32Const(0));
+                self.store_value(loop_var, &int_type);
+
+                self.emit(wasm::Instruction::Block);
+                self.emit(wasm::Instruction::Loop);
+
+                // Are we done?
+                self.get_local(loop_var);
+                self.get_local(iter_var);
+                self.builtin_list_len();
+                self.emit(wasm::Instruction::I32GeS);
+                self.emit(wasm::Instruction::BrIf(1));
+
+                if let Some(index_target) = index_target {
+                    self.store_target(index_target, |c| c.get_local(loop_var));
+                }
+
+                // Load current element from iter var:
+                match iter.get_type() {
+                    analyze::Type::List(element_type) => {
+                        // The loop variable is always in range:
+                        self.store_target(target, |c| {
+                            c.get_local(iter_var);
+                            c.get_local(loop_var);
+                            c.builtin_list_index(element_type, false);
+                        });
+                    }
+                    _ => {
+                        unimplemented!();
+                    }
+                }
+
+                // Execute body:
+                self.compile_suite(suite);
+
+                // i++ (update loop variable)
+                self.get_local(loop_var);
+                self.emit(wasm::Instruction::I32Const(1));
+                self.emit(wasm::Instruction::I32Add);
+                self.store_value(loop_var, &int_type);
+                self.emit(wasm::Instruction::Br(0));
+
+                self.emit(wasm::Instruction::End);
+                self.emit(wasm::Instruction::End);
+            }
+            analyze::Statement::ForRange {
+                counter,
+                target,
+                range,
+                suite,
+            } => {
+                let int_type = analyze::Type::Integer;
+
+                // Evaluate range arguments once:
+                self.compile_expression(&range.start);
+                self.store_value(counter, &int_type);
+                self.compile_expression(&range.stop);
+                self.store_value(&range.stop_var, &int_type);
+                self.compile_expression(&range.step);
+                self.store_value(&range.step_var, &int_type);
+
+                let constant_step = match range.step {
+                    analyze::Expression::Number(step) => Some(step),
+                    _ => None,
+                };
+                if constant_step.is_none() {
+                    self.get_local(&range.step_var);
+                    self.emit(wasm::Instruction::I32Eqz);
+                    self.emit(wasm::Instruction::If);
+                    self.call_runtime(RuntimeFunction::ValueError);
+                    self.emit(wasm::Instruction::End);
+                }
+
+                self.emit(wasm::Instruction::Block);
+                self.emit(wasm::Instruction::Loop);
+
+                // Are we done? This depends on the direction of the step.
+                match constant_step {
+                    Some(step) => {
+                        self.get_local(counter);
+                        self.get_local(&range.stop_var);
+                        if step > 0 {
+                            self.emit(wasm::Instruction::I32GeS);
+                        } else {
+                            self.emit(wasm::Instruction::I32LeS);
+                        }
+                    }
+                    None => {
+                        self.get_local(counter);
+                        self.get_local(&range.stop_var);
+                        self.emit(wasm::Instruction::I32GeS);
+                        self.get_local(counter);
+                        self.get_local(&range.stop_var);
+                        self.emit(wasm::Instruction::I32LeS);
+                        self.get_local(&range.step_var);
+                        self.emit(wasm::Instruction::I32Const(0));
+                        self.emit(wasm::Instruction::I32GtS);
+                        self.emit(wasm::Instruction::Select);
+                    }
+                }
+                self.emit(wasm::Instruction::BrIf(1));
+
+                // Assigning to the target in the body does not influence the loop:
+                self.get_local(counter);
+                self.store_value(target, &int_type);
+
+                self.compile_suite(suite);
+
+                // Stop before the counter passes the stop value, so that it does not wrap around.
+                // The distance to the stop value and the step size are compared unsigned:
+                match constant_step {
+                    Some(step) => {
+                        if step > 0 {
+                            self.get_local(&range.stop_var);
+                            self.get_local(counter);
+                            self.emit(wasm::Instruction::I32Sub);
+                            self.get_local(&range.step_var);
+                        } else {
+                            self.get_local(counter);
+                            self.get_local(&range.stop_var);
+                            self.emit(wasm::Instruction::I32Sub);
+                            self.emit(wasm::Instruction::I32Const(0));
+                            self.get_local(&range.step_var);
+                            self.emit(wasm::Instruction::I32Sub);
+                        }
+                    }
+                    None => {
+                        self.get_local(&range.stop_var);
+                        self.get_local(counter);
+                        self.emit(wasm::Instruction::I32Sub);
+                        self.get_local(counter);
+                        self.get_local(&range.stop_var);
+                        self.emit(wasm::Instruction::I32Sub);
+                        self.get_local(&range.step_var);
+                        self.emit(wasm::Instruction::I32Const(0));
+                        self.emit(wasm::Instruction::I32GtS);
+                        self.emit(wasm::Instruction::Select);
+                        self.get_local(&range.step_var);
+                        self.emit(wasm::Instruction::I32Const(0));
+                        self.get_local(&range.step_var);
+                        self.emit(wasm::Instruction::I32Sub);
+                        self.get_local(&range.step_var);
+                        self.emit(wasm::Instruction::I32Const(0));
+                        self.emit(wasm::Instruction::I32GtS);
+                        self.emit(wasm::Instruction::Select);
+                    }
+                }
+                self.emit(wasm::Instruction::I32LeU);
+                self.emit(wasm::Instruction::BrIf(1));
+
+                self.get_local(counter);
+                self.get_local(&range.step_var);
+                self.emit(wasm::Instruction::I32Add);
+                self.store_value(counter, &int_type);
+                self.emit(wasm::Instruction::Br(0));
+
+                self.emit(wasm::Instruction::End);
+                self.emit(wasm::Instruction::End);
+            }
+            analyze::Statement::Assignment { target, value } => match target {
+                analyze::Target::Tuple { targets, typ } => {
+                    // Unpack without creating a tuple in memory:
+                    self.compile_unpacked(value);
+                    self.store_unpacked(targets, typ);
+                }
+                target => self.store_target(target, |c| c.compile_expression(value)),
+            },
+        }
+    }
+
+    /// Store a value into an assignment target, the value is produced by the given function.
+    ///
+    /// Like Python, the value is evaluated before any part of the target.
+    fn store_target<F>(&mut self, target: &analyze::Target, value: F)
+    where
+        F: FnOnce(&mut Self),
+    {
+        match target {
+            analyze::Target::Symbol(symbol) => {
+                value(self);
+                self.store_value(symbol, symbol.get_type());
+            }
+            analyze::Target::Indexed { base, index, typ } => {
+                let wasm_typ = self.get_type(typ);
+                let temporary = self.spill_value(wasm_typ.clone(), value);
+                self.compile_expression(base);
+                self.compile_expression(index);
+                let offset = self.builtin_list_element(typ, true);
+                self.emit(wasm::Instruction::LocalGet(temporary));
+                self.write_mem(offset, &wasm_typ);
+            }
+            analyze::Target::Slice { base, slice } => {
+                let temporary = self.spill_value(wasm::Type::I32, value);
+                self.compile_expression(base);
+                self.compile_slice(slice);
+                self.emit(wasm::Instruction::I32Const(
+                    self.get_element_size(base.get_type()) as i32,
+                ));
+                self.emit(wasm::Instruction::LocalGet(temporary));
+                self.call_runtime(RuntimeFunction::SliceAssign);
+            }
+            analyze::Target::Tuple { targets, typ } => {
+                value(self);
+                self.unbox_tuple(typ);
+                self.store_unpacked(targets, typ);
+            }
+            analyze::Target::DictItem { base, key, typ } => {
+                // Only add the key once the value is known, it may look at the dictionary:
+                let wasm_typ = self.get_type(typ);
+                let temporary = self.spill_value(wasm_typ.clone(), value);
+                self.compile_expression(base);
+                self.compile_expression(key);
+                let kind = self.get_key_kind(base.get_type());
+                self.call_runtime(RuntimeFunction::DictInsert(kind));
+                self.emit(wasm::Instruction::LocalGet(temporary));
+                self.write_mem(ENTRY_VALUE, &wasm_typ);
+            }
+            analyze::Target::Attribute { base, field, typ } => {
+                let (offsets, _) = self.get_object_layout(base.get_type());
+                let wasm_typ = self.get_type(typ);
+                let temporary = self.spill_value(wasm_typ.clone(), value);
+                self.compile_expression(base);
+                self.emit(wasm::Instruction::LocalGet(temporary));
+                self.write_mem(offsets[*field], &wasm_typ);
+            }
+        }
+    }
+
+    /// Move a value, produced by the given function, into a new temporary.
+    fn spill_value<F>(&mut self, typ: wasm::Type, value: F) -> usize
+    where
+        F: FnOnce(&mut Self),
+    {
+        value(self);
+        let temporary = self.new_temporary(typ);
+        self.emit(wasm::Instruction::LocalSet(temporary));
+        temporary
+    }
+
+    /// Store tuple elements, which are on the stack, into targets.
+    fn store_unpacked(&mut self, targets: &[analyze::Target], typ: &analyze::Type) {
+        // Assign from left to right, like Python does:
+        let temporaries = self.spill_tuple(typ);
+        for (target, temporary) in targets.iter().zip(temporaries) {
+            self.store_target(target, |c| c.emit(wasm::Instruction::LocalGet(temporary)));
+        }
+    }
+
+    /// Given a list as top of stack, retrieve its length.
+    fn builtin_list_len(&mut self) {
+        self.emit(wasm::Instruction::I32Load(2, 0));
+    }
+
+    /// Given a list and an index as top of stack, index the list
+    /// List element is at top of stack.
+    ///
+    /// When checked, the index is normalized and checked against the
+    /// length of the list. Without bounds checks it is only normalized.
+    fn builtin_list_index(&mut self, element_type: &analyze::Type, checked: bool) {
+        let element_wasm_typ = self.get_type(element_type);
+        let offset = self.builtin_list_element(element_type, checked);
+        self.read_mem(offset, &element_wasm_typ);
+    }
+
+    /// Given a list and an index as top of stack, determine where the
+    /// element is in memory. Returns the offset to use with this address.
+    fn builtin_list_element(&mut self, element_type: &analyze::Type, checked: bool) -> usize {
+        let element_size = self.get_sizeof(element_type);
+        let header_size = 4; // i32 for length of list
+        let data_start = round_to_multiple_of(header_size, element_size);
+
+        self.emit(wasm::Instruction::I32Const(element_size as i32));
+        if checked && self.options.bounds_checks {
+            self.call_runtime(RuntimeFunction::IndexSequence);
+        } else if checked {
+            self.call_runtime(RuntimeFunction::IndexSequenceUnchecked);
+        } else {
+            self.emit(wasm::Instruction::I32Mul);
+            self.emit(wasm::Instruction::I32Add);
+        }
+        data_start
+    }
+
+    /// Push slice start, stop and step on the stack.
+    fn compile_slice(&mut self, slice: &analyze::Slice) {
+        for bound in &[&slice.lower, &slice.upper] {
+            match bound {
+                Some(bound) => self.compile_expression(bound),
+                None => self.emit(wasm::Instruction::I32Const(SLICE_NONE)),
+            }
+        }
+        match &slice.step {
+            Some(step) => self.compile_expression(step),
+            None => self.emit(wasm::Instruction::I32Const(1)),
+        }
+    }
+
+    /// Determine the size of the elements in a list or string.
+    fn get_element_size(&self, sequence_type: &analyze::Type) -> usize {
+        match sequence_type {
+            analyze::Type::Str | analyze::Type::Bytes => 1,
+            analyze::Type::List(element_type) => self.get_sizeof(element_type),
+            other => panic!("Not a sequence type: {}", other),
+        }
+    }
+
+    /// Determine the offset of each tuple element, and the size of the tuple.
+    ///
+    /// Elements are layed out in order, and are aligned to their size.
+    fn get_tuple_layout(&self, element_types: &[analyze::Type]) -> (Vec<usize>, usize) {
+        let mut offsets = vec![];
+        let mut size = 0;
+        for element_type in element_types {
+            let element_size = self.get_sizeof(element_type);
+            let offset = round_to_multiple_of(size, element_size);
+            offsets.push(offset);
+            size = offset + element_size;
+        }
+        (offsets, size)
+    }
+
+    /// Determine the offset of each field of an object, and the size of the object.
+    ///
+    /// Objects are layed out like tuples of the class id and their fields.
+    fn get_object_layout(&self, typ: &analyze::Type) -> (Vec<usize>, usize) {
+        let class = match typ {
+            analyze::Type::Class(name) => &self.classes[name],
+            other => panic!("Not a class type: {}", other),
+        };
+        let mut types = vec![analyze::Type::Integer];
+        types.extend(class.fields.iter().map(|f| f.typ.clone()));
+        let (mut offsets, size) = self.get_tuple_layout(&types);
+        offsets.remove(0);
+        (offsets, size)
+    }
+
+    /// Get the offset of the line where an exception was raised, in exception objects.
+    fn get_lineno_offset(&self) -> usize {
+        let typ = analyze::Type::Class(analyze::EXCEPTION.to_owned());
+        let (offsets, _) = self.get_object_layout(&typ);
+        let field = self.classes[analyze::EXCEPTION]
+            .get_field("lineno")
+            .expect("Exceptions have a line");
+        offsets[field]
+    }
+
+    fn get_class_id(&self, typ: &analyze::Type) -> (usize, usize) {
+        match typ {
+            analyze::Type::Class(name) => self.class_ids[name],
+            other => panic!("Not a class type: {}", other),
+        }
+    }
+
+    fn get_tuple_types<'t>(&self, typ: &'t analyze::Type) -> &'t [analyze::Type] {
+        match typ {
+            analyze::Type::Tuple(element_types) => element_types,
+            other => panic!("Not a tuple type: {}", other),
+        }
+    }
+
+    /// Create a tuple in memory from the elements on the stack.
+    fn box_tuple(&mut self, typ: &analyze::Type) {
+        let element_types = self.get_tuple_types(typ);
+        let (offsets, size) = self.get_tuple_layout(element_types);
+        let temporaries = self.spill_tuple(typ);
+
+        let pointer = self.new_temporary(wasm::Type::I32);
+        self.allocate(size);
+        self.emit(wasm::Instruction::LocalSet(pointer));
+
+        for ((element_type, offset), temporary) in
+            element_types.iter().zip(offsets).zip(temporaries)
+        {
+            self.emit(wasm::Instruction::LocalGet(pointer));
+            self.emit(wasm::Instruction::LocalGet(temporary));
+            let wasm_typ = self.get_type(element_type);
+            self.write_mem(offset, &wasm_typ);
+        }
+
+        self.emit(wasm::Instruction::LocalGet(pointer));
+    }
+
+    /// Replace a tuple on the stack by its elements.
+    fn unbox_tuple(&mut self, typ: &analyze::Type) {
+        let element_types = self.get_tuple_types(typ);
+        let (offsets, _) = self.get_tuple_layout(element_types);
+
+        let pointer = self.new_temporary(wasm::Type::I32);
+        self.emit(wasm::Instruction::LocalSet(pointer));
+
+        for (element_type, offset) in element_types.iter().zip(offsets) {
+            self.emit(wasm::Instruction::LocalGet(pointer));
+            let wasm_typ = self.get_type(element_type);
+            self.read_mem(offset, &wasm_typ);
+        }
+    }
+
+    /// Move tuple elements from the stack into new temporaries.
+    fn spill_tuple(&mut self, typ: &analyze::Type) -> Vec<usize> {
+        let element_types = self.get_tuple_types(typ);
+        let wasm_types: Vec<wasm::Type> = element_types.iter().map(|t| self.get_type(t)).collect();
+        let temporaries: Vec<usize> = wasm_types
+            .into_iter()
+            .map(|t| self.new_temporary(t))
+            .collect();
+
+        // Last element is on top of the stack:
+        for temporary in temporaries.iter().rev() {
+            self.emit(wasm::Instruction::LocalSet(*temporary));
+        }
+        temporaries
+    }
+
+    /// Evaluate a tuple expression, and leave its elements on the stack.
+    fn compile_unpacked(&mut self, expression: &analyze::Expression) {
+        match expression {
+            analyze::Expression::Tuple { elements, .. } => {
+                for element in elements {
+                    self.compile_expression(element);
+                }
+            }
+            analyze::Expression::Call {
+                callee, arguments, ..
+            } => {
+                self.compile_call(callee, arguments);
+            }
+            analyze::Expression::MethodCall {
+                slot, arguments, ..
+            } => {
+                self.compile_method_call(*slot, arguments);
+            }
+            analyze::Expression::CallClosure {
+                closure, arguments, ..
+            } => {
+                self.compile_closure_call(closure, arguments);
+            }
+            expression => {
+                self.compile_expression(expression);
+                self.unbox_tuple(expression.get_type());
+            }
+        }
+    }
+
+    /// Evaluate an expression, and throw away the result.
+    fn compile_discarded(&mut self, expression: &analyze::Expression) {
+        let num_values = match expression {
+            analyze::Expression::Call {
+                callee,
+                arguments,
+                typ,
+            } => {
+                self.compile_call(callee, arguments);
+                self.get_result_types(typ).len()
+            }
+            analyze::Expression::MethodCall {
+                slot,
+                arguments,
+                typ,
+            } => {
+                self.compile_method_call(*slot, arguments);
+                self.get_result_types(typ).len()
+            }
+            analyze::Expression::CallClosure {
+                closure,
+                arguments,
+                typ,
+            } => {
+                self.compile_closure_call(closure, arguments);
+                self.get_result_types(typ).len()
+            }
+            expression => {
+                self.compile_expression(expression);
+                match expression.get_type() {
+                    analyze::Type::None => 0,
+                    _ => 1,
+                }
+            }
+        };
+        for _ in 0..num_values {
+            self.emit(wasm::Instruction::Drp);
+        }
+    }
+
+    /// Determine how the keys of a dict are hashed.
+    fn get_key_kind(&self, dict_type: &analyze::Type) -> KeyKind {
+        match dict_type {
+            analyze::Type::Dict(key_type, _) | analyze::Type::Set(key_type) => {
+                KeyKind::from_type(key_type)
+            }
+            other => panic!("Not a dict or set: {}", other),
+        }
+    }
+
+    /// Add a local to the current function, for use by the compiler.
+    fn new_temporary(&mut self, typ: wasm::Type) -> usize {
+        let index = self.num_params + self.locals.len();
+        self.locals.push(typ);
+        index
+    }
+
+    fn get_sizeof(&self, element_type: &analyze::Type) -> usize {
+        match element_type {
+            analyze::Type::Float => 8,
+            analyze::Type::Integer
+            | analyze::Type::Bool
+            | analyze::Type::Enum(_)
+            | analyze::Type::Str
+            | analyze::Type::Bytes
+            | analyze::Type::List(_)
+            | analyze::Type::Tuple(_)
+            | analyze::Type::Dict(..)
+            | analyze::Type::Set(_)
+            | analyze::Type::Class(_)
+            | analyze::Type::Optional(_)
+            | analyze::Type::NoneValue
+            | analyze::Type::Union(_)
+            | analyze::Type::Callable(..) => 4,
+            analyze::Type::None => {
+                panic!("None is not a value");
+            }
+        }
+    }
+
+    fn compile_expression(&mut self, expression: &analyze::Expression) {
+        match expression {
+            analyze::Expression::Number(value) | analyze::Expression::EnumMember { value, .. } => {
+                self.emit(wasm::Instruction::I32Const(*value));
+            }
+            analyze::Expression::None => {
+                self.emit(wasm::Instruction::I32Const(0));
+            }
+            analyze::Expression::Float(value) => {
+                self.emit(wasm::Instruction::F64Const(*value));
+            }
+            analyze::Expression::String(value) => {
+                let address = self.add_string(value);
+                self.emit(wasm::Instruction::I32Const(address as i32));
+            }
+            analyze::Expression::List {
+                elements,
+                typ,
+                helper_local,
+            } => {
+                // Hmm, okay, list. Now what.
+                // Plan:
+                // Layout list literal in memory. Grab some memory, store all
+                // elements sequentially.
+                // Another idea: evaluate all elements and finally push the length
+                // on the stack. Popping the elements will be in reversed order..
+                let int_type = analyze::Type::Integer;
+
+                // The list of a * parameter can be empty, so the element type comes from the list type:
+                let element_typ = match typ {
+                    analyze::Type::List(element_typ) => element_typ.as_ref(),
+                    other => panic!("List literal has type {}", other),
+                };
+                let element_size = self.get_sizeof(element_typ);
+                let element_wasm_type = self.get_type(element_typ);
+
+                let header_size = 4; // i32 for length of list
+                let data_start = round_to_multiple_of(header_size, element_size);
+
+                // TODO: determine size of element:
+                let mem_size = element_size * elements.len() + data_start;
+
+                // Grab memory:
+                self.allocate(mem_size);
+                self.store_value(helper_local, &int_type);
+
+                // Write len in header:
+                self.get_local(helper_local);
+                self.emit(wasm::Instruction::I32Const(elements.len() as i32));
+                self.write_mem(0, &wasm::Type::I32);
+
+                // Store:
+                let mut offset = data_start;
+                for element in elements {
+                    self.get_local(helper_local);
+                    self.compile_expression(element);
+                    self.write_mem(offset, &element_wasm_type);
+                    offset += element_size;
+                }
+
+                self.get_local(helper_local);
+            }
+            analyze::Expression::Identifier(value) => {
+                self.get_local(value);
+            }
+            analyze::Expression::BinaryOperation {
+                a,
+                op: analyze::BinaryOperation::Boolean(op),
+                b,
+                ..
+            } => {
+                // Like Python, b is only evaluated when a does not decide the result:
+                let result = self.new_temporary(wasm::Type::I32);
+                self.compile_expression(a);
+                self.emit(wasm::Instruction::LocalTee(result));
+                if let ast::BooleanOperator::Or = op {
+                    self.emit(wasm::Instruction::I32Eqz);
+                }
+                self.emit(wasm::Instruction::If);
+                self.compile_expression(b);
+                self.emit(wasm::Instruction::LocalSet(result));
+                self.emit(wasm::Instruction::End);
+                self.emit(wasm::Instruction::LocalGet(result));
+            }
+            analyze::Expression::BinaryOperation { a, op, b, typ: _ } => {
+                self.compile_expression(a);
+                self.compile_expression(b);
+                let typ = self.get_type(a.get_type());
+                match op {
+                    analyze::BinaryOperation::ArithmaticOperation(op) => {
+                        self.emit_arithmatic_operator(op, typ);
+                    }
+                    analyze::BinaryOperation::Comparison(
+                        op @ ast::Comparison::Equal | op @ ast::Comparison::NotEqual,
+                    ) if *a.get_type() == analyze::Type::Str => {
+                        self.call_runtime(RuntimeFunction::StrEqual);
+                        if matches!(op, ast::Comparison::NotEqual) {
+                            self.emit(wasm::Instruction::I32Eqz);
+                        }
+                    }
+                    analyze::BinaryOperation::Comparison(op) => {
+                        self.emit_comparison(op, typ);
+                    }
+                    analyze::BinaryOperation::Boolean(_) => {
+                        panic!("Boolean operators are evaluated lazily");
+                    }
+                }
+            }
+            analyze::Expression::IntDivision { a, b, line } => {
+                self.compile_expression(a);
+                self.compile_expression(b);
+                self.emit(wasm::Instruction::I32Const(*line as i32));
+                self.call_runtime(RuntimeFunction::IntDivide);
+            }
+            analyze::Expression::UnaryOperation { op, a } => match op {
+                ast::UnaryOperation::Minus => match self.get_type(a.get_type()) {
+                    wasm::Type::I32 => {
+                        self.emit(wasm::Instruction::I32Const(0));
+                        self.compile_expression(a);
+                        self.emit(wasm::Instruction::I32Sub);
+                    }
+                    wasm::Type::F64 => {
+                        self.compile_expression(a);
+                        self.emit(wasm::Instruction::F64Neg);
+                    }
+                },
+            },
+            analyze::Expression::Call {
+                callee,
+                typ,
+                arguments,
+            } => {
+                self.compile_call(callee, arguments);
+                if let analyze::Type::Tuple(_) = typ {
+                    // Multiple values are returned, create a tuple from them:
+                    self.box_tuple(typ);
+                }
+            }
+            analyze::Expression::CallClosure {
+                closure,
+                arguments,
+                typ,
+            } => {
+                self.compile_closure_call(closure, arguments);
+                if let analyze::Type::Tuple(_) = typ {
+                    self.box_tuple(typ);
+                }
+            }
+            analyze::Expression::Closure {
+                function,
+                environment,
+                ..
+            } => {
+                let closure = self.new_temporary(wasm::Type::I32);
+                self.allocate(CLOSURE_SIZE);
+                self.emit(wasm::Instruction::LocalTee(closure));
+                let slot = self.get_closure_slot(function);
+                self.emit(wasm::Instruction::I32Const(slot as i32));
+                self.write_mem(CLOSURE_FUNCTION, &wasm::Type::I32);
+                self.emit(wasm::Instruction::LocalGet(closure));
+                match environment {
+                    Some(environment) => self.get_local(environment),
+                    None => self.emit(wasm::Instruction::I32Const(0)),
+                }
+                self.write_mem(CLOSURE_ENVIRONMENT, &wasm::Type::I32);
+                self.emit(wasm::Instruction::LocalGet(closure));
+            }
+            analyze::Expression::Tuple { elements, typ } => {
+                for element in elements {
+                    self.compile_expression(element);
+                }
+                self.box_tuple(typ);
+            }
+            analyze::Expression::TupleElement { base, index, .. } => {
+                let element_types = self.get_tuple_types(base.get_type());
+                let (offsets, _) = self.get_tuple_layout(element_types);
+                let wasm_typ = self.get_type(&element_types[*index]);
+                self.compile_expression(base);
+                self.read_mem(offsets[*index], &wasm_typ);
+            }
+            analyze::Expression::Dict { entries, typ } => {
+                let kind = self.get_key_kind(typ);
+                let dict = self.new_temporary(wasm::Type::I32);
+                self.call_runtime(RuntimeFunction::DictNew);
+                self.emit(wasm::Instruction::LocalSet(dict));
+                for (key, value) in entries {
+                    self.emit(wasm::Instruction::LocalGet(dict));
+                    self.compile_expression(key);
+                    self.call_runtime(RuntimeFunction::DictInsert(kind));
+                    self.compile_expression(value);
+                    let wasm_typ = self.get_type(value.get_type());
+                    self.write_mem(ENTRY_VALUE, &wasm_typ);
+                }
+                self.emit(wasm::Instruction::LocalGet(dict));
+            }
+            analyze::Expression::Set { elements, typ } => {
+                let kind = self.get_key_kind(typ);
+                let set = self.new_temporary(wasm::Type::I32);
+                self.call_runtime(RuntimeFunction::DictNew);
+                self.emit(wasm::Instruction::LocalSet(set));
+                for element in elements {
+                    self.emit(wasm::Instruction::LocalGet(set));
+                    self.compile_expression(element);
+                    self.call_runtime(RuntimeFunction::DictInsert(kind));
+                    self.emit(wasm::Instruction::Drp);
+                }
+                self.emit(wasm::Instruction::LocalGet(set));
+            }
+            analyze::Expression::ListToSet { list, typ } => {
+                let kind = self.get_key_kind(typ);
+                let element_type = match list.get_type() {
+                    analyze::Type::List(element_type) => element_type,
+                    other => panic!("Not a list: {}", other),
+                };
+                let (list_var, set, i, count) = (
+                    self.new_temporary(wasm::Type::I32),
+                    self.new_temporary(wasm::Type::I32),
+                    self.new_temporary(wasm::Type::I32),
+                    self.new_temporary(wasm::Type::I32),
+                );
+
+                self.compile_expression(list);
+                self.emit(wasm::Instruction::LocalSet(list_var));
+                self.call_runtime(RuntimeFunction::DictNew);
+                self.emit(wasm::Instruction::LocalSet(set));
+                self.emit(wasm::Instruction::LocalGet(list_var));
+                self.builtin_list_len();
+                self.emit(wasm::Instruction::LocalSet(count));
+                self.emit_counted_loop(i, count, |c| {
+                    c.emit(wasm::Instruction::LocalGet(set));
+                    c.emit(wasm::Instruction::LocalGet(list_var));
+                    c.emit(wasm::Instruction::LocalGet(i));
+                    c.builtin_list_index(element_type, false);
+                    c.call_runtime(RuntimeFunction::DictInsert(kind));
+                    c.emit(wasm::Instruction::Drp);
+                });
+                self.emit(wasm::Instruction::LocalGet(set));
+            }
+            analyze::Expression::SetOperation { a, op, b } => {
+                let kind = self.get_key_kind(a.get_type());
+                let op = match op {
+                    ast::BinaryOperation::BitOr => SetOperator::Union,
+                    ast::BinaryOperation::BitAnd => SetOperator::Intersection,
+                    ast::BinaryOperation::Sub => SetOperator::Difference,
+                    other => panic!("Not a set operation: {:?}", other),
+                };
+                self.compile_expression(a);
+                self.compile_expression(b);
+                self.call_runtime(RuntimeFunction::SetOperation(op, kind));
+            }
+            analyze::Expression::SetAdd { base, item } => {
+                let kind = self.get_key_kind(base.get_type());
+                self.compile_expression(base);
+                self.compile_expression(item);
+                self.call_runtime(RuntimeFunction::DictInsert(kind));
+                self.emit(wasm::Instruction::Drp);
+            }
+            analyze::Expression::SetDiscard { base, item } => {
+                let kind = self.get_key_kind(base.get_type());
+                self.compile_expression(base);
+                self.compile_expression(item);
+                self.call_runtime(RuntimeFunction::SetDiscard(kind));
+            }
+            analyze::Expression::DictItem { base, key, typ } => {
+                self.compile_expression(base);
+                self.compile_expression(key);
+                let kind = self.get_key_kind(base.get_type());
+                self.call_runtime(RuntimeFunction::DictItem(kind));
+                let wasm_typ = self.get_type(typ);
+                self.read_mem(ENTRY_VALUE, &wasm_typ);
+            }
+            analyze::Expression::DictGet { base, key, default } => {
+                let kind = self.get_key_kind(base.get_type());
+                let wasm_typ = self.get_type(default.get_type());
+                let entry = self.new_temporary(wasm::Type::I32);
+                let result = self.new_temporary(wasm_typ.clone());
+
+                self.compile_expression(base);
+                self.compile_expression(key);
+                self.call_runtime(RuntimeFunction::DictLookup(kind));
+                self.emit(wasm::Instruction::LocalSet(entry));
+                self.compile_expression(default);
+                self.emit(wasm::Instruction::LocalSet(result));
+
+                self.emit(wasm::Instruction::LocalGet(entry));
+                self.emit(wasm::Instruction::If);
+                self.emit(wasm::Instruction::LocalGet(entry));
+                self.read_mem(ENTRY_VALUE, &wasm_typ);
+                self.emit(wasm::Instruction::LocalSet(result));
+                self.emit(wasm::Instruction::End);
+                self.emit(wasm::Instruction::LocalGet(result));
+            }
+            analyze::Expression::Contains {
+                item,
+                container,
+                negate,
+            } => {
+                let kind = self.get_key_kind(container.get_type());
+                let key = self.new_temporary(wasm::Type::I32);
+
+                // The item is evaluated first:
+                self.compile_expression(item);
+                self.emit(wasm::Instruction::LocalSet(key));
+                self.compile_expression(container);
+                self.emit(wasm::Instruction::LocalGet(key));
+                self.call_runtime(RuntimeFunction::DictLookup(kind));
+                if *negate {
+                    self.emit(wasm::Instruction::I32Eqz);
+                } else {
+                    self.emit(wasm::Instruction::I32Const(0));
+                    self.emit(wasm::Instruction::I32Ne);
+                }
+            }
+            analyze::Expression::TupleSlice { base, indices, typ } => {
+                self.compile_unpacked(base);
+                let temporaries = self.spill_tuple(base.get_type());
+                for index in indices {
+                    self.emit(wasm::Instruction::LocalGet(temporaries[*index]));
+                }
+                self.box_tuple(typ);
+            }
+            analyze::Expression::Indexed { base, index, typ } => {
+                self.compile_expression(base);
+                self.compile_expression(index);
+                self.builtin_list_index(typ, true);
+            }
+            analyze::Expression::Attribute { base, field, typ } => {
+                let (offsets, _) = self.get_object_layout(base.get_type());
+                let wasm_typ = self.get_type(typ);
+                self.compile_expression(base);
+                self.read_mem(offsets[*field], &wasm_typ);
+            }
+            analyze::Expression::MethodCall {
+                slot,
+                arguments,
+                typ,
+            } => {
+                self.compile_method_call(*slot, arguments);
+                if let analyze::Type::Tuple(_) = typ {
+                    self.box_tuple(typ);
+                }
+            }
+            analyze::Expression::ToStr { value, repr } => {
+                self.compile_expression(value);
+                self.emit_to_str(value.get_type(), *repr);
+            }
+            analyze::Expression::Concat { parts } => {
+                self.compile_expression(&parts[0]);
+                for part in &parts[1..] {
+                    self.compile_expression(part);
+                    self.call_runtime(RuntimeFunction::StrConcat);
+                }
+            }
+            analyze::Expression::Print { arguments, output } => {
+                // Values are separated by spaces, as in Python:
+                let separator = self.add_string(" ");
+                if arguments.is_empty() {
+                    let address = self.add_string("");
+                    self.emit(wasm::Instruction::I32Const(address as i32));
+                }
+                for (index, argument) in arguments.iter().enumerate() {
+                    self.compile_expression(argument);
+                    self.emit_to_str(argument.get_type(), false);
+                    if index > 0 {
+                        let part = self.new_temporary(wasm::Type::I32);
+                        self.emit(wasm::Instruction::LocalSet(part));
+                        self.emit(wasm::Instruction::I32Const(separator as i32));
+                        self.call_runtime(RuntimeFunction::StrConcat);
+                        self.emit(wasm::Instruction::LocalGet(part));
+                        self.call_runtime(RuntimeFunction::StrConcat);
+                    }
+                }
+
+                self.emit_text_for_host();
+                self.compile_call(output, &[]);
+            }
+            analyze::Expression::EnumValue { member } => {
+                // An enum member is represented by its value:
+                self.compile_expression(member);
+            }
+            analyze::Expression::IsInstance { object, class } => {
+                self.compile_expression(object);
+                self.emit_isinstance(class);
+            }
+            analyze::Expression::ToUnion { value, .. } => {
+                let tag = value.get_type().get_tag().expect("Union member");
+                let union = self.new_temporary(wasm::Type::I32);
+                self.allocate(UNION_SIZE);
+                self.emit(wasm::Instruction::LocalSet(union));
+                self.emit(wasm::Instruction::LocalGet(union));
+                self.emit(wasm::Instruction::I32Const(tag as i32));
+                self.write_mem(UNION_TAG, &wasm::Type::I32);
+                self.emit(wasm::Instruction::LocalGet(union));
+                self.compile_expression(value);
+                self.write_mem(UNION_VALUE, &self.get_type(value.get_type()));
+                self.emit(wasm::Instruction::LocalGet(union));
+            }
+            analyze::Expression::UnionValue { union, typ } => {
+                self.compile_expression(union);
+                self.read_mem(UNION_VALUE, &self.get_type(typ));
+            }
+            analyze::Expression::UnionIsInstance {
+                union,
+                tags,
+                classes,
+            } => {
+                let value = self.new_temporary(wasm::Type::I32);
+                let result = self.new_temporary(wasm::Type::I32);
+                self.compile_expression(union);
+                self.emit(wasm::Instruction::LocalSet(value));
+                self.emit(wasm::Instruction::I32Const(0));
+                for tag in tags {
+                    self.emit_tag_check(value, *tag);
+                    self.emit(wasm::Instruction::I32Or);
+                }
+                self.emit(wasm::Instruction::LocalSet(result));
+                // Objects also need a check of their class:
+                if !classes.is_empty() {
+                    self.emit_tag_check(value, analyze::TypeTag::Object);
+                    self.emit(wasm::Instruction::If);
+                    for class in classes {
+                        self.emit(wasm::Instruction::LocalGet(value));
+                        self.read_mem(UNION_VALUE, &wasm::Type::I32);
+                        self.emit_isinstance(class);
+                        self.emit(wasm::Instruction::LocalGet(result));
+                        self.emit(wasm::Instruction::I32Or);
+                        self.emit(wasm::Instruction::LocalSet(result));
+                    }
+                    self.emit(wasm::Instruction::End);
+                }
+                self.emit(wasm::Instruction::LocalGet(result));
+            }
+            analyze::Expression::NewObject {
+                init,
+                arguments,
+                typ,
+            } => {
+                let (_, size) = self.get_object_layout(typ);
+                let (class_id, _) = self.get_class_id(typ);
+                let object = self.new_temporary(wasm::Type::I32);
+                self.allocate(size);
+                self.emit(wasm::Instruction::LocalSet(object));
+                self.emit(wasm::Instruction::LocalGet(object));
+                self.emit(wasm::Instruction::I32Const(class_id as i32));
+                self.emit(wasm::Instruction::I32Store(2, OBJECT_CLASS_ID));
+                if let Some(init) = init {
+                    self.emit(wasm::Instruction::LocalGet(object));
+                    self.compile_call(init, arguments);
+                }
+                self.emit(wasm::Instruction::LocalGet(object));
+            }
+            analyze::Expression::Slice { base, slice } => {
+                self.compile_expression(base);
+                self.compile_slice(slice);
+                self.emit(wasm::Instruction::I32Const(
+                    self.get_element_size(base.get_type()) as i32,
+                ));
+                self.call_runtime(RuntimeFunction::SliceSequence);
+            }
+        }
+    }
+
+    /// Run a suite, and handle its exceptions with the first handler for their class:
+    ///
+    /// block
+    ///   try
+    ///     suite
+    ///   catch
+    ///     block
+    ///       isinstance check, br_if 0 when false
+    ///       handler suite
+    ///       br 2
+    ///     end
+    ///     ... next handler
+    ///     throw the exception again
+    ///   end
+    ///   else suite
+    /// end
+    fn compile_try_except(
+        &mut self,
+        exception_var: &analyze::Symbol,
+        suite: &[analyze::Statement],
+        handlers: &[analyze::ExceptHandler],
+        else_suite: &[analyze::Statement],
+    ) {
+        if handlers.is_empty() {
+            self.compile_suite(suite);
+            return;
+        }
+        if self.exception_global.is_some() {
+            self.compile_try_except_flag(exception_var, suite, handlers, else_suite);
+            return;
+        }
+
+        let tag = self.get_exception_tag();
+        self.emit(wasm::Instruction::Block);
+        self.emit(wasm::Instruction::Try);
+        self.compile_suite(suite);
+        self.emit(wasm::Instruction::Catch(tag));
+        self.store_value(exception_var, exception_var.get_type());
+        for handler in handlers {
+            self.emit(wasm::Instruction::Block);
+            for (index, class) in handler.classes.iter().enumerate() {
+                self.get_local(exception_var);
+                self.emit_isinstance(class);
+                if index > 0 {
+                    self.emit(wasm::Instruction::I32Or);
+                }
+            }
+            if !handler.classes.is_empty() {
+                self.emit(wasm::Instruction::I32Eqz);
+                self.emit(wasm::Instruction::BrIf(0));
+            }
+            self.compile_suite(&handler.suite);
+            self.emit(wasm::Instruction::Br(2));
+            self.emit(wasm::Instruction::End);
+            if handler.classes.is_empty() {
+                // The handlers after this one are never used:
+                break;
+            }
+        }
+        // No handler for this class:
+        self.get_local(exception_var);
+        self.emit_raise();
+        self.emit(wasm::Instruction::End);
+        self.compile_suite(else_suite);
+        self.emit(wasm::Instruction::End);
+    }
+
+    /// Run a suite and handle its exceptions as compile_try_except does,
+    /// when exceptions are lowered without the exception handling proposal:
+    ///
+    /// block
+    ///   block
+    ///     suite, exceptions branch here
+    ///     else suite
+    ///     br 1
+    ///   end
+    ///   take the exception from the global
+    ///   block
+    ///     isinstance check, br_if 0 when false
+    ///     handler suite
+    ///     br 1
+    ///   end
+    ///   ... next handler
+    ///   raise the exception again
+    /// end
+    fn compile_try_except_flag(
+        &mut self,
+        exception_var: &analyze::Symbol,
+        suite: &[analyze::Statement],
+        handlers: &[analyze::ExceptHandler],
+        else_suite: &[analyze::Statement],
+    ) {
+        let global = self.exception_global.unwrap();
+        self.emit(wasm::Instruction::Block);
+        self.emit(wasm::Instruction::Block);
+        self.handler_depths.push(self.depth);
+        self.compile_suite(suite);
+        self.handler_depths.pop();
+        self.compile_suite(else_suite);
+        self.emit(wasm::Instruction::Br(1));
+        self.emit(wasm::Instruction::End);
+        self.emit(wasm::Instruction::GlobalGet(global));
+        self.store_value(exception_var, exception_var.get_type());
+        self.emit(wasm::Instruction::I32Const(0));
+        self.emit(wasm::Instruction::GlobalSet(global));
+        for handler in handlers {
+            self.emit(wasm::Instruction::Block);
+            for (index, class) in handler.classes.iter().enumerate() {
+                self.get_local(exception_var);
+                self.emit_isinstance(class);
+                if index > 0 {
+                    self.emit(wasm::Instruction::I32Or);
+                }
+            }
+            if !handler.classes.is_empty() {
+                self.emit(wasm::Instruction::I32Eqz);
+                self.emit(wasm::Instruction::BrIf(0));
+            }
+            self.compile_suite(&handler.suite);
+            self.emit(wasm::Instruction::Br(1));
+            self.emit(wasm::Instruction::End);
+            if handler.classes.is_empty() {
+                // The handlers after this one are never used:
+                break;
+            }
+        }
+        // No handler for this class:
+        self.get_local(exception_var);
+        self.emit_raise();
+        self.emit(wasm::Instruction::End);
+    }
+
+    /// Run the finally suite once after the other suites, when exceptions
+    /// are lowered without the exception handling proposal. An exception
+    /// is set aside while the finally suite runs, and raised again after it.
+    fn compile_try_finally(
+        &mut self,
+        exception_var: &analyze::Symbol,
+        suite: &[analyze::Statement],
+        handlers: &[analyze::ExceptHandler],
+        else_suite: &[analyze::Statement],
+        finally_suite: &[analyze::Statement],
+    ) {
+        let global = self.exception_global.unwrap();
+        self.emit(wasm::Instruction::Block);
+        self.handler_depths.push(self.depth);
+        self.compile_try_except(exception_var, suite, handlers, else_suite);
+        self.handler_depths.pop();
+        self.emit(wasm::Instruction::End);
+        let exception = self.new_temporary(wasm::Type::I32);
+        self.emit(wasm::Instruction::GlobalGet(global));
+        self.emit(wasm::Instruction::LocalSet(exception));
+        self.emit(wasm::Instruction::I32Const(0));
+        self.emit(wasm::Instruction::GlobalSet(global));
+        self.compile_suite(finally_suite);
+        self.emit(wasm::Instruction::LocalGet(exception));
+        self.emit(wasm::Instruction::If);
+        self.emit(wasm::Instruction::LocalGet(exception));
+        self.emit_raise();
+        self.emit(wasm::Instruction::End);
+    }
+
+    /// Throw the exception object on top of the stack.
+    fn emit_raise(&mut self) {
+        match self.exception_global {
+            Some(global) => {
+                self.emit(wasm::Instruction::GlobalSet(global));
+                self.emit_propagate();
+            }
+            None => {
+                let tag = self.get_exception_tag();
+                self.emit(wasm::Instruction::Throw(tag));
+            }
+        }
+    }
+
+    /// Pass on an exception raised by a call, when exceptions are lowered
+    /// without the exception handling proposal.
+    fn emit_exception_check(&mut self) {
+        if let Some(global) = self.exception_global {
+            self.emit(wasm::Instruction::GlobalGet(global));
+            self.emit(wasm::Instruction::If);
+            self.emit_propagate();
+            self.emit(wasm::Instruction::End);
+        }
+    }
+
+    /// Leave the innermost block with a handler, or return to the caller,
+    /// with the exception in the global.
+    fn emit_propagate(&mut self) {
+        match self.handler_depths.last() {
+            Some(&depth) => {
+                self.emit(wasm::Instruction::Br(self.depth - depth));
+            }
+            None => {
+                self.exception_returns.push(self.code.len());
+                self.emit(wasm::Instruction::Return);
+            }
+        }
+    }
+
+    /// Get the tag of exceptions, which is exported so that the host
+    /// can recognize exceptions which are not handled.
+    fn get_exception_tag(&mut self) -> usize {
+        if let Some(tag) = self.exception_tag {
+            return tag;
+        }
+        let tag = self.module.add_tag(vec![wasm::Type::I32]);
+        self.module.export_tag("exception".to_owned(), tag);
+        self.exception_tag = Some(tag);
+        tag
+    }
+
+    /// Check the cases of a match statement one by one:
+    ///
+    /// block
+    ///   block
+    ///     condition, br_if 0 when false, bindings, guard, br_if 0 when false
+    ///     suite
+    ///     br 1
+    ///   end
+    ///   ... next case
+    /// end
+    fn compile_match_chain(&mut self, cases: &[analyze::MatchCase]) {
+        self.emit(wasm::Instruction::Block);
+        for case in cases {
+            self.emit(wasm::Instruction::Block);
+            if let Some(condition) = &case.condition {
+                self.compile_expression(condition);
+                self.emit(wasm::Instruction::I32Eqz);
+                self.emit(wasm::Instruction::BrIf(0));
+            }
+            self.compile_suite(&case.bindings);
+            if let Some(guard) = &case.guard {
+                self.compile_expression(guard);
+                self.emit(wasm::Instruction::I32Eqz);
+                self.emit(wasm::Instruction::BrIf(0));
+            }
+            self.compile_suite(&case.suite);
+            self.emit(wasm::Instruction::Br(1));
+            self.emit(wasm::Instruction::End);
+        }
+        self.emit(wasm::Instruction::End);
+    }
+
+    /// Select the case of a match statement by the subject, with a block per case:
+    ///
+    /// block             -- end
+    ///   block           -- the last case, when it matches anything
+    ///     block         -- case 1
+    ///       block       -- case 0
+    ///         br_table
+    ///       end
+    ///       suite of case 0, br to end
+    ///     end
+    ///     suite of case 1, br to end
+    ///   end
+    ///   suite of the last case
+    /// end
+    fn compile_match_table(
+        &mut self,
+        subject_var: &analyze::Symbol,
+        cases: &[analyze::MatchCase],
+        lowest: i32,
+        size: usize,
+    ) {
+        let (cases, default) = match cases.split_last() {
+            Some((last, others)) if last.values.is_none() => (others, Some(last)),
+            _ => (cases, None),
+        };
+        let default_label = cases.len();
+        let mut labels = vec![default_label; size];
+        for (index, case) in cases.iter().enumerate() {
+            for value in case.values.as_ref().expect("Numbers of case") {
+                // The first case with a number is used:
+                let label = &mut labels[(value - lowest) as usize];
+                if *label == default_label {
+                    *label = index;
+                }
+            }
+        }
+
+        self.emit(wasm::Instruction::Block);
+        if default.is_some() {
+            self.emit(wasm::Instruction::Block);
+        }
+        for _ in cases {
+            self.emit(wasm::Instruction::Block);
+        }
+        self.get_local(subject_var);
+        self.emit(wasm::Instruction::I32Const(lowest));
+        self.emit(wasm::Instruction::I32Sub);
+        self.emit(wasm::Instruction::BrTable(labels, default_label));
+        for (index, case) in cases.iter().enumerate() {
+            self.emit(wasm::Instruction::End);
+            self.compile_suite(&case.suite);
+            let outer_blocks = cases.len() - 1 - index + usize::from(default.is_some());
+            self.emit(wasm::Instruction::Br(outer_blocks));
+        }
+        if let Some(case) = default {
+            self.emit(wasm::Instruction::End);
+            self.compile_suite(&case.bindings);
+            self.compile_suite(&case.suite);
+        }
+        self.emit(wasm::Instruction::End);
+    }
+
+    /// Check whether the object on the stack is an instance of a class.
+    fn emit_isinstance(&mut self, class: &str) {
+        // The ids of a class and its subclasses form a range:
+        let (class_id, count) = self.class_ids[class];
+        self.emit(wasm::Instruction::I32Load(2, OBJECT_CLASS_ID));
+        self.emit(wasm::Instruction::I32Const(class_id as i32));
+        self.emit(wasm::Instruction::I32Sub);
+        self.emit(wasm::Instruction::I32Const(count as i32));
+        self.emit(wasm::Instruction::I32LtU);
+    }
+
+    /// Replace the string on the stack by the address and length of its
+    /// text, which is how host functions receive text.
+    fn emit_text_for_host(&mut self) {
+        let text = self.new_temporary(wasm::Type::I32);
+        self.emit(wasm::Instruction::LocalSet(text));
+        self.emit(wasm::Instruction::LocalGet(text));
+        self.emit(wasm::Instruction::I32Const(4));
+        self.emit(wasm::Instruction::I32Add);
+        self.emit(wasm::Instruction::LocalGet(text));
+        self.builtin_list_len();
+    }
+
+    /// Check whether the value in the union in a local has a certain type.
+    fn emit_tag_check(&mut self, union: usize, tag: analyze::TypeTag) {
+        self.emit(wasm::Instruction::LocalGet(union));
+        self.read_mem(UNION_TAG, &wasm::Type::I32);
+        self.emit(wasm::Instruction::I32Const(tag as i32));
+        self.emit(wasm::Instruction::I32Eq);
+    }
+
+    /// Call a function, leaving all its result values on the stack.
+    fn compile_call(&mut self, callee: &analyze::Symbol, arguments: &[analyze::Expression]) {
+        if let analyze::Symbol::Builtin(analyze::Builtin::Len) = callee {
+            if let analyze::Type::Tuple(element_types) = arguments[0].get_type() {
+                // The length of a tuple is known at compile time:
+                self.compile_discarded(&arguments[0]);
+                self.emit(wasm::Instruction::I32Const(element_types.len() as i32));
+                return;
+            }
+        }
+
+        for argument in arguments {
+            self.compile_expression(argument);
+        }
+
+        match callee {
+            analyze::Symbol::Function { .. } => {
+                let func = self.get_function_index(callee);
+                self.emit(wasm::Instruction::Call(func));
+                self.emit_exception_check();
+            }
+            analyze::Symbol::ExternFunction { index, .. } => {
+                let func = *index;
+                self.emit(wasm::Instruction::Call(func));
+            }
+            analyze::Symbol::Builtin(builtin) => match builtin {
+                analyze::Builtin::Ord => {
+                    unimplemented!();
+                }
+                analyze::Builtin::Len => {
+                    self.builtin_list_len();
+                }
+                analyze::Builtin::Range | analyze::Builtin::Enumerate => {
+                    panic!("{:?} can only be used in for loops", builtin);
+                }
+                analyze::Builtin::Set
+                | analyze::Builtin::Super
+                | analyze::Builtin::IsInstance
+                | analyze::Builtin::Print
+                | analyze::Builtin::Str
+                | analyze::Builtin::Repr
+                | analyze::Builtin::Dataclass
+                | analyze::Builtin::Enum
+                | analyze::Builtin::Final
+                | analyze::Builtin::Optional
+                | analyze::Builtin::Union
+                | analyze::Builtin::Callable
+                | analyze::Builtin::TypeVar => {
+                    panic!("{:?} is lowered by the analyzer", builtin);
+                }
+            },
+            _ => {
+                panic!("Cannot call this!");
+            }
+        };
+    }
+
+    /// Call a closure through the table, with its environment as first argument.
+    fn compile_closure_call(
+        &mut self,
+        closure: &analyze::Expression,
+        arguments: &[analyze::Expression],
+    ) {
+        let (params, results) = match closure.get_type() {
+            analyze::Type::Callable(parameter_types, result) => {
+                let mut params = vec![wasm::Type::I32];
+                params.extend(parameter_types.iter().map(|t| self.get_type(t)));
+                (params, self.get_result_types(result))
+            }
+            other => panic!("Not callable: {}", other),
+        };
+        let type_index = self.module.add_type(params, results);
+
+        let temporary = self.new_temporary(wasm::Type::I32);
+        self.compile_expression(closure);
+        self.emit(wasm::Instruction::LocalTee(temporary));
+        self.read_mem(CLOSURE_ENVIRONMENT, &wasm::Type::I32);
+        for argument in arguments {
+            self.compile_expression(argument);
+        }
+        self.emit(wasm::Instruction::LocalGet(temporary));
+        self.read_mem(CLOSURE_FUNCTION, &wasm::Type::I32);
+        self.emit(wasm::Instruction::CallIndirect(type_index));
+        self.emit_exception_check();
+    }
+
+    /// Call a method, using the vtable if the method is overridden by a subclass.
+    fn compile_method_call(&mut self, slot: usize, arguments: &[analyze::Expression]) {
+        let class_name = match arguments[0].get_type() {
+            analyze::Type::Class(name) => name.clone(),
+            other => panic!("Not a class type: {}", other),
+        };
+        if let Some(method) = self.get_static_method(&class_name, slot) {
+            self.compile_call(&method, arguments);
+            return;
+        }
+
+        let object = self.new_temporary(wasm::Type::I32);
+        self.compile_expression(&arguments[0]);
+        self.emit(wasm::Instruction::LocalSet(object));
+        self.emit(wasm::Instruction::LocalGet(object));
+        for argument in &arguments[1..] {
+            self.compile_expression(argument);
+        }
+        self.emit_vtable_call(&class_name, slot, object);
+    }
+
+    /// Find the method in a slot, if it is not overridden by a subclass.
+    fn get_static_method(&self, class_name: &str, slot: usize) -> Option<Rc<analyze::Symbol>> {
+        let (class_id, count) = self.class_ids[class_name];
+        let mut implementations: Vec<usize> = self
+            .classes
+            .values()
+            .filter(|c| {
+                let (id, _) = self.class_ids[&c.name];
+                id >= class_id && id < class_id + count
+            })
+            .map(|c| match c.methods[slot].function.as_ref() {
+                analyze::Symbol::Function { index, .. } => *index,
+                _ => panic!("Not a function"),
+            })
+            .collect();
+        implementations.sort_unstable();
+        implementations.dedup();
+        // Not overridden, so it can be called directly:
+        if implementations.len() == 1 {
+            Some(self.classes[class_name].methods[slot].function.clone())
+        } else {
+            None
+        }
+    }
+
+    /// Call a method through the vtable of the object in a local.
+    ///
+    /// The arguments, including the object, are on the stack.
+    fn emit_vtable_call(&mut self, class_name: &str, slot: usize, object: usize) {
+        let method = self.classes[class_name].methods[slot].function.clone();
+        let (params, results) = match method.as_ref() {
+            analyze::Symbol::Function { function, .. } => {
+                let params = function
+                    .parameters
+                    .iter()
+                    .map(|p| self.get_type(&p.typ))
+                    .collect();
+                let results = match &function.return_type {
+                    Some(t) => self.get_result_types(t),
+                    None => vec![],
+                };
+                (params, results)
+            }
+            _ => panic!("Not a function"),
+        };
+        let type_index = self.module.add_type(params, results);
+
+        self.emit(wasm::Instruction::LocalGet(object));
+        self.emit(wasm::Instruction::I32Load(2, OBJECT_CLASS_ID));
+        self.emit(wasm::Instruction::I32Const(self.vtable_stride as i32));
+        self.emit(wasm::Instruction::I32Mul);
+        self.emit(wasm::Instruction::I32Const(slot as i32));
+        self.emit(wasm::Instruction::I32Add);
+        self.emit(wasm::Instruction::CallIndirect(type_index));
+        self.emit_exception_check();
+        self.uses_vtables = true;
+    }
+
+    fn emit_comparison(&mut self, op: &ast::Comparison, typ: wasm::Type) {
+        match typ {
+            wasm::Type::I32 => match op {
+                ast::Comparison::Lt => {
+                    self.emit(wasm::Instruction::I32LtS);
+                }
+                ast::Comparison::Gt => {
+                    self.emit(wasm::Instruction::I32GtS);
+                }
+                ast::Comparison::Le => {
+                    self.emit(wasm::Instruction::I32LeS);
+                }
+                ast::Comparison::Ge => {
+                    self.emit(wasm::Instruction::I32GeS);
+                }
+                // References are the same when they point to the same memory:
+                ast::Comparison::Equal | ast::Comparison::Is => {
+                    self.emit(wasm::Instruction::I32Eq);
+                }
+                ast::Comparison::NotEqual | ast::Comparison::IsNot => {
+                    self.emit(wasm::Instruction::I32Ne);
+                }
+                ast::Comparison::In | ast::Comparison::NotIn => {
+                    panic!("Membership tests are analyzed as contains expressions");
+                }
+            },
+            wasm::Type::F64 => match op {
+                ast::Comparison::Lt => {
+                    self.emit(wasm::Instruction::F64Lt);
+                }
+                ast::Comparison::Gt => {
+                    self.emit(wasm::Instruction::F64Gt);
+                }
+                ast::Comparison::Le => {
+                    self.emit(wasm::Instruction::F64Le);
+                }
+                ast::Comparison::Ge => {
+                    self.emit(wasm::Instruction::F64Ge);
+                }
+                ast::Comparison::Equal => {
+                    self.emit(wasm::Instruction::F64Eq);
+                }
+                ast::Comparison::NotEqual => {
+                    self.emit(wasm::Instruction::F64Ne);
+                }
+                ast::Comparison::In | ast::Comparison::NotIn => {
+                    panic!("Membership tests are analyzed as contains expressions");
+                }
+                ast::Comparison::Is | ast::Comparison::IsNot => {
+                    panic!("Identity is only tested for references");
+                }
+            },
+        }
+    }
+
+    fn emit_arithmatic_operator(&mut self, op: &ast::BinaryOperation, typ: wasm::Type) {
+        match typ {
+            wasm::Type::I32 => match op {
+                ast::BinaryOperation::Add => {
+                    self.emit(wasm::Instruction::I32Add);
+                }
+                ast::BinaryOperation::Sub => {
+                    self.emit(wasm::Instruction::I32Sub);
+                }
+                ast::BinaryOperation::Mul => {
+                    self.emit(wasm::Instruction::I32Mul);
+                }
+                ast::BinaryOperation::Div => {
+                    self.emit(wasm::Instruction::I32DivS);
+                }
+                ast::BinaryOperation::BitOr => {
+                    self.emit(wasm::Instruction::I32Or);
+                }
+                ast::BinaryOperation::BitAnd => {
+                    self.emit(wasm::Instruction::I32And);
+                }
+            },
+            wasm::Type::F64 => match op {
+                ast::BinaryOperation::Add => {
+                    self.emit(wasm::Instruction::F64Add);
+                }
+                ast::BinaryOperation::Sub => {
+                    self.emit(wasm::Instruction::F64Sub);
+                }
+                ast::BinaryOperation::Mul => {
+                    self.emit(wasm::Instruction::F64Mul);
+                }
+                ast::BinaryOperation::Div => {
+                    self.emit(wasm::Instruction::F64Div);
+                }
+                ast::BinaryOperation::BitOr | ast::BinaryOperation::BitAnd => {
+                    panic!("Bitwise operations on floats are rejected by the analyzer");
+                }
+            },
+        }
+    }
+
+    fn store_value(&mut self, symbol: &analyze::Symbol, typ: &analyze::Type) {
+        match symbol {
+            analyze::Symbol::Local { local: _, index } => {
+                // assert!(typ == &local.typ);
+                self.emit(wasm::Instruction::LocalSet(*index));
+            }
+            analyze::Symbol::Parameter { parameter, index } => {
+                assert!(typ == &parameter.typ);
+                self.emit(wasm::Instruction::LocalSet(*index));
+            }
+            analyze::Symbol::Global { index, .. } => {
+                self.emit(wasm::Instruction::GlobalSet(*index));
+            }
+            analyze::Symbol::Cell {
+                cell,
+                environment,
+                depth,
+            } => {
+                let wasm_typ = self.get_type(&cell.typ);
+                let value = self.new_temporary(wasm_typ.clone());
+                self.emit(wasm::Instruction::LocalSet(value));
+                self.emit_environment(environment, *depth);
+                self.emit(wasm::Instruction::LocalGet(value));
+                self.write_mem(ENVIRONMENT_CELLS + cell.slot * CELL_SIZE, &wasm_typ);
+            }
+            analyze::Symbol::Function { .. }
+            | analyze::Symbol::ExternFunction { .. }
+            | analyze::Symbol::Builtin(..)
+            | analyze::Symbol::Type { .. }
+            | analyze::Symbol::Constant { .. }
+            | analyze::Symbol::GenericFunction(..)
+            | analyze::Symbol::TypeVariable => {
+                panic!("Cannot store to this");
+            }
+        }
+    }
+
+    fn get_local(&mut self, symbol: &analyze::Symbol) {
+        match symbol {
+            analyze::Symbol::Local { local: _, index } => {
+                self.emit(wasm::Instruction::LocalGet(*index));
+            }
+            analyze::Symbol::Parameter {
+                parameter: _,
+                index,
+            } => {
+                self.emit(wasm::Instruction::LocalGet(*index));
+            }
+            analyze::Symbol::Global { index, .. } => {
+                self.emit(wasm::Instruction::GlobalGet(*index));
+            }
+            analyze::Symbol::Cell {
+                cell,
+                environment,
+                depth,
+            } => {
+                self.emit_environment(environment, *depth);
+                let wasm_typ = self.get_type(&cell.typ);
+                self.read_mem(ENVIRONMENT_CELLS + cell.slot * CELL_SIZE, &wasm_typ);
+            }
+            analyze::Symbol::Function { .. }
+            | analyze::Symbol::ExternFunction { .. }
+            | analyze::Symbol::Builtin(..)
+            | analyze::Symbol::Type { .. }
+            | analyze::Symbol::Constant { .. }
+            | analyze::Symbol::GenericFunction(..)
+            | analyze::Symbol::TypeVariable => {
+                panic!("Cannot load from this");
+            }
+        }
+    }
+
+    /// Load the environment with a cell, by following the parent links.
+    fn emit_environment(&mut self, environment: &analyze::Symbol, depth: usize) {
+        self.get_local(environment);
+        for _ in 0..depth {
+            self.read_mem(ENVIRONMENT_PARENT, &wasm::Type::I32);
+        }
+    }
+
+    /// Emit code to allocate some memory, and leave pointer on stack.
+    fn allocate(&mut self, amount: usize) {
+        debug!("Allocating {} bytes", amount);
+        self.emit(wasm::Instruction::I32Const(amount as i32));
+        self.call_runtime(RuntimeFunction::Allocate);
+    }
+
+    /// Write top of stack (TOS) to memory at TOS[-1] + offset
+    fn write_mem(&mut self, offset: usize, typ: &wasm::Type) {
+        match typ {
+            wasm::Type::F64 => {
+                self.emit(wasm::Instruction::F64Store(3, offset));
+            }
+            wasm::Type::I32 => {
+                self.emit(wasm::Instruction::I32Store(2, offset));
+            }
+        }
+    }
+
+    fn read_mem(&mut self, offset: usize, typ: &wasm::Type) {
+        match typ {
+            wasm::Type::F64 => {
+                self.emit(wasm::Instruction::F64Load(3, offset));
+            }
+            wasm::Type::I32 => {
+                self.emit(wasm::Instruction::I32Load(2, offset));
+            }
+        }
+    }
+
+    fn emit(&mut self, opcode: wasm::Instruction) {
+        // info!("Emit: {:?}", opcode);
+        match opcode {
+            wasm::Instruction::Block
+            | wasm::Instruction::Loop
+            | wasm::Instruction::If
+            | wasm::Instruction::Try => self.depth += 1,
+            wasm::Instruction::End => self.depth -= 1,
+            _ => {}
+        }
+        self.code.push(opcode);
+    }
+}
+
+/// Determine the lowest number and the size of a jump table for the cases of a
+/// match statement, if the cases only compare the subject with enough numbers.
+/// Only the last case can do something else, when it matches anything.
+fn get_jump_table(cases: &[analyze::MatchCase]) -> Option<(i32, usize)> {
+    let cases = match cases.split_last() {
+        Some((last, others))
+            if last.values.is_none() && last.condition.is_none() && last.guard.is_none() =>
+        {
+            others
+        }
+        _ => cases,
+    };
+    let mut values = vec![];
+    for case in cases {
+        values.extend(case.values.as_ref()?);
+    }
+    let lowest = *values.iter().min()?;
+    let highest = *values.iter().max()?;
+    let size = (highest as i64 - lowest as i64 + 1) as usize;
+    if values.len() < MIN_JUMP_TABLE_VALUES || size > 2 * values.len() {
+        return None;
+    }
+    Some((lowest, size))
+}
+
+/// Number classes depth first, so that a class and its subclasses have consecutive ids.
+///
+/// Returns the id of each class, and the number of classes in its subtree.
+fn number_classes(classes: &[Rc<analyze::Class>]) -> HashMap<String, (usize, usize)> {
+    fn visit(
+        base: Option<&str>,
+        classes: &[Rc<analyze::Class>],
+        ids: &mut HashMap<String, (usize, usize)>,
+    ) {
+        for class in classes.iter().filter(|c| c.base.as_deref() == base) {
+            let id = ids.len();
+            ids.insert(class.name.clone(), (id, 0));
+            visit(Some(&class.name), classes, ids);
+            let count = ids.len() - id;
+            ids.insert(class.name.clone(), (id, count));
+        }
+    }
+
+    let mut ids = HashMap::new();
+    visit(None, classes, &mut ids);
+    ids
+}
+
+fn round_to_multiple_of(value: usize, alignment: usize) -> usize {
+    let remaining = value % alignment;
+    if remaining > 0 {
+        value + alignment - remaining
+    } else {
+        value
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::{analyze, compile_ast, number_classes, round_to_multiple_of, wasm};
+    use crate::parser::parse_python;
+    use crate::CompileOptions;
+    use std::rc::Rc;
+
+    /// Compile a program, and find where a function first calls each of the given functions.
+    fn call_positions(source: &str, caller: &str, callees: &[&str]) -> Vec<usize> {
+        let prog = parse_python(source).expect("Ok");
+        let module = compile_ast(prog, &CompileOptions::default()).expect("Ok");
+        let (_, code) = module.get_function(caller).expect("Caller");
+        callees
+            .iter()
+            .map(|callee| {
+                let (index, _) = module.get_function(callee).expect("Callee");
+                code.iter()
+                    .position(|i| matches!(i, wasm::Instruction::Call(f) if *f == index))
+                    .expect("Call")
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_round_to_multiple_of() {
+        assert_eq!(16, round_to_multiple_of(9, 8));
+        assert_eq!(8, round_to_multiple_of(8, 4));
+        assert_eq!(21, round_to_multiple_of(20, 7));
+        assert_eq!(0, round_to_multiple_of(0, 3));
+    }
+
+    #[test]
+    fn test_number_classes() {
+        let class = |name: &str, base: Option<&str>| {
+            Rc::new(analyze::Class {
+                name: name.to_owned(),
+                base: base.map(|b| b.to_owned()),
+                fields: vec![],
+                methods: vec![],
+                match_args: vec![],
+            })
+        };
+        // C is defined between A and its subclass B:
+        let classes = vec![class("A", None), class("C", None), class("B", Some("A"))];
+        let ids = number_classes(&classes);
+        assert_eq!((0, 2), ids["A"]);
+        assert_eq!((1, 1), ids["B"]);
+        assert_eq!((2, 1), ids["C"]);
+    }
+
+    #[test]
+    fn test_dict_item_assignment_order() {
+        // The value may look into the dictionary, so the key is added after it:
+        let source = r###"
+def count(d: dict[str, int], k: str):
+    d[k] = d.get(k, 5) + 1
+"###;
+        let calls = call_positions(source, "count", &["dict_lookup_str", "dict_insert_str"]);
+        assert!(calls[0] < calls[1]);
+    }
+
+    #[test]
+    fn test_assignment_order() {
+        // Python evaluates the value before the target:
+        let source = r###"
+def value() -> int:
+    return 1
+
+def values() -> list[int]:
+    return [1]
+
+def index() -> int:
+    return 0
+
+def store(a: list[int]):
+    a[index()] = value()
+
+def store_slice(a: list[int]):
+    a[index():] = values()
+"###;
+        let calls = call_positions(source, "store", &["value", "index"]);
+        assert!(calls[0] < calls[1]);
+        let calls = call_positions(source, "store_slice", &["values", "index"]);
+        assert!(calls[0] < calls[1]);
+    }
+
+    #[test]
+    fn test_prelude_only_when_raising() {
+        let compile = |source: &str| {
+            let prog = parse_python(source).expect("Ok");
+            compile_ast(prog, &CompileOptions::default()).expect("Ok")
+        };
+        let module = compile(
+            r###"
+class A:
+    def f(self) -> int:
+        return 1
+
+def g(a: A) -> int:
+    return a.f()
+"###,
+        );
+        assert!(module.get_function("g").is_some());
+        assert!(module.get_function("Exception.__init__").is_none());
+        assert!(module.get_function("str_repr").is_none());
+
+        let module = compile(
+            r###"
+def g(x: int) -> int:
+    if x < 0:
+        raise ValueError('negative')
+    else:
+        return x
+"###,
+        );
+        assert!(module.get_function("Exception.__init__").is_some());
+    }
+}
@@ -0,0 +1,890 @@
+//! Runtime support functions.
+//!
+//! Some operations are too large to emit inline, such as slicing a list.
+//! These are implemented as helper functions in the generated module.
+//! A helper function is only added to the module when it is used.
+
+use super::hashtable::{KeyKind, SetOperator};
+use super::{analyze, wasm, Compiler, OBJECT_CLASS_ID};
+
+/// Marker for a missing slice bound, such as the upper bound in `a[1:]`.
+///
+/// This is also the smallest possible index, so a real index with
+/// this value is only interpreted differently for negative steps.
+pub const SLICE_NONE: i32 = i32::MIN;
+
+/// Helper functions which are added to the module when they are used.
+#[derive(Clone, Copy, PartialEq)]
+pub enum RuntimeFunction {
+    /// Raise an IndexError, for an index which is out of range.
+    IndexError,
+
+    /// Raise a ValueError, for an invalid value such as a slice step of zero.
+    ValueError,
+
+    /// Raise a KeyError, for a key which is not in a dictionary.
+    KeyError,
+
+    /// Raise a ValueError, for a list assigned to a slice of another length.
+    SliceSizeError,
+
+    /// Divide two integers, raise ZeroDivisionError for a divisor of zero,
+    /// and OverflowError when the result does not fit in an int.
+    IntDivide,
+
+    /// Grab an amount of memory, and return a pointer to it.
+    Allocate,
+
+    /// Copy a number of bytes from one place to another.
+    CopyMemory,
+
+    /// Check an index into a list or tuple, and return the
+    /// address of the element relative to the data start.
+    IndexSequence,
+
+    /// Translate a negative index, without checking it against the
+    /// length, for when bounds checks are disabled.
+    IndexSequenceUnchecked,
+
+    /// Fill in a missing slice bound, and clip it to the sequence length.
+    SliceAdjust,
+
+    /// Determine the number of elements selected by a slice.
+    SliceLength,
+
+    /// Create a new sequence from a slice of a sequence.
+    SliceSequence,
+
+    /// Overwrite a slice of a list with elements from another list.
+    SliceAssign,
+
+    /// Compute the hash of a key, which is never negative.
+    Hash(KeyKind),
+
+    /// Compare the contents of two strings.
+    StrEqual,
+
+    /// Create an empty dictionary.
+    DictNew,
+
+    /// Make room for more entries in a dictionary.
+    DictResize,
+
+    /// Find the slot in the index table of a dictionary for a key.
+    DictFind(KeyKind),
+
+    /// Return the entry for a key, or 0 when the key is not present.
+    DictLookup(KeyKind),
+
+    /// Return the entry for a key, signal a key error when the key is not present.
+    DictItem(KeyKind),
+
+    /// Return the entry for a key, add an entry when the key is not present.
+    DictInsert(KeyKind),
+
+    /// Remove a key from a dictionary.
+    DictDelete(KeyKind),
+
+    /// Remove an element from a set, if it is present.
+    SetDiscard(KeyKind),
+
+    /// Create a new set from two sets.
+    SetOperation(SetOperator, KeyKind),
+
+    /// Write a fixed number of decimal digits of an unsigned integer.
+    WriteDigits,
+
+    /// Look up a power of ten as a float.
+    Pow10,
+
+    /// Convert an integer to a string.
+    IntToStr,
+
+    /// Convert a float to a string, with the shortest digits that read back as the same value.
+    FloatToStr,
+
+    /// Quote a string, as repr does.
+    StrRepr,
+
+    /// Join two strings into a new string.
+    StrConcat,
+
+    /// A method of a builtin class from the prelude, such as `Exception.__str__`,
+    /// by its index in the functions of the program.
+    Method(usize),
+}
+
+impl RuntimeFunction {
+    /// Whether the function can raise an exception, which the caller
+    /// checks for when exceptions are lowered to a global.
+    pub fn can_raise(self) -> bool {
+        matches!(
+            self,
+            RuntimeFunction::IndexError
+                | RuntimeFunction::ValueError
+                | RuntimeFunction::KeyError
+                | RuntimeFunction::SliceSizeError
+                | RuntimeFunction::IntDivide
+                | RuntimeFunction::IndexSequence
+                | RuntimeFunction::SliceSequence
+                | RuntimeFunction::SliceAssign
+                | RuntimeFunction::DictItem(_)
+                | RuntimeFunction::DictDelete(_)
+        )
+    }
+}
+
+impl Compiler {
+    /// Emit a call to a runtime function, and schedule it for inclusion in the module.
+    pub(super) fn call_runtime(&mut self, function: RuntimeFunction) {
+        let func = self.get_runtime_function_index(function);
+        self.emit(wasm::Instruction::Call(func));
+        if function.can_raise() {
+            self.emit_exception_check();
+        }
+    }
+
+    /// Find the function index of a runtime function, and schedule it for inclusion in the module.
+    pub(super) fn get_runtime_function_index(&mut self, function: RuntimeFunction) -> usize {
+        let index = match self.runtime_functions.iter().position(|f| *f == function) {
+            Some(index) => index,
+            None => {
+                self.runtime_functions.push(function);
+                self.runtime_functions.len() - 1
+            }
+        };
+        self.runtime_offset + index
+    }
+
+    pub(super) fn compile_runtime_functions(&mut self) {
+        // Runtime functions can use other runtime functions, so the list may grow:
+        let mut index = 0;
+        while index < self.runtime_functions.len() {
+            self.compile_runtime_function(self.runtime_functions[index]);
+            index += 1;
+        }
+    }
+
+    fn compile_runtime_function(&mut self, function: RuntimeFunction) {
+        let int = || wasm::Type::I32;
+        let (name, params, results, locals) = match function {
+            RuntimeFunction::IndexError => {
+                self.compile_raise_error("IndexError", "index out of range", 0, None);
+                ("index_error".to_owned(), vec![], vec![], vec![int()])
+            }
+            RuntimeFunction::ValueError => {
+                self.compile_raise_error("ValueError", "step cannot be zero", 0, None);
+                ("value_error".to_owned(), vec![], vec![], vec![int()])
+            }
+            RuntimeFunction::KeyError => {
+                self.compile_raise_error("KeyError", "key not found", 0, None);
+                ("key_error".to_owned(), vec![], vec![], vec![int()])
+            }
+            RuntimeFunction::SliceSizeError => {
+                self.compile_slice_size_error();
+                (
+                    "slice_size_error".to_owned(),
+                    vec![int(); 2],
+                    vec![],
+                    vec![int()],
+                )
+            }
+            RuntimeFunction::IntDivide => {
+                self.compile_int_divide();
+                (
+                    "int_divide".to_owned(),
+                    vec![int(); 3],
+                    vec![int()],
+                    vec![int()],
+                )
+            }
+            RuntimeFunction::Allocate => {
+                self.compile_allocate();
+                ("allocate".to_owned(), vec![int()], vec![int()], vec![])
+            }
+            RuntimeFunction::CopyMemory => {
+                self.compile_copy_memory();
+                (
+                    "copy_memory".to_owned(),
+                    vec![int(), int(), int()],
+                    vec![],
+                    vec![],
+                )
+            }
+            RuntimeFunction::IndexSequence => {
+                self.compile_index_sequence(true);
+                (
+                    "index_sequence".to_owned(),
+                    vec![int(); 3],
+                    vec![int()],
+                    vec![],
+                )
+            }
+            RuntimeFunction::IndexSequenceUnchecked => {
+                self.compile_index_sequence(false);
+                (
+                    "index_sequence_unchecked".to_owned(),
+                    vec![int(); 3],
+                    vec![int()],
+                    vec![],
+                )
+            }
+            RuntimeFunction::SliceAdjust => {
+                self.compile_slice_adjust();
+                (
+                    "slice_adjust".to_owned(),
+                    vec![int(); 4],
+                    vec![int()],
+                    vec![],
+                )
+            }
+            RuntimeFunction::SliceLength => {
+                self.compile_slice_length();
+                (
+                    "slice_length".to_owned(),
+                    vec![int(); 3],
+                    vec![int()],
+                    vec![int()],
+                )
+            }
+            RuntimeFunction::SliceSequence => {
+                self.compile_slice_sequence();
+                (
+                    "slice_sequence".to_owned(),
+                    vec![int(); 5],
+                    vec![int()],
+                    vec![int(); 4],
+                )
+            }
+            RuntimeFunction::SliceAssign => {
+                self.compile_slice_assign();
+                (
+                    "slice_assign".to_owned(),
+                    vec![int(); 6],
+                    vec![],
+                    vec![int(); 3],
+                )
+            }
+            RuntimeFunction::Hash(kind) => {
+                self.compile_hash(kind);
+                let name = format!("hash_{}", kind.name());
+                let locals = match kind {
+                    KeyKind::Int => vec![],
+                    KeyKind::Str => vec![int(); 3],
+                };
+                (name, vec![int()], vec![int()], locals)
+            }
+            RuntimeFunction::StrEqual => {
+                self.compile_str_equal();
+                (
+                    "str_equal".to_owned(),
+                    vec![int(); 2],
+                    vec![int()],
+                    vec![int(); 2],
+                )
+            }
+            RuntimeFunction::DictNew => {
+                self.compile_dict_new();
+                ("dict_new".to_owned(), vec![], vec![int()], vec![int()])
+            }
+            RuntimeFunction::DictResize => {
+                self.compile_dict_resize();
+                (
+                    "dict_resize".to_owned(),
+                    vec![int()],
+                    vec![],
+                    vec![int(); 10],
+                )
+            }
+            RuntimeFunction::DictFind(kind) => {
+                self.compile_dict_find(kind);
+                let name = format!("dict_find_{}", kind.name());
+                (name, vec![int(); 3], vec![int()], vec![int(); 5])
+            }
+            RuntimeFunction::DictLookup(kind) => {
+                self.compile_dict_lookup(kind);
+                let name = format!("dict_lookup_{}", kind.name());
+                (name, vec![int(); 2], vec![int()], vec![int(); 2])
+            }
+            RuntimeFunction::DictItem(kind) => {
+                self.compile_dict_item(kind);
+                let name = format!("dict_item_{}", kind.name());
+                (name, vec![int(); 2], vec![int()], vec![int()])
+            }
+            RuntimeFunction::DictInsert(kind) => {
+                self.compile_dict_insert(kind);
+                let name = format!("dict_insert_{}", kind.name());
+                (name, vec![int(); 2], vec![int()], vec![int(); 4])
+            }
+            RuntimeFunction::DictDelete(kind) => {
+                self.compile_dict_delete(kind, false);
+                let name = format!("dict_delete_{}", kind.name());
+                (name, vec![int(); 2], vec![], vec![int(); 3])
+            }
+            RuntimeFunction::SetDiscard(kind) => {
+                self.compile_dict_delete(kind, true);
+                let name = format!("set_discard_{}", kind.name());
+                (name, vec![int(); 2], vec![], vec![int(); 3])
+            }
+            RuntimeFunction::SetOperation(op, kind) => {
+                self.compile_set_operation(op, kind);
+                let name = format!("set_{}_{}", op.name(), kind.name());
+                (name, vec![int(); 2], vec![int()], vec![int(); 4])
+            }
+            RuntimeFunction::WriteDigits => {
+                self.compile_write_digits();
+                ("write_digits".to_owned(), vec![int(); 3], vec![], vec![])
+            }
+            RuntimeFunction::Pow10 => {
+                self.compile_pow10();
+                (
+                    "pow10".to_owned(),
+                    vec![int()],
+                    vec![wasm::Type::F64],
+                    vec![],
+                )
+            }
+            RuntimeFunction::IntToStr => {
+                self.compile_int_to_str();
+                (
+                    "int_to_str".to_owned(),
+                    vec![int()],
+                    vec![int()],
+                    vec![int(); 5],
+                )
+            }
+            RuntimeFunction::FloatToStr => {
+                self.compile_float_to_str();
+                let mut locals = vec![wasm::Type::F64; 3];
+                locals.extend(vec![int(); 10]);
+                (
+                    "float_to_str".to_owned(),
+                    vec![wasm::Type::F64],
+                    vec![int()],
+                    locals,
+                )
+            }
+            RuntimeFunction::StrRepr => {
+                self.compile_str_repr();
+                (
+                    "str_repr".to_owned(),
+                    vec![int()],
+                    vec![int()],
+                    vec![int(); 8],
+                )
+            }
+            RuntimeFunction::StrConcat => {
+                self.compile_str_concat();
+                (
+                    "str_concat".to_owned(),
+                    vec![int(); 2],
+                    vec![int()],
+                    vec![int(); 3],
+                )
+            }
+            RuntimeFunction::Method(index) => {
+                // A method is compiled like any other function:
+                let method = self.prelude[index].clone();
+                self.compile_function(&method);
+                return;
+            }
+        };
+
+        let code = self.take_code(&results);
+        self.module
+            .add_function(name, params, results, locals, code);
+    }
+
+    /// Raise a new exception of a builtin class, with a fixed message, and
+    /// the line in a local when it is known. The exception is built in a local.
+    fn compile_raise_error(
+        &mut self,
+        class: &str,
+        message: &str,
+        exception: usize,
+        line: Option<usize>,
+    ) {
+        let message = self.add_string(message);
+        self.compile_raise_message(class, exception, line, |c| {
+            c.emit(wasm::Instruction::I32Const(message as i32));
+        });
+    }
+
+    /// Raise a new exception of a builtin class, like `compile_raise_error`,
+    /// with a message string which is produced by the given function.
+    fn compile_raise_message<F>(
+        &mut self,
+        class: &str,
+        exception: usize,
+        line: Option<usize>,
+        message: F,
+    ) where
+        F: FnOnce(&mut Self),
+    {
+        let typ = analyze::Type::Class(class.to_owned());
+        let (offsets, size) = self.get_object_layout(&typ);
+        let (class_id, _) = self.get_class_id(&typ);
+
+        self.allocate(size);
+        self.emit(wasm::Instruction::LocalSet(exception));
+        self.emit(wasm::Instruction::LocalGet(exception));
+        self.emit(wasm::Instruction::I32Const(class_id as i32));
+        self.emit(wasm::Instruction::I32Store(2, OBJECT_CLASS_ID));
+        self.emit(wasm::Instruction::LocalGet(exception));
+        message(self);
+        self.emit(wasm::Instruction::I32Store(2, offsets[0]));
+        if let Some(line) = line {
+            self.emit(wasm::Instruction::LocalGet(exception));
+            self.emit(wasm::Instruction::LocalGet(line));
+            self.emit(wasm::Instruction::I32Store(2, self.get_lineno_offset()));
+        }
+        self.emit(wasm::Instruction::LocalGet(exception));
+        self.emit_raise();
+    }
+
+    /// Raise a ValueError which tells the size of the assigned list and of the slice.
+    fn compile_slice_size_error(&mut self) {
+        // Parameters: size of the value, size of the slice
+        let (value_size, slice_size) = (0, 1);
+        let exception = 2;
+
+        self.compile_raise_message("ValueError", exception, None, |c| {
+            let parts = [
+                ("attempt to assign sequence of size ", value_size),
+                (" to extended slice of size ", slice_size),
+            ];
+            for &(part, size) in parts.iter() {
+                let part = c.add_string(part);
+                c.emit(wasm::Instruction::I32Const(part as i32));
+                c.emit(wasm::Instruction::LocalGet(size));
+                c.call_runtime(RuntimeFunction::IntToStr);
+                c.call_runtime(RuntimeFunction::StrConcat);
+            }
+            c.call_runtime(RuntimeFunction::StrConcat);
+        });
+    }
+
+    /// Divide a by b, like i32.div_s, but raise an exception where it would trap.
+    fn compile_int_divide(&mut self) {
+        let a = 0;
+        let b = 1;
+        let line = 2;
+        let exception = 3;
+
+        self.emit(wasm::Instruction::LocalGet(b));
+        self.emit(wasm::Instruction::I32Eqz);
+        self.emit(wasm::Instruction::If);
+        self.compile_raise_error(
+            "ZeroDivisionError",
+            "division by zero",
+            exception,
+            Some(line),
+        );
+        self.emit(wasm::Instruction::End);
+
+        // The result of i32::MIN / -1 is one more than i32::MAX:
+        self.emit(wasm::Instruction::LocalGet(a));
+        self.emit(wasm::Instruction::I32Const(i32::MIN));
+        self.emit(wasm::Instruction::I32Eq);
+        self.emit(wasm::Instruction::LocalGet(b));
+        self.emit(wasm::Instruction::I32Const(-1));
+        self.emit(wasm::Instruction::I32Eq);
+        self.emit(wasm::Instruction::I32And);
+        self.emit(wasm::Instruction::If);
+        self.compile_raise_error(
+            "OverflowError",
+            "integer division result too large",
+            exception,
+            Some(line),
+        );
+        self.emit(wasm::Instruction::End);
+
+        self.emit(wasm::Instruction::LocalGet(a));
+        self.emit(wasm::Instruction::LocalGet(b));
+        self.emit(wasm::Instruction::I32DivS);
+    }
+
+    /// Simple implementation of malloc which only increments memory.
+    ///
+    /// The pointer to free memory is stored at address 0.
+    fn compile_allocate(&mut self) {
+        let amount = 0;
+
+        // Round amount to multiple of 8 bytes:
+        self.emit(wasm::Instruction::LocalGet(amount));
+        self.emit(wasm::Instruction::I32Const(7));
+        self.emit(wasm::Instruction::I32Add);
+        self.emit(wasm::Instruction::I32Const(-8));
+        self.emit(wasm::Instruction::I32And);
+        self.emit(wasm::Instruction::LocalSet(amount));
+
+        // Current value on stack:
+        self.emit(wasm::Instruction::I32Const(0));
+        self.emit(wasm::Instruction::I32Load(2, 0));
+
+        // Increment free memory base:
+        self.emit(wasm::Instruction::I32Const(0));
+        self.emit(wasm::Instruction::I32Const(0));
+        self.emit(wasm::Instruction::I32Load(2, 0));
+        self.emit(wasm::Instruction::LocalGet(amount));
+        self.emit(wasm::Instruction::I32Add);
+        self.emit(wasm::Instruction::I32Store(2, 0));
+
+        // Add header size (aligned to 8 bytes):
+        self.emit(wasm::Instruction::I32Const(8));
+        self.emit(wasm::Instruction::I32Add);
+    }
+
+    fn compile_copy_memory(&mut self) {
+        // Parameters: destination, source, size
+        let (destination, source, size) = (0, 1, 2);
+
+        // Copy byte by byte, from the end to the start:
+        self.emit(wasm::Instruction::Block);
+        self.emit(wasm::Instruction::Loop);
+        self.emit(wasm::Instruction::LocalGet(size));
+        self.emit(wasm::Instruction::I32Eqz);
+        self.emit(wasm::Instruction::BrIf(1));
+
+        self.emit(wasm::Instruction::LocalGet(size));
+        self.emit(wasm::Instruction::I32Const(1));
+        self.emit(wasm::Instruction::I32Sub);
+        self.emit(wasm::Instruction::LocalSet(size));
+
+        self.emit(wasm::Instruction::LocalGet(destination));
+        self.emit(wasm::Instruction::LocalGet(size));
+        self.emit(wasm::Instruction::I32Add);
+        self.emit(wasm::Instruction::LocalGet(source));
+        self.emit(wasm::Instruction::LocalGet(size));
+        self.emit(wasm::Instruction::I32Add);
+        self.emit(wasm::Instruction::I32Load8U(0, 0));
+        self.emit(wasm::Instruction::I32Store8(0, 0));
+        self.emit(wasm::Instruction::Br(0));
+        self.emit(wasm::Instruction::End);
+        self.emit(wasm::Instruction::End);
+    }
+
+    fn compile_index_sequence(&mut self, checked: bool) {
+        // Parameters: sequence, index, element size
+        let (sequence, index, element_size) = (0, 1, 2);
+
+        // Negative indices count from the end:
+        self.emit(wasm::Instruction::LocalGet(index));
+        self.emit(wasm::Instruction::I32Const(0));
+        self.emit(wasm::Instruction::I32LtS);
+        self.emit(wasm::Instruction::If);
+        self.emit(wasm::Instruction::LocalGet(index));
+        self.emit(wasm::Instruction::LocalGet(sequence));
+        self.builtin_list_len();
+        self.emit(wasm::Instruction::I32Add);
+        self.emit(wasm::Instruction::LocalSet(index));
+        self.emit(wasm::Instruction::End);
+
+        if checked {
+            // Unsigned compare, so that indices which are still negative fail too:
+            self.emit(wasm::Instruction::LocalGet(index));
+            self.emit(wasm::Instruction::LocalGet(sequence));
+            self.builtin_list_len();
+            self.emit(wasm::Instruction::I32GeU);
+            self.emit(wasm::Instruction::If);
+            self.call_runtime(RuntimeFunction::IndexError);
+            self.emit(wasm::Instruction::End);
+        }
+
+        self.emit(wasm::Instruction::LocalGet(sequence));
+        self.emit(wasm::Instruction::LocalGet(index));
+        self.emit(wasm::Instruction::LocalGet(element_size));
+        self.emit(wasm::Instruction::I32Mul);
+        self.emit(wasm::Instruction::I32Add);
+    }
+
+    /// Same rules as PySlice_AdjustIndices in CPython.
+    fn compile_slice_adjust(&mut self) {
+        // Parameters: index, length, step, is start (1) or stop (0)
+        let (index, length, step, is_start) = (0, 1, 2, 3);
+
+        // A missing bound is the begin or end of the sequence,
+        // depending on the direction of the step:
+        self.emit(wasm::Instruction::LocalGet(index));
+        self.emit(wasm::Instruction::I32Const(SLICE_NONE));
+        self.emit(wasm::Instruction::I32Eq);
+        self.emit(wasm::Instruction::If);
+        self.emit(wasm::Instruction::I32Const(i32::MAX));
+        self.emit(wasm::Instruction::I32Const(i32::MIN));
+        self.emit_is_negative(step);
+        self.emit(wasm::Instruction::LocalGet(is_start));
+        self.emit(wasm::Instruction::I32Eq);
+        self.emit(wasm::Instruction::Select);
+        self.emit(wasm::Instruction::LocalSet(index));
+        self.emit(wasm::Instruction::End);
+
+        // Clip to the sequence:
+        self.emit_is_negative(index);
+        self.emit(wasm::Instruction::If);
+        self.emit(wasm::Instruction::LocalGet(index));
+        self.emit(wasm::Instruction::LocalGet(length));
+        self.emit(wasm::Instruction::I32Add);
+        self.emit(wasm::Instruction::LocalSet(index));
+        self.emit_is_negative(index);
+        self.emit(wasm::Instruction::If);
+        self.emit(wasm::Instruction::I32Const(-1));
+        self.emit(wasm::Instruction::I32Const(0));
+        self.emit_is_negative(step);
+        self.emit(wasm::Instruction::Select);
+        self.emit(wasm::Instruction::LocalSet(index));
+        self.emit(wasm::Instruction::End);
+        self.emit(wasm::Instruction::Else);
+        self.emit(wasm::Instruction::LocalGet(index));
+        self.emit(wasm::Instruction::LocalGet(length));
+        self.emit(wasm::Instruction::I32GeS);
+        self.emit(wasm::Instruction::If);
+        self.emit(wasm::Instruction::LocalGet(length));
+        self.emit(wasm::Instruction::I32Const(1));
+        self.emit(wasm::Instruction::I32Sub);
+        self.emit(wasm::Instruction::LocalGet(length));
+        self.emit_is_negative(step);
+        self.emit(wasm::Instruction::Select);
+        self.emit(wasm::Instruction::LocalSet(index));
+        self.emit(wasm::Instruction::End);
+        self.emit(wasm::Instruction::End);
+
+        self.emit(wasm::Instruction::LocalGet(index));
+    }
+
+    fn compile_slice_length(&mut self) {
+        // Parameters: start, stop, step
+        let (start, stop, step) = (0, 1, 2);
+        let count = 3;
+
+        self.emit_is_negative(step);
+        self.emit(wasm::Instruction::If);
+
+        // (start - stop - 1) / -step + 1
+        self.emit(wasm::Instruction::LocalGet(stop));
+        self.emit(wasm::Instruction::LocalGet(start));
+        self.emit(wasm::Instruction::I32LtS);
+        self.emit(wasm::Instruction::If);
+        self.emit(wasm::Instruction::LocalGet(start));
+        self.emit(wasm::Instruction::LocalGet(stop));
+        self.emit(wasm::Instruction::I32Sub);
+        self.emit(wasm::Instruction::I32Const(1));
+        self.emit(wasm::Instruction::I32Sub);
+        self.emit(wasm::Instruction::I32Const(0));
+        self.emit(wasm::Instruction::LocalGet(step));
+        self.emit(wasm::Instruction::I32Sub);
+        self.emit(wasm::Instruction::I32DivS);
+        self.emit(wasm::Instruction::I32Const(1));
+        self.emit(wasm::Instruction::I32Add);
+        self.emit(wasm::Instruction::LocalSet(count));
+        self.emit(wasm::Instruction::End);
+
+        self.emit(wasm::Instruction::Else);
+
+        // (stop - start - 1) / step + 1
+        self.emit(wasm::Instruction::LocalGet(start));
+        self.emit(wasm::Instruction::LocalGet(stop));
+        self.emit(wasm::Instruction::I32LtS);
+        self.emit(wasm::Instruction::If);
+        self.emit(wasm::Instruction::LocalGet(stop));
+        self.emit(wasm::Instruction::LocalGet(start));
+        self.emit(wasm::Instruction::I32Sub);
+        self.emit(wasm::Instruction::I32Const(1));
+        self.emit(wasm::Instruction::I32Sub);
+        self.emit(wasm::Instruction::LocalGet(step));
+        self.emit(wasm::Instruction::I32DivS);
+        self.emit(wasm::Instruction::I32Const(1));
+        self.emit(wasm::Instruction::I32Add);
+        self.emit(wasm::Instruction::LocalSet(count));
+        self.emit(wasm::Instruction::End);
+
+        self.emit(wasm::Instruction::End);
+
+        self.emit(wasm::Instruction::LocalGet(count));
+    }
+
+    /// Check the step of a slice, and turn start and stop into
+    /// positions in the sequence. Also determine the data start.
+    ///
+    /// Returns the number of selected elements on the stack.
+    fn emit_slice_prologue(
+        &mut self,
+        sequence: usize,
+        start: usize,
+        stop: usize,
+        step: usize,
+        element_size: usize,
+        data_start: usize,
+    ) {
+        self.emit(wasm::Instruction::LocalGet(step));
+        self.emit(wasm::Instruction::I32Eqz);
+        self.emit(wasm::Instruction::If);
+        self.call_runtime(RuntimeFunction::ValueError);
+        self.emit(wasm::Instruction::End);
+
+        // Elements are aligned to their size, but at least behind the length:
+        self.emit(wasm::Instruction::LocalGet(element_size));
+        self.emit(wasm::Instruction::I32Const(4));
+        self.emit(wasm::Instruction::LocalGet(element_size));
+        self.emit(wasm::Instruction::I32Const(4));
+        self.emit(wasm::Instruction::I32GtS);
+        self.emit(wasm::Instruction::Select);
+        self.emit(wasm::Instruction::LocalSet(data_start));
+
+        for (index, is_start) in &[(start, 1), (stop, 0)] {
+            self.emit(wasm::Instruction::LocalGet(*index));
+            self.emit(wasm::Instruction::LocalGet(sequence));
+            self.builtin_list_len();
+            self.emit(wasm::Instruction::LocalGet(step));
+            self.emit(wasm::Instruction::I32Const(*is_start));
+            self.call_runtime(RuntimeFunction::SliceAdjust);
+            self.emit(wasm::Instruction::LocalSet(*index));
+        }
+
+        self.emit(wasm::Instruction::LocalGet(start));
+        self.emit(wasm::Instruction::LocalGet(stop));
+        self.emit(wasm::Instruction::LocalGet(step));
+        self.call_runtime(RuntimeFunction::SliceLength);
+    }
+
+    /// Emit the address of element start + i * step of a sequence.
+    fn emit_slice_element(
+        &mut self,
+        sequence: usize,
+        start: usize,
+        step: usize,
+        i: usize,
+        element_size: usize,
+        data_start: usize,
+    ) {
+        self.emit(wasm::Instruction::LocalGet(sequence));
+        self.emit(wasm::Instruction::LocalGet(data_start));
+        self.emit(wasm::Instruction::I32Add);
+        self.emit(wasm::Instruction::LocalGet(start));
+        self.emit(wasm::Instruction::LocalGet(i));
+        self.emit(wasm::Instruction::LocalGet(step));
+        self.emit(wasm::Instruction::I32Mul);
+        self.emit(wasm::Instruction::I32Add);
+        self.emit(wasm::Instruction::LocalGet(element_size));
+        self.emit(wasm::Instruction::I32Mul);
+        self.emit(wasm::Instruction::I32Add);
+    }
+
+    /// Emit a loop over i from 0 to count, the body is emitted by the given function.
+    pub(super) fn emit_counted_loop<F>(&mut self, i: usize, count: usize, body: F)
+    where
+        F: Fn(&mut Self),
+    {
+        self.emit(wasm::Instruction::I32Const(0));
+        self.emit(wasm::Instruction::LocalSet(i));
+        self.emit(wasm::Instruction::Block);
+        self.emit(wasm::Instruction::Loop);
+        self.emit(wasm::Instruction::LocalGet(i));
+        self.emit(wasm::Instruction::LocalGet(count));
+        self.emit(wasm::Instruction::I32GeS);
+        self.emit(wasm::Instruction::BrIf(1));
+        body(self);
+        self.emit(wasm::Instruction::LocalGet(i));
+        self.emit(wasm::Instruction::I32Const(1));
+        self.emit(wasm::Instruction::I32Add);
+        self.emit(wasm::Instruction::LocalSet(i));
+        self.emit(wasm::Instruction::Br(0));
+        self.emit(wasm::Instruction::End);
+        self.emit(wasm::Instruction::End);
+    }
+
+    fn compile_slice_sequence(&mut self) {
+        // Parameters: sequence, start, stop, step, element size
+        let (sequence, start, stop, step, element_size) = (0, 1, 2, 3, 4);
+        let (count, result, i, data_start) = (5, 6, 7, 8);
+
+        self.emit_slice_prologue(sequence, start, stop, step, element_size, data_start);
+        self.emit(wasm::Instruction::LocalSet(count));
+
+        // Allocate new sequence:
+        self.emit(wasm::Instruction::LocalGet(count));
+        self.emit(wasm::Instruction::LocalGet(element_size));
+        self.emit(wasm::Instruction::I32Mul);
+        self.emit(wasm::Instruction::LocalGet(data_start));
+        self.emit(wasm::Instruction::I32Add);
+        self.call_runtime(RuntimeFunction::Allocate);
+        self.emit(wasm::Instruction::LocalSet(result));
+
+        self.emit(wasm::Instruction::LocalGet(result));
+        self.emit(wasm::Instruction::LocalGet(count));
+        self.emit(wasm::Instruction::I32Store(2, 0));
+
+        self.emit_counted_loop(i, count, |c| {
+            // Destination is result[i]:
+            c.emit(wasm::Instruction::LocalGet(result));
+            c.emit(wasm::Instruction::LocalGet(data_start));
+            c.emit(wasm::Instruction::I32Add);
+            c.emit(wasm::Instruction::LocalGet(i));
+            c.emit(wasm::Instruction::LocalGet(element_size));
+            c.emit(wasm::Instruction::I32Mul);
+            c.emit(wasm::Instruction::I32Add);
+
+            c.emit_slice_element(sequence, start, step, i, element_size, data_start);
+            c.emit(wasm::Instruction::LocalGet(element_size));
+            c.call_runtime(RuntimeFunction::CopyMemory);
+        });
+
+        self.emit(wasm::Instruction::LocalGet(result));
+    }
+
+    fn compile_slice_assign(&mut self) {
+        // Parameters: list, start, stop, step, element size, value
+        let (sequence, start, stop, step, element_size, value) = (0, 1, 2, 3, 4, 5);
+        let (count, i, data_start) = (6, 7, 8);
+
+        self.emit_slice_prologue(sequence, start, stop, step, element_size, data_start);
+        self.emit(wasm::Instruction::LocalSet(count));
+
+        // Lists have a fixed size, so the slice must have the length of the value:
+        self.emit(wasm::Instruction::LocalGet(count));
+        self.emit(wasm::Instruction::LocalGet(value));
+        self.builtin_list_len();
+        self.emit(wasm::Instruction::I32Ne);
+        self.emit(wasm::Instruction::If);
+        self.emit(wasm::Instruction::LocalGet(value));
+        self.builtin_list_len();
+        self.emit(wasm::Instruction::LocalGet(count));
+        self.call_runtime(RuntimeFunction::SliceSizeError);
+        self.emit(wasm::Instruction::End);
+
+        // Take a copy when assigning part of a list to itself:
+        self.emit(wasm::Instruction::LocalGet(value));
+        self.emit(wasm::Instruction::LocalGet(sequence));
+        self.emit(wasm::Instruction::I32Eq);
+        self.emit(wasm::Instruction::If);
+        self.emit(wasm::Instruction::LocalGet(value));
+        self.emit(wasm::Instruction::I32Const(SLICE_NONE));
+        self.emit(wasm::Instruction::I32Const(SLICE_NONE));
+        self.emit(wasm::Instruction::I32Const(1));
+        self.emit(wasm::Instruction::LocalGet(element_size));
+        self.call_runtime(RuntimeFunction::SliceSequence);
+        self.emit(wasm::Instruction::LocalSet(value));
+        self.emit(wasm::Instruction::End);
+
+        self.emit_counted_loop(i, count, |c| {
+            c.emit_slice_element(sequence, start, step, i, element_size, data_start);
+
+            // Source is value[i]:
+            c.emit(wasm::Instruction::LocalGet(value));
+            c.emit(wasm::Instruction::LocalGet(data_start));
+            c.emit(wasm::Instruction::I32Add);
+            c.emit(wasm::Instruction::LocalGet(i));
+            c.emit(wasm::Instruction::LocalGet(element_size));
+            c.emit(wasm::Instruction::I32Mul);
+            c.emit(wasm::Instruction::I32Add);
+
+            c.emit(wasm::Instruction::LocalGet(element_size));
+            c.call_runtime(RuntimeFunction::CopyMemory);
+        });
+    }
+
+    fn emit_is_negative(&mut self, local: usize) {
+        self.emit(wasm::Instruction::LocalGet(local));
+        self.emit(wasm::Instruction::I32Const(0));
+        self.emit(wasm::Instruction::I32LtS);
+    }
+}
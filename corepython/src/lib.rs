@@ -1,6 +1,7 @@
 //! A single function library, to turn opiniated python code into WebAssembly.
 //!
 //! Call the function python_to_wasm to generate some WebAssembly bytecodes.
+//! Use python_to_wasm_with_options to tweak the generated code.
 
 #[macro_use]
 extern crate log;
@@ -8,11 +9,13 @@ extern crate log;
 mod analyze;
 mod compile;
 mod error;
+mod options;
 mod parser;
 mod wasm;
 
 use compile::compile_ast;
 use error::CompilationError;
+pub use options::CompileOptions;
 use parser::parse_python;
 use wasm::write_wasm;
 
@@ -20,11 +23,23 @@ use wasm::write_wasm;
 ///
 /// This library function takes python-ish sourcecode and transforms it into WebAssembly.
 pub fn python_to_wasm<W>(source: &str, dest: &mut W) -> Result<(), CompilationError>
+where
+    W: std::io::Write,
+{
+    python_to_wasm_with_options(source, dest, &CompileOptions::default())
+}
+
+/// Same as python_to_wasm, but with explicit compiler options.
+pub fn python_to_wasm_with_options<W>(
+    source: &str,
+    dest: &mut W,
+    options: &CompileOptions,
+) -> Result<(), CompilationError>
 where
     W: std::io::Write,
 {
     let ast = parse_python(source)?;
-    let wasm_module = compile_ast(ast)?;
+    let wasm_module = compile_ast(ast, options)?;
     write_wasm(wasm_module, dest).map_err(|e| CompilationError {
         location: None,
         message: e.to_string(),
@@ -34,11 +49,1230 @@ where
 
 #[cfg(test)]
 mod tests {
-    use super::python_to_wasm;
+    use super::{compile_ast, parse_python};
+    use super::{python_to_wasm, python_to_wasm_with_options, CompileOptions};
+    use crate::wasm::Instruction;
+
+    fn contains(haystack: &[u8], needle: &str) -> bool {
+        haystack
+            .windows(needle.len())
+            .any(|w| w == needle.as_bytes())
+    }
+
+    /// Compile a program without wasm exceptions, which wasmi does not support,
+    /// and call one of its functions. An exception which is not handled is an error.
+    fn run(source: &str, function: &str) -> Result<i32, wasmi::Error> {
+        let options = CompileOptions {
+            wasm_exceptions: false,
+            ..CompileOptions::default()
+        };
+        run_with_options(source, function, &options)
+    }
+
+    fn run_with_options(
+        source: &str,
+        function: &str,
+        options: &CompileOptions,
+    ) -> Result<i32, wasmi::Error> {
+        let mut buf = vec![];
+        python_to_wasm_with_options(source, &mut buf, options).expect("Ok");
+
+        let engine = wasmi::Engine::default();
+        let module = wasmi::Module::new(&engine, &buf[..])?;
+        let mut store = wasmi::Store::new(&engine, ());
+        let linker = wasmi::Linker::new(&engine);
+        let instance = linker.instantiate(&mut store, &module)?.start(&mut store)?;
+        let function = instance.get_typed_func::<(), i32>(&store, function)?;
+        function.call(&mut store, ())
+    }
 
     #[test]
     fn test_parse_empty() {
         let mut buf = vec![];
         python_to_wasm("", &mut buf).expect("Ok");
     }
+
+    #[test]
+    fn test_bounds_checks() {
+        let source = r###"
+def last(a: list[float]) -> float:
+    return a[-1]
+"###;
+        let mut buf = vec![];
+        python_to_wasm(source, &mut buf).expect("Ok");
+        assert!(contains(&buf, "index_error"));
+
+        let options = CompileOptions {
+            bounds_checks: false,
+            ..CompileOptions::default()
+        };
+        let mut buf = vec![];
+        python_to_wasm_with_options(source, &mut buf, &options).expect("Ok");
+        assert!(!contains(&buf, "index_error"));
+
+        // Negative indices still count from the end:
+        let source = r###"
+def last() -> int:
+    a = [1, 2, 3]
+    a[-2] = 5
+    return a[-1] * 100 + a[-2] * 10 + a[-3]
+"###;
+        let options = CompileOptions {
+            bounds_checks: false,
+            wasm_exceptions: false,
+            ..CompileOptions::default()
+        };
+        assert_eq!(run_with_options(source, "last", &options).unwrap(), 351);
+    }
+
+    #[test]
+    fn test_slicing() {
+        let source = r###"
+def foo(a: list[int], i: int) -> int:
+    b = a[i:]
+    a[::2] = b[:-1:3]
+    return len('abc'[::-1])
+
+def bar(a: int) -> int:
+    return a[1:2]
+"###;
+        let mut buf = vec![];
+        let error = python_to_wasm(source, &mut buf).expect_err("Slice int");
+        assert_eq!(error.location.unwrap().row, 8);
+    }
+
+    #[test]
+    fn test_slice_values() {
+        let source = r###"
+def digits(a: list[int]) -> int:
+    r = 0
+    for x in a:
+        r = r * 10 + x
+    return r
+
+def negative_step() -> int:
+    a = [1, 2, 3, 4, 5, 6, 7]
+    return digits(a[::-1]) * 10 + len(a[1:5:-1])
+
+def negative_step_bounds() -> int:
+    a = [1, 2, 3, 4, 5, 6, 7]
+    return digits(a[5:1:-2]) * 10000 + digits(a[-2::-3])
+
+def clamped() -> int:
+    a = [1, 2, 3, 4, 5]
+    return digits(a[-100:100]) * 10 + len(a[3:1])
+
+def clamped_negative_step() -> int:
+    a = [1, 2, 3, 4, 5]
+    return digits(a[10:-10:-1]) * 1000 + digits(a[:-4:-1])
+
+def string() -> int:
+    s = 'abcdef'
+    return len(s[::-2]) * 100 + len(s[-3:]) * 10 + len(s[4:2])
+
+def assign() -> int:
+    a = [1, 2, 3, 4, 5, 6]
+    a[::-2] = [7, 8, 9]
+    a[1:3] = a[4:6]
+    return digits(a)
+
+def zero_step() -> int:
+    a = [1, 2, 3]
+    return len(a[::0])
+"###;
+        assert_eq!(run(source, "negative_step").unwrap(), 76543210);
+        assert_eq!(run(source, "negative_step_bounds").unwrap(), 640063);
+        assert_eq!(run(source, "clamped").unwrap(), 123450);
+        assert_eq!(run(source, "clamped_negative_step").unwrap(), 54321543);
+        assert_eq!(run(source, "string").unwrap(), 330);
+        assert_eq!(run(source, "assign").unwrap(), 157857);
+        assert!(run(source, "zero_step").is_err());
+    }
+
+    #[test]
+    fn test_slice_assignment_size() {
+        let source = r###"
+def foo(a: list[int]):
+    a[1:3] = [9]
+"###;
+        let mut buf = vec![];
+        python_to_wasm(source, &mut buf).expect("Ok");
+        assert!(contains(&buf, "attempt to assign sequence of size "));
+        assert!(contains(&buf, " to extended slice of size "));
+
+        let source = r###"
+def foo() -> int:
+    a = [1, 2, 3]
+    a[1:3] = [9]
+    return a[1]
+"###;
+        assert!(run(source, "foo").is_err());
+    }
+
+    #[test]
+    fn test_range() {
+        let source = r###"
+def foo(n: int) -> int:
+    for i in range(n, 0, -1):
+        n = n + i
+    return n
+
+def bar() -> int:
+    x = range(3)
+    return 0
+"###;
+        let mut buf = vec![];
+        let error = python_to_wasm(source, &mut buf).expect_err("range as value");
+        assert_eq!(error.location.unwrap().row, 8);
+
+        // The loop counter does not wrap around near the ends of the int range:
+        let source = r###"
+def count(start: int, stop: int, step: int) -> int:
+    n = 0
+    for i in range(start, stop, step):
+        n = n + 1
+    return n
+
+def near_max() -> int:
+    n = 0
+    for i in range(2147483640, 2147483647, 5):
+        n = n + 1
+    return n
+
+def near_min() -> int:
+    n = 0
+    for i in range(-2147483640, -2147483647, -5):
+        n = n + 1
+    return n
+
+def variable_step() -> int:
+    a = count(2147483640, 2147483647, 5) * 10 + count(-2147483640, -2147483647, -5)
+    return a * 10 + count(-2147483647, 2147483647, 2147483647)
+"###;
+        assert_eq!(run(source, "near_max").unwrap(), 2);
+        assert_eq!(run(source, "near_min").unwrap(), 2);
+        assert_eq!(run(source, "variable_step").unwrap(), 222);
+    }
+
+    #[test]
+    fn test_subscript_assignment_type() {
+        let source = r###"
+def foo(a: list[int]):
+    a[0] = 2.0
+"###;
+        let mut buf = vec![];
+        let error = python_to_wasm(source, &mut buf).expect_err("Type error");
+        assert_eq!(error.location.unwrap().row, 3);
+    }
+
+    #[test]
+    fn test_tuple_unpacking() {
+        let source = r###"
+def divide(a: int, b: int) -> tuple[int, int]:
+    return a / b, a - (a / b) * b
+
+def foo(a: int) -> int:
+    q, r = divide(a, 3)
+    a, b, c = divide(a, 4)
+    return q
+"###;
+        let mut buf = vec![];
+        let error = python_to_wasm(source, &mut buf).expect_err("Unpack error");
+        assert_eq!(error.location.unwrap().row, 7);
+    }
+
+    #[test]
+    fn test_tuple_values() {
+        let source = r###"
+def pair(a: int, b: int) -> tuple[int, int]:
+    return a * b, a - b
+
+def unpack() -> int:
+    p, d = pair(47, 5)
+    return p * 100 + d
+
+def swap() -> int:
+    a = 1
+    b = 2
+    a, b = b, a
+    return a * 10 + b
+
+def nested() -> int:
+    t = (1, (2.5, 'xy'), [3, 4])
+    n, (f, s), l = t
+    if f > 2.0:
+        n = n + 1
+    else:
+        n = n
+    return n * 1000 + len(s) * 10 + l[1]
+
+def index() -> int:
+    t = (7, 'abc', 9)
+    return t[0] * 100 + len(t[1]) * 10 + t[-1]
+
+def loop() -> int:
+    pairs = [(1, 2), (3, 4), (5, 6)]
+    r = 0
+    for a, b in pairs:
+        r = r * 100 + a * 10 + b
+    return r
+"###;
+        assert_eq!(run(source, "unpack").unwrap(), 23542);
+        assert_eq!(run(source, "swap").unwrap(), 21);
+        assert_eq!(run(source, "nested").unwrap(), 2024);
+        assert_eq!(run(source, "index").unwrap(), 739);
+        assert_eq!(run(source, "loop").unwrap(), 123456);
+    }
+
+    #[test]
+    fn test_dict() {
+        let source = r###"
+def count(words: list[str]) -> int:
+    counts: dict[str, int] = {}
+    for word in words:
+        counts[word] = counts.get(word, 0) + 1
+    del counts['a']
+    for word, n in counts.items():
+        counts[word] = n * 2
+    return len(counts)
+
+def bar(d: dict[int, float]) -> float:
+    return d[1.5]
+"###;
+        let mut buf = vec![];
+        let error = python_to_wasm(source, &mut buf).expect_err("Key type error");
+        assert_eq!(error.location.unwrap().row, 12);
+    }
+
+    #[test]
+    fn test_dict_order() {
+        let source = r###"
+def order() -> int:
+    d: dict[int, int] = {}
+    for i in range(20):
+        d[i] = i * i
+    for i in range(0, 20, 3):
+        del d[i]
+    d[0] = 7
+    d[5] = 9
+    keys = 0
+    for k in d.keys():
+        keys = keys * 2 + k
+    values = 0
+    for v in d.values():
+        values = values * 2 + v
+    return keys * 1000 + values * 10 - len(d)
+
+def str_keys() -> int:
+    d: dict[str, int] = {'a': 1, 'b': 2}
+    d['c'] = 3
+    del d['a']
+    d['a'] = 4
+    r = 0
+    for k, v in d.items():
+        r = r * 10 + v
+    return r * 10 + d.get('z', 5)
+
+def churn() -> int:
+    d: dict[int, int] = {}
+    j = 0
+    for i in range(1000):
+        d[j] = i
+        if j == 3:
+            del d[j]
+        else:
+            j = j
+        j = j + 1
+        if j == 37:
+            j = 0
+        else:
+            j = j
+    total = 0
+    for k, v in d.items():
+        total = total + k * v
+    return total + len(d) * 1000000
+
+def count() -> int:
+    k = 4
+    d: dict[int, int] = {}
+    d[k] = d.get(k, 5) + 1
+    d[k + 1] = len(d)
+    return d[k] * 10 + d[k + 1]
+
+def missing() -> int:
+    d: dict[int, int] = {1: 2}
+    return d[3]
+"###;
+        // Deleted keys leave a tombstone, and re-inserted keys go to the end:
+        assert_eq!(run(source, "order").unwrap(), 39596516);
+        assert_eq!(run(source, "str_keys").unwrap(), 2345);
+        assert_eq!(run(source, "churn").unwrap(), 36654003);
+        assert_eq!(run(source, "count").unwrap(), 61);
+        assert!(run(source, "missing").is_err());
+    }
+
+    #[test]
+    fn test_set() {
+        let source = r###"
+def unique(xs: list[int]) -> int:
+    seen = set(xs)
+    seen.add(42)
+    seen.discard(0)
+    common = seen & {1, 2, 3}
+    return len(seen | common)
+
+def empty() -> int:
+    s = set()
+    return len(s)
+"###;
+        let mut buf = vec![];
+        let error = python_to_wasm(source, &mut buf).expect_err("Empty set without type");
+        assert_eq!(error.location.unwrap().row, 10);
+    }
+
+    #[test]
+    fn test_set_values() {
+        let source = r###"
+def algebra() -> int:
+    a = {1, 2, 3, 4}
+    b = {3, 4, 5}
+    return len(a | b) * 1000 + len(a & b) * 100 + len(a - b) * 10 + len(b - a)
+
+def membership() -> int:
+    a = {1, 2, 3}
+    a.add(10)
+    a.discard(2)
+    a.discard(99)
+    r = 0
+    if 10 in a:
+        r = r + 1
+    else:
+        r = r
+    if 2 not in a:
+        r = r + 10
+    else:
+        r = r
+    return r * 100 + len(a)
+
+def elements() -> int:
+    a = set([5, 5, 6, 7, 7, 7])
+    b = {6, 7, 8}
+    total = 0
+    for x in a - b:
+        total = total + x
+    for x in a & b:
+        total = total * 100 + x
+    return total
+
+def strings() -> int:
+    a = {'a', 'bb'}
+    b = {'bb', 'ccc'}
+    total = 0
+    for s in a | b:
+        total = total + len(s)
+    return total * 10 + len(a - b)
+
+def churn() -> int:
+    a: set[int] = set()
+    for i in range(100):
+        a.add(i)
+    for i in range(0, 100, 2):
+        a.discard(i)
+    total = 0
+    for x in a:
+        total = total + x
+    return total * 100 + len(a)
+"###;
+        assert_eq!(run(source, "algebra").unwrap(), 5221);
+        assert_eq!(run(source, "membership").unwrap(), 1103);
+        assert_eq!(run(source, "elements").unwrap(), 50607);
+        assert_eq!(run(source, "strings").unwrap(), 61);
+        assert_eq!(run(source, "churn").unwrap(), 250050);
+    }
+
+    #[test]
+    fn test_class() {
+        let source = r###"
+class Point:
+    def __init__(self, x: int, y: int):
+        self.x = x
+        self.y = y
+
+    def add(self, other: Point) -> Point:
+        return Point(self.x + other.x, self.y + other.y)
+
+def norm1(p: Point) -> int:
+    p.z = 0
+    return p.x + p.y
+"###;
+        let mut buf = vec![];
+        let error = python_to_wasm(source, &mut buf).expect_err("Unknown field");
+        assert_eq!(error.location.unwrap().row, 11);
+    }
+
+    #[test]
+    fn test_unsupported_operations() {
+        let source = r###"
+def numbers(a: int, b: float, s: str, t: set[int]) -> float:
+    c = (a + 1) * a - a / 2
+    d = a & 3 | a
+    u = s + 'x'
+    v = t | t & t - t
+    return b * b / b - b
+"###;
+        let mut buf = vec![];
+        python_to_wasm(source, &mut buf).expect("Ok");
+
+        let cases = [
+            ("a: Point, b: Point", "a + b", "Add", "Point"),
+            (
+                "a: dict[int, int], b: dict[int, int]",
+                "a + b",
+                "Add",
+                "dict[int, int]",
+            ),
+            (
+                "a: tuple[int, int], b: tuple[int, int]",
+                "a * b",
+                "Mul",
+                "tuple[int, int]",
+            ),
+            (
+                "a: Callable[[int], int], b: Callable[[int], int]",
+                "a - b",
+                "Sub",
+                "Callable[[int], int]",
+            ),
+            ("a: list[int], b: list[int]", "a + b", "Add", "list[int]"),
+            ("a: float, b: float", "a | b", "BitOr", "float"),
+            ("a: str, b: str", "a * b", "Mul", "str"),
+            ("a: bool, b: bool", "a + b", "Add", "bool"),
+        ];
+        for (params, expression, op, typ) in &cases {
+            let source = format!(
+                "
+from typing import Callable
+
+class Point:
+    def __init__(self, x: int):
+        self.x = x
+
+def f({}):
+    c = {}
+",
+                params, expression
+            );
+            let error = python_to_wasm(&source, &mut buf).expect_err("Unsupported operation");
+            assert_eq!(error.location.unwrap().row, 9);
+            assert_eq!(
+                error.message,
+                format!("Unsupported operation {} for type {}", op, typ)
+            );
+        }
+    }
+
+    #[test]
+    fn test_inheritance() {
+        let source = r###"
+class Shape:
+    def area(self) -> int:
+        return 0
+
+class Square(Shape):
+    def __init__(self, size: int):
+        self.size = size
+
+    def area(self) -> int:
+        return self.size * self.size
+
+class Circle(Shape):
+    def area(self) -> float:
+        return 3.14
+"###;
+        let mut buf = vec![];
+        let error = python_to_wasm(source, &mut buf).expect_err("Override signature");
+        assert_eq!(error.location.unwrap().row, 14);
+    }
+
+    #[test]
+    fn test_dataclass() {
+        let source = r###"
+from dataclasses import dataclass
+
+@dataclass
+class Point:
+    x: int
+    y: float
+
+def show(p: Point):
+    print(p, str(p.x) + '!')
+
+@property
+class Size:
+    w: int
+"###;
+        let mut buf = vec![];
+        let error = python_to_wasm(source, &mut buf).expect_err("Unknown decorator");
+        assert_eq!(error.location.unwrap().row, 12);
+    }
+
+    #[test]
+    fn test_globals() {
+        let source = r###"
+LIMIT = 10
+count = 0
+
+def step() -> int:
+    global count
+    count = count + 1
+    return LIMIT - count
+
+def reset():
+    global total
+    total = 0
+"###;
+        let mut buf = vec![];
+        let error = python_to_wasm(source, &mut buf).expect_err("Not a global variable");
+        assert_eq!(error.location.unwrap().row, 11);
+        let source = r###"
+def memory() -> int:
+    return 1
+"###;
+        let mut buf = vec![];
+        let error = python_to_wasm(source, &mut buf).expect_err("Export name");
+        assert_eq!(error.location.unwrap().row, 2);
+        assert_eq!(
+            error.message,
+            "memory is reserved for an export of the module"
+        );
+
+        let source = r###"
+def exception(code: int) -> int:
+    return code
+"###;
+        let mut buf = vec![];
+        let error = python_to_wasm(source, &mut buf).expect_err("Export name");
+        assert_eq!(error.location.unwrap().row, 2);
+    }
+
+    #[test]
+    fn test_constants() {
+        let source = r###"
+from enum import Enum
+from typing import Final
+
+WIDTH: Final = 80
+
+class Color(Enum):
+    RED = 1
+    GREEN = 2
+
+def brightness(color: Color) -> int:
+    if color == Color.RED:
+        return WIDTH
+    else:
+        return color.value
+
+WIDTH = 100
+"###;
+        let mut buf = vec![];
+        let error = python_to_wasm(source, &mut buf).expect_err("Cannot assign to a constant");
+        assert_eq!(error.location.unwrap().row, 17);
+    }
+
+    #[test]
+    fn test_optional() {
+        let source = r###"
+from typing import Optional
+
+def length(s: Optional[str]) -> int:
+    if s is not None:
+        return len(s)
+    else:
+        return 0
+
+def reset(s: Optional[str]) -> int:
+    if s is not None:
+        s = None
+        return len(s)
+    else:
+        return 0
+"###;
+        let mut buf = vec![];
+        let error = python_to_wasm(source, &mut buf).expect_err("Value may be None");
+        assert_eq!(error.location.unwrap().row, 13);
+
+        // The nested function can set s back to None:
+        let source = r###"
+from typing import Optional
+
+def outer(s: Optional[str]) -> int:
+    def clear():
+        nonlocal s
+        s = None
+    if s is not None:
+        clear()
+        return len(s)
+    else:
+        return 0
+"###;
+        let mut buf = vec![];
+        let error = python_to_wasm(source, &mut buf).expect_err("Cell may be None");
+        assert_eq!(error.location.unwrap().row, 10);
+        assert!(error.message.contains("Optional[str]"));
+    }
+
+    #[test]
+    fn test_union() {
+        let source = r###"
+def describe(x: int | str) -> int:
+    if isinstance(x, int):
+        return x + 1
+    else:
+        return len(x)
+
+def twice(x: int | str) -> int:
+    if isinstance(x, str):
+        return len(x) * 2
+    else:
+        return x * 2 + len(x)
+"###;
+        let mut buf = vec![];
+        let error = python_to_wasm(source, &mut buf).expect_err("Value is not a str");
+        assert_eq!(error.location.unwrap().row, 12);
+
+        // The call can assign a str to the global:
+        let source = r###"
+value: int | str = 1
+
+def change():
+    global value
+    value = 'x'
+
+def next_value() -> int:
+    if isinstance(value, int):
+        change()
+        return value + 1
+    else:
+        return 0
+"###;
+        let mut buf = vec![];
+        let error = python_to_wasm(source, &mut buf).expect_err("Global is not narrowed");
+        assert_eq!(error.location.unwrap().row, 11);
+    }
+
+    #[test]
+    fn test_match() {
+        let source = r###"
+def opcode(op: int) -> int:
+    match op:
+        case 0 | 1:
+            return 10
+        case n if n > 5:
+            return n
+        case _:
+            return 0
+
+def pair(t: tuple[int, int]) -> int:
+    match t:
+        case (0, y):
+            return y
+        case (x, y, z):
+            return x
+        case _:
+            return 0
+"###;
+        let mut buf = vec![];
+        let error = python_to_wasm(source, &mut buf).expect_err("Tuple has two elements");
+        assert_eq!(error.location.unwrap().row, 15);
+    }
+
+    #[test]
+    fn test_match_values() {
+        // The int cases are compiled to a br_table, with a default for values in the gaps:
+        let source = r###"
+def table(op: int) -> int:
+    match op:
+        case 1 | 2:
+            return 10
+        case 4:
+            return 40
+        case 2 | 5:
+            return 50
+        case 7:
+            return 70
+        case n:
+            return n - 1
+
+def no_default(op: int) -> int:
+    r = -1
+    match op:
+        case 3:
+            r = 30
+        case 4:
+            r = 40
+        case 6:
+            r = 60
+        case 7:
+            r = 70
+    return r
+
+def tables() -> int:
+    r = 0
+    for op in range(-2, 10):
+        r = r * 3 + table(op)
+    return r
+
+def extremes() -> int:
+    return table(2147483647) + table(-2147483647) + table(3)
+
+def no_defaults() -> int:
+    r = 0
+    for op in range(0, 10):
+        r = r + no_default(op)
+    return r * 10 + no_default(-2147483647 - 1)
+
+def chain(t: tuple[int, int]) -> int:
+    match t:
+        case (0, y):
+            return y
+        case (x, y) if x > y:
+            return x - y
+        case (x, 5):
+            return x * 100
+        case _:
+            return -1
+
+def chains() -> int:
+    return chain((0, 9)) * 10000 + chain((7, 3)) * 1000 + chain((2, 5)) + chain((3, 4))
+"###;
+        assert_eq!(run(source, "tables").unwrap(), -565720);
+        assert_eq!(run(source, "extremes").unwrap(), 0);
+        assert_eq!(run(source, "no_defaults").unwrap(), 1939);
+        assert_eq!(run(source, "chains").unwrap(), 94199);
+    }
+
+    #[test]
+    fn test_exceptions() {
+        let source = r###"
+class ParseError(ValueError):
+    def __init__(self, position: int):
+        super().__init__('Unexpected character')
+        self.position = position
+
+def parse(text: str) -> int:
+    try:
+        if len(text) > 3:
+            raise ParseError(3)
+        else:
+            return len(text)
+    except ParseError as e:
+        return e.position
+    finally:
+        print('done')
+
+def check(n: int) -> int:
+    try:
+        return n
+    except n:
+        raise
+"###;
+        let mut buf = vec![];
+        let error = python_to_wasm(source, &mut buf).expect_err("Cannot catch an int");
+        assert_eq!(error.location.unwrap().row, 21);
+    }
+
+    #[test]
+    fn test_exception_values() {
+        let source = r###"
+count = 0
+
+class MyError(ValueError):
+    def __init__(self, code: int):
+        super().__init__('code')
+        self.code = code
+
+def check(n: int) -> int:
+    if n < 0:
+        raise ValueError('negative')
+    else:
+        if n > 100:
+            raise MyError(n)
+        else:
+            return n
+
+def leave(n: int) -> int:
+    global count
+    try:
+        return check(n)
+    finally:
+        count = count + 1
+
+def handle(n: int) -> int:
+    try:
+        return check(n) * 2
+    except MyError as e:
+        return e.code
+    except ValueError:
+        return -1
+
+def finally_return() -> int:
+    global count
+    count = 0
+    r = leave(5)
+    try:
+        r = r + leave(-1)
+    except ValueError:
+        r = r + 100
+    return r * 10 + count
+
+def handlers() -> int:
+    return handle(7) * 10000 + handle(-3) * 1000 + handle(123)
+
+def nested(n: int) -> int:
+    global count
+    try:
+        try:
+            check(n)
+        except MyError:
+            count = count + 10
+            raise
+        finally:
+            count = count + 1
+    except ValueError:
+        count = count + 100
+    return count
+
+def reraise() -> int:
+    global count
+    count = 0
+    nested(200)
+    nested(-1)
+    nested(1)
+    return count
+
+def loop_finally() -> int:
+    global count
+    total = 0
+    for i in range(5):
+        try:
+            if i == 3:
+                return total
+            else:
+                total = total + i
+        finally:
+            count = count + 1
+    return -1
+
+def loop_return() -> int:
+    global count
+    count = 0
+    return loop_finally() * 10 + count
+
+def uncaught() -> int:
+    return check(-5)
+
+def index_error() -> int:
+    a = [1, 2, 3]
+    try:
+        return a[3]
+    except IndexError:
+        return 42
+"###;
+        // A finally clause runs when a return or an exception leaves the try statement:
+        assert_eq!(run(source, "finally_return").unwrap(), 1052);
+        assert_eq!(run(source, "loop_return").unwrap(), 34);
+        assert_eq!(run(source, "handlers").unwrap(), 139123);
+        assert_eq!(run(source, "reraise").unwrap(), 213);
+        assert_eq!(run(source, "index_error").unwrap(), 42);
+        assert!(run(source, "uncaught").is_err());
+    }
+
+    #[test]
+    fn test_exceptions_without_wasm_exceptions() {
+        let source = r###"
+def get(i: int) -> int:
+    a = [10, 20, 30]
+    try:
+        return a[i]
+    except IndexError:
+        raise ValueError('bad index')
+    finally:
+        a[0] = 0
+"###;
+        let options = CompileOptions {
+            wasm_exceptions: false,
+            ..CompileOptions::default()
+        };
+        let compile = |options: &CompileOptions| {
+            let prog = parse_python(source).expect("Ok");
+            compile_ast(prog, options).expect("Ok")
+        };
+        let is_exception_instruction = |i: &&Instruction| {
+            matches!(
+                i,
+                Instruction::Try
+                    | Instruction::Catch(_)
+                    | Instruction::CatchAll
+                    | Instruction::Throw(_)
+                    | Instruction::Rethrow(_)
+            )
+        };
+        let module = compile(&CompileOptions::default());
+        assert_eq!(module.tag_count(), 1);
+        assert!(module.instructions().any(|i| is_exception_instruction(&i)));
+
+        // Without wasm exceptions there is no tag, and no instructions which use one:
+        let module = compile(&options);
+        assert_eq!(module.tag_count(), 0);
+        assert!(!module.instructions().any(|i| is_exception_instruction(&i)));
+
+        // The exception is passed in the exported global, which stays set after the trap:
+        let mut buf = vec![];
+        python_to_wasm_with_options(source, &mut buf, &options).expect("Ok");
+        let engine = wasmi::Engine::default();
+        let module = wasmi::Module::new(&engine, &buf[..]).expect("Ok");
+        let mut store = wasmi::Store::new(&engine, ());
+        let linker = wasmi::Linker::new(&engine);
+        let instance = linker
+            .instantiate(&mut store, &module)
+            .expect("Ok")
+            .start(&mut store)
+            .expect("Ok");
+        let exception = instance.get_global(&store, "exception").expect("Global");
+        assert_eq!(exception.ty(&store).content(), wasmi::core::ValType::I32);
+        let get = instance
+            .get_typed_func::<i32, i32>(&store, "get")
+            .expect("Ok");
+        assert_eq!(get.call(&mut store, 1).expect("Ok"), 20);
+        assert_eq!(exception.get(&store).i32(), Some(0));
+        assert!(get.call(&mut store, 5).is_err());
+        assert_ne!(exception.get(&store).i32(), Some(0));
+    }
+
+    #[test]
+    fn test_division() {
+        let source = r###"
+def half(n: int) -> int:
+    return n / 2
+"###;
+        let mut buf = vec![];
+        python_to_wasm(source, &mut buf).expect("Ok");
+        assert!(!contains(&buf, "int_divide"));
+
+        let source = r###"
+def ratio(a: int, b: int) -> int:
+    return a / b
+"###;
+        let mut buf = vec![];
+        python_to_wasm(source, &mut buf).expect("Ok");
+        assert!(contains(&buf, "int_divide"));
+    }
+
+    #[test]
+    fn test_asserts() {
+        let source = r###"
+def decrement(n: int) -> int:
+    assert n > 0, 'n must be positive'
+    return n - 1
+"###;
+        let mut buf = vec![];
+        python_to_wasm(source, &mut buf).expect("Ok");
+        assert!(contains(&buf, "assertion_failed"));
+
+        let options = CompileOptions {
+            asserts: false,
+            ..CompileOptions::default()
+        };
+        let mut buf = vec![];
+        python_to_wasm_with_options(source, &mut buf, &options).expect("Ok");
+        assert!(!contains(&buf, "assertion_failed"));
+
+        // Asserts are still checked when they are left out:
+        let source = r###"
+def decrement(n: int) -> int:
+    assert n > 0, 'n must be positive'
+    assert n, reason
+    return n - 1
+"###;
+        let error = python_to_wasm_with_options(source, &mut buf, &options)
+            .expect_err("reason is not defined");
+        assert_eq!(error.location.unwrap().row, 4);
+    }
+
+    #[test]
+    fn test_nested_functions() {
+        let source = r###"
+def counter() -> int:
+    count = 0
+    def increment(step: int) -> int:
+        nonlocal count
+        count = count + step
+        return count
+    increment(2)
+    return increment(3)
+"###;
+        let mut buf = vec![];
+        python_to_wasm(source, &mut buf).expect("Ok");
+        assert!(contains(&buf, "counter.increment"));
+
+        let source = r###"
+def counter() -> int:
+    def increment(step: int) -> int:
+        nonlocal count
+        return step
+    return increment(3)
+"###;
+        let error = python_to_wasm(source, &mut buf).expect_err("count is not defined");
+        assert_eq!(error.location.unwrap().row, 4);
+    }
+
+    #[test]
+    fn test_function_values() {
+        let source = r###"
+from typing import Callable
+
+def square(x: int) -> int:
+    return x * x
+
+def apply(f: Callable[[int], int], x: int) -> int:
+    return f(x)
+
+def test() -> int:
+    return apply(square, 3)
+"###;
+        let mut buf = vec![];
+        python_to_wasm(source, &mut buf).expect("Ok");
+        assert!(contains(&buf, "square.<wrapper>"));
+
+        let source = r###"
+from typing import Callable
+
+def add(a: int, b: int) -> int:
+    return a + b
+
+def apply(f: Callable[[int], int], x: int) -> int:
+    return f(x)
+
+def test() -> int:
+    return apply(add, 3)
+"###;
+        let error = python_to_wasm(source, &mut buf).expect_err("add takes two arguments");
+        assert_eq!(error.location.unwrap().row, 11);
+    }
+
+    #[test]
+    fn test_lambda() {
+        let source = r###"
+from typing import Callable
+
+def apply(f: Callable[[int], int], x: int) -> int:
+    return f(x)
+
+def test(n: int) -> int:
+    return apply(lambda x: x * n, 3)
+"###;
+        let mut buf = vec![];
+        python_to_wasm(source, &mut buf).expect("Ok");
+        assert!(contains(&buf, "test.<lambda>"));
+
+        let source = r###"
+def test() -> int:
+    f = lambda x: x * 2
+    return 0
+"###;
+        let error = python_to_wasm(source, &mut buf).expect_err("lambda has no type");
+        assert_eq!(error.location.unwrap().row, 3);
+    }
+
+    #[test]
+    fn test_keyword_arguments() {
+        let source = r###"
+def scale(x: float, factor: float = 1.5, offset: float = 0.0) -> float:
+    return x * factor + offset
+
+def test() -> float:
+    return scale(2.0, offset=1.0)
+"###;
+        let mut buf = vec![];
+        python_to_wasm(source, &mut buf).expect("Ok");
+
+        let source = r###"
+def scale(x: float, factor: float = 1.5) -> float:
+    return x * factor
+
+def test() -> float:
+    return scale(2.0, factor=1.0, factor=3.0)
+"###;
+        let error = python_to_wasm(source, &mut buf).expect_err("factor is repeated");
+        assert_eq!(error.message, "Keyword argument factor is repeated");
+        assert_eq!(error.location.unwrap().row, 6);
+
+        let source = r###"
+def scale(x: float, factor: float = 1.5) -> float:
+    return x * factor
+
+def test() -> float:
+    return scale(2.0, offset=1.0)
+"###;
+        let error = python_to_wasm(source, &mut buf).expect_err("offset is unknown");
+        assert_eq!(error.message, "Unexpected keyword argument offset");
+        assert_eq!(error.location.unwrap().row, 6);
+    }
+
+    #[test]
+    fn test_variadic_parameters() {
+        let source = r###"
+def total(*values: int) -> int:
+    result = 0
+    for value in values:
+        result = result + value
+    return result
+
+def test() -> int:
+    xs = [1, 2]
+    return total() + total(1, 2, 3) + total(*xs)
+"###;
+        let mut buf = vec![];
+        python_to_wasm(source, &mut buf).expect("Ok");
+
+        let source = r###"
+def total(*values: int) -> int:
+    return len(values)
+
+def test() -> int:
+    return total(1, 'two')
+"###;
+        let error = python_to_wasm(source, &mut buf).expect_err("'two' is not an int");
+        assert_eq!(error.location.unwrap().row, 6);
+    }
+
+    #[test]
+    fn test_generic_functions() {
+        let source = r###"
+def first[T](xs: list[T]) -> T:
+    return xs[0]
+
+def test() -> int:
+    return first([1, 2]) + first([3]) + len(first(['a']))
+"###;
+        let mut buf = vec![];
+        python_to_wasm(source, &mut buf).expect("Ok");
+        assert!(contains(&buf, "first[int]"));
+        assert!(contains(&buf, "first[str]"));
+
+        let source = r###"
+from typing import TypeVar
+
+T = TypeVar('T')
+
+def same(a: T, b: T) -> T:
+    return a
+
+def test() -> int:
+    return same(1, 'one')
+"###;
+        let error = python_to_wasm(source, &mut buf).expect_err("T cannot be int and str");
+        assert_eq!(error.location.unwrap().row, 10);
+    }
 }
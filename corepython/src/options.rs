@@ -0,0 +1,33 @@
+//! Options which influence code generation.
+
+/// Settings for the compiler.
+#[derive(Clone, Debug)]
+pub struct CompileOptions {
+    /// Check indices against the length of a list or tuple, and
+    /// translate negative indices into positions from the end.
+    ///
+    /// Only disable this when all indices are known to be in range.
+    /// Negative indices are still translated then.
+    pub bounds_checks: bool,
+
+    /// Raise exceptions with the WebAssembly exception handling proposal.
+    ///
+    /// When disabled, a raised exception is stored in the exported global
+    /// `exception`, which is checked after every call that can raise. This
+    /// works on runtimes without exception handling, at the cost of larger code.
+    pub wasm_exceptions: bool,
+
+    /// Compile assert statements. When disabled, as for release builds,
+    /// asserts are only type checked.
+    pub asserts: bool,
+}
+
+impl Default for CompileOptions {
+    fn default() -> Self {
+        CompileOptions {
+            bounds_checks: true,
+            wasm_exceptions: true,
+            asserts: true,
+        }
+    }
+}
@@ -7,14 +7,23 @@ pub struct Program {
 
 #[derive(Debug)]
 pub enum TopLevel {
-    Import { module: String, name: String },
+    Import {
+        location: Location,
+        module: String,
+        name: String,
+    },
     FunctionDef(FunctionDef),
     ClassDef(ClassDef),
+    /// A statement at module level, executed when the module is instantiated.
+    Statement(Statement),
 }
 
-#[derive(Debug)]
+#[derive(Clone, Debug)]
 pub struct FunctionDef {
+    pub location: Location,
     pub name: String,
+    /// The type parameters of a generic function, as in def first[T](xs: list[T]) -> T
+    pub type_parameters: Vec<String>,
     pub parameters: Vec<Parameter>,
     pub result: Option<Expression>,
     pub body: Suite,
@@ -22,19 +31,63 @@ pub struct FunctionDef {
 
 #[derive(Debug)]
 pub struct ClassDef {
+    pub location: Location,
+    pub decorators: Vec<Decorator>,
     pub name: String,
+    pub base: Option<String>,
+    pub fields: Vec<Field>,
+    pub attributes: Vec<ClassAttribute>,
     pub methods: Vec<FunctionDef>,
 }
 
-type Suite = Vec<Statement>;
+/// A decorator of a class, as in @dataclass
+#[derive(Debug)]
+pub struct Decorator {
+    pub location: Location,
+    pub name: String,
+}
 
+/// A field declared in the class body, as in x: int
 #[derive(Debug)]
-pub struct Parameter {
+pub struct Field {
+    pub location: Location,
     pub name: String,
     pub typ: Expression,
 }
 
+/// A class attribute with a value, as in RED = 1
+#[derive(Debug)]
+pub struct ClassAttribute {
+    pub location: Location,
+    pub name: String,
+    pub value: Expression,
+}
+
+/// Something defined in the body of a class.
 #[derive(Debug)]
+pub enum ClassMember {
+    Field(Field),
+    Attribute(ClassAttribute),
+    Method(FunctionDef),
+    /// An empty class body, as in class MyError(ValueError): pass
+    Pass,
+}
+
+type Suite = Vec<Statement>;
+
+#[derive(Clone, Debug)]
+pub struct Parameter {
+    pub location: Location,
+    pub name: String,
+    /// The type annotation, which can only be left out for self and lambda parameters.
+    pub typ: Option<Expression>,
+    /// The value when the argument is left out, as in scale: float = 1.0
+    pub default: Option<Expression>,
+    /// Takes the remaining positional arguments as a list, as in *values: int
+    pub variadic: bool,
+}
+
+#[derive(Clone, Debug)]
 pub enum Statement {
     Return(Expression),
     If {
@@ -47,15 +100,61 @@ pub enum Statement {
         suite: Box<Suite>,
     },
     For {
-        target: String,
+        target: AssignmentTarget,
         iter: Box<Expression>,
         suite: Box<Suite>,
     },
+    /// Run the suite of the first case which matches the subject, as in match command:
+    Match {
+        location: Location,
+        subject: Box<Expression>,
+        cases: Vec<MatchCase>,
+    },
     Assignment {
-        target: String,
+        target: AssignmentTarget,
         value: Box<Expression>,
     },
+    /// Assignment with a type annotation, as in x: dict[str, int] = {}
+    AnnotatedAssignment {
+        target: AssignmentTarget,
+        annotation: Expression,
+        value: Box<Expression>,
+    },
+    Delete {
+        location: Location,
+        target: AssignmentTarget,
+    },
+    /// Raise an exception, or re-raise the exception being handled, as in raise
+    Raise {
+        location: Location,
+        exception: Option<Expression>,
+    },
+    /// Check a condition, as in assert x > 0, 'x must be positive'
+    Assert {
+        location: Location,
+        condition: Expression,
+        message: Option<Expression>,
+    },
+    /// Handle exceptions raised by the suite, the else and finally suites are empty when left out.
+    Try {
+        suite: Box<Suite>,
+        handlers: Vec<ExceptHandler>,
+        else_suite: Box<Suite>,
+        finally_suite: Box<Suite>,
+    },
     Expression(Expression),
+    /// Declare module level variables, so that a function can assign to them.
+    Global {
+        location: Location,
+        names: Vec<String>,
+    },
+    /// Declare variables of an enclosing function, so that a nested function can assign to them.
+    Nonlocal {
+        location: Location,
+        names: Vec<String>,
+    },
+    /// A function in a function, which can use the variables of the enclosing functions.
+    FunctionDef(FunctionDef),
     // AugmentAssignment {
     //     target: String,
     //     op: BinaryOperation,
@@ -66,22 +165,117 @@ pub enum Statement {
     Pass,
 }
 
-#[derive(Debug)]
+/// An except clause of a try statement, as in except ValueError as e:
+#[derive(Clone, Debug)]
+pub struct ExceptHandler {
+    pub location: Location,
+    /// The class or tuple of classes to handle, all exceptions when left out.
+    pub typ: Option<Expression>,
+    pub name: Option<String>,
+    pub suite: Suite,
+}
+
+/// A case of a match statement, as in case Point(x=0) if y > 0:
+#[derive(Clone, Debug)]
+pub struct MatchCase {
+    pub location: Location,
+    pub pattern: Pattern,
+    pub guard: Option<Expression>,
+    pub suite: Suite,
+}
+
+#[derive(Clone, Debug)]
+pub struct Pattern {
+    pub location: Location,
+    pub kind: PatternType,
+}
+
+#[derive(Clone, Debug)]
+pub enum PatternType {
+    /// Compare with a literal or a named constant, as in case 0: or case Color.RED:
+    Value(Expression),
+    /// Bind the subject to a name, as in case x:
+    Capture(String),
+    /// Match anything, as in case _:
+    Wildcard,
+    /// Match any of the alternatives, as in case 1 | 2:
+    Or(Vec<Pattern>),
+    /// Match the elements of a tuple, as in case (x, 0):
+    Sequence(Vec<Pattern>),
+    /// Match an instance of a class and its fields, as in case Point(x=0):
+    Class {
+        class: Expression,
+        arguments: Vec<Pattern>,
+        keywords: Vec<(String, Pattern)>,
+    },
+}
+
+/// Something which can be assigned to.
+#[derive(Clone, Debug)]
+pub enum AssignmentTarget {
+    Identifier(String),
+    Indexed {
+        base: Box<Expression>,
+        index: Box<Expression>,
+    },
+    /// Unpacking of a tuple, as in a, b = b, a
+    Tuple(Vec<AssignmentTarget>),
+    /// Field of an object, as in self.x = x
+    Attribute {
+        base: Box<Expression>,
+        name: String,
+    },
+}
+
+#[derive(Clone, Debug)]
 pub struct Expression {
     pub location: Location,
     pub kind: ExpressionType,
 }
 
-#[derive(Debug)]
+impl Expression {
+    /// Try to use this expression as the left hand side of an assignment.
+    pub fn into_target(self) -> Result<AssignmentTarget, Location> {
+        match self.kind {
+            ExpressionType::Identifier(name) => Ok(AssignmentTarget::Identifier(name)),
+            ExpressionType::Indexed { base, index } => {
+                Ok(AssignmentTarget::Indexed { base, index })
+            }
+            ExpressionType::Attribute { base, name } => {
+                Ok(AssignmentTarget::Attribute { base, name })
+            }
+            ExpressionType::Tuple { elements } => {
+                let targets = elements
+                    .into_iter()
+                    .map(|e| e.into_target())
+                    .collect::<Result<Vec<AssignmentTarget>, Location>>()?;
+                Ok(AssignmentTarget::Tuple(targets))
+            }
+            _ => Err(self.location),
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
 pub enum ExpressionType {
     Number(i32),
     Float(f64),
     Str(String),
+    None,
     // Bool(bool),
     Identifier(String),
     List {
         elements: Vec<Expression>,
     },
+    Tuple {
+        elements: Vec<Expression>,
+    },
+    Dict {
+        entries: Vec<(Expression, Expression)>,
+    },
+    Set {
+        elements: Vec<Expression>,
+    },
     Comparison {
         a: Box<Expression>,
         op: Comparison,
@@ -97,14 +291,37 @@ pub enum ExpressionType {
         op: BooleanOperator,
         b: Box<Expression>,
     },
+    UnaryOperation {
+        op: UnaryOperation,
+        a: Box<Expression>,
+    },
     Call {
         callee: Box<Expression>,
         arguments: Vec<Expression>,
+        /// Arguments passed by name, as in f(3, scale=2.0)
+        keywords: Vec<(String, Expression)>,
     },
     Indexed {
         base: Box<Expression>,
         index: Box<Expression>,
     },
+    Attribute {
+        base: Box<Expression>,
+        name: String,
+    },
+    /// A slice, only valid as index, as in a[lower:upper:step].
+    Slice {
+        lower: Option<Box<Expression>>,
+        upper: Option<Box<Expression>>,
+        step: Option<Box<Expression>>,
+    },
+    /// A list unpacked into the arguments of a call, as in total(*xs)
+    Starred(Box<Expression>),
+    /// An anonymous function, as in lambda x: x * 2
+    Lambda {
+        parameters: Vec<Parameter>,
+        body: Box<Expression>,
+    },
 }
 
 #[derive(Clone, Debug)]
@@ -119,6 +336,13 @@ pub enum BinaryOperation {
     Sub,
     Mul,
     Div,
+    BitOr,
+    BitAnd,
+}
+
+#[derive(Clone, Debug)]
+pub enum UnaryOperation {
+    Minus,
 }
 
 #[derive(Clone, Debug)]
@@ -129,4 +353,8 @@ pub enum Comparison {
     Ge,
     Equal,
     NotEqual,
+    In,
+    NotIn,
+    Is,
+    IsNot,
 }
@@ -8,7 +8,8 @@
 //! Post processing steps:
 //! - White space handling: create indent and dedent tokens.
 //! - Newline counting (for proper source locations)
-//! - Detection of keywords
+//! - Detection of keywords, and of soft keywords like match, which are only
+//!   keywords at the start of a line which ends with a colon
 
 use super::location::Location;
 use super::token::Token;
@@ -29,7 +30,7 @@ fn hex_number(txt: &str) -> i32 {
 
 #[derive(Logos, Debug, Clone)]
 pub enum LogosToken {
-    #[regex("[a-zA-Z][a-zA-Z0-9_]*", |l| l.slice().to_string())]
+    #[regex("[a-zA-Z_][a-zA-Z0-9_]*", |l| l.slice().to_string())]
     Identifier(String),
 
     #[regex("[0-9]+", |l| i32::from_str(l.slice()))]
@@ -47,8 +48,8 @@ pub enum LogosToken {
     #[regex("'[^']+'", |l| shrink_string(l.slice()))]
     SmallString(String),
 
-    #[regex(r#"#.+\n"#, |l| l.slice().to_string())]
-    Comment(String),
+    #[regex(r#"#.+\n"#)]
+    Comment,
 
     #[token(":")]
     Colon,
@@ -74,12 +75,21 @@ pub enum LogosToken {
     #[token(",")]
     Comma,
 
+    #[token(".")]
+    Dot,
+
     #[token("+")]
     Plus,
 
     #[token("-")]
     Minus,
 
+    #[token("|")]
+    Pipe,
+
+    #[token("&")]
+    Ampersand,
+
     #[token("*")]
     Asterix,
 
@@ -110,6 +120,9 @@ pub enum LogosToken {
     #[token("->")]
     Arrow,
 
+    #[token("@")]
+    At,
+
     #[token("\\\n")]
     BackslashNewLine,
 
@@ -142,28 +155,52 @@ pub struct MyLexer<'t> {
     at_end: bool,
     at_bol: bool, // begin of line
     keywords: HashMap<String, Token>,
+    /// Index in pending of a soft keyword, which is decided at the end of its line.
+    soft_keyword: Option<usize>,
 }
 
 fn get_keyword_map() -> HashMap<String, Token> {
     let mut keywords = HashMap::new();
     keywords.insert("and".to_owned(), Token::KeywordAnd);
+    keywords.insert("as".to_owned(), Token::KeywordAs);
+    keywords.insert("assert".to_owned(), Token::KeywordAssert);
     keywords.insert("break".to_owned(), Token::KeywordBreak);
     keywords.insert("class".to_owned(), Token::KeywordClass);
     keywords.insert("continue".to_owned(), Token::KeywordContinue);
     keywords.insert("def".to_owned(), Token::KeywordDef);
+    keywords.insert("del".to_owned(), Token::KeywordDel);
     keywords.insert("else".to_owned(), Token::KeywordElse);
+    keywords.insert("except".to_owned(), Token::KeywordExcept);
+    keywords.insert("finally".to_owned(), Token::KeywordFinally);
     keywords.insert("for".to_owned(), Token::KeywordFor);
     keywords.insert("from".to_owned(), Token::KeywordFrom);
+    keywords.insert("global".to_owned(), Token::KeywordGlobal);
     keywords.insert("if".to_owned(), Token::KeywordIf);
     keywords.insert("import".to_owned(), Token::KeywordImport);
     keywords.insert("in".to_owned(), Token::KeywordIn);
+    keywords.insert("is".to_owned(), Token::KeywordIs);
+    keywords.insert("lambda".to_owned(), Token::KeywordLambda);
+    keywords.insert("None".to_owned(), Token::KeywordNone);
+    keywords.insert("nonlocal".to_owned(), Token::KeywordNonlocal);
+    keywords.insert("not".to_owned(), Token::KeywordNot);
     keywords.insert("or".to_owned(), Token::KeywordOr);
     keywords.insert("pass".to_owned(), Token::KeywordPass);
+    keywords.insert("raise".to_owned(), Token::KeywordRaise);
     keywords.insert("return".to_owned(), Token::KeywordReturn);
+    keywords.insert("try".to_owned(), Token::KeywordTry);
     keywords.insert("while".to_owned(), Token::KeywordWhile);
     keywords
 }
 
+/// Names which are only keywords at the start of a statement, as in match x:
+fn get_soft_keyword(name: &str) -> Option<Token> {
+    match name {
+        "match" => Some(Token::KeywordMatch),
+        "case" => Some(Token::KeywordCase),
+        _ => None,
+    }
+}
+
 impl<'t> MyLexer<'t> {
     pub fn new(txt: &'t str) -> Self {
         let inner = LogosToken::lexer(txt);
@@ -180,6 +217,7 @@ impl<'t> MyLexer<'t> {
             at_end: false,
             at_bol: true,
             keywords,
+            soft_keyword: None,
         }
     }
 
@@ -251,9 +289,13 @@ impl<'t> MyLexer<'t> {
             match t {
                 LogosToken::Colon => self.emit(Token::Colon),
                 LogosToken::Comma => self.emit(Token::Comma),
+                LogosToken::Dot => self.emit(Token::Dot),
                 LogosToken::Minus => self.emit(Token::Minus),
+                LogosToken::Pipe => self.emit(Token::Pipe),
+                LogosToken::Ampersand => self.emit(Token::Ampersand),
                 LogosToken::Plus => self.emit(Token::Plus),
                 LogosToken::Arrow => self.emit(Token::Arrow),
+                LogosToken::At => self.emit(Token::At),
                 LogosToken::Asterix => self.emit(Token::Asterix),
                 LogosToken::Slash => self.emit(Token::Slash),
                 LogosToken::Less => self.emit(Token::Less),
@@ -264,27 +306,27 @@ impl<'t> MyLexer<'t> {
                 LogosToken::NotEqual => self.emit(Token::NotEqual),
                 LogosToken::Equal => self.emit(Token::Equal),
                 LogosToken::OpeningBracket => {
-                    self.parenthesis_level += 1;
                     self.emit(Token::OpeningBracket);
+                    self.parenthesis_level += 1;
                 }
                 LogosToken::ClosingBracket => {
                     self.parenthesis_level -= 1;
                     self.emit(Token::ClosingBracket);
                 }
                 LogosToken::OpeningParenthesis => {
-                    self.parenthesis_level += 1;
                     self.emit(Token::OpeningParenthesis);
+                    self.parenthesis_level += 1;
                 }
                 LogosToken::ClosingParenthesis => {
                     self.parenthesis_level -= 1;
                     self.emit(Token::ClosingParenthesis);
                 }
                 LogosToken::OpeningBrace => {
-                    self.parenthesis_level += 1;
                     self.emit(Token::OpeningBrace);
+                    self.parenthesis_level += 1;
                 }
                 LogosToken::ClosingBrace => {
-                    self.parenthesis_level += 1;
+                    self.parenthesis_level -= 1;
                     self.emit(Token::ClosingBrace);
                 }
                 LogosToken::NewLine => {
@@ -304,7 +346,11 @@ impl<'t> MyLexer<'t> {
                     if self.keywords.contains_key(&value) {
                         self.emit(self.keywords[&value].clone());
                     } else {
-                        self.emit(Token::Identifier { value })
+                        let at_start = self.at_bol && self.parenthesis_level == 0;
+                        self.emit(Token::Identifier { value });
+                        if at_start {
+                            self.soft_keyword = Some(self.pending.len() - 1);
+                        }
                     }
                 }
                 LogosToken::Number(value) | LogosToken::HexNumber(value) => {
@@ -316,8 +362,7 @@ impl<'t> MyLexer<'t> {
                     // warn!("Ignoring assumed doc-string {}", value);
                     self.emit(Token::Str { value })
                 }
-                LogosToken::Comment(_) => {
-                    // info!("Skipping comment {}", value);
+                LogosToken::Comment => {
                     self.newline();
                 }
                 LogosToken::Error => {
@@ -345,7 +390,8 @@ impl<'t> MyLexer<'t> {
     }
 
     fn newline(&mut self) {
-        if !self.at_bol {
+        // Expressions within brackets can span multiple lines:
+        if !self.at_bol && self.parenthesis_level == 0 {
             let span = self.inner.span();
             self.emit_spanned(Token::NewLine, span);
         }
@@ -355,6 +401,23 @@ impl<'t> MyLexer<'t> {
         self.row += 1;
     }
 
+    /// An identifier at the start of a line is a soft keyword when the line
+    /// ends with a colon, as in match command: but not in match = 1
+    fn resolve_soft_keyword(&mut self, index: usize) {
+        let end = self.pending[index..]
+            .iter()
+            .position(|(_, token, _)| matches!(token, Token::NewLine))
+            .map_or(self.pending.len(), |offset| index + offset);
+        if end < index + 2 || !matches!(self.pending[end - 1].1, Token::Colon) {
+            return;
+        }
+        if let Token::Identifier { value } = &self.pending[index].1 {
+            if let Some(keyword) = get_soft_keyword(value) {
+                self.pending[index].1 = keyword;
+            }
+        }
+    }
+
     fn indent(&mut self, new_indentation: usize, location: Location) {
         self.indentations.push(new_indentation);
         let spanned = (location.clone(), Token::Indent, location);
@@ -372,11 +435,23 @@ impl<'t> std::iter::Iterator for MyLexer<'t> {
     type Item = Spanned<Token, LexicalError>;
 
     fn next(&mut self) -> Option<Self::Item> {
-        while self.pending.is_empty() && !self.at_end {
+        while (self.pending.is_empty() || self.soft_keyword.is_some()) && !self.at_end {
+            // A soft keyword needs the rest of its line:
+            if let Some(index) = self.soft_keyword {
+                if self.pending[index..]
+                    .iter()
+                    .any(|(_, token, _)| matches!(token, Token::NewLine))
+                {
+                    break;
+                }
+            }
             if let Err(err) = self.process() {
                 return Some(Err(err));
             }
         }
+        if let Some(index) = self.soft_keyword.take() {
+            self.resolve_soft_keyword(index);
+        }
 
         if self.pending.is_empty() {
             None
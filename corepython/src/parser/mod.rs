@@ -25,7 +25,7 @@ use crate::CompilationError;
 use lexer::MyLexer;
 
 pub fn parse_python(source: &str) -> Result<ast::Program, CompilationError> {
-    let lexer = MyLexer::new(&source);
+    let lexer = MyLexer::new(source);
 
     corepython::ProgramParser::new()
         .parse(lexer)
@@ -73,4 +73,31 @@ def foo():
         let error = parse_python(source).expect_err("Indentation");
         assert_eq!(error.location.unwrap().row, 4);
     }
+
+    #[test]
+    fn test_bad_assignment_target() {
+        let source = r###"
+def foo():
+    a[1] = 2
+    foo() = 2
+        "###;
+        let error = parse_python(source).expect_err("Assignment to call");
+        assert_eq!(error.location.unwrap().row, 4);
+    }
+
+    #[test]
+    fn test_soft_keywords() {
+        let source = r###"
+def foo(match: int) -> int:
+    case = match
+    match case:
+        case 1:
+            return match
+        case _:
+            return case
+    match case + 1
+        "###;
+        let error = parse_python(source).expect_err("match without colon");
+        assert_eq!(error.location.unwrap().row, 9);
+    }
 }
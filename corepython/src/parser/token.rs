@@ -3,19 +3,34 @@ pub enum Token {
     Indent,
     Dedent,
     KeywordAnd,
+    KeywordAs,
+    KeywordAssert,
     KeywordBreak,
+    KeywordCase,
     KeywordClass,
     KeywordContinue,
     KeywordDef,
+    KeywordDel,
     KeywordElse,
+    KeywordExcept,
+    KeywordFinally,
     KeywordFor,
     KeywordFrom,
+    KeywordGlobal,
     KeywordIf,
     KeywordIn,
     KeywordImport,
+    KeywordIs,
+    KeywordLambda,
+    KeywordMatch,
+    KeywordNone,
+    KeywordNonlocal,
+    KeywordNot,
     KeywordOr,
     KeywordPass,
+    KeywordRaise,
     KeywordReturn,
+    KeywordTry,
     KeywordWhile,
     Identifier { value: String },
     Number { value: i32 },
@@ -29,8 +44,11 @@ pub enum Token {
     OpeningBracket,
     ClosingBracket,
     Comma,
+    Dot,
     Plus,
     Minus,
+    Pipe,
+    Ampersand,
     Asterix,
     Slash,
     Less,
@@ -41,5 +59,6 @@ pub enum Token {
     NotEqual,
     Equal,
     Arrow,
+    At,
     NewLine,
 }
@@ -0,0 +1,55 @@
+class Exception:
+    message: str
+    lineno: int
+
+    def __init__(self, message: str):
+        self.message = message
+        self.lineno = 0
+
+    def __str__(self) -> str:
+        return self.message
+
+
+class ArithmeticError(Exception):
+    pass
+
+
+class OverflowError(ArithmeticError):
+    pass
+
+
+class ZeroDivisionError(ArithmeticError):
+    pass
+
+
+class AssertionError(Exception):
+    pass
+
+
+class LookupError(Exception):
+    pass
+
+
+class IndexError(LookupError):
+    pass
+
+
+class KeyError(LookupError):
+    def __str__(self) -> str:
+        return repr(self.message)
+
+
+class RuntimeError(Exception):
+    pass
+
+
+class NotImplementedError(RuntimeError):
+    pass
+
+
+class TypeError(Exception):
+    pass
+
+
+class ValueError(Exception):
+    pass
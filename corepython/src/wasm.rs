@@ -3,6 +3,12 @@ pub struct WasmModule {
     imports: Vec<Import>,
     exports: Vec<Export>,
     functions: Vec<Function>,
+    globals: Vec<Global>,
+    /// The type index of each exception tag.
+    tags: Vec<usize>,
+    start: Option<usize>,
+    elements: Vec<Elements>,
+    data: Vec<Data>,
 }
 
 impl WasmModule {
@@ -12,9 +18,19 @@ impl WasmModule {
             imports: vec![],
             exports: vec![],
             functions: vec![],
+            globals: vec![],
+            tags: vec![],
+            start: None,
+            elements: vec![],
+            data: vec![],
         }
     }
 
+    /// Add initial memory content at the given address.
+    pub fn add_data(&mut self, offset: usize, bytes: Vec<u8>) {
+        self.data.push(Data { offset, bytes });
+    }
+
     pub fn add_import(&mut self, modname: &str, name: &str, params: Vec<Type>, results: Vec<Type>) {
         // TODO: determine type of imported function.
         let type_index = self.add_type(params, results);
@@ -26,13 +42,50 @@ impl WasmModule {
         });
     }
 
-    fn add_type(&mut self, params: Vec<Type>, results: Vec<Type>) -> usize {
+    /// Add a global variable with an initial value, and return its global index.
+    pub fn add_global(&mut self, typ: Type, mutable: bool, init: Instruction) -> usize {
+        self.globals.push(Global { typ, mutable, init });
+        self.globals.len() - 1
+    }
+
+    /// Add a tag for exceptions with values of the given types, and return its tag index.
+    pub fn add_tag(&mut self, params: Vec<Type>) -> usize {
+        let type_index = self.add_type(params, vec![]);
+        self.tags.push(type_index);
+        self.tags.len() - 1
+    }
+
+    /// Run a function when the module is instantiated.
+    pub fn set_start(&mut self, index: usize) {
+        self.start = Some(index);
+    }
+
+    /// Add initial table content at the given table index.
+    pub fn add_elements(&mut self, offset: usize, functions: Vec<usize>) {
+        self.elements.push(Elements { offset, functions });
+    }
+
+    /// Get the index of a function type, as used by call_indirect.
+    pub fn add_type(&mut self, params: Vec<Type>, results: Vec<Type>) -> usize {
+        let signature = (params, results);
+        if let Some(index) = self.types.iter().position(|t| *t == signature) {
+            return index;
+        }
         let index = self.types.len();
-        // TODO: re-use types!
-        self.types.push((params, results));
+        self.types.push(signature);
         index
     }
 
+    /// The table holds all functions in element segments.
+    fn get_table_size(&self) -> usize {
+        self.elements
+            .iter()
+            .map(|e| e.offset + e.functions.len())
+            .max()
+            .unwrap_or(0)
+    }
+
+    /// Add a function to the module, and return its function index.
     pub fn add_function(
         &mut self,
         name: String,
@@ -40,18 +93,73 @@ impl WasmModule {
         results: Vec<Type>,
         locals: Vec<Type>,
         code: Vec<Instruction>,
-    ) {
+    ) -> usize {
         let type_index = self.add_type(params, results);
 
         // Meh: kind of sucks to add import.len here:
         let index = self.functions.len() + self.imports.len();
 
-        self.exports.push(Export { name, index });
         self.functions.push(Function {
+            name,
             type_index,
             locals,
             code,
         });
+        index
+    }
+
+    /// Find a function by name, and return its function index and code.
+    #[cfg(test)]
+    pub fn get_function(&self, name: &str) -> Option<(usize, &[Instruction])> {
+        let position = self.functions.iter().position(|f| f.name == name)?;
+        let code = &self.functions[position].code;
+        Some((position + self.imports.len(), code))
+    }
+
+    /// The instructions of all functions in the module.
+    #[cfg(test)]
+    pub fn instructions(&self) -> impl Iterator<Item = &Instruction> {
+        self.functions.iter().flat_map(|f| f.code.iter())
+    }
+
+    #[cfg(test)]
+    pub fn tag_count(&self) -> usize {
+        self.tags.len()
+    }
+
+    pub fn add_export(&mut self, name: String, index: usize) {
+        self.exports.push(Export {
+            name,
+            kind: ExportType::Func,
+            index,
+        });
+    }
+
+    /// Export a tag, so that the host can recognize the exceptions thrown with it.
+    pub fn export_tag(&mut self, name: String, index: usize) {
+        self.exports.push(Export {
+            name,
+            kind: ExportType::Tag,
+            index,
+        });
+    }
+
+    /// Export a global, so that the host can read it.
+    pub fn export_global(&mut self, name: String, index: usize) {
+        self.exports.push(Export {
+            name,
+            kind: ExportType::Global,
+            index,
+        });
+    }
+
+    /// Export the memory, so that the host can read strings and lists.
+    pub fn export_memory(&mut self, name: String) {
+        self.exports.push(Export {
+            name,
+            kind: ExportType::Memory,
+            index: 0,
+        });
     }
 }
 
@@ -66,6 +174,9 @@ enum ImportType {
 }
 
 struct Function {
+    /// Name, used in the name section for debugging purposes.
+    name: String,
+
     /// Type index:
     type_index: usize,
     code: Vec<Instruction>,
@@ -74,9 +185,34 @@ struct Function {
 
 struct Export {
     name: String,
+    kind: ExportType,
     index: usize,
 }
 
+enum ExportType {
+    Func,
+    Memory,
+    Global,
+    Tag,
+}
+
+struct Global {
+    typ: Type,
+    mutable: bool,
+    /// A constant instruction, such as I32Const.
+    init: Instruction,
+}
+
+struct Elements {
+    offset: usize,
+    functions: Vec<usize>,
+}
+
+struct Data {
+    offset: usize,
+    bytes: Vec<u8>,
+}
+
 struct Writer<'w, W>
 where
     W: std::io::Write,
@@ -106,9 +242,16 @@ where
         self.write_type_section(&wasm)?;
         self.write_import_section(&wasm)?;
         self.write_func_section(&wasm)?;
+        self.write_table_section(&wasm)?;
         self.write_memory_section(&wasm)?;
+        self.write_tag_section(&wasm)?;
+        self.write_global_section(&wasm)?;
         self.write_export_section(&wasm)?;
+        self.write_start_section(&wasm)?;
+        self.write_element_section(&wasm)?;
         self.write_code_section(&wasm)?;
+        self.write_data_section(&wasm)?;
+        self.write_name_section(&wasm)?;
 
         Ok(())
     }
@@ -192,6 +335,24 @@ where
         Ok(())
     }
 
+    fn write_table_section(&mut self, module: &WasmModule) -> Result<(), std::io::Error> {
+        if module.elements.is_empty() {
+            return Ok(());
+        }
+
+        let mut buf: Vec<u8> = vec![];
+        let mut w2 = Writer::new(&mut buf);
+        // 1 table with function references, and no max:
+        w2.write_vu32(1)?;
+        w2.write_byte(0x70)?;
+        w2.write_byte(0x0)?;
+        w2.write_vu32(module.get_table_size() as u32)?;
+
+        self.write_section(4, &buf)?;
+
+        Ok(())
+    }
+
     fn write_memory_section(&mut self, module: &WasmModule) -> Result<(), std::io::Error> {
         let mut buf: Vec<u8> = vec![];
         let mut w2 = Writer::new(&mut buf);
@@ -223,6 +384,45 @@ where
         Ok(())
     }
 
+    fn write_tag_section(&mut self, module: &WasmModule) -> Result<(), std::io::Error> {
+        if module.tags.is_empty() {
+            return Ok(());
+        }
+
+        let mut buf: Vec<u8> = vec![];
+        let mut w2 = Writer::new(&mut buf);
+        w2.write_vu32(module.tags.len() as u32)?;
+        for type_index in &module.tags {
+            // Attribute 0 is an exception:
+            w2.write_byte(0)?;
+            w2.write_index(*type_index)?;
+        }
+
+        self.write_section(13, &buf)?;
+
+        Ok(())
+    }
+
+    fn write_global_section(&mut self, module: &WasmModule) -> Result<(), std::io::Error> {
+        if module.globals.is_empty() {
+            return Ok(());
+        }
+
+        let mut buf: Vec<u8> = vec![];
+        let mut w2 = Writer::new(&mut buf);
+        w2.write_vu32(module.globals.len() as u32)?;
+        for global in &module.globals {
+            w2.write_type(&global.typ)?;
+            w2.write_byte(if global.mutable { 1 } else { 0 })?;
+            w2.write_instruction(&global.init)?;
+            w2.write_instruction(&Instruction::End)?;
+        }
+
+        self.write_section(6, &buf)?;
+
+        Ok(())
+    }
+
     fn write_export_section(&mut self, module: &WasmModule) -> Result<(), std::io::Error> {
         let mut buf: Vec<u8> = vec![];
         let mut w2 = Writer::new(&mut buf);
@@ -237,17 +437,57 @@ where
         self.write_vu32(module.exports.len() as u32)?;
         for export in &module.exports {
             self.write_str(&export.name)?;
-            let kind = 0; // TODO: for now..
+            let kind = match export.kind {
+                ExportType::Func => 0,
+                ExportType::Memory => 2,
+                ExportType::Global => 3,
+                ExportType::Tag => 4,
+            };
             self.write_byte(kind)?;
             self.write_index(export.index)?;
         }
         Ok(())
     }
 
+    fn write_start_section(&mut self, module: &WasmModule) -> Result<(), std::io::Error> {
+        if let Some(start) = module.start {
+            let mut buf: Vec<u8> = vec![];
+            let mut w2 = Writer::new(&mut buf);
+            w2.write_index(start)?;
+            self.write_section(8, &buf)?;
+        }
+
+        Ok(())
+    }
+
+    fn write_element_section(&mut self, module: &WasmModule) -> Result<(), std::io::Error> {
+        if module.elements.is_empty() {
+            return Ok(());
+        }
+
+        let mut buf: Vec<u8> = vec![];
+        let mut w2 = Writer::new(&mut buf);
+        w2.write_vu32(module.elements.len() as u32)?;
+        for elements in &module.elements {
+            // Active segment in table 0:
+            w2.write_byte(0)?;
+            w2.write_instruction(&Instruction::I32Const(elements.offset as i32))?;
+            w2.write_instruction(&Instruction::End)?;
+            w2.write_vu32(elements.functions.len() as u32)?;
+            for function in &elements.functions {
+                w2.write_index(*function)?;
+            }
+        }
+
+        self.write_section(9, &buf)?;
+
+        Ok(())
+    }
+
     fn write_code_section(&mut self, module: &WasmModule) -> Result<(), std::io::Error> {
         let mut buf: Vec<u8> = vec![];
         let mut w2 = Writer::new(&mut buf);
-        w2.write_function_defs(&module)?;
+        w2.write_function_defs(module)?;
 
         self.write_section(10, &buf)?;
 
@@ -267,6 +507,65 @@ where
         Ok(())
     }
 
+    fn write_data_section(&mut self, module: &WasmModule) -> Result<(), std::io::Error> {
+        if module.data.is_empty() {
+            return Ok(());
+        }
+
+        let mut buf: Vec<u8> = vec![];
+        let mut w2 = Writer::new(&mut buf);
+        w2.write_data(module)?;
+
+        self.write_section(11, &buf)?;
+
+        Ok(())
+    }
+
+    fn write_data(&mut self, module: &WasmModule) -> Result<(), std::io::Error> {
+        self.write_vu32(module.data.len() as u32)?;
+        for data in &module.data {
+            // Active segment in memory 0:
+            self.write_byte(0)?;
+            self.write_instruction(&Instruction::I32Const(data.offset as i32))?;
+            self.write_instruction(&Instruction::End)?;
+            self.write_blob(&data.bytes)?;
+        }
+        Ok(())
+    }
+
+    /// Write custom name section, so that runtimes can show
+    /// function names in stack traces.
+    fn write_name_section(&mut self, module: &WasmModule) -> Result<(), std::io::Error> {
+        let mut buf: Vec<u8> = vec![];
+        let mut w2 = Writer::new(&mut buf);
+        w2.write_str("name")?;
+        w2.write_function_names(module)?;
+
+        self.write_section(0, &buf)?;
+
+        Ok(())
+    }
+
+    fn write_function_names(&mut self, module: &WasmModule) -> Result<(), std::io::Error> {
+        let mut buf: Vec<u8> = vec![];
+        let mut w2 = Writer::new(&mut buf);
+        let num_names = module.imports.len() + module.functions.len();
+        w2.write_vu32(num_names as u32)?;
+        for (index, import) in module.imports.iter().enumerate() {
+            w2.write_index(index)?;
+            w2.write_str(&import.name)?;
+        }
+        for (index, function) in module.functions.iter().enumerate() {
+            w2.write_index(index + module.imports.len())?;
+            w2.write_str(&function.name)?;
+        }
+
+        // Subsection 1 contains function names:
+        self.write_byte(1)?;
+        self.write_blob(&buf)?;
+        Ok(())
+    }
+
     fn write_header(&mut self) -> Result<(), std::io::Error> {
         self.buffer.write_all(b"\x00asm")?;
         self.write_u32(1)?;
@@ -296,6 +595,7 @@ where
 
     fn write_instruction(&mut self, opcode: &Instruction) -> Result<(), std::io::Error> {
         match opcode {
+            Instruction::Unreachable => self.write_byte(0x00)?,
             // Instruction::Nop => self.write_byte(0x01)?,
             Instruction::Block => {
                 self.write_byte(0x02)?;
@@ -310,6 +610,23 @@ where
                 self.write_byte(0x40)?;
             }
             Instruction::Else => self.write_byte(0x05)?,
+            Instruction::Try => {
+                self.write_byte(0x06)?;
+                self.write_byte(0x40)?;
+            }
+            Instruction::Catch(tag) => {
+                self.write_byte(0x07)?;
+                self.write_index(*tag)?;
+            }
+            Instruction::Throw(tag) => {
+                self.write_byte(0x08)?;
+                self.write_index(*tag)?;
+            }
+            Instruction::Rethrow(label) => {
+                self.write_byte(0x09)?;
+                self.write_index(*label)?;
+            }
+            Instruction::CatchAll => self.write_byte(0x19)?,
             Instruction::End => self.write_byte(0x0B)?,
             Instruction::Br(label) => {
                 self.write_byte(0x0C)?;
@@ -319,13 +636,29 @@ where
                 self.write_byte(0x0D)?;
                 self.write_index(*label)?;
             }
+            Instruction::BrTable(labels, default) => {
+                self.write_byte(0x0E)?;
+                self.write_vu32(labels.len() as u32)?;
+                for label in labels {
+                    self.write_index(*label)?;
+                }
+                self.write_index(*default)?;
+            }
             Instruction::Call(func) => {
                 self.write_byte(0x10)?;
                 self.write_index(*func)?;
             }
+            Instruction::CallIndirect(type_index) => {
+                self.write_byte(0x11)?;
+                self.write_index(*type_index)?;
+                self.write_byte(0x00)?; // table 0
+            }
             Instruction::Drp => {
                 self.write_byte(0x1A)?;
             }
+            Instruction::Select => {
+                self.write_byte(0x1B)?;
+            }
             Instruction::LocalGet(index) => {
                 self.write_byte(0x20)?;
                 self.write_index(*index)?;
@@ -334,10 +667,18 @@ where
                 self.write_byte(0x21)?;
                 self.write_index(*index)?;
             }
-            // Instruction::LocalTee(index) => {
-            //     self.write_byte(0x22)?;
-            //     self.write_index(*index)?;
-            // }
+            Instruction::LocalTee(index) => {
+                self.write_byte(0x22)?;
+                self.write_index(*index)?;
+            }
+            Instruction::GlobalGet(index) => {
+                self.write_byte(0x23)?;
+                self.write_index(*index)?;
+            }
+            Instruction::GlobalSet(index) => {
+                self.write_byte(0x24)?;
+                self.write_index(*index)?;
+            }
             Instruction::I32Load(align, offset) => {
                 self.write_byte(0x28)?;
                 self.write_index(*align)?;
@@ -348,6 +689,11 @@ where
                 self.write_index(*align)?;
                 self.write_index(*offset)?;
             }
+            Instruction::I32Load8U(align, offset) => {
+                self.write_byte(0x2D)?;
+                self.write_index(*align)?;
+                self.write_index(*offset)?;
+            }
             Instruction::I32Store(align, offset) => {
                 self.write_byte(0x36)?;
                 self.write_index(*align)?;
@@ -358,6 +704,11 @@ where
                 self.write_index(*align)?;
                 self.write_index(*offset)?;
             }
+            Instruction::I32Store8(align, offset) => {
+                self.write_byte(0x3A)?;
+                self.write_index(*align)?;
+                self.write_index(*offset)?;
+            }
             Instruction::Return => {
                 self.write_byte(0x0F)?;
             }
@@ -381,16 +732,31 @@ where
             Instruction::I32LtS => {
                 self.write_byte(0x48)?;
             }
+            Instruction::I32LtU => {
+                self.write_byte(0x49)?;
+            }
             Instruction::I32GtS => {
                 self.write_byte(0x4A)?;
             }
             Instruction::I32LeS => {
                 self.write_byte(0x4C)?;
             }
+            Instruction::I32LeU => {
+                self.write_byte(0x4D)?;
+            }
             Instruction::I32GeS => {
                 self.write_byte(0x4E)?;
             }
+            Instruction::I32GeU => {
+                self.write_byte(0x4F)?;
+            }
 
+            Instruction::F64Eq => {
+                self.write_byte(0x61)?;
+            }
+            Instruction::F64Ne => {
+                self.write_byte(0x62)?;
+            }
             Instruction::F64Lt => {
                 self.write_byte(0x63)?;
             }
@@ -416,6 +782,12 @@ where
             Instruction::I32DivS => {
                 self.write_byte(0x6D)?;
             }
+            Instruction::I32DivU => {
+                self.write_byte(0x6E)?;
+            }
+            Instruction::I32RemU => {
+                self.write_byte(0x70)?;
+            }
 
             Instruction::I32And => {
                 self.write_byte(0x71)?;
@@ -423,7 +795,28 @@ where
             Instruction::I32Or => {
                 self.write_byte(0x72)?;
             }
+            Instruction::I32Xor => {
+                self.write_byte(0x73)?;
+            }
+            Instruction::I32Shl => {
+                self.write_byte(0x74)?;
+            }
+            Instruction::I32ShrU => {
+                self.write_byte(0x76)?;
+            }
 
+            Instruction::F64Abs => {
+                self.write_byte(0x99)?;
+            }
+            Instruction::F64Neg => {
+                self.write_byte(0x9A)?;
+            }
+            Instruction::F64Floor => {
+                self.write_byte(0x9C)?;
+            }
+            Instruction::F64Nearest => {
+                self.write_byte(0x9E)?;
+            }
             Instruction::F64Add => {
                 self.write_byte(0xA0)?;
             }
@@ -436,6 +829,13 @@ where
             Instruction::F64Div => {
                 self.write_byte(0xA3)?;
             }
+
+            Instruction::I32TruncF64U => {
+                self.write_byte(0xAB)?;
+            }
+            Instruction::F64ConvertI32U => {
+                self.write_byte(0xB8)?;
+            }
         }
 
         Ok(())
@@ -459,7 +859,7 @@ where
 
     fn write_blob(&mut self, buf: &[u8]) -> Result<(), std::io::Error> {
         self.write_vu32(buf.len() as u32)?;
-        self.buffer.write_all(&buf)?;
+        self.buffer.write_all(buf)?;
         Ok(())
     }
 
@@ -500,36 +900,56 @@ pub fn write_wasm<W: std::io::Write>(wasm: WasmModule, buf: &mut W) -> Result<()
 
 #[derive(Debug)]
 pub enum Instruction {
+    Unreachable,
     // Nop,
     Block,
     Loop,
     If,
     Else,
+    /// Start a block with exception handlers, from the exception handling proposal.
+    Try,
+    /// Handle the exceptions with this tag, their values are pushed on the stack.
+    Catch(usize),
+    /// Handle all exceptions, including those from the host.
+    CatchAll,
+    Throw(usize),
+    /// Throw the exception caught by the handler of this label again.
+    Rethrow(usize),
     End,
     Br(usize),
     BrIf(usize),
+    /// Branch to the label selected by the index on the stack, or the default.
+    BrTable(Vec<usize>, usize),
     Call(usize),
+    CallIndirect(usize),
     Drp,
+    Select,
     LocalGet(usize),
     LocalSet(usize),
-    // LocalTee(usize),
+    LocalTee(usize),
+    GlobalGet(usize),
+    GlobalSet(usize),
     I32Load(usize, usize),
     F64Load(usize, usize),
+    I32Load8U(usize, usize),
     I32Store(usize, usize),
     F64Store(usize, usize),
+    I32Store8(usize, usize),
     I32Const(i32),
     F64Const(f64),
     I32Eqz,
     I32Eq,
     I32Ne,
     I32LtS,
-    // I32Lt_u,
+    I32LtU,
     I32GtS,
     // I32Gt_u,
     I32LeS,
-    // I32Le_u,
+    I32LeU,
     I32GeS,
-    // I32Ge_u,
+    I32GeU,
+    F64Eq,
+    F64Ne,
     F64Lt,
     F64Gt,
     F64Le,
@@ -538,12 +958,23 @@ pub enum Instruction {
     I32Sub,
     I32Mul,
     I32DivS,
+    I32DivU,
+    I32RemU,
     I32And,
     I32Or,
-
+    I32Xor,
+    I32Shl,
+    I32ShrU,
+
+    F64Abs,
+    F64Neg,
+    F64Floor,
+    F64Nearest,
     F64Add,
     F64Sub,
     F64Mul,
     F64Div,
+    I32TruncF64U,
+    F64ConvertI32U,
     Return,
 }
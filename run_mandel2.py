@@ -1,3 +1,5 @@
+# ppci has no exception handling, so compile mandel2.py first with:
+#   cargo run -- --no-wasm-exceptions mandel2.py
 
 from ppci.wasm import instantiate, read_wasm
 